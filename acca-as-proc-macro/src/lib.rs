@@ -52,7 +52,7 @@ impl Argument {
 fn instruction_helper(item: TokenStream) -> (proc_macro2::TokenStream, proc_macro2::Ident) {
 	let all_tokens: Vec<_> = item.into_iter().collect();
 
-	if all_tokens.len() == 0 {
+	if all_tokens.is_empty() {
 		panic!("Expected an instruction name");
 	}
 
@@ -193,7 +193,7 @@ fn instruction_helper(item: TokenStream) -> (proc_macro2::TokenStream, proc_macr
 		};
 
 		let mut argtype = if typestring.starts_with("imm") || typestring.starts_with("rel") {
-			match (&typestring[3..]).parse::<u64>() {
+			match typestring[3..].parse::<u64>() {
 				Ok(width) if typestring.starts_with("imm") => ArgumentType::Immediate(width),
 				Ok(width) => ArgumentType::RelativeImmediate(width),
 				Err(_) => panic!("Invalid immediate width"),
@@ -227,7 +227,7 @@ fn instruction_helper(item: TokenStream) -> (proc_macro2::TokenStream, proc_macr
 					} else if alt_typestring.starts_with("imm") || alt_typestring.starts_with("rel")
 					{
 						match argtype {
-							ArgumentType::Register => match (&alt_typestring[3..]).parse::<u64>() {
+							ArgumentType::Register => match alt_typestring[3..].parse::<u64>() {
 								Ok(width) if alt_typestring.starts_with("imm") => ArgumentType::RegisterOrImmediate(width),
 								Ok(width) => ArgumentType::RegisterOrRelativeImmediate(width),
 								Err(_) => panic!("Invalid immediate width"),
@@ -397,12 +397,12 @@ fn instruction_helper(item: TokenStream) -> (proc_macro2::TokenStream, proc_macr
 				Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Bracket => {
 					let stream: proc_macro2::TokenStream = group.stream().into();
 
-					quote_spanned!(source_span=> instruction_body! { write_instruction #(#default_pattern),* ; #size_ident #cond_ident [ #stream ] })
+					quote_spanned!(source_span=> instruction_body! { write_instruction register_size #(#default_pattern),* ; #size_ident #cond_ident [ #stream ] })
 				},
 				Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Brace => {
 					let stream: proc_macro2::TokenStream = group.stream().into();
 
-					quote_spanned!(source_span=> instruction_body! { write_instruction #size_ident #cond_ident { #stream }})
+					quote_spanned!(source_span=> instruction_body! { write_instruction register_size #size_ident #cond_ident { #stream }})
 				},
 				_ => panic!("Expected bracketed encoding"),
 			}
@@ -452,11 +452,11 @@ fn instruction_helper(item: TokenStream) -> (proc_macro2::TokenStream, proc_macr
 	let size_unwrap = if size_mod == Modifier::Required {
 		quote_spanned! {source_span=>
 			let size = size.unwrap();
-			let s = common_register_size(&[#(#args_as_call_args),*], Some(size)) as u64;
+			let s = register_size(&[#(#args_as_call_args),*], Some(size)) as u64;
 		}
 	} else if size_mod == Modifier::Optional {
 		quote_spanned! {source_span=>
-			let s = common_register_size(&[#(#args_as_call_args),*], size) as u64;
+			let s = register_size(&[#(#args_as_call_args),*], size) as u64;
 		}
 	} else {
 		quote_spanned! {source_span=>}
@@ -572,7 +572,7 @@ fn instruction_helper(item: TokenStream) -> (proc_macro2::TokenStream, proc_macr
 		format!(
 			"{}{}{} = {{:?}}",
 			acc,
-			if acc.len() == 0 { " " } else { ", " },
+			if acc.is_empty() { " " } else { ", " },
 			arg.name
 		)
 	});
@@ -589,8 +589,7 @@ fn instruction_helper(item: TokenStream) -> (proc_macro2::TokenStream, proc_macr
 
 				#body
 			}
-		}
-		.into(),
+		},
 		source_ident,
 	)
 }
@@ -611,7 +610,7 @@ pub fn instructions(item: TokenStream) -> TokenStream {
 			.position(|tok| matches!(tok, TokenTree::Punct(punct) if punct.as_char() == ';'));
 
 		if idx.is_none() {
-			if tokens.len() > 0 {
+			if !tokens.is_empty() {
 				instruction_sequences.push(tokens);
 			}
 			break;
@@ -639,15 +638,11 @@ pub fn instructions(item: TokenStream) -> TokenStream {
 			#(#instr),*
 
 			Rule::instr_unknown => {
-				let loc = instr.line_col();
-				eprintln!(
-					"Error: encountered unknown instruction \"{}\": {}:{}:{}",
-					instr.into_inner().next().unwrap().as_str(),
-					cli.source.display(),
-					loc.0,
-					loc.1
+				let name = instr.into_inner().next().unwrap();
+				diagnostics.borrow_mut().error(
+					name.as_span(),
+					format!("unknown instruction \"{}\"", name.as_str()),
 				);
-				exit(1);
 			},
 
 			_ => unreachable!(),
@@ -0,0 +1,355 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	fmt::{self, Display, Write},
+	path::PathBuf,
+};
+
+use pest::{error::InputLocation, RuleType};
+
+/// A range of bytes in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+	pub start: usize,
+	pub end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+	Error,
+	Warning,
+}
+
+/// Additional information attached to a diagnostic, optionally pointing somewhere else in the source.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Note {
+	pub span: Option<Span>,
+	pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+	pub severity: Severity,
+	pub span: Span,
+	pub message: String,
+	pub notes: Vec<Note>,
+}
+
+/// A file read by `.include`.
+///
+/// Spans past the end of the source refer to included files, whose text starts at `start`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncludedFile {
+	pub path: PathBuf,
+	pub start: usize,
+	pub text: String,
+}
+
+/// All the problems found while assembling a source file.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+	list: Vec<Diagnostic>,
+	/// The files the diagnostics' spans may refer to, besides the source.
+	files: Vec<IncludedFile>,
+}
+
+impl Span {
+	pub fn new(start: usize, end: usize) -> Self {
+		Self { start, end }
+	}
+
+	/// Returns the (1-based) line and column of the start of the span within `source`.
+	pub fn line_col(&self, source: &str) -> (usize, usize) {
+		let start = self.start.min(source.len());
+		let line_start = source[..start].rfind('\n').map_or(0, |index| index + 1);
+		(
+			source[..start].matches('\n').count() + 1,
+			source[line_start..start].chars().count() + 1,
+		)
+	}
+}
+
+impl From<pest::Span<'_>> for Span {
+	fn from(value: pest::Span<'_>) -> Self {
+		Self::new(value.start(), value.end())
+	}
+}
+
+impl Display for Severity {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Severity::Error => write!(f, "error"),
+			Severity::Warning => write!(f, "warning"),
+		}
+	}
+}
+
+impl Diagnostic {
+	pub fn error(span: impl Into<Span>, message: impl Into<String>) -> Self {
+		Self {
+			severity: Severity::Error,
+			span: span.into(),
+			message: message.into(),
+			notes: Vec::new(),
+		}
+	}
+
+	pub fn warning(span: impl Into<Span>, message: impl Into<String>) -> Self {
+		Self {
+			severity: Severity::Warning,
+			..Self::error(span, message)
+		}
+	}
+
+	/// Adds a note pointing at `span`.
+	pub fn with_note(mut self, span: impl Into<Span>, message: impl Into<String>) -> Self {
+		self.notes.push(Note {
+			span: Some(span.into()),
+			message: message.into(),
+		});
+		self
+	}
+
+	/// Creates an error for a syntax error reported by pest.
+	pub fn from_parse_error<R: RuleType>(error: &pest::error::Error<R>) -> Self {
+		let span = match error.location {
+			InputLocation::Pos(position) => Span::new(position, position),
+			InputLocation::Span((start, end)) => Span::new(start, end),
+		};
+		Self::error(span, error.variant.message())
+	}
+
+	/// Renders the diagnostic along with the source lines it points to, e.g.:
+	///
+	/// ```text
+	/// error: unknown label "foo"
+	///  --> example.acca:3:10
+	///   |
+	/// 3 |     ldr r0, foo
+	///   |             ^^^
+	/// ```
+	pub fn render(&self, path: &str, source: &str) -> String {
+		self.render_with_files(path, source, &[])
+	}
+
+	fn render_with_files(&self, path: &str, source: &str, files: &[IncludedFile]) -> String {
+		let mut output = format!("{}: {}\n", self.severity, self.message);
+		render_snippet(&mut output, path, source, files, self.span);
+		for note in &self.notes {
+			let _ = writeln!(output, "note: {}", note.message);
+			if let Some(span) = note.span {
+				render_snippet(&mut output, path, source, files, span);
+			}
+		}
+		output
+	}
+}
+
+/// Writes the location of `span` and the first source line it covers, underlining the span with carets.
+fn render_snippet(
+	output: &mut String,
+	path: &str,
+	source: &str,
+	files: &[IncludedFile],
+	span: Span,
+) {
+	// spans past the end of the source are in one of the included files
+	let (path, source, span) = match files.iter().rev().find(|file| file.start <= span.start) {
+		Some(file) if span.start > source.len() => (
+			file.path.display().to_string(),
+			file.text.as_str(),
+			Span::new(span.start - file.start, span.end.saturating_sub(file.start)),
+		),
+		_ => (path.to_string(), source, span),
+	};
+	let start = span.start.min(source.len());
+	let line_start = source[..start].rfind('\n').map_or(0, |index| index + 1);
+	let line_end = source[start..]
+		.find('\n')
+		.map_or(source.len(), |index| start + index);
+	let line = source[line_start..line_end].trim_end_matches('\r');
+	let (line_number, column) = span.line_col(source);
+
+	// keep tabs in the indentation so that the carets line up with the source
+	let indent: String = source[line_start..start]
+		.chars()
+		.map(|char| if char == '\t' { '\t' } else { ' ' })
+		.collect();
+	let width = source[start..span.end.clamp(start, line_end)]
+		.chars()
+		.count()
+		.max(1);
+
+	let gutter = " ".repeat(line_number.to_string().len());
+	let _ = writeln!(output, "{}--> {}:{}:{}", gutter, path, line_number, column);
+	let _ = writeln!(output, "{} |", gutter);
+	let _ = writeln!(output, "{} | {}", line_number, line);
+	let _ = writeln!(output, "{} | {}{}", gutter, indent, "^".repeat(width));
+}
+
+impl Diagnostics {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Adds a diagnostic, unless an identical one was already reported (e.g. when an expression is evaluated in both
+	/// passes).
+	pub fn push(&mut self, diagnostic: Diagnostic) {
+		if !self.list.contains(&diagnostic) {
+			self.list.push(diagnostic);
+		}
+	}
+
+	pub fn error(&mut self, span: impl Into<Span>, message: impl Into<String>) {
+		self.push(Diagnostic::error(span, message));
+	}
+
+	pub fn warning(&mut self, span: impl Into<Span>, message: impl Into<String>) {
+		self.push(Diagnostic::warning(span, message));
+	}
+
+	/// Returns the value of `result`, or reports its error and returns `fallback` so that assembly can continue.
+	pub fn recover<T>(&mut self, result: Result<T, Diagnostic>, fallback: T) -> T {
+		result.unwrap_or_else(|diagnostic| {
+			self.push(diagnostic);
+			fallback
+		})
+	}
+
+	/// Returns the files included by the source, which the diagnostics' spans may refer to.
+	pub fn files(&self) -> &[IncludedFile] {
+		&self.files
+	}
+
+	pub(crate) fn set_files(&mut self, files: Vec<IncludedFile>) {
+		self.files = files;
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+		self.list.iter()
+	}
+
+	pub fn error_count(&self) -> usize {
+		self.count(Severity::Error)
+	}
+
+	pub fn warning_count(&self) -> usize {
+		self.count(Severity::Warning)
+	}
+
+	pub fn has_errors(&self) -> bool {
+		self.error_count() > 0
+	}
+
+	fn count(&self, severity: Severity) -> usize {
+		self.list
+			.iter()
+			.filter(|diagnostic| diagnostic.severity == severity)
+			.count()
+	}
+
+	/// Renders all diagnostics, in source order. `path` and `source` are those of the assembled source; included files
+	/// are rendered from [`Diagnostics::files`].
+	pub fn render(&self, path: &str, source: &str) -> String {
+		let mut sorted: Vec<_> = self.list.iter().collect();
+		sorted.sort_by_key(|diagnostic| diagnostic.span.start);
+		sorted
+			.iter()
+			.map(|diagnostic| diagnostic.render_with_files(path, source, &self.files))
+			.collect::<Vec<_>>()
+			.join("\n")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SOURCE: &str = "entry:\n\tldr r0, foo\nfoo2:\n\tnop\n";
+
+	#[test]
+	fn renders_notes_under_the_diagnostic() {
+		let mut diagnostic = Diagnostic::error(Span::new(16, 19), "unknown label \"foo\"")
+			.with_note(Span::new(20, 24), "did you mean \"foo2\"?");
+		diagnostic.notes.push(Note {
+			span: None,
+			message: "labels are case-sensitive".to_string(),
+		});
+
+		assert_eq!(
+			diagnostic.render("test.acca", SOURCE),
+			"\
+error: unknown label \"foo\"
+ --> test.acca:2:10
+  |
+2 | \tldr r0, foo
+  | \t        ^^^
+note: did you mean \"foo2\"?
+ --> test.acca:3:1
+  |
+3 | foo2:
+  | ^^^^
+note: labels are case-sensitive
+"
+		);
+	}
+
+	#[test]
+	fn renders_diagnostics_in_source_order() {
+		let mut diagnostics = Diagnostics::new();
+		// spans that run past the end of their line are cut off there, and empty spans still get a caret
+		diagnostics.warning(Span::new(21, 32), "unused label");
+		diagnostics.error(Span::new(6, 6), "expected an instruction");
+		diagnostics.error(Span::new(6, 6), "expected an instruction");
+		assert_eq!(diagnostics.error_count(), 1);
+		assert_eq!(diagnostics.warning_count(), 1);
+
+		assert_eq!(
+			diagnostics.render("test.acca", SOURCE),
+			"\
+error: expected an instruction
+ --> test.acca:1:7
+  |
+1 | entry:
+  |       ^
+
+warning: unused label
+ --> test.acca:3:2
+  |
+3 | foo2:
+  |  ^^^^
+"
+		);
+	}
+
+	#[test]
+	fn renders_spans_in_included_files() {
+		let mut diagnostics = Diagnostics::new();
+		diagnostics.set_files(vec![IncludedFile {
+			path: PathBuf::from("lib.acca"),
+			start: SOURCE.len() + 1,
+			text: "\n.def X Y\n".to_string(),
+		}]);
+		diagnostics.error(
+			Span::new(SOURCE.len() + 8, SOURCE.len() + 9),
+			"unknown definition \"Y\"",
+		);
+
+		assert_eq!(
+			diagnostics.render("test.acca", SOURCE),
+			"\
+error: unknown definition \"Y\"
+ --> lib.acca:2:7
+  |
+2 | .def X Y
+  |       ^
+"
+		);
+	}
+}
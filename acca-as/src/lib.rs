@@ -0,0 +1,1886 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! The Acca assembler, usable without touching the filesystem (unless the source uses `.include` or `.incbin`).
+//!
+//! ```
+//! let image = acca_as::assemble(".addr 0x400\nentry:\n\tnop\n", &Default::default()).unwrap();
+//! assert_eq!(image.symbol("entry"), Some(0x400));
+//! assert_eq!(image.bytes.len(), 0x404);
+//! assert_eq!(image.bytes[0x400..], [0x00, 0x00, 0x00, 0x04]);
+//! ```
+
+use std::{
+	cell::{Cell, RefCell},
+	collections::HashMap,
+	fmt::Display,
+	path::PathBuf,
+};
+
+pub mod diagnostics;
+pub mod macros;
+pub mod object;
+
+use diagnostics::{Diagnostic, Diagnostics, IncludedFile, Span};
+use object::{Binding, Object, ObjectSymbol, RelocationKind, RelocationTarget, SymbolValue};
+
+extern crate pest;
+#[macro_use]
+extern crate pest_derive;
+extern crate proc_macro;
+#[macro_use]
+extern crate acca_as_proc_macro;
+
+use lazy_static::lazy_static;
+use pest::{
+	iterators::{Pair, Pairs},
+	pratt_parser::{Assoc, Op, PrattParser},
+	Parser,
+};
+
+#[derive(Parser)]
+#[grammar = "grammar.pest"]
+struct ASMParser;
+
+/// The default for [`AssembleOptions::max_image_size`].
+pub const DEFAULT_MAX_IMAGE_SIZE: u64 = 1 << 30;
+
+#[derive(Debug, Clone, Default)]
+pub struct AssembleOptions {
+	/// Values to define before assembling, as if with `.def` at the start of the source.
+	pub defines: HashMap<String, u64>,
+	/// Where the source was read from. `.include` and `.incbin` look for files relative to its directory (or to the
+	/// current directory, if it isn't given).
+	pub source_path: Option<PathBuf>,
+	/// Directories to look for files used by `.include` and `.incbin` in, after the directory of the file using them.
+	pub include_paths: Vec<PathBuf>,
+	/// The largest image (or object section) that can be assembled, in bytes. Images are kept in memory, so anything
+	/// written past this is an error. Defaults to [`DEFAULT_MAX_IMAGE_SIZE`].
+	pub max_image_size: Option<u64>,
+}
+
+/// A label and its address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+	pub name: String,
+	pub address: u64,
+}
+
+/// The bytes at `address` that were assembled from `span` (an instruction or a value written with `.write`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceMapping {
+	pub address: u64,
+	pub size: u64,
+	pub span: Span,
+}
+
+/// The result of assembling a source file.
+#[derive(Debug, Clone)]
+pub struct AssembledImage {
+	/// The image, starting at address 0. Gaps between the assembled bytes are zero.
+	pub bytes: Vec<u8>,
+	/// All labels (but not `.def`s), sorted by address.
+	pub symbols: Vec<Symbol>,
+	/// Sorted by address.
+	pub source_map: Vec<SourceMapping>,
+	/// The files included by the source, which the spans in `source_map` may refer to.
+	pub included_files: Vec<IncludedFile>,
+	/// Warnings about the source; assembly succeeded despite them.
+	pub warnings: Diagnostics,
+}
+
+impl AssembledImage {
+	/// Returns the address of the label called `name`.
+	pub fn symbol(&self, name: &str) -> Option<u64> {
+		self.symbols
+			.iter()
+			.find(|symbol| symbol.name == name)
+			.map(|symbol| symbol.address)
+	}
+
+	/// Finds the source of the byte at `address`.
+	pub fn source_at(&self, address: u64) -> Option<&SourceMapping> {
+		let index = self
+			.source_map
+			.partition_point(|mapping| mapping.address + mapping.size <= address);
+		self.source_map
+			.get(index)
+			.filter(|mapping| mapping.address <= address)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Size {
+	Byte = 0,
+	DoubleByte = 1,
+	QuadByte = 2,
+	Word = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+enum Condition {
+	C = 0,
+	NC = 1,
+	Z = 2,
+	NZ = 3,
+	O = 4,
+	NO = 5,
+	S = 6,
+	NS = 7,
+	L = 8,
+	NL = 9,
+	NONE = 15,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Register {
+	/// A number within 0-15 that identifies the register.
+	id: u8,
+	size: Option<Size>,
+	span: Span,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Argument {
+	Register(Register),
+	Immediate(u64),
+}
+
+/// What a relocatable value is relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Base<'a> {
+	/// The start of a section of the object being assembled.
+	Section(usize),
+	/// A symbol defined by another object.
+	External(&'a str),
+}
+
+/// The value of an expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value<'a> {
+	Constant(u64),
+	/// `(base + addend) >> shift`, which is only known once the object is linked. `masked` records an `& 0xffff`.
+	Relocatable {
+		base: Base<'a>,
+		addend: u64,
+		shift: u8,
+		masked: bool,
+	},
+}
+
+/// How an operand that depends on a relocatable value is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OperandForm {
+	Absolute,
+	/// Relative to the next instruction, in instructions.
+	Relative,
+	/// Relative to the next instruction, in bytes.
+	ByteRelative,
+}
+
+/// An operand whose value can only be determined by the linker.
+#[derive(Debug, Clone, Copy)]
+struct PendingRelocation<'a> {
+	value: Value<'a>,
+	form: OperandForm,
+	span: Span,
+}
+
+/// A relocation, before the symbols it refers to are numbered.
+#[derive(Debug, Clone, Copy)]
+struct ObjectRelocation<'a> {
+	section: usize,
+	offset: u64,
+	kind: RelocationKind,
+	/// `None` for absolute addresses.
+	base: Option<Base<'a>>,
+	addend: u64,
+	shift: u8,
+}
+
+impl<'a> Value<'a> {
+	fn relocatable(base: Base<'a>, addend: u64) -> Self {
+		Self::Relocatable {
+			base,
+			addend,
+			shift: 0,
+			masked: false,
+		}
+	}
+
+	/// Returns the value plus `constant`, if that's still something the linker can compute.
+	fn offset_by(self, constant: u64) -> Option<Self> {
+		match self {
+			Self::Constant(value) => Some(Self::Constant(value.wrapping_add(constant))),
+			Self::Relocatable {
+				base,
+				addend,
+				shift: 0,
+				masked: false,
+			} => Some(Self::relocatable(base, addend.wrapping_add(constant))),
+			Self::Relocatable { .. } => None,
+		}
+	}
+
+	/// Returns both values relative to a common base, if they have one (i.e. if their difference is constant).
+	fn difference(self, other: Self) -> Option<(u64, u64)> {
+		match (self, other) {
+			(Self::Constant(lhs), Self::Constant(rhs)) => Some((lhs, rhs)),
+			(
+				Self::Relocatable {
+					base: lhs_base,
+					addend: lhs,
+					shift: 0,
+					masked: false,
+				},
+				Self::Relocatable {
+					base: rhs_base,
+					addend: rhs,
+					shift: 0,
+					masked: false,
+				},
+			) if lhs_base == rhs_base => Some((lhs, rhs)),
+			_ => None,
+		}
+	}
+
+	fn constant(self, span: impl Into<Span>) -> Result<u64, Diagnostic> {
+		match self {
+			Self::Constant(value) => Ok(value),
+			Self::Relocatable { .. } => Err(Diagnostic::error(
+				span,
+				"this must be a constant, but it depends on where the object is linked",
+			)),
+		}
+	}
+}
+
+impl Display for Register {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "r{}", self.id).and_then(|_| match self.size {
+			Some(size) => write!(
+				f,
+				"{}",
+				match size {
+					Size::Byte => "b",
+					Size::DoubleByte => "d",
+					Size::QuadByte => "q",
+					Size::Word => "w",
+				}
+			),
+			None => std::fmt::Result::Ok(()),
+		})
+	}
+}
+
+impl Display for Argument {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Register(reg) => write!(f, "Register({})", reg),
+			Self::Immediate(imm) => write!(f, "Immediate({})", imm),
+		}
+	}
+}
+
+#[allow(dead_code)]
+impl Argument {
+	pub fn map_immediate<F>(self, f: F) -> Self
+	where
+		F: FnOnce(u64) -> u64,
+	{
+		match self {
+			Self::Immediate(imm) => Self::Immediate(f(imm)),
+			_ => self,
+		}
+	}
+
+	pub fn map_register<F>(self, f: F) -> Self
+	where
+		F: FnOnce(Register) -> Register,
+	{
+		match self {
+			Self::Register(reg) => Self::Register(f(reg)),
+			_ => self,
+		}
+	}
+
+	pub fn map<F, G>(self, map_reg: F, map_imm: G) -> Self
+	where
+		F: FnOnce(Register) -> Register,
+		G: FnOnce(u64) -> u64,
+	{
+		match self {
+			Self::Register(reg) => Self::Register(map_reg(reg)),
+			Self::Immediate(imm) => Self::Immediate(map_imm(imm)),
+		}
+	}
+}
+
+impl Size {
+	pub const fn byte_size(&self) -> u8 {
+		match self {
+			Size::Byte => 1,
+			Size::DoubleByte => 2,
+			Size::QuadByte => 4,
+			Size::Word => 8,
+		}
+	}
+}
+
+lazy_static! {
+	static ref PRATT: PrattParser<Rule> = PrattParser::new()
+		.op(Op::infix(Rule::or, Assoc::Left))
+		.op(Op::infix(Rule::xor, Assoc::Left))
+		.op(Op::infix(Rule::and, Assoc::Left))
+		.op(Op::infix(Rule::shift_left, Assoc::Left)
+			| Op::infix(Rule::shift_right_logical, Assoc::Left)
+			| Op::infix(Rule::shift_right_arithmetic, Assoc::Left))
+		.op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::sub, Assoc::Left))
+		.op(Op::infix(Rule::mul, Assoc::Left)
+			| Op::infix(Rule::div, Assoc::Left)
+			| Op::infix(Rule::rem, Assoc::Left))
+		.op(Op::prefix(Rule::neg) | Op::prefix(Rule::not));
+}
+
+fn parse_integer(integer: Pair<Rule>) -> Result<u64, Diagnostic> {
+	if integer.as_rule() != Rule::integer {
+		panic!("Tried to parse integer that wasn't an integer");
+	}
+
+	let span = integer.as_span();
+	let lit = integer.into_inner().next().unwrap();
+
+	let as_str = match lit.as_rule() {
+		Rule::decimal_literal
+			if !lit.as_str().starts_with("0d") && !lit.as_str().starts_with("0D") =>
+		{
+			lit.as_str()
+		},
+		_ => &lit.as_str()[2..],
+	};
+
+	let radix = match lit.as_rule() {
+		Rule::binary_literal => 2,
+		Rule::octal_literal => 8,
+		Rule::decimal_literal => 10,
+		Rule::hex_literal => 16,
+		_ => unreachable!(),
+	};
+
+	let filtered: String = as_str.chars().filter(|&char| char != '_').collect();
+
+	u64::from_str_radix(&filtered, radix)
+		.map_err(|_| Diagnostic::error(span, "integer literal doesn't fit in 64 bits"))
+}
+
+fn parse_machine_register(mreg: &str) -> u64 {
+	match mreg {
+		"flags" => 0,
+		"elr" => 1,
+		"esp" => 2,
+		"eflags" => 3,
+		"einfo" => 4,
+		"eaddr" => 5,
+		"evtable" => 6,
+		"ectable" => 7,
+		"ptbase" => 8,
+		"tlbinv" => 9,
+		_ => panic!("Invalid machine register literal"),
+	}
+}
+
+/// Evaluates an expression. Names that aren't labels or `.def`s are errors, unless `externals` is set, in which case
+/// they're assumed to be defined by another object.
+fn evaluate_immediate<'a>(
+	immediate: Pair<'a, Rule>,
+	label_addrs: &HashMap<&'a str, Value<'a>>,
+	externals: bool,
+	current_address: Value<'a>,
+) -> Result<Value<'a>, Diagnostic> {
+	if immediate.as_rule() != Rule::immediate {
+		panic!("Tried to evaluate immediate that wasn't an immediate");
+	}
+
+	PRATT
+		.map_primary(|primary| match primary.as_rule() {
+			Rule::integer => parse_integer(primary).map(Value::Constant),
+			Rule::boolean => match primary.as_str() {
+				"true" => Ok(Value::Constant(1)),
+				"false" => Ok(Value::Constant(0)),
+				_ => unreachable!(),
+			},
+			Rule::machine_register_literal => Ok(Value::Constant(parse_machine_register(
+				primary.into_inner().next().unwrap().as_str(),
+			))),
+			Rule::ident => match label_addrs.get(primary.as_str()) {
+				Some(value) => Ok(*value),
+				None if externals => Ok(Value::relocatable(Base::External(primary.as_str()), 0)),
+				None => Err(Diagnostic::error(
+					primary.as_span(),
+					format!("unknown label \"{}\"", primary.as_str()),
+				)),
+			},
+			Rule::current_address => Ok(current_address),
+			Rule::character => {
+				let inner = primary.into_inner().next().unwrap();
+
+				Ok(Value::Constant(
+					(match inner.as_rule() {
+						Rule::normal_char => inner.as_str().chars().nth(1).unwrap(),
+						Rule::escaped_char => match inner.as_str().chars().nth(2).unwrap() {
+							'\'' => '\'',
+							'\\' => '\\',
+							'n' => '\n',
+							'f' => char::from_u32(12).unwrap(),
+							't' => '\t',
+							'r' => '\r',
+							'b' => char::from_u32(8).unwrap(),
+							_ => unreachable!(),
+						},
+						_ => unreachable!(),
+					} as u32) as u64,
+				))
+			},
+			Rule::immediate => evaluate_immediate(primary, label_addrs, externals, current_address),
+			_ => unreachable!(),
+		})
+		.map_prefix(|op, rhs| {
+			let Value::Constant(rhs) = rhs? else {
+				return Err(not_relocatable(op.as_span()));
+			};
+			Ok(Value::Constant(match op.as_rule() {
+				Rule::neg => rhs.wrapping_neg(),
+				Rule::not => !rhs,
+				_ => unreachable!(),
+			}))
+		})
+		.map_infix(move |lhs, op, rhs| {
+			let (lhs, rhs) = match (lhs?, rhs?) {
+				(Value::Constant(lhs), Value::Constant(rhs)) => (lhs, rhs),
+				(lhs, rhs) => return evaluate_relocatable_infix(op, lhs, rhs),
+			};
+			let division_by_zero = || Diagnostic::error(op.as_span(), "division by zero");
+			Ok(Value::Constant(match op.as_rule() {
+				Rule::or => lhs | rhs,
+				Rule::xor => lhs ^ rhs,
+				Rule::and => lhs & rhs,
+				Rule::shift_left => lhs.checked_shl(rhs as u32).unwrap_or(0),
+				Rule::shift_right_logical => lhs.checked_shr(rhs as u32).unwrap_or(0),
+				Rule::shift_right_arithmetic => (lhs as i64)
+					.checked_shr(rhs as u32)
+					.unwrap_or(if (lhs as i64) < 0 { -1 } else { 0 })
+					as u64,
+				Rule::add => lhs.wrapping_add(rhs),
+				Rule::sub => lhs.wrapping_sub(rhs),
+				Rule::mul => lhs.wrapping_mul(rhs),
+				Rule::div => lhs.checked_div(rhs).ok_or_else(division_by_zero)?,
+				Rule::rem => lhs.checked_rem(rhs).ok_or_else(division_by_zero)?,
+				_ => unreachable!(),
+			}))
+		})
+		.parse(immediate.into_inner())
+}
+
+fn not_relocatable(span: impl Into<Span>) -> Diagnostic {
+	Diagnostic::error(
+		span,
+		"this can't be applied to an address that's only known once the object is linked",
+	)
+}
+
+/// Applies an operator to a relocatable value. Only the operations the linker can redo are allowed: adding or
+/// subtracting a constant, taking the difference of two addresses in the same section, shifting right (to pick a
+/// 16-bit chunk for `ldi`), and masking with `0xffff`.
+fn evaluate_relocatable_infix<'a>(
+	op: Pair<'a, Rule>,
+	lhs: Value<'a>,
+	rhs: Value<'a>,
+) -> Result<Value<'a>, Diagnostic> {
+	use Value::{Constant, Relocatable};
+
+	Ok(match (op.as_rule(), lhs, rhs) {
+		(Rule::add, value @ Relocatable { .. }, Constant(constant))
+		| (Rule::add, Constant(constant), value @ Relocatable { .. }) => value
+			.offset_by(constant)
+			.ok_or_else(|| not_relocatable(op.as_span()))?,
+		(Rule::sub, value @ Relocatable { .. }, Constant(constant)) => value
+			.offset_by(constant.wrapping_neg())
+			.ok_or_else(|| not_relocatable(op.as_span()))?,
+		(Rule::sub, lhs, rhs) => {
+			let (lhs, rhs) = lhs
+				.difference(rhs)
+				.ok_or_else(|| not_relocatable(op.as_span()))?;
+			Constant(lhs.wrapping_sub(rhs))
+		},
+		(
+			Rule::shift_right_logical,
+			Relocatable {
+				base,
+				addend,
+				shift,
+				masked: false,
+			},
+			Constant(amount),
+		) if (shift as u64) + amount < 64 => Relocatable {
+			base,
+			addend,
+			shift: shift + amount as u8,
+			masked: false,
+		},
+		(
+			Rule::and,
+			Relocatable {
+				base,
+				addend,
+				shift,
+				..
+			},
+			Constant(0xffff),
+		)
+		| (
+			Rule::and,
+			Constant(0xffff),
+			Relocatable {
+				base,
+				addend,
+				shift,
+				..
+			},
+		) => Relocatable {
+			base,
+			addend,
+			shift,
+			masked: true,
+		},
+		_ => return Err(not_relocatable(op.as_span())),
+	})
+}
+
+fn parse_size(size: Pair<Rule>) -> Size {
+	if size.as_rule() != Rule::size {
+		panic!("Tried to parse size that wasn't a size");
+	}
+
+	match size.as_str() {
+		"b" => Size::Byte,
+		"d" => Size::DoubleByte,
+		"q" => Size::QuadByte,
+		"w" => Size::Word,
+		_ => unreachable!(),
+	}
+}
+
+fn parse_register(register: Pair<Rule>) -> Register {
+	if register.as_rule() != Rule::register && register.as_rule() != Rule::register_no_size {
+		panic!("Tried to parse register that wasn't a register");
+	}
+
+	let span = register.as_span().into();
+	let mut pairs = register.into_inner();
+	let num_or_name = pairs.next().unwrap();
+
+	Register {
+		id: match num_or_name.as_rule() {
+			Rule::register_number => num_or_name.as_str().parse().unwrap(),
+			Rule::register_name => match num_or_name.as_str() {
+				"sp" => 13,
+				"fp" => 14,
+				"lr" => 15,
+				_ => unreachable!(),
+			},
+			_ => unreachable!(),
+		},
+		size: pairs.next().map(|size| parse_size(size)),
+		span,
+	}
+}
+
+fn parse_register_or_null(register_or_null: Pair<Rule>) -> Option<Register> {
+	match register_or_null.as_rule() {
+		Rule::register => Some(parse_register(register_or_null)),
+		Rule::null => None,
+		_ => panic!("Tried to parse register-or-null that wasn't a register or null"),
+	}
+}
+
+fn parse_condition(condition: Pair<Rule>) -> Condition {
+	if condition.as_rule() != Rule::condition {
+		panic!("Tried to parse condition that wasn't a condition");
+	}
+
+	match condition.as_str() {
+		"c" => Condition::C,
+		"nc" => Condition::NC,
+		"z" => Condition::Z,
+		"nz" => Condition::NZ,
+		"o" => Condition::O,
+		"no" => Condition::NO,
+		"s" => Condition::S,
+		"ns" => Condition::NS,
+		"l" => Condition::L,
+		"nl" => Condition::NL,
+		_ => unreachable!(),
+	}
+}
+
+fn parse_instr_size(instr_name: Pair<Rule>) -> Option<Size> {
+	instr_name
+		.into_inner()
+		.next()
+		.map(|pair| parse_size(pair.into_inner().next().unwrap()))
+}
+
+fn parse_instr_condition(instr_name: Pair<Rule>) -> Option<Condition> {
+	instr_name
+		.into_inner()
+		.next()
+		.map(|pair| parse_condition(pair.into_inner().next().unwrap()))
+}
+
+fn parse_instr_condition_and_size(instr_name: Pair<Rule>) -> (Option<Condition>, Option<Size>) {
+	let mut pairs = instr_name.into_inner();
+	(
+		pairs
+			.next()
+			.map(|pair| parse_condition(pair.into_inner().next().unwrap())),
+		pairs
+			.next()
+			.map(|pair| parse_size(pair.into_inner().next().unwrap())),
+	)
+}
+
+/// Parses a register or an immediate, using `evaluate` to determine the value of immediates.
+fn parse_argument<'a>(
+	argument: Pair<'a, Rule>,
+	evaluate: impl FnOnce(Pair<'a, Rule>) -> u64,
+) -> Argument {
+	match argument.as_rule() {
+		Rule::register | Rule::register_no_size => Argument::Register(parse_register(argument)),
+		Rule::immediate => Argument::Immediate(evaluate(argument)),
+		_ => panic!("Tried to parse argument that wasn't a register or immediate"),
+	}
+}
+
+fn next_register(pairs: &mut Pairs<Rule>) -> Option<Register> {
+	pairs.next().map(parse_register)
+}
+
+fn next_register_or_null(pairs: &mut Pairs<Rule>) -> Option<Register> {
+	pairs.next().and_then(parse_register_or_null)
+}
+
+fn next_instr_size(pairs: &mut Pairs<Rule>) -> Option<Size> {
+	pairs.next().and_then(parse_instr_size)
+}
+
+fn next_instr_condition(pairs: &mut Pairs<Rule>) -> Option<Condition> {
+	pairs.next().and_then(parse_instr_condition)
+}
+
+fn next_instr_condition_and_size(pairs: &mut Pairs<Rule>) -> (Option<Condition>, Option<Size>) {
+	match pairs.next() {
+		Some(instr_name) => parse_instr_condition_and_size(instr_name),
+		None => (None, None),
+	}
+}
+
+fn parse_machine_register_or_immediate<'a>(
+	pair: Pair<'a, Rule>,
+	evaluate: impl FnOnce(Pair<'a, Rule>) -> u64,
+) -> u64 {
+	match pair.as_rule() {
+		Rule::machine_register => parse_machine_register(pair.as_str()),
+		Rule::immediate => evaluate(pair),
+		_ => panic!("Tried to parse machinr register or immediate that wasn't a machine register or immediate"),
+	}
+}
+
+fn truncate_immediate(immediate: u64, bits: u8, sign_extend: bool) -> u64 {
+	const ALL_BITS: u64 = !0u64;
+
+	let msb_pos = bits - 1;
+	let msb_mask = 1u64 << msb_pos;
+
+	let mask = ALL_BITS >> (u64::BITS - (bits as u32));
+	let masked = immediate & mask;
+
+	if sign_extend && (masked & msb_mask) != 0 {
+		!mask | masked
+	} else {
+		masked
+	}
+}
+
+/// Checks whether a value fits in `bits` bits, either as an unsigned or as a signed number.
+fn fits_immediate(immediate: u64, bits: u8) -> bool {
+	bits >= 64 || immediate >> bits == 0 || (immediate as i64) >> (bits - 1) == -1
+}
+
+/// Determines the size of an operation from its size suffix (if any) and the sizes of its register arguments, which
+/// must all agree.
+fn common_register_size(
+	arguments: &[Option<Argument>],
+	mut operation_size: Option<Size>,
+) -> Result<Size, Diagnostic> {
+	// the register that determined the size, if it wasn't the suffix
+	let mut sized_by: Option<Register> = None;
+
+	for arg in arguments {
+		if let Some(Argument::Register(
+			register @ Register {
+				size: Some(reg_size),
+				..
+			},
+		)) = arg
+		{
+			match &operation_size {
+				Some(current_size) => {
+					if current_size != reg_size {
+						let error = Diagnostic::error(
+							register.span,
+							format!(
+								"register {} is {:?}-sized, but the operation is {:?}-sized",
+								register, reg_size, current_size
+							),
+						);
+						return Err(match sized_by {
+							Some(first) => error.with_note(
+								first.span,
+								format!("the operation size comes from {} here", first),
+							),
+							None => error,
+						});
+					}
+				},
+				None => {
+					operation_size = Some(*reg_size);
+					sized_by = Some(*register);
+				},
+			}
+		}
+	}
+
+	Ok(*operation_size.get_or_insert(Size::Word))
+}
+
+macro_rules! instruction_match_pattern {
+	($tmp:tt, reg) => {
+		Some(Argument::Register($tmp))
+	};
+	($tmp:tt, null) => {
+		None
+	};
+	($tmp:tt, $other:ident) => {
+		Some(Argument::Immediate($tmp))
+	};
+}
+
+macro_rules! instruction_match_body {
+	($tmp:tt, reg) => {{
+		$tmp.id as u64
+	}};
+	($tmp:tt, null) => {{
+		31u64
+	}};
+	($tmp:tt, $other:ident) => {{
+		$tmp
+	}};
+}
+
+macro_rules! instruction_body {
+	($write_instr:ident $register_size:ident $($additional_ident:ident)* { $($arg:ident : $($ty:ident)|*),+ $(,)? $({ $(let $size_name:ident = size_of($($reg:ident),+ $(,)?) ;)* })? => [ $($value:tt)* ] $($(,)? $($rest_arg:ident : $($rest_ty:ident)|*),+ $(,)? $({ $($size_block:tt)* })? => $rest_value:tt)* $(,)? }) => {
+		if $(matches!($arg, $(instruction_match_pattern!(_, $ty))|+))&&+ {
+			$(
+				$(
+					#[allow(non_snake_case)]
+					let $size_name = $register_size(&[$($reg),+], None) as u64;
+				)*
+			)?
+			$(
+				#[allow(non_snake_case)]
+				let $arg = match $arg {
+					$(
+						instruction_match_pattern!(tmp, $ty) => instruction_match_body!(tmp, $ty),
+					)*
+					_ => unreachable!(),
+				};
+			)+
+			instruction_encoding! { $write_instr $($($size_name)*)? $($arg)+ $($additional_ident)* ; $($value)* }
+		}
+		instruction_body! { $write_instr $register_size $($additional_ident)* { $($($rest_arg : $($rest_ty)|*),+ $({ $($size_block)* })? => $rest_value)* }}
+	};
+	($write_instr:ident $register_size:ident $($additional_ident:ident)* $({})?) => {};
+	($write_instr:ident $register_size:ident $($arg:ident : $($ty:ident)|*),* ; $($additional_ident:ident)* [ $($value:tt)* ]) => {
+		if true $(&& matches!($arg, $(instruction_match_pattern!(_, $ty))|+))* {
+			$(
+				#[allow(non_snake_case)]
+				let $arg = match $arg {
+					$(
+						instruction_match_pattern!(tmp, $ty) => instruction_match_body!(tmp, $ty),
+					)*
+					_ => unreachable!(),
+				};
+			)*
+			instruction_encoding! { $write_instr $($arg)* $($additional_ident)* ; $($value)* }
+		} else {
+			panic!("Internal instruction evaluation error")
+		}
+	};
+}
+
+/// Copies `data` into `image` at `address`, growing the image as needed.
+///
+/// Returns `false` (and writes nothing) if the image would grow past `max_size` bytes.
+fn write_image(image: &mut Vec<u8>, address: u64, data: &[u8], max_size: u64) -> bool {
+	let end = match address.checked_add(data.len() as u64) {
+		Some(end) if end <= max_size => end as usize,
+		_ => return false,
+	};
+	if image.len() < end {
+		image.resize(end, 0);
+	}
+	image[address as usize..end].copy_from_slice(data);
+	true
+}
+
+/// The result of assembling a relocatable object.
+#[derive(Debug, Clone)]
+pub struct AssembledObject {
+	pub object: Object,
+	/// Warnings about the source; assembly succeeded despite them.
+	pub warnings: Diagnostics,
+}
+
+enum Assembled {
+	Image(AssembledImage),
+	Object(AssembledObject),
+}
+
+/// Assembles `source` into a flat image.
+///
+/// Returns all errors (and warnings) found if the source can't be assembled.
+pub fn assemble(source: &str, options: &AssembleOptions) -> Result<AssembledImage, Diagnostics> {
+	match assemble_source(source, options, false)? {
+		Assembled::Image(image) => Ok(image),
+		Assembled::Object(_) => unreachable!(),
+	}
+}
+
+/// Assembles `source` into a relocatable object.
+///
+/// Addresses start at 0 in each section (`.addr` sets the offset within the current one). Labels become local symbols,
+/// or global ones if named by `.global`, and names the source doesn't define are assumed to be global symbols of other
+/// objects.
+pub fn assemble_object(
+	source: &str,
+	options: &AssembleOptions,
+) -> Result<AssembledObject, Diagnostics> {
+	match assemble_source(source, options, true)? {
+		Assembled::Object(object) => Ok(object),
+		Assembled::Image(_) => unreachable!(),
+	}
+}
+
+/// Makes the section called `name` the current one, creating it if needed.
+fn switch_section<'a>(
+	sections: &mut Vec<(&'a str, u64)>,
+	section: &mut usize,
+	addr: &mut u64,
+	name: &'a str,
+) {
+	sections[*section].1 = *addr;
+	*section = match sections.iter().position(|&(existing, _)| existing == name) {
+		Some(index) => index,
+		None => {
+			sections.push((name, 0));
+			sections.len() - 1
+		},
+	};
+	*addr = sections[*section].1;
+}
+
+fn assemble_source(
+	source: &str,
+	options: &AssembleOptions,
+	object: bool,
+) -> Result<Assembled, Diagnostics> {
+	// errors in the expansion don't stop assembly, so that the problems in the rest of the source are found too
+	let expanded = macros::Expanded::new(source, options);
+	let expansion_failed = expanded.diagnostics().has_errors();
+
+	// everything after this point refers to the expanded source, so map it back to the original
+	match assemble_expanded(&expanded, options, object) {
+		Ok(Assembled::Image(image)) if expansion_failed => {
+			Err(expanded.map_diagnostics(image.warnings))
+		},
+		Ok(Assembled::Object(object)) if expansion_failed => {
+			Err(expanded.map_diagnostics(object.warnings))
+		},
+		Ok(Assembled::Image(mut image)) => {
+			for mapping in &mut image.source_map {
+				mapping.span = expanded.map_source_span(mapping.span);
+			}
+			image.included_files = expanded.files().to_vec();
+			image.warnings = expanded.map_diagnostics(image.warnings);
+			Ok(Assembled::Image(image))
+		},
+		Ok(Assembled::Object(mut object)) => {
+			object.warnings = expanded.map_diagnostics(object.warnings);
+			Ok(Assembled::Object(object))
+		},
+		Err(diagnostics) => Err(expanded.map_diagnostics(diagnostics)),
+	}
+}
+
+fn assemble_expanded<'a>(
+	expanded: &'a macros::Expanded,
+	options: &'a AssembleOptions,
+	object: bool,
+) -> Result<Assembled, Diagnostics> {
+	let source = expanded.text();
+	let pairs = ASMParser::parse(Rule::root, source).map_err(|e| {
+		let mut diagnostics = Diagnostics::new();
+		diagnostics.push(Diagnostic::from_parse_error(&e));
+		diagnostics
+	})?;
+
+	// problems are collected (rather than reported right away) so that all of them can be reported at once
+	let diagnostics = RefCell::new(Diagnostics::new());
+	let recover = |result: Result<u64, Diagnostic>| diagnostics.borrow_mut().recover(result, 0);
+
+	// the value of the address `addr` in `section`; a flat image is a single section starting at address 0
+	let location = |section: usize, addr: u64| {
+		if object {
+			Value::relocatable(Base::Section(section), addr)
+		} else {
+			Value::Constant(addr)
+		}
+	};
+	// `.addr`s must be constant, but in objects they can also be offsets within the current section
+	let address_in = |value: Value, section: usize, span: Span| match value {
+		Value::Relocatable {
+			base: Base::Section(value_section),
+			addend,
+			shift: 0,
+			masked: false,
+		} if value_section == section => addend,
+		value => recover(value.constant(span)),
+	};
+
+	let mut label_addrs = HashMap::<&str, Value>::new();
+	for (name, value) in &options.defines {
+		label_addrs.insert(name, Value::Constant(*value));
+	}
+	// only actual labels (not `.def`s) go into the symbol map
+	let mut label_names = Vec::<&str>::new();
+	// where each label and `.def` was (first) defined
+	let mut definitions = HashMap::<&str, Span>::new();
+	// names exported with `.global`
+	let mut globals = Vec::<(&str, Span)>::new();
+	// the part of the file each `.incbin` writes, by the offset of its file name
+	let mut incbins = HashMap::<usize, &[u8]>::new();
+	// each section's name and current address
+	let mut sections = vec![(".text", 0u64)];
+	let mut section = 0usize;
+	let mut addr = 0u64;
+
+	let mut peekable_pairs = pairs.clone();
+
+	// moves `addr` past `size` bytes (after aligning it to `align`), unless that would overflow
+	let advance = |addr: &mut u64, align: u64, size: u64, span: Span| match addr
+		.checked_next_multiple_of(align)
+		.and_then(|start| start.checked_add(size))
+	{
+		Some(end) => *addr = end,
+		None => diagnostics
+			.borrow_mut()
+			.error(span, "address overflows the address space"),
+	};
+
+	// first, determine all addresses
+	while let Some(pair) = peekable_pairs.next() {
+		match pair.as_rule() {
+			Rule::label => {
+				// first, gather up all consecutive labels
+				let mut labels = vec![pair];
+				while let Some(next_pair) = peekable_pairs.peek() {
+					match next_pair.as_rule() {
+						Rule::label => labels.push(peekable_pairs.next().unwrap()),
+						_ => break,
+					}
+				}
+				// now check if we need to align the address (i.e. if the next item is an instruction)
+				match peekable_pairs.peek() {
+					Some(next_pair) if next_pair.as_rule() == Rule::instr => {
+						// align it to instruction size (if that overflows, the instruction reports it)
+						addr = addr.checked_next_multiple_of(4).unwrap_or(addr);
+					},
+					_ => {},
+				}
+				// now assign the same address to all the labels
+				for label in labels {
+					let ident = label.into_inner().next().unwrap();
+					let label_name = ident.as_str();
+					if let Some(first) = definitions.get(label_name) {
+						diagnostics.borrow_mut().push(
+							Diagnostic::error(
+								ident.as_span(),
+								format!("duplicate label \"{}\"", label_name),
+							)
+							.with_note(*first, "first defined here"),
+						);
+						continue;
+					}
+					label_addrs.insert(label_name, location(section, addr));
+					definitions.insert(label_name, ident.as_span().into());
+					label_names.push(label_name);
+				}
+			},
+			Rule::instr => {
+				// align it to instruction size and advance by one instruction
+				advance(&mut addr, 4, 4, pair.as_span().into());
+			},
+			Rule::directive => {
+				let dir = pair.into_inner().next().unwrap();
+				let mut dir_pairs = dir.clone().into_inner();
+
+				match dir.as_rule() {
+					Rule::directive_addr => {
+						let _name = dir_pairs.next().unwrap();
+						let immediate = dir_pairs.next().unwrap();
+						let span = immediate.as_span().into();
+						let new_addr = diagnostics.borrow_mut().recover(
+							evaluate_immediate(
+								immediate,
+								&label_addrs,
+								false,
+								location(section, addr),
+							),
+							Value::Constant(0),
+						);
+
+						addr = address_in(new_addr, section, span);
+					},
+					Rule::directive_write => {
+						let size = next_instr_size(&mut dir_pairs).unwrap();
+						let immediate_count = dir_pairs.count() as u64;
+
+						advance(
+							&mut addr,
+							1,
+							(size.byte_size() as u64) * immediate_count,
+							dir.as_span().into(),
+						);
+					},
+					Rule::directive_def => {
+						let _name = dir_pairs.next().unwrap();
+						let ident = dir_pairs.next().unwrap();
+						let name = ident.as_str();
+						let val = dir_pairs
+							.next()
+							.map(|immediate| {
+								diagnostics.borrow_mut().recover(
+									evaluate_immediate(
+										immediate,
+										&label_addrs,
+										false,
+										location(section, addr),
+									),
+									Value::Constant(0),
+								)
+							})
+							.unwrap();
+
+						if label_names.contains(&name) {
+							diagnostics.borrow_mut().push(
+								Diagnostic::warning(
+									ident.as_span(),
+									format!("\"{}\" is already defined as a label", name),
+								)
+								.with_note(definitions[name], "label defined here"),
+							);
+						}
+						definitions.entry(name).or_insert(ident.as_span().into());
+						label_addrs.insert(name, val);
+					},
+					Rule::directive_section => {
+						let _name = dir_pairs.next().unwrap();
+						let name = dir_pairs.next().unwrap();
+
+						if !object {
+							diagnostics.borrow_mut().error(
+								name.as_span(),
+								"sections can only be used when assembling a relocatable object",
+							);
+							continue;
+						}
+						switch_section(&mut sections, &mut section, &mut addr, name.as_str());
+					},
+					Rule::directive_global => {
+						let _name = dir_pairs.next().unwrap();
+						// flat images have no symbol table to export names in
+						globals.extend(
+							dir_pairs.map(|ident| (ident.as_str(), ident.as_span().into())),
+						);
+					},
+					Rule::directive_incbin => {
+						let _name = dir_pairs.next().unwrap();
+						let file = dir_pairs.next().unwrap();
+						// the file was read while expanding the source, but only for `.incbin`s at the start of a line
+						let Some(contents) = expanded.binary(file.as_span().start()) else {
+							diagnostics.borrow_mut().error(
+								file.as_span(),
+								"\".incbin\" must be at the start of a line",
+							);
+							continue;
+						};
+
+						let mut bounds = dir_pairs.map(|immediate| {
+							let span: Span = immediate.as_span().into();
+							recover(
+								evaluate_immediate(
+									immediate,
+									&label_addrs,
+									false,
+									location(section, addr),
+								)
+								.and_then(|value| value.constant(span)),
+							)
+						});
+						let offset = bounds.next().unwrap_or(0);
+						let length = bounds.next();
+						let size = contents.len() as u64;
+
+						let error = match length {
+							_ if offset > size => {
+								Some(format!("offset {:#x} is past the end", offset))
+							},
+							Some(length)
+								if offset.checked_add(length).is_none_or(|end| end > size) =>
+							{
+								Some(format!(
+									"{:#x} bytes at offset {:#x} go past the end",
+									length, offset
+								))
+							},
+							_ => None,
+						};
+						let data = match error {
+							Some(error) => {
+								diagnostics.borrow_mut().error(
+									dir.as_span(),
+									format!("{} of {} ({:#x} bytes)", error, file.as_str(), size),
+								);
+								&[]
+							},
+							None => {
+								let end = length.map_or(size, |length| offset + length);
+								&contents[offset as usize..end as usize]
+							},
+						};
+						incbins.insert(file.as_span().start(), data);
+						advance(&mut addr, 1, data.len() as u64, dir.as_span().into());
+					},
+					_ => unreachable!(),
+				}
+			},
+			Rule::EOI => {},
+			_ => unreachable!(),
+		}
+	}
+
+	// the linker fills in operands that depend on where the object is linked; they're assembled as 0
+	let current_section = Cell::new(0usize);
+	let pending = RefCell::new(Vec::<PendingRelocation>::new());
+	let relocations = RefCell::new(Vec::<ObjectRelocation>::new());
+
+	let evaluate = |immediate: Pair<'a, Rule>, addr: u64| {
+		diagnostics.borrow_mut().recover(
+			evaluate_immediate(
+				immediate,
+				&label_addrs,
+				object,
+				location(current_section.get(), addr),
+			),
+			Value::Constant(0),
+		)
+	};
+
+	let absolute = |immediate: Pair<'a, Rule>, addr: u64| {
+		let span = immediate.as_span().into();
+		match evaluate(immediate, addr) {
+			Value::Constant(value) => value,
+			value => {
+				pending.borrow_mut().push(PendingRelocation {
+					value,
+					form: OperandForm::Absolute,
+					span,
+				});
+				0
+			},
+		}
+	};
+
+	let relative =
+		|immediate: Pair<'a, Rule>, addr: u64, relative_address: u64, form: OperandForm| {
+			let span: Span = immediate.as_span().into();
+			let value = evaluate(immediate, addr);
+			match value.difference(location(current_section.get(), relative_address)) {
+				Some((target, relative_address)) => match form {
+					OperandForm::Relative if (target & 3) != 0 => {
+						diagnostics.borrow_mut().error(
+							span,
+							format!("relative address {:#x} isn't aligned to 4 bytes", target),
+						);
+						0
+					},
+					OperandForm::Relative => (target / 4).wrapping_sub(relative_address / 4),
+					_ => target.wrapping_sub(relative_address),
+				},
+				None => {
+					pending
+						.borrow_mut()
+						.push(PendingRelocation { value, form, span });
+					0
+				},
+			}
+		};
+
+	// records a relocation of the bytes at `offset` in the current section, if `value` can be stored in them
+	let relocate = |kind: RelocationKind, offset: u64, value: Value<'a>, span: Span| {
+		let (base, addend, shift, masked) = match value {
+			Value::Constant(value) => (None, value, 0, false),
+			Value::Relocatable {
+				base,
+				addend,
+				shift,
+				masked,
+			} => (Some(base), addend, shift, masked),
+		};
+		let representable = match kind {
+			RelocationKind::Rel22 | RelocationKind::Rel13 | RelocationKind::ByteRel22 => {
+				shift == 0 && !masked
+			},
+			RelocationKind::Ldi16 | RelocationKind::Data8 | RelocationKind::Data16 => true,
+			RelocationKind::Data32 | RelocationKind::Data64 => !masked,
+		};
+		if !representable {
+			diagnostics.borrow_mut().push(not_relocatable(span));
+			return;
+		}
+		relocations.borrow_mut().push(ObjectRelocation {
+			section: current_section.get(),
+			offset,
+			kind,
+			base,
+			addend,
+			shift,
+		});
+	};
+
+	let next_immediate = |pairs: &mut Pairs<'a, Rule>, addr: u64| {
+		pairs.next().map(|immediate| absolute(immediate, addr))
+	};
+
+	#[allow(unused)]
+	let next_relative_immediate = |pairs: &mut Pairs<'a, Rule>, addr: u64, relative_address: u64| {
+		pairs
+			.next()
+			.map(|immediate| relative(immediate, addr, relative_address, OperandForm::Relative))
+	};
+
+	let next_byte_relative_immediate = |pairs: &mut Pairs<'a, Rule>, addr: u64| {
+		pairs
+			.next()
+			.map(|immediate| relative(immediate, addr, addr + 4, OperandForm::ByteRelative))
+	};
+
+	let next_argument = |pairs: &mut Pairs<'a, Rule>, addr: u64| {
+		pairs
+			.next()
+			.map(|argument| parse_argument(argument, |immediate| absolute(immediate, addr)))
+	};
+
+	let next_relative_argument = |pairs: &mut Pairs<'a, Rule>, addr: u64, relative_address: u64| {
+		pairs.next().map(|argument| {
+			parse_argument(argument, |immediate| {
+				relative(immediate, addr, relative_address, OperandForm::Relative)
+			})
+		})
+	};
+
+	let next_machine_register_or_immediate = |pairs: &mut Pairs<'a, Rule>, addr: u64| {
+		pairs.next().map(|machine_register_or_immediate| {
+			parse_machine_register_or_immediate(machine_register_or_immediate, |immediate| {
+				absolute(immediate, addr)
+			})
+		})
+	};
+
+	let register_size = |arguments: &[Option<Argument>], operation_size: Option<Size>| {
+		diagnostics
+			.borrow_mut()
+			.recover(common_register_size(arguments, operation_size), Size::Word)
+	};
+
+	for (_, section_addr) in &mut sections {
+		*section_addr = 0;
+	}
+	let mut section = 0usize;
+	addr = 0;
+
+	let mut images = vec![Vec::<u8>::new(); sections.len()];
+	let mut source_map = Vec::<SourceMapping>::new();
+
+	// only the first write past the limit is reported, since everything after it is usually past it too
+	let max_image_size = options.max_image_size.unwrap_or(DEFAULT_MAX_IMAGE_SIZE);
+	let too_large_reported = Cell::new(false);
+	let write = |image: &mut Vec<u8>, address: u64, data: &[u8], span: Span| {
+		if !write_image(image, address, data, max_image_size) && !too_large_reported.replace(true) {
+			diagnostics.borrow_mut().error(
+				span,
+				format!(
+					"this goes past the maximum image size of {:#x} bytes",
+					max_image_size
+				),
+			);
+		}
+	};
+
+	// now process each instruction
+	for pair in pairs {
+		match pair.as_rule() {
+			Rule::directive => {
+				let dir = pair.into_inner().next().unwrap();
+				let mut dir_pairs = dir.clone().into_inner();
+
+				match dir.as_rule() {
+					Rule::directive_addr => {
+						let _name = dir_pairs.next().unwrap();
+						let immediate = dir_pairs.next().unwrap();
+						let span = immediate.as_span().into();
+						let new_addr = evaluate(immediate, addr);
+
+						addr = address_in(new_addr, section, span);
+					},
+					Rule::directive_write => {
+						let size = next_instr_size(&mut dir_pairs).unwrap();
+						let mut immediate_count = 0u64;
+
+						for dir_pair in dir_pairs {
+							let span = dir_pair.as_span();
+							// the first pass reported values past the end of the address space
+							let Some(address) =
+								addr.checked_add(immediate_count * size.byte_size() as u64)
+							else {
+								break;
+							};
+							let val = match evaluate(dir_pair, addr) {
+								Value::Constant(val) => {
+									if !fits_immediate(val, size.byte_size() * 8) {
+										diagnostics.borrow_mut().warning(
+											span,
+											format!(
+												"value {:#x} doesn't fit in {} bits and will be truncated",
+												val,
+												size.byte_size() * 8
+											),
+										);
+									}
+									val
+								},
+								value => {
+									relocate(
+										RelocationKind::data(size.byte_size()),
+										address,
+										value,
+										span.into(),
+									);
+									0
+								},
+							};
+							let val = truncate_immediate(val, size.byte_size() * 8, false);
+							let bytes = val.to_le_bytes();
+							write(
+								&mut images[section],
+								address,
+								&bytes[0..size.byte_size() as usize],
+								span.into(),
+							);
+							source_map.push(SourceMapping {
+								address,
+								size: size.byte_size() as u64,
+								span: span.into(),
+							});
+							immediate_count += 1;
+						}
+
+						addr = addr.saturating_add((size.byte_size() as u64) * immediate_count);
+					},
+					Rule::directive_section if object => {
+						let _name = dir_pairs.next().unwrap();
+						let name = dir_pairs.next().unwrap();
+						switch_section(&mut sections, &mut section, &mut addr, name.as_str());
+						current_section.set(section);
+					},
+					Rule::directive_incbin => {
+						let _name = dir_pairs.next().unwrap();
+						let file = dir_pairs.next().unwrap();
+						let Some(&data) = incbins.get(&file.as_span().start()) else {
+							continue;
+						};
+
+						write(&mut images[section], addr, data, dir.as_span().into());
+						if !data.is_empty() {
+							source_map.push(SourceMapping {
+								address: addr,
+								size: data.len() as u64,
+								span: dir.as_span().into(),
+							});
+						}
+						addr = addr.saturating_add(data.len() as u64);
+					},
+					Rule::directive_def | Rule::directive_section | Rule::directive_global => {},
+					_ => unreachable!(),
+				}
+
+				continue;
+			},
+			Rule::instr => { /* handled below */ },
+			_ => continue,
+		}
+
+		// align it to instruction size and advance by one instruction (the first pass reported overflows)
+		addr = match addr
+			.checked_next_multiple_of(4)
+			.and_then(|start| start.checked_add(4))
+		{
+			Some(end) => end,
+			None => continue,
+		};
+
+		let span = pair.as_span().into();
+		let image = &mut images[section];
+		let mut write_instruction = |encoded: u32| {
+			write(image, addr - 4, &encoded.to_le_bytes(), span);
+			source_map.push(SourceMapping {
+				address: addr - 4,
+				size: 4,
+				span,
+			});
+		};
+
+		let instr = pair.into_inner().next().unwrap();
+		let mut instr_pairs = instr.clone().into_inner();
+		let instr_rule = instr.as_rule();
+		// `ldi`'s value (as opposed to its shift and mode)
+		let first_immediate = instr
+			.clone()
+			.into_inner()
+			.find(|pair| pair.as_rule() == Rule::immediate)
+			.map(|pair| Span::from(pair.as_span()));
+
+		instructions! {
+			pushs[.s] a:reg | null               => [1101110000000000000000000ssaaaaa];
+			pushp[.s] a:reg | null, b:reg | null => [11011000000000000000ssaaaaabbbbb];
+			pops[.s]  a:reg | null               => [1101010000000000000000000ssaaaaa];
+			popp[.s]  a:reg | null, b:reg | null => [11010000000000000000ssaaaaabbbbb];
+
+			lds[.s] d:reg, a:reg        => [1100110000000000000000ssddddaaaa];
+			ldp[.s] d:reg, e:reg, a:reg => [110010000000000000ssddddeeeeaaaa];
+			sts[.s] a:reg, b:reg        => [1100010000000000000000ssaaaabbbb];
+			stp[.s] a:reg, b:reg, c:reg => [110000000000000000ssaaaabbbbcccc];
+
+			ldi d:reg, a:imm16, [b:imm6], [c:imm2] => [1110ccaaaaaaaaaaaaaaaabbbbbbdddd];
+
+			ldr d:reg, a:rel22(next_byte_relative_immediate) => [001100ddddaaaaaaaaaaaaaaaaaaaaaa];
+
+			copy[.s] d:reg, S:reg => [1010100000000000000000ssddddSSSS];
+
+			add_reg = add[.s] d:reg | null, a:reg, b:reg,                        [c:bool], [f:bool] => [101001000000000sscfdddddaaaabbbb];
+			add_imm = add[.s] d:reg | null, a:reg, b:imm11, [S:imm3], [A: bool], [c:bool], [f:bool] => [1011sscfdddddaaaaASSSbbbbbbbbbbb];
+			sub_reg = sub[.s] d:reg | null, a:reg, b:reg,                        [B:bool], [f:bool] => [101000000000000ssBfdddddaaaabbbb];
+			sub_imm = sub[.s] d:reg | null, a:reg, b:imm11, [S:imm3], [A: bool], [B:bool], [f:bool] => [1001ssBfdddddaaaaASSSbbbbbbbbbbb];
+
+			mul d:reg, a:reg, b:reg, [S:bool], [f:bool] => {
+				d: reg, a: reg, b: reg, S: bool, f: bool {
+					let s = size_of(a, b);
+					let t = size_of(d);
+				} => [10001100000000ssttSfddddaaaabbbb],
+			};
+
+			div[.s] d:reg, r:reg, a:reg, b:reg, [S:bool], [f:bool] => [100010000000ssSfddddrrrraaaabbbb];
+
+			and_reg = and[.s] d:reg | null, a:reg, b:reg,                        [f:bool] => [1010110000000000ssfdddddaaaabbbb];
+			and_imm = and[.s] d:reg | null, a:reg, b:imm11, [S: imm3], [A:bool], [f:bool] => [10000AssfdddddaaaabbbbbbbbbbbSSS];
+			or_reg  = or[.s]  d:reg | null, a:reg, b:reg,                        [f:bool] => [0100000000000000ssfdddddaaaabbbb];
+			or_imm  = or[.s]  d:reg | null, a:reg, b:imm11, [S: imm3], [A:bool], [f:bool] => [01111AssfdddddaaaabbbbbbbbbbbSSS];
+			xor_reg = xor[.s] d:reg | null, a:reg, b:reg,                        [f:bool] => [0011100000000000ssfdddddaaaabbbb];
+			xor_imm = xor[.s] d:reg | null, a:reg, b:imm11, [S: imm3], [A:bool], [f:bool] => [01110AssfdddddaaaabbbbbbbbbbbSSS];
+
+			shl[.s] d:reg | null, a:reg, b:reg | imm7, [f: bool] => {
+				d: reg | null, a: reg, b: reg,  f: bool => [0110100000000000ssfdddddaaaabbbb],
+				d: reg | null, a: reg, b: imm7, f: bool => [0110110000000ssfdddddaaaabbbbbbb],
+			};
+			shr[.s] d:reg | null, a:reg, b:reg | imm7, [A:bool], [f:bool] => {
+				d: reg | null, a: reg, b: reg,  A: bool, f: bool => [011000000000000ssAfdddddaaaabbbb],
+				d: reg | null, a: reg, b: imm7, A: bool, f: bool => [011001000000ssAfdddddaaaabbbbbbb],
+			};
+			rot[.s] d:reg | null, a:reg, b:reg | imm7, [f: bool] => {
+				d: reg | null, a: reg, b: reg,  f: bool => [0101100000000000ssfdddddaaaabbbb],
+				d: reg | null, a: reg, b: imm7, f: bool => [0101110000000ssfdddddaaaabbbbbbb],
+			};
+
+			neg[.s]   d:reg, a:reg, [f: bool] => [010101000000000000000ssfddddaaaa];
+			bswap[.s] d:reg, a:reg, [f: bool] => [010100000000000000000ssfddddaaaa];
+
+			soc.c[.s] d:reg, a:reg, b:reg, [B: bool] => [1111000000000ccccBssddddaaaabbbb];
+			sof.c[.s] d:reg => [1111010000000000000000ccccssdddd];
+
+			jmpa[.c] a:reg => [010011000000000000000000ccccaaaa];
+			jmpr[.c] a:reg | rel22 => {
+				a: reg   => [010010000000000000000000ccccaaaa],
+				a: rel22 => [010001ccccaaaaaaaaaaaaaaaaaaaaaa],
+			};
+
+			cjmpa.c[.s] a:reg, b:reg, C:reg => [00111100000000ccccssaaaabbbbCCCC];
+			cjmpr.c[.s] a:reg | rel13, b:reg, C:reg => {
+				a: reg,   b: reg, C: reg => [00110100000000ccccssaaaabbbbCCCC],
+				a: rel13, b: reg, C: reg => [11111ccccssbbbbCCCCaaaaaaaaaaaaa],
+			};
+
+			calla[.c] a:reg => [001010000000000000000000ccccaaaa];
+			callr[.c] a:reg | rel22 => {
+				a: reg   => [001001000000000000000000ccccaaaa],
+				a: rel22 => [001000ccccaaaaaaaaaaaaaaaaaaaaaa],
+			};
+
+			ret         => [00011100000000000000000000000000];
+			eret        => [00011000000000000000000000000000];
+			udf         => [00000000000000000000000000000000];
+			dbg         => [00001000000000000000000000000000];
+			exc a:imm16 => [0000110000000000aaaaaaaaaaaaaaaa];
+			nop         => [00000100000000000000000000000000];
+
+			ldm d:reg, a:imm22(next_machine_register_or_immediate) => [000100ddddaaaaaaaaaaaaaaaaaaaaaa];
+			stm d:imm22(next_machine_register_or_immediate), a:reg => [000101aaaadddddddddddddddddddddd];
+
+			//
+			// pseudo-instructions
+			//
+
+			// cmp is a pseudo-instruction for sub (with a null destination and flag-setting)
+			pseudo_cmp_reg = cmp [.s] a:reg, b:reg,                        [B:bool] => [101000000000000ssB111111aaaabbbb];
+			pseudo_cmp_imm = cmp [.s] a:reg, b:imm11, [S:imm3], [A: bool], [B:bool] => [1001ssB111111aaaaASSSbbbbbbbbbbb];
+		}
+
+		// find out how to relocate operands that depend on where the object is linked
+		for operand in pending.borrow_mut().drain(..) {
+			let kind = match (instr_rule, operand.form) {
+				(Rule::instr_ldi, OperandForm::Absolute)
+					if Some(operand.span) == first_immediate =>
+				{
+					RelocationKind::Ldi16
+				},
+				(Rule::instr_jmpr | Rule::instr_callr, OperandForm::Relative) => {
+					RelocationKind::Rel22
+				},
+				(Rule::instr_cjmpr, OperandForm::Relative) => RelocationKind::Rel13,
+				(Rule::instr_ldr, OperandForm::ByteRelative) => RelocationKind::ByteRel22,
+				_ => {
+					diagnostics
+						.borrow_mut()
+						.recover(operand.value.constant(operand.span), 0);
+					continue;
+				},
+			};
+			relocate(kind, addr - 4, operand.value, operand.span);
+		}
+	}
+
+	let mut diagnostics = diagnostics.into_inner();
+
+	if !object {
+		if diagnostics.has_errors() {
+			return Err(diagnostics);
+		}
+
+		let mut symbols: Vec<_> = label_names
+			.iter()
+			.map(|name| Symbol {
+				name: name.to_string(),
+				address: match label_addrs[name] {
+					Value::Constant(address) => address,
+					Value::Relocatable { .. } => unreachable!(),
+				},
+			})
+			.collect();
+		symbols.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+		// `.addr` can move backwards, so the source isn't necessarily in address order
+		source_map.sort_by_key(|mapping| mapping.address);
+
+		return Ok(Assembled::Image(AssembledImage {
+			bytes: images.remove(0),
+			symbols,
+			source_map,
+			included_files: Vec::new(),
+			warnings: diagnostics,
+		}));
+	}
+
+	let mut object = Object {
+		sections: sections
+			.iter()
+			.zip(images)
+			.map(|(&(name, _), data)| object::Section {
+				name: name.to_string(),
+				alignment: 4,
+				data,
+			})
+			.collect(),
+		..Default::default()
+	};
+	let binding = |name: &str| {
+		if globals.iter().any(|&(global, _)| global == name) {
+			Binding::Global
+		} else {
+			Binding::Local
+		}
+	};
+
+	for name in &label_names {
+		if let Value::Relocatable {
+			base: Base::Section(section),
+			addend,
+			..
+		} = label_addrs[name]
+		{
+			object.symbols.push(ObjectSymbol {
+				name: name.to_string(),
+				binding: binding(name),
+				value: SymbolValue::Section {
+					section: section as u32,
+					offset: addend,
+				},
+			});
+		}
+	}
+
+	// `.def`s can be exported too, and `.global`s that aren't defined anywhere just declare the symbol
+	for &(name, span) in &globals {
+		if object.symbols.iter().any(|symbol| symbol.name == name) {
+			continue;
+		}
+		let value =
+			match label_addrs.get(name) {
+				None => SymbolValue::Undefined,
+				Some(Value::Constant(value)) => SymbolValue::Absolute(*value),
+				Some(&Value::Relocatable {
+					base: Base::Section(section),
+					addend,
+					shift: 0,
+					masked: false,
+				}) => SymbolValue::Section {
+					section: section as u32,
+					offset: addend,
+				},
+				Some(Value::Relocatable { .. }) => {
+					diagnostics.error(
+					span,
+					format!("\"{}\" can't be exported, since it's neither an address nor a constant", name),
+				);
+					continue;
+				},
+			};
+		object.symbols.push(ObjectSymbol {
+			name: name.to_string(),
+			binding: Binding::Global,
+			value,
+		});
+	}
+
+	for relocation in relocations.into_inner() {
+		let target = match relocation.base {
+			None => RelocationTarget::Absolute,
+			Some(Base::Section(section)) => RelocationTarget::Section(section as u32),
+			Some(Base::External(name)) => {
+				let index = match object.symbols.iter().position(|symbol| symbol.name == name) {
+					Some(index) => index,
+					None => {
+						object.symbols.push(ObjectSymbol {
+							name: name.to_string(),
+							binding: Binding::Global,
+							value: SymbolValue::Undefined,
+						});
+						object.symbols.len() - 1
+					},
+				};
+				RelocationTarget::Symbol(index as u32)
+			},
+		};
+		object.relocations.push(object::Relocation {
+			section: relocation.section as u32,
+			offset: relocation.offset,
+			kind: relocation.kind,
+			target,
+			addend: relocation.addend,
+			shift: relocation.shift,
+		});
+	}
+
+	if diagnostics.has_errors() {
+		return Err(diagnostics);
+	}
+
+	Ok(Assembled::Object(AssembledObject {
+		object,
+		warnings: diagnostics,
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Returns the messages of the errors in `diagnostics`, along with the source text each one points at.
+	fn errors<'a>(diagnostics: &Diagnostics, source: &'a str) -> Vec<(String, &'a str)> {
+		diagnostics
+			.iter()
+			.filter(|diagnostic| diagnostic.severity == diagnostics::Severity::Error)
+			.map(|diagnostic| {
+				(
+					diagnostic.message.clone(),
+					&source[diagnostic.span.start..diagnostic.span.end],
+				)
+			})
+			.collect()
+	}
+
+	#[test]
+	fn assemble_reports_every_error() {
+		let source = "entry:\n\tjmpr missing\nentry:\n\tldi r0, 1 / 0, 0, 3\n\t.section .data\n";
+		let diagnostics = assemble(source, &Default::default()).unwrap_err();
+
+		assert_eq!(diagnostics.error_count(), 4);
+		// labels and sections are checked in the first pass, operands in the second
+		assert_eq!(
+			errors(&diagnostics, source),
+			[
+				("duplicate label \"entry\"".to_string(), "entry"),
+				(
+					"sections can only be used when assembling a relocatable object".to_string(),
+					".data"
+				),
+				("unknown label \"missing\"".to_string(), "missing"),
+				("division by zero".to_string(), "/"),
+			]
+		);
+
+		let duplicate = diagnostics.iter().next().unwrap();
+		assert_eq!(duplicate.notes.len(), 1);
+		assert_eq!(duplicate.notes[0].message, "first defined here");
+		assert_eq!(duplicate.notes[0].span, Some(Span::new(0, 5)));
+	}
+
+	#[test]
+	fn assemble_reports_parse_errors() {
+		let diagnostics = assemble(".addr\n", &Default::default()).unwrap_err();
+		assert!(diagnostics.has_errors());
+		assert_eq!(diagnostics.error_count(), 1);
+		assert_eq!(
+			diagnostics.iter().next().unwrap().message,
+			"expected immediate"
+		);
+	}
+
+	#[test]
+	fn assemble_reports_errors_after_expansion_errors() {
+		let source = ".include \"missing.acca\"\ndata: .incbin \"missing.bin\"\n\tjmpr data\n\tjmpr nowhere\n";
+		let diagnostics = assemble(source, &Default::default()).unwrap_err();
+
+		assert_eq!(
+			errors(&diagnostics, source),
+			[
+				(
+					"couldn't find \"missing.acca\"".to_string(),
+					"\"missing.acca\""
+				),
+				(
+					"couldn't find \"missing.bin\"".to_string(),
+					"\"missing.bin\""
+				),
+				("unknown label \"nowhere\"".to_string(), "nowhere"),
+			]
+		);
+	}
+
+	#[test]
+	fn assemble_limits_image_size() {
+		let source = ".addr 0x10_0000_0000\n\tnop\n\tnop\n";
+		let diagnostics = assemble(source, &Default::default()).unwrap_err();
+		assert_eq!(
+			errors(&diagnostics, source),
+			[(
+				format!(
+					"this goes past the maximum image size of {:#x} bytes",
+					DEFAULT_MAX_IMAGE_SIZE
+				),
+				"nop"
+			)]
+		);
+
+		let options = AssembleOptions {
+			max_image_size: Some(0x404),
+			..Default::default()
+		};
+		assert!(assemble(".addr 0x400\n\tnop\n", &options).is_ok());
+		assert!(assemble(".addr 0x400\n\tnop\n\tnop\n", &options).is_err());
+	}
+
+	#[test]
+	fn assemble_reports_address_overflow() {
+		let source = ".addr 0xffff_ffff_ffff_fff8\n\tnop\n\tnop\n\t.write.w 1\n";
+		let diagnostics = assemble(source, &Default::default()).unwrap_err();
+		let overflows: Vec<_> = errors(&diagnostics, source)
+			.into_iter()
+			.filter(|(message, _)| message == "address overflows the address space")
+			.map(|(_, text)| text.trim_end())
+			.collect();
+		assert_eq!(overflows, ["nop", ".write.w 1"]);
+	}
+
+	#[test]
+	fn assemble_object_reports_errors() {
+		let source = ".section .text\nentry:\n\tldi r0, entry * 2, 0, 3\n\tjmpr entry + 1\n";
+		let diagnostics = assemble_object(source, &Default::default()).unwrap_err();
+
+		assert_eq!(
+			errors(&diagnostics, source),
+			[
+				(
+					"this can't be applied to an address that's only known once the object is linked"
+						.to_string(),
+					"*"
+				),
+				(
+					"relative address 0x1 isn't aligned to 4 bytes".to_string(),
+					"entry + 1"
+				),
+			]
+		);
+	}
+
+	#[test]
+	fn assemble_object_places_code_in_sections() {
+		let object =
+			assemble_object(".section .text\nentry:\n\tnop\n", &Default::default()).unwrap();
+		assert!(!object.warnings.has_errors());
+		assert_eq!(object.object.sections[0].data, [0x00, 0x00, 0x00, 0x04]);
+	}
+}
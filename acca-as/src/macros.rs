@@ -0,0 +1,1067 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Macros and includes, which are expanded before the source is parsed.
+//!
+//! ```text
+//! .macro load_address reg, address, mode=3
+//!     ldi \reg, (\address >> 16) & 0xffff, 16, \mode
+//!     ldi \reg, \address & 0xffff, 0, 1
+//! .endm
+//!
+//!     load_address r0, message
+//! ```
+//!
+//! In a macro's body, `\name` is replaced by the argument for the parameter `name` (or by its default, if the argument
+//! is empty or missing), `\@` by a number that's unique to each expansion (for labels like `loop\@`), and `\()` by
+//! nothing (to separate a parameter from the text after it). Macros can use other macros (including themselves), up
+//! to [`MAX_DEPTH`] expansions deep.
+//!
+//! `.include "file.acca"` is replaced by the contents of the file, which is looked for relative to the directory of the
+//! file containing the `.include`, then in each of [`AssembleOptions::include_paths`]. `.incbin "file"` is looked for
+//! the same way, but it's assembled like any other directive; its contents are read here and handed to the assembler.
+
+use std::{
+	collections::HashMap,
+	fs,
+	ops::Range,
+	path::{Path, PathBuf},
+};
+
+use crate::{
+	diagnostics::{Diagnostic, Diagnostics, IncludedFile, Note, Span},
+	AssembleOptions,
+};
+
+/// How deeply macro expansions can be nested.
+pub const MAX_DEPTH: usize = 64;
+
+/// How many of the expansions a diagnostic occurred in are listed.
+const MAX_EXPANSION_NOTES: usize = 8;
+
+/// Part of a [`MappedText`] that was copied from a single place.
+#[derive(Debug, Clone, Copy)]
+struct Piece {
+	/// The offset of the piece in the text.
+	start: usize,
+	/// The offset of the piece in the original source.
+	origin: usize,
+	/// The macro expansion that produced the piece, if any.
+	expansion: Option<usize>,
+}
+
+/// Text assembled from parts of the original source, along with where each part came from.
+#[derive(Debug, Clone, Default)]
+struct MappedText {
+	text: String,
+	pieces: Vec<Piece>,
+}
+
+impl MappedText {
+	fn push(&mut self, text: &str, origin: usize, expansion: Option<usize>) {
+		if text.is_empty() {
+			return;
+		}
+		match self.pieces.last() {
+			// keep consecutive text from the same place in a single piece
+			Some(last)
+				if last.expansion == expansion
+					&& last.origin + (self.text.len() - last.start) == origin => {},
+			_ => self.pieces.push(Piece {
+				start: self.text.len(),
+				origin,
+				expansion,
+			}),
+		}
+		self.text.push_str(text);
+	}
+
+	/// Appends `range` of `other`, attributing it to `expansion` (if given) instead of the expansion that produced it.
+	fn push_slice(
+		&mut self,
+		other: &MappedText,
+		range: Range<usize>,
+		expansion: Option<Option<usize>>,
+	) {
+		for (index, piece) in other.pieces.iter().enumerate() {
+			let end = other
+				.pieces
+				.get(index + 1)
+				.map_or(other.text.len(), |next| next.start);
+			let (start, end) = (piece.start.max(range.start), end.min(range.end));
+			if start < end {
+				self.push(
+					&other.text[start..end],
+					piece.origin + (start - piece.start),
+					expansion.unwrap_or(piece.expansion),
+				);
+			}
+		}
+	}
+
+	/// Returns where the byte at `offset` came from.
+	fn locate(&self, offset: usize) -> (usize, Option<usize>) {
+		let index = self
+			.pieces
+			.partition_point(|piece| piece.start <= offset)
+			.saturating_sub(1);
+		match self.pieces.get(index) {
+			Some(piece) => (
+				piece.origin + offset.saturating_sub(piece.start),
+				piece.expansion,
+			),
+			None => (offset, None),
+		}
+	}
+
+	fn span(&self, range: Range<usize>) -> Span {
+		Span::new(self.locate(range.start).0, self.locate(range.end).0)
+	}
+}
+
+#[derive(Debug, Clone)]
+struct Parameter {
+	name: String,
+	default: Option<MappedText>,
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+	parameters: Vec<Parameter>,
+	body: MappedText,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ExpansionKind {
+	Macro(String),
+	/// Contains the canonical path of the file.
+	Include(PathBuf),
+}
+
+/// One use of a macro or `.include`.
+#[derive(Debug, Clone)]
+struct Expansion {
+	kind: ExpansionKind,
+	call_site: Span,
+	/// The expansion the use is in, if any.
+	parent: Option<usize>,
+}
+
+/// A macro whose body is being read.
+struct Definition {
+	name: String,
+	/// Where the `.macro` directive is.
+	span: Span,
+	parameters: Vec<Parameter>,
+	body: MappedText,
+}
+
+/// The source, with all macros and includes expanded.
+pub struct Expanded {
+	text: MappedText,
+	expansions: Vec<Expansion>,
+	files: Vec<IncludedFile>,
+	/// The contents of each `.incbin`, by the offset of its file name in the expanded text.
+	binaries: HashMap<usize, Vec<u8>>,
+	/// The problems found while expanding, which refer to the original source.
+	diagnostics: Diagnostics,
+}
+
+struct Expander<'a> {
+	options: &'a AssembleOptions,
+	source_length: usize,
+	/// The canonical path of the source, if it's known.
+	source_path: Option<PathBuf>,
+	macros: HashMap<String, Macro>,
+	expansions: Vec<Expansion>,
+	definition: Option<Definition>,
+	files: Vec<IncludedFile>,
+	/// The canonical path of each of `files`.
+	canonical_paths: Vec<PathBuf>,
+	binaries: HashMap<usize, Vec<u8>>,
+	diagnostics: Diagnostics,
+}
+
+/// Returns the characters of `text` that aren't within quotes (including the quotes themselves).
+fn unquoted(text: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+	let mut quote = None;
+	let mut escaped = false;
+	text.char_indices().filter(move |&(_, char)| {
+		match quote {
+			Some(_) if escaped => escaped = false,
+			// only characters have escapes; strings can't contain quotes
+			Some('\'') if char == '\\' => escaped = true,
+			Some(open) if char == open => quote = None,
+			Some(_) => {},
+			None if char == '\'' || char == '"' => quote = Some(char),
+			None => return true,
+		}
+		false
+	})
+}
+
+/// Returns the length of the part of `line` before its comment (if any).
+fn code_length(line: &str) -> usize {
+	unquoted(line)
+		.find(|&(_, char)| char == '#')
+		.map_or(line.len(), |(index, _)| index)
+}
+
+/// Returns the contents of `text` if it's a string (e.g. `"file.acca"`).
+fn string_contents(text: &str) -> Option<&str> {
+	let contents = text.strip_prefix('"')?.strip_suffix('"')?;
+	(!contents.contains('"')).then_some(contents)
+}
+
+fn is_ident_char(char: char) -> bool {
+	char.is_ascii_alphanumeric() || char == '_'
+}
+
+/// Returns the length of the identifier at the start of `text` (0 if there isn't one).
+fn ident_length(text: &str) -> usize {
+	if !text.starts_with(|char: char| char.is_ascii_alphabetic() || char == '_') {
+		return 0;
+	}
+	text.find(|char| !is_ident_char(char)).unwrap_or(text.len())
+}
+
+/// Returns the offset of the first statement in `code`, after any labels.
+fn skip_labels(code: &str) -> usize {
+	let mut offset = 0;
+	loop {
+		let rest = &code[offset..];
+		let start = offset + (rest.len() - rest.trim_start().len());
+		let length = ident_length(&code[start..]);
+		let after = &code[start + length..];
+		match after.trim_start().strip_prefix(':') {
+			Some(after_colon) if length > 0 => offset = code.len() - after_colon.len(),
+			_ => return start,
+		}
+	}
+}
+
+/// Splits `text` at commas that aren't within parentheses or quotes, returning the (trimmed) range of each part.
+fn split_arguments(text: &str) -> Vec<Range<usize>> {
+	if text.trim().is_empty() {
+		return Vec::new();
+	}
+
+	let mut ranges = Vec::new();
+	let mut start = 0;
+	let mut depth = 0usize;
+	for (index, char) in unquoted(text) {
+		match char {
+			'(' => depth += 1,
+			')' => depth = depth.saturating_sub(1),
+			',' if depth == 0 => {
+				ranges.push(start..index);
+				start = index + 1;
+			},
+			_ => {},
+		}
+	}
+	ranges.push(start..text.len());
+
+	ranges
+		.into_iter()
+		.map(|range| {
+			let part = &text[range.clone()];
+			let start = range.start + (part.len() - part.trim_start().len());
+			start..range.start + part.trim_end().len()
+		})
+		.collect()
+}
+
+/// Adds notes pointing at the uses of the macros and `.include`s `expansion` is nested in.
+fn with_expansion_notes(
+	expansions: &[Expansion],
+	mut diagnostic: Diagnostic,
+	mut expansion: Option<usize>,
+) -> Diagnostic {
+	let mut count = 0;
+	while let Some(index) = expansion {
+		if count == MAX_EXPANSION_NOTES {
+			let remaining =
+				std::iter::successors(Some(index), |&index| expansions[index].parent).count();
+			diagnostic.notes.push(Note {
+				span: None,
+				message: format!("...and {} more expansion(s)", remaining),
+			});
+			break;
+		}
+
+		// recursive macros use themselves from the same place over and over, which only needs to be shown once
+		let Expansion {
+			kind, call_site, ..
+		} = &expansions[index];
+		let mut repeats = 0;
+		while let Some(parent) = expansion.and_then(|index| {
+			let parent = &expansions[index];
+			(&parent.kind == kind && parent.call_site == *call_site).then_some(parent.parent)
+		}) {
+			expansion = parent;
+			repeats += 1;
+		}
+
+		diagnostic = diagnostic.with_note(
+			*call_site,
+			match kind {
+				ExpansionKind::Include(_) => "included from here".to_string(),
+				ExpansionKind::Macro(name) if repeats == 1 => {
+					format!("in this expansion of macro \"{}\"", name)
+				},
+				ExpansionKind::Macro(name) => {
+					format!("in {} nested expansions of macro \"{}\"", repeats, name)
+				},
+			},
+		);
+		count += 1;
+	}
+	diagnostic
+}
+
+/// Keeps the labels in `range` (before a macro or `.include`), on a line of their own.
+fn push_labels(output: &mut MappedText, input: &MappedText, range: Range<usize>) {
+	output.push_slice(input, range.clone(), None);
+	let (origin, expansion) = input.locate(range.end);
+	output.push("\n", origin, expansion);
+}
+
+impl Expander<'_> {
+	fn error(&mut self, text: &MappedText, range: Range<usize>, message: String) {
+		let (_, expansion) = text.locate(range.start);
+		let diagnostic = Diagnostic::error(text.span(range), message);
+		let diagnostic = with_expansion_notes(&self.expansions, diagnostic, expansion);
+		self.diagnostics.push(diagnostic);
+	}
+
+	/// Expands the macros used in `input`, appending the result to `output`.
+	fn expand(&mut self, input: &MappedText, depth: usize, output: &mut MappedText) {
+		let mut line_start = 0;
+		for line in input.text.split_inclusive('\n') {
+			let line_range = line_start..line_start + line.len();
+			line_start += line.len();
+
+			let code = &line[..code_length(line)];
+			let statement = skip_labels(code);
+			let word_length = code[statement..]
+				.find(|char: char| !(is_ident_char(char) || char == '.'))
+				.unwrap_or(code.len() - statement);
+			let word = &code[statement..statement + word_length];
+			let word_range =
+				line_range.start + statement..line_range.start + statement + word_length;
+			let rest_range = word_range.end..line_range.start + code.len();
+
+			if self.definition.is_some() {
+				match word {
+					".endm" => self.finish_definition(),
+					".macro" => self.error(
+						input,
+						word_range,
+						"macros can't be defined inside other macros".to_string(),
+					),
+					_ => self
+						.definition
+						.as_mut()
+						.unwrap()
+						.body
+						.push_slice(input, line_range, None),
+				}
+				continue;
+			}
+
+			match word {
+				".macro" => self.start_definition(input, word_range, rest_range),
+				".include" => {
+					push_labels(output, input, line_range.start..word_range.start);
+					self.include(input, word_range, rest_range, depth, output);
+					// keep the comment (and newline) after the `.include`
+					output.push_slice(input, line_range.start + code.len()..line_range.end, None);
+				},
+				".incbin" => {
+					let line_offset = output.text.len();
+					match self.read_binary(input, word_range.clone(), rest_range) {
+						Some((range, contents)) => {
+							self.binaries
+								.insert(line_offset + (range.start - line_range.start), contents);
+							output.push_slice(input, line_range, None);
+						},
+						None => {
+							// the error has been reported, but the labels are still needed by the rest of the source
+							push_labels(output, input, line_range.start..word_range.start);
+							output.push_slice(
+								input,
+								line_range.start + code.len()..line_range.end,
+								None,
+							);
+						},
+					}
+				},
+				".endm" => self.error(
+					input,
+					word_range,
+					"\".endm\" without \".macro\"".to_string(),
+				),
+				_ if self.macros.contains_key(word) => {
+					push_labels(output, input, line_range.start..word_range.start);
+
+					if depth >= MAX_DEPTH {
+						self.error(
+							input,
+							word_range,
+							format!(
+								"macro expansions are nested more than {} deep (is \"{}\" recursive?)",
+								MAX_DEPTH, word
+							),
+						);
+					} else {
+						let (call_site_origin, parent) = input.locate(word_range.start);
+						self.expansions.push(Expansion {
+							kind: ExpansionKind::Macro(word.to_string()),
+							call_site: Span::new(call_site_origin, call_site_origin + word_length),
+							parent,
+						});
+						let expansion = self.expansions.len() - 1;
+						if let Some(body) =
+							self.instantiate(input, word_range.clone(), rest_range, expansion)
+						{
+							self.expand(&body, depth + 1, output);
+						}
+					}
+
+					// keep the comment (and newline) after the macro
+					output.push_slice(input, line_range.start + code.len()..line_range.end, None);
+				},
+				_ => output.push_slice(input, line_range, None),
+			}
+		}
+	}
+
+	/// Returns the range and contents of the string that's the first argument of a `.include` or `.incbin`.
+	fn file_name_argument(
+		&mut self,
+		input: &MappedText,
+		word_range: Range<usize>,
+		rest_range: Range<usize>,
+	) -> Option<(Range<usize>, String)> {
+		let range = split_arguments(&input.text[rest_range.clone()])
+			.first()
+			.map(|range| rest_range.start + range.start..rest_range.start + range.end);
+		match range
+			.clone()
+			.and_then(|range| string_contents(&input.text[range]))
+		{
+			Some(name) if !name.is_empty() => Some((range.unwrap(), name.to_string())),
+			_ => {
+				let range = range
+					.filter(|range| !range.is_empty())
+					.unwrap_or(word_range);
+				self.error(
+					input,
+					range,
+					"expected a file name in double quotes".to_string(),
+				);
+				None
+			},
+		}
+	}
+
+	/// Looks for the file called `name` (used at `range`), relative to the file that uses it and then in the include
+	/// paths.
+	fn find_file(
+		&mut self,
+		input: &MappedText,
+		range: Range<usize>,
+		name: &str,
+	) -> Option<PathBuf> {
+		let (origin, _) = input.locate(range.start);
+		let directory = match self.files.iter().rev().find(|file| file.start <= origin) {
+			Some(file) if origin > self.source_length => file.path.parent(),
+			_ => self.options.source_path.as_deref().and_then(Path::parent),
+		}
+		.unwrap_or(Path::new(""))
+		.to_path_buf();
+
+		let found = std::iter::once(&directory)
+			.chain(&self.options.include_paths)
+			.map(|directory| directory.join(name))
+			.find(|path| path.is_file());
+		if found.is_none() {
+			self.error(input, range, format!("couldn't find \"{}\"", name));
+		}
+		found
+	}
+
+	fn include(
+		&mut self,
+		input: &MappedText,
+		word_range: Range<usize>,
+		rest_range: Range<usize>,
+		depth: usize,
+		output: &mut MappedText,
+	) {
+		let Some((range, name)) = self.file_name_argument(input, word_range.clone(), rest_range)
+		else {
+			return;
+		};
+		let Some(path) = self.find_file(input, range.clone(), &name) else {
+			return;
+		};
+		let canonical_path = match fs::canonicalize(&path) {
+			Ok(path) => path,
+			Err(error) => {
+				self.error(
+					input,
+					range,
+					format!("failed to read \"{}\": {}", path.display(), error),
+				);
+				return;
+			},
+		};
+
+		let (origin, parent) = input.locate(word_range.start);
+		let mut includers = std::iter::successors(parent, |&index| self.expansions[index].parent)
+			.filter_map(|index| match &self.expansions[index].kind {
+				ExpansionKind::Include(path) => Some(path),
+				ExpansionKind::Macro(_) => None,
+			});
+		if self.source_path.as_ref() == Some(&canonical_path)
+			|| includers.any(|includer| *includer == canonical_path)
+		{
+			self.error(
+				input,
+				range,
+				format!("\"{}\" includes itself", path.display()),
+			);
+			return;
+		}
+
+		// files included more than once are only read (and shown in diagnostics) once
+		let index = match self
+			.canonical_paths
+			.iter()
+			.position(|existing| *existing == canonical_path)
+		{
+			Some(index) => index,
+			None => {
+				let text = match fs::read_to_string(&path) {
+					Ok(text) => text,
+					Err(error) => {
+						self.error(
+							input,
+							range,
+							format!("failed to read \"{}\": {}", path.display(), error),
+						);
+						return;
+					},
+				};
+				let start = match self.files.last() {
+					Some(file) => file.start + file.text.len(),
+					None => self.source_length,
+				} + 1;
+				self.files.push(IncludedFile { path, start, text });
+				self.canonical_paths.push(canonical_path.clone());
+				self.files.len() - 1
+			},
+		};
+
+		let (end, _) = input.locate(range.end);
+		self.expansions.push(Expansion {
+			kind: ExpansionKind::Include(canonical_path),
+			call_site: Span::new(origin, end),
+			parent,
+		});
+		let mut text = MappedText::default();
+		let file = &self.files[index];
+		text.push(&file.text, file.start, Some(self.expansions.len() - 1));
+		self.expand(&text, depth, output);
+	}
+
+	/// Reads the file used by a `.incbin`, returning the range of its name and its contents.
+	fn read_binary(
+		&mut self,
+		input: &MappedText,
+		word_range: Range<usize>,
+		rest_range: Range<usize>,
+	) -> Option<(Range<usize>, Vec<u8>)> {
+		let (range, name) = self.file_name_argument(input, word_range.clone(), rest_range)?;
+		let path = self.find_file(input, range.clone(), &name)?;
+		match fs::read(&path) {
+			Ok(contents) => Some((range, contents)),
+			Err(error) => {
+				self.error(
+					input,
+					range,
+					format!("failed to read \"{}\": {}", path.display(), error),
+				);
+				None
+			},
+		}
+	}
+
+	fn start_definition(
+		&mut self,
+		input: &MappedText,
+		word_range: Range<usize>,
+		rest_range: Range<usize>,
+	) {
+		let rest = &input.text[rest_range.clone()];
+		let name_start = rest.len() - rest.trim_start().len();
+		let name_length = ident_length(&rest[name_start..]);
+		if name_length == 0 {
+			self.error(
+				input,
+				word_range,
+				"expected the name of the macro".to_string(),
+			);
+			// skip the body anyway
+			self.definition = Some(Definition {
+				name: String::new(),
+				span: input.span(rest_range),
+				parameters: Vec::new(),
+				body: MappedText::default(),
+			});
+			return;
+		}
+		let name = &rest[name_start..name_start + name_length];
+
+		// the parameters may be separated from the name by a comma
+		let mut parameters_offset = name_start + name_length;
+		if let Some(after_comma) = rest[parameters_offset..].trim_start().strip_prefix(',') {
+			parameters_offset = rest.len() - after_comma.len();
+		}
+
+		let mut parameters = Vec::<Parameter>::new();
+		for range in split_arguments(&rest[parameters_offset..]) {
+			let range = rest_range.start + parameters_offset + range.start
+				..rest_range.start + parameters_offset + range.end;
+			let parameter = &input.text[range.clone()];
+			let (name, default) = match parameter.split_once('=') {
+				Some((name, default)) => {
+					let default_start = range.start + name.len() + 1;
+					let default_start =
+						default_start + (default.len() - default.trim_start().len());
+					let mut text = MappedText::default();
+					text.push_slice(input, default_start..range.end, None);
+					(name.trim_end(), Some(text))
+				},
+				None => (parameter, None),
+			};
+
+			if name.is_empty() || ident_length(name) != name.len() {
+				self.error(input, range, format!("invalid parameter name \"{}\"", name));
+			} else if parameters.iter().any(|existing| existing.name == name) {
+				self.error(input, range, format!("duplicate parameter \"{}\"", name));
+			} else {
+				parameters.push(Parameter {
+					name: name.to_string(),
+					default,
+				});
+			}
+		}
+
+		let name_range = rest_range.start + name_start..rest_range.start + name_start + name_length;
+		self.definition = Some(Definition {
+			name: name.to_string(),
+			span: input.span(name_range),
+			parameters,
+			body: MappedText::default(),
+		});
+	}
+
+	fn finish_definition(&mut self) {
+		let definition = self.definition.take().unwrap();
+		if !definition.name.is_empty() {
+			self.macros.insert(
+				definition.name,
+				Macro {
+					parameters: definition.parameters,
+					body: definition.body,
+				},
+			);
+		}
+	}
+
+	/// Substitutes the arguments of a use of `name` into its body.
+	fn instantiate(
+		&mut self,
+		input: &MappedText,
+		name_range: Range<usize>,
+		arguments_range: Range<usize>,
+		expansion: usize,
+	) -> Option<MappedText> {
+		let name = &input.text[name_range.clone()];
+		let definition = self.macros[name].clone();
+		let arguments: Vec<Range<usize>> = split_arguments(&input.text[arguments_range.clone()])
+			.into_iter()
+			.map(|range| arguments_range.start + range.start..arguments_range.start + range.end)
+			.collect();
+
+		if arguments.len() > definition.parameters.len() {
+			self.error(
+				input,
+				arguments[definition.parameters.len()].start..arguments.last().unwrap().end,
+				format!(
+					"macro \"{}\" takes {} argument(s), but {} were given",
+					name,
+					definition.parameters.len(),
+					arguments.len()
+				),
+			);
+			return None;
+		}
+
+		let mut values = HashMap::<&str, MappedText>::new();
+		for (index, parameter) in definition.parameters.iter().enumerate() {
+			let value = match arguments.get(index) {
+				Some(range) if !range.is_empty() => {
+					let mut text = MappedText::default();
+					text.push_slice(input, range.clone(), None);
+					text
+				},
+				_ => match &parameter.default {
+					Some(default) => default.clone(),
+					None => {
+						self.error(
+							input,
+							name_range,
+							format!(
+								"missing argument \"{}\" of macro \"{}\"",
+								parameter.name, name
+							),
+						);
+						return None;
+					},
+				},
+			};
+			values.insert(&parameter.name, value);
+		}
+
+		let body = &definition.body;
+		let mut output = MappedText::default();
+		let mut copied = 0;
+		let mut in_quotes = false;
+		let mut chars = body.text.char_indices().peekable();
+		while let Some((index, char)) = chars.next() {
+			match char {
+				'\'' => in_quotes = !in_quotes,
+				'\\' if in_quotes => {
+					chars.next();
+				},
+				'\\' => {
+					output.push_slice(body, copied..index, Some(Some(expansion)));
+					let rest = &body.text[index + 1..];
+					let length = if rest.starts_with('@') {
+						output.push(
+							&expansion.to_string(),
+							body.locate(index).0,
+							Some(expansion),
+						);
+						1
+					} else if rest.starts_with("()") {
+						2
+					} else {
+						let length = ident_length(rest);
+						match values.get(&rest[..length]) {
+							Some(value) if length > 0 => {
+								output.push_slice(value, 0..value.text.len(), None)
+							},
+							_ => {
+								let message = if length == 0 {
+									"expected a parameter name after \"\\\"".to_string()
+								} else {
+									format!(
+										"macro \"{}\" has no parameter \"{}\"",
+										name,
+										&rest[..length]
+									)
+								};
+								let (origin, _) = body.locate(index);
+								let diagnostic = Diagnostic::error(
+									Span::new(origin, origin + length + 1),
+									message,
+								);
+								let diagnostic = with_expansion_notes(
+									&self.expansions,
+									diagnostic,
+									Some(expansion),
+								);
+								self.diagnostics.push(diagnostic);
+							},
+						}
+						length
+					};
+					for _ in 0..length {
+						chars.next();
+					}
+					copied = index + 1 + length;
+				},
+				_ => {},
+			}
+		}
+		output.push_slice(body, copied..body.text.len(), Some(Some(expansion)));
+
+		Some(output)
+	}
+}
+
+impl Expanded {
+	/// Expands the macros and includes in `source`.
+	///
+	/// Expansion carries on past errors (e.g. by leaving out a missing `.include`), so that the expanded text can still
+	/// be assembled to find the problems in the rest of the source. The errors are kept in [`Expanded::diagnostics`].
+	pub fn new(source: &str, options: &AssembleOptions) -> Self {
+		let mut input = MappedText::default();
+		input.push(source, 0, None);
+
+		let mut expander = Expander {
+			options,
+			source_length: source.len(),
+			source_path: options
+				.source_path
+				.as_ref()
+				.and_then(|path| fs::canonicalize(path).ok()),
+			macros: HashMap::new(),
+			expansions: Vec::new(),
+			definition: None,
+			files: Vec::new(),
+			canonical_paths: Vec::new(),
+			binaries: HashMap::new(),
+			diagnostics: Diagnostics::new(),
+		};
+		let mut output = MappedText::default();
+		expander.expand(&input, 0, &mut output);
+
+		if let Some(definition) = expander.definition.take() {
+			expander.diagnostics.push(Diagnostic::error(
+				definition.span,
+				format!("macro \"{}\" is missing \".endm\"", definition.name),
+			));
+		}
+		expander.diagnostics.set_files(expander.files.clone());
+
+		Self {
+			text: output,
+			expansions: expander.expansions,
+			files: expander.files,
+			binaries: expander.binaries,
+			diagnostics: expander.diagnostics,
+		}
+	}
+
+	/// Returns the problems found while expanding the source.
+	pub fn diagnostics(&self) -> &Diagnostics {
+		&self.diagnostics
+	}
+
+	pub fn text(&self) -> &str {
+		&self.text.text
+	}
+
+	pub fn files(&self) -> &[IncludedFile] {
+		&self.files
+	}
+
+	/// Returns the contents of the file used by the `.incbin` whose file name starts at `offset` in the expanded text.
+	pub fn binary(&self, offset: usize) -> Option<&[u8]> {
+		self.binaries.get(&offset).map(Vec::as_slice)
+	}
+
+	/// Translates a span of the expanded text to the original source, along with the expansion it's in (if any).
+	fn map_span(&self, span: Span) -> (Span, Option<usize>) {
+		let (start, expansion) = self.text.locate(span.start);
+		let end = match span.end.checked_sub(1) {
+			Some(last) if span.end > span.start => match self.text.locate(last) {
+				// a span that starts and ends in different places can only be shown from its start
+				(last, last_expansion) if last_expansion == expansion && last >= start => last + 1,
+				_ => start + 1,
+			},
+			_ => start,
+		};
+		(Span::new(start, end), expansion)
+	}
+
+	pub fn map_source_span(&self, span: Span) -> Span {
+		self.map_span(span).0
+	}
+
+	/// Translates a diagnostic about the expanded text to the original source, noting the macros and `.include`s it came from.
+	pub fn map_diagnostic(&self, diagnostic: Diagnostic) -> Diagnostic {
+		let (span, expansion) = self.map_span(diagnostic.span);
+		let notes = diagnostic
+			.notes
+			.into_iter()
+			.map(|note| Note {
+				span: note.span.map(|span| self.map_source_span(span)),
+				message: note.message,
+			})
+			.collect();
+
+		with_expansion_notes(
+			&self.expansions,
+			Diagnostic {
+				span,
+				notes,
+				..diagnostic
+			},
+			expansion,
+		)
+	}
+
+	/// Translates diagnostics about the expanded text to the original source, after the ones found while expanding it.
+	pub fn map_diagnostics(&self, diagnostics: Diagnostics) -> Diagnostics {
+		let mut mapped = Diagnostics::new();
+		for diagnostic in self.diagnostics.iter() {
+			mapped.push(diagnostic.clone());
+		}
+		for diagnostic in diagnostics.iter() {
+			mapped.push(self.map_diagnostic(diagnostic.clone()));
+		}
+		mapped.set_files(self.files.clone());
+		mapped
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn expand(source: &str) -> Expanded {
+		Expanded::new(source, &AssembleOptions::default())
+	}
+
+	/// Returns the non-empty lines of the expanded text, without surrounding whitespace.
+	fn lines(expanded: &Expanded) -> Vec<&str> {
+		expanded
+			.text()
+			.lines()
+			.map(str::trim)
+			.filter(|line| !line.is_empty())
+			.collect()
+	}
+
+	#[test]
+	fn substitutes_parameters() {
+		let expanded = expand(
+			".macro put reg, value\n\tldi \\reg\\()b, \\value, 0, 3\n.endm\n\tput r1, 'x'\n\tput r2, (1, 2)\n",
+		);
+		assert!(!expanded.diagnostics().has_errors());
+		assert_eq!(
+			lines(&expanded),
+			["ldi r1b, 'x', 0, 3", "ldi r2b, (1, 2), 0, 3"]
+		);
+	}
+
+	#[test]
+	fn uses_defaults_for_missing_arguments() {
+		let expanded = expand(
+			".macro put reg, value=7, mode=3\n\tldi \\reg, \\value, 0, \\mode\n.endm\n\tput r1\n\tput r2, , 1\n\tput r3, 8\n",
+		);
+		assert!(!expanded.diagnostics().has_errors());
+		assert_eq!(
+			lines(&expanded),
+			["ldi r1, 7, 0, 3", "ldi r2, 7, 0, 1", "ldi r3, 8, 0, 3"]
+		);
+	}
+
+	#[test]
+	fn numbers_each_expansion_uniquely() {
+		let expanded = expand(
+			".macro inner\ninner\\@:\n\tnop\n.endm\n.macro outer\nouter\\@:\n\tinner\n\tinner\n.endm\n\touter\n\touter\n",
+		);
+		assert!(!expanded.diagnostics().has_errors());
+
+		let labels: Vec<_> = lines(&expanded)
+			.into_iter()
+			.filter_map(|line| line.strip_suffix(':'))
+			.collect();
+		assert_eq!(labels.len(), 6);
+		for (index, label) in labels.iter().enumerate() {
+			assert!(
+				!labels[index + 1..].contains(label),
+				"duplicate label {:?} in {:?}",
+				label,
+				labels
+			);
+		}
+	}
+
+	#[test]
+	fn limits_nesting() {
+		let source = ".macro forever\n\tforever\n.endm\n\tforever\n";
+		let expanded = expand(source);
+
+		let errors: Vec<_> = expanded.diagnostics().iter().collect();
+		assert_eq!(errors.len(), 1);
+		assert_eq!(
+			errors[0].message,
+			format!(
+				"macro expansions are nested more than {} deep (is \"forever\" recursive?)",
+				MAX_DEPTH
+			)
+		);
+		// the error is in the body, but each expansion in between is only shown once
+		assert_eq!(&source[errors[0].span.start..errors[0].span.end], "forever");
+		assert_eq!(errors[0].span.start, source.find("\tforever").unwrap() + 1);
+		let notes: Vec<_> = errors[0]
+			.notes
+			.iter()
+			.map(|note| note.message.as_str())
+			.collect();
+		assert_eq!(
+			notes,
+			[
+				format!(
+					"in {} nested expansions of macro \"forever\"",
+					MAX_DEPTH - 1
+				),
+				"in this expansion of macro \"forever\"".to_string(),
+			]
+		);
+	}
+
+	/// Returns each note's message along with the source text it points at.
+	fn notes<'a>(diagnostic: &'a Diagnostic, source: &'a str) -> Vec<(&'a str, &'a str)> {
+		diagnostic
+			.notes
+			.iter()
+			.map(|note| {
+				let span = note.span.unwrap();
+				(note.message.as_str(), &source[span.start..span.end])
+			})
+			.collect()
+	}
+
+	#[test]
+	fn notes_call_sites_of_errors_in_bodies() {
+		let source = ".macro divide\n\tldi r0, 1 / 0, 0, 3\n.endm\n.macro outer target\n\tdivide\n\tjmpr \\target\n.endm\n\touter nowhere\n";
+		let diagnostics = crate::assemble(source, &AssembleOptions::default()).unwrap_err();
+		let errors: Vec<_> = diagnostics.iter().collect();
+		assert_eq!(errors.len(), 2);
+
+		// errors in a body point at the body, with a note for each macro it's in
+		assert_eq!(errors[0].message, "division by zero");
+		assert_eq!(errors[0].span.start, source.find('/').unwrap());
+		assert_eq!(
+			notes(errors[0], source),
+			[
+				("in this expansion of macro \"divide\"", "divide"),
+				("in this expansion of macro \"outer\"", "outer"),
+			]
+		);
+		assert_eq!(
+			errors[0].notes[0].span.unwrap().start,
+			source.find("\tdivide").unwrap() + 1
+		);
+
+		// errors in arguments point at the argument itself, which is already at the call site
+		assert_eq!(errors[1].message, "unknown label \"nowhere\"");
+		assert_eq!(&source[errors[1].span.start..errors[1].span.end], "nowhere");
+		assert!(errors[1].notes.is_empty());
+	}
+}
@@ -0,0 +1,468 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Relocatable objects, as produced by `acca-as --object` and linked by `acca-ld`.
+//!
+//! The file format is described in the spec's "Object Files" chapter.
+
+use std::fmt::{self, Display};
+
+pub const MAGIC: &[u8; 8] = b"ACCAOBJ\0";
+pub const VERSION: u32 = 1;
+
+/// Written as the target index of relocations against absolute addresses.
+const NO_INDEX: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+	pub sections: Vec<Section>,
+	pub symbols: Vec<ObjectSymbol>,
+	pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+	pub name: String,
+	/// The section must be placed at a multiple of this (a power of two).
+	pub alignment: u64,
+	pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+	/// Only visible within the object.
+	Local = 0,
+	/// Visible to (and resolves undefined symbols in) every object being linked.
+	Global = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolValue {
+	/// Defined by another object.
+	Undefined,
+	Absolute(u64),
+	/// At `offset` bytes into a section of this object.
+	Section {
+		section: u32,
+		offset: u64,
+	},
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectSymbol {
+	pub name: String,
+	pub binding: Binding,
+	pub value: SymbolValue,
+}
+
+/// How the value of a relocation is computed and where it's stored.
+///
+/// `S` is the address of the target, `A` the addend, and `P` the address of the relocated bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+	/// `((S + A) / 4) - ((P + 4) / 4)` in bits 0-21 of an instruction (`jmpr`, `callr`).
+	Rel22 = 1,
+	/// `((S + A) / 4) - ((P + 4) / 4)` in bits 0-12 of an instruction (`cjmpr`).
+	Rel13 = 2,
+	/// `(S + A) - (P + 4)` in bits 0-21 of an instruction (`ldr`).
+	ByteRel22 = 3,
+	/// `((S + A) >> shift) & 0xffff` in bits 10-25 of an instruction (`ldi`).
+	Ldi16 = 4,
+	/// `(S + A) >> shift` in a byte (`.write.b`).
+	Data8 = 5,
+	/// `(S + A) >> shift` in 2 bytes (`.write.d`).
+	Data16 = 6,
+	/// `(S + A) >> shift` in 4 bytes (`.write.q`).
+	Data32 = 7,
+	/// `(S + A) >> shift` in 8 bytes (`.write.w`).
+	Data64 = 8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationTarget {
+	/// Address 0, i.e. `S + A` is just the addend.
+	Absolute,
+	/// The start of a section of this object.
+	Section(u32),
+	Symbol(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+	/// The section containing the bytes to relocate.
+	pub section: u32,
+	pub offset: u64,
+	pub kind: RelocationKind,
+	pub target: RelocationTarget,
+	pub addend: u64,
+	/// Only used by `Ldi16` and the data relocations.
+	pub shift: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ObjectError {
+	NotAnObject,
+	UnsupportedVersion(u32),
+	Truncated,
+	InvalidString,
+	InvalidValue(&'static str, u64),
+	/// A section, symbol, or relocation refers to something that doesn't exist.
+	InvalidIndex(&'static str, u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelocationError {
+	/// The relocated bytes lie outside their section.
+	OutOfBounds,
+	/// The target of a relative jump isn't aligned to 4 bytes.
+	Misaligned(u64),
+	/// The value doesn't fit in the relocated field.
+	Overflow(u64),
+}
+
+impl Display for ObjectError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::NotAnObject => write!(f, "not an Acca object file"),
+			Self::UnsupportedVersion(version) => {
+				write!(f, "unsupported object version {}", version)
+			},
+			Self::Truncated => write!(f, "object file is truncated"),
+			Self::InvalidString => write!(f, "object file contains a name that isn't valid UTF-8"),
+			Self::InvalidValue(what, value) => write!(f, "invalid {} {}", what, value),
+			Self::InvalidIndex(what, index) => write!(f, "invalid {} index {}", what, index),
+		}
+	}
+}
+
+impl std::error::Error for ObjectError {}
+
+impl Display for RelocationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::OutOfBounds => write!(f, "relocation lies outside its section"),
+			Self::Misaligned(target) => write!(f, "target {:#x} isn't aligned to 4 bytes", target),
+			Self::Overflow(value) => {
+				write!(f, "value {:#x} doesn't fit in the relocated field", value)
+			},
+		}
+	}
+}
+
+impl std::error::Error for RelocationError {}
+
+impl RelocationKind {
+	/// Returns the data relocation for values of `size` bytes.
+	pub fn data(size: u8) -> Self {
+		match size {
+			1 => Self::Data8,
+			2 => Self::Data16,
+			4 => Self::Data32,
+			8 => Self::Data64,
+			_ => panic!("Invalid data relocation size"),
+		}
+	}
+
+	/// The number of bytes the relocation modifies.
+	pub fn size(&self) -> usize {
+		match self {
+			Self::Data8 => 1,
+			Self::Data16 => 2,
+			Self::Data64 => 8,
+			_ => 4,
+		}
+	}
+
+	/// Stores `target` (`S + A`) into `data`, which holds the relocated bytes and is located at `place` (`P`).
+	pub fn apply(
+		&self,
+		data: &mut [u8],
+		place: u64,
+		target: u64,
+		shift: u8,
+	) -> Result<(), RelocationError> {
+		let data = data
+			.get_mut(..self.size())
+			.ok_or(RelocationError::OutOfBounds)?;
+		let relative = |scale: u64| {
+			if scale == 4 && target & 3 != 0 {
+				return Err(RelocationError::Misaligned(target));
+			}
+			Ok((target / scale).wrapping_sub(place.wrapping_add(4) / scale))
+		};
+		let shifted = target.checked_shr(shift as u32).unwrap_or(0);
+
+		let (value, bits, lowest_bit, signed) = match self {
+			Self::Rel22 => (relative(4)?, 22, 0, true),
+			Self::Rel13 => (relative(4)?, 13, 0, true),
+			Self::ByteRel22 => (relative(1)?, 22, 0, true),
+			Self::Ldi16 => (shifted & 0xffff, 16, 10, false),
+			Self::Data8 | Self::Data16 | Self::Data32 | Self::Data64 => {
+				(shifted, self.size() as u32 * 8, 0, false)
+			},
+		};
+
+		let fits = bits >= 64
+			|| (value as i64) >> (bits - 1) == -1
+			|| if signed {
+				(value as i64) >> (bits - 1) == 0
+			} else {
+				value >> bits == 0
+			};
+		if !fits {
+			return Err(RelocationError::Overflow(value));
+		}
+
+		if bits >= 32 {
+			data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+		} else if data.len() < 4 {
+			let bytes = data.len();
+			data.copy_from_slice(&value.to_le_bytes()[..bytes]);
+		} else {
+			let mask = ((1u32 << bits) - 1) << lowest_bit;
+			let word = u32::from_le_bytes(data[..4].try_into().unwrap());
+			let word = (word & !mask) | (((value as u32) << lowest_bit) & mask);
+			data.copy_from_slice(&word.to_le_bytes());
+		}
+		Ok(())
+	}
+}
+
+impl TryFrom<u8> for RelocationKind {
+	type Error = ObjectError;
+
+	fn try_from(value: u8) -> Result<Self, Self::Error> {
+		Ok(match value {
+			1 => Self::Rel22,
+			2 => Self::Rel13,
+			3 => Self::ByteRel22,
+			4 => Self::Ldi16,
+			5 => Self::Data8,
+			6 => Self::Data16,
+			7 => Self::Data32,
+			8 => Self::Data64,
+			_ => return Err(ObjectError::InvalidValue("relocation kind", value as u64)),
+		})
+	}
+}
+
+/// Appends little-endian values to a byte buffer.
+struct Writer(Vec<u8>);
+
+impl Writer {
+	fn u8(&mut self, value: u8) {
+		self.0.push(value);
+	}
+
+	fn u32(&mut self, value: u32) {
+		self.0.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn u64(&mut self, value: u64) {
+		self.0.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn bytes(&mut self, value: &[u8]) {
+		self.0.extend_from_slice(value);
+	}
+
+	fn string(&mut self, value: &str) {
+		self.u32(value.len() as u32);
+		self.bytes(value.as_bytes());
+	}
+}
+
+/// Reads little-endian values from a byte buffer.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+	fn bytes(&mut self, count: usize) -> Result<&'a [u8], ObjectError> {
+		if self.0.len() < count {
+			return Err(ObjectError::Truncated);
+		}
+		let (bytes, rest) = self.0.split_at(count);
+		self.0 = rest;
+		Ok(bytes)
+	}
+
+	fn u8(&mut self) -> Result<u8, ObjectError> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	fn u32(&mut self) -> Result<u32, ObjectError> {
+		Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+	}
+
+	fn u64(&mut self) -> Result<u64, ObjectError> {
+		Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+	}
+
+	fn string(&mut self) -> Result<String, ObjectError> {
+		let length = self.u32()? as usize;
+		String::from_utf8(self.bytes(length)?.to_vec()).map_err(|_| ObjectError::InvalidString)
+	}
+}
+
+impl Object {
+	/// Checks whether `data` starts like an object file.
+	pub fn is_object(data: &[u8]) -> bool {
+		data.starts_with(MAGIC)
+	}
+
+	/// Returns the index of the global symbol called `name`, if the object defines one.
+	pub fn global(&self, name: &str) -> Option<u32> {
+		self.symbols
+			.iter()
+			.position(|symbol| {
+				symbol.name == name
+					&& symbol.binding == Binding::Global
+					&& symbol.value != SymbolValue::Undefined
+			})
+			.map(|index| index as u32)
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut writer = Writer(Vec::new());
+		writer.bytes(MAGIC);
+		writer.u32(VERSION);
+		writer.u32(self.sections.len() as u32);
+		writer.u32(self.symbols.len() as u32);
+		writer.u32(self.relocations.len() as u32);
+
+		for section in &self.sections {
+			writer.string(&section.name);
+			writer.u64(section.alignment);
+			writer.u64(section.data.len() as u64);
+			writer.bytes(&section.data);
+		}
+
+		for symbol in &self.symbols {
+			writer.string(&symbol.name);
+			writer.u8(symbol.binding as u8);
+			let (kind, section, value) = match symbol.value {
+				SymbolValue::Undefined => (0, NO_INDEX, 0),
+				SymbolValue::Absolute(value) => (1, NO_INDEX, value),
+				SymbolValue::Section { section, offset } => (2, section, offset),
+			};
+			writer.u8(kind);
+			writer.u32(section);
+			writer.u64(value);
+		}
+
+		for relocation in &self.relocations {
+			writer.u32(relocation.section);
+			writer.u64(relocation.offset);
+			writer.u8(relocation.kind as u8);
+			let (kind, index) = match relocation.target {
+				RelocationTarget::Absolute => (0, NO_INDEX),
+				RelocationTarget::Section(section) => (1, section),
+				RelocationTarget::Symbol(symbol) => (2, symbol),
+			};
+			writer.u8(kind);
+			writer.u32(index);
+			writer.u64(relocation.addend);
+			writer.u8(relocation.shift);
+		}
+
+		writer.0
+	}
+
+	pub fn parse(data: &[u8]) -> Result<Self, ObjectError> {
+		let mut reader = Reader(data);
+		if reader.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+			return Err(ObjectError::NotAnObject);
+		}
+		let version = reader.u32()?;
+		if version != VERSION {
+			return Err(ObjectError::UnsupportedVersion(version));
+		}
+		let section_count = reader.u32()?;
+		let symbol_count = reader.u32()?;
+		let relocation_count = reader.u32()?;
+
+		let mut object = Object::default();
+
+		for _ in 0..section_count {
+			let name = reader.string()?;
+			let alignment = reader.u64()?;
+			if !alignment.is_power_of_two() {
+				return Err(ObjectError::InvalidValue("section alignment", alignment));
+			}
+			let size = reader.u64()?;
+			let data = reader
+				.bytes(usize::try_from(size).map_err(|_| ObjectError::Truncated)?)?
+				.to_vec();
+			object.sections.push(Section {
+				name,
+				alignment,
+				data,
+			});
+		}
+
+		let check_section = |section: u32| {
+			if (section as usize) < object.sections.len() {
+				Ok(section)
+			} else {
+				Err(ObjectError::InvalidIndex("section", section))
+			}
+		};
+
+		for _ in 0..symbol_count {
+			let name = reader.string()?;
+			let binding = match reader.u8()? {
+				0 => Binding::Local,
+				1 => Binding::Global,
+				other => return Err(ObjectError::InvalidValue("symbol binding", other as u64)),
+			};
+			let kind = reader.u8()?;
+			let section = reader.u32()?;
+			let value = reader.u64()?;
+			let value = match kind {
+				0 => SymbolValue::Undefined,
+				1 => SymbolValue::Absolute(value),
+				2 => SymbolValue::Section {
+					section: check_section(section)?,
+					offset: value,
+				},
+				other => return Err(ObjectError::InvalidValue("symbol kind", other as u64)),
+			};
+			object.symbols.push(ObjectSymbol {
+				name,
+				binding,
+				value,
+			});
+		}
+
+		for _ in 0..relocation_count {
+			let section = check_section(reader.u32()?)?;
+			let offset = reader.u64()?;
+			let kind = RelocationKind::try_from(reader.u8()?)?;
+			let target_kind = reader.u8()?;
+			let index = reader.u32()?;
+			let target = match target_kind {
+				0 => RelocationTarget::Absolute,
+				1 => RelocationTarget::Section(check_section(index)?),
+				2 if (index as usize) < object.symbols.len() => RelocationTarget::Symbol(index),
+				2 => return Err(ObjectError::InvalidIndex("symbol", index)),
+				other => return Err(ObjectError::InvalidValue("relocation target", other as u64)),
+			};
+			let addend = reader.u64()?;
+			let shift = reader.u8()?;
+			object.relocations.push(Relocation {
+				section,
+				offset,
+				kind,
+				target,
+				addend,
+				shift,
+			});
+		}
+
+		Ok(object)
+	}
+}
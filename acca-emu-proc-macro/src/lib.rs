@@ -11,7 +11,7 @@ mod util;
 use std::collections::HashMap;
 
 use proc_macro2::{Ident, Span, TokenStream, TokenTree};
-use quote::quote;
+use quote::{quote, ToTokens};
 use syn::{
 	bracketed,
 	parse::{Parse, ParseStream},
@@ -23,7 +23,7 @@ use syn::{
 use crate::util::CollectIntoArray;
 
 mod kw {
-	use proc_macro2::{Ident, Span};
+	use proc_macro2::Ident;
 	use syn::{custom_keyword, parse::Parse};
 
 	custom_keyword!(reg);
@@ -34,7 +34,6 @@ mod kw {
 
 	#[allow(non_camel_case_types)]
 	pub struct immrel {
-		pub span: Span,
 		pub width: u64,
 		pub relative: std::primitive::bool,
 	}
@@ -52,7 +51,7 @@ mod kw {
 				));
 			}
 
-			let width: u64 = match (&ident_str[3..]).parse() {
+			let width: u64 = match ident_str[3..].parse() {
 				Ok(result) => result,
 				Err(_) => {
 					return Err(syn::Error::new(
@@ -62,11 +61,7 @@ mod kw {
 				},
 			};
 
-			Ok(Self {
-				span: ident.span(),
-				width,
-				relative,
-			})
+			Ok(Self { width, relative })
 		}
 	}
 }
@@ -82,7 +77,7 @@ enum InstructionBit {
 enum ParameterType {
 	Register,
 	NullableRegister,
-	Immediate(u64),
+	Immediate,
 	RelativeImmediate(u64),
 	Boolean,
 	Size,
@@ -104,6 +99,8 @@ struct Instruction {
 	encoding_span: Span,
 	bits: [InstructionBit; 32],
 	parameters: HashMap<char, Parameter>,
+	/// The encoding names of the parameters, in the order they were declared.
+	parameter_order: Vec<char>,
 }
 
 struct InstructionWithBody {
@@ -211,11 +208,13 @@ impl Parse for Instruction {
 		let name: Ident = input.parse()?;
 
 		let mut params = HashMap::new();
+		let mut parameter_order = Vec::new();
 
 		while input.peek(syn::Ident) {
 			let param: Parameter = input.parse()?;
+			parameter_order.push(param.encoding_name);
 			params.insert(param.encoding_name, param);
-			if let Err(_) = input.parse::<Token![,]>() {
+			if input.parse::<Token![,]>().is_err() {
 				// no trailing comma? no way to have another parameter.
 				break;
 			}
@@ -226,6 +225,7 @@ impl Parse for Instruction {
 			encoding_span: encoding_brackets.span.span(),
 			bits,
 			parameters: params,
+			parameter_order,
 		})
 	}
 }
@@ -298,12 +298,104 @@ impl Parse for ParameterType {
 			if imm.relative {
 				Ok(Self::RelativeImmediate(imm.width))
 			} else {
-				Ok(Self::Immediate(imm.width))
+				Ok(Self::Immediate)
 			}
 		}
 	}
 }
 
+/// Computes the mask and value of the fixed bits in an instruction's encoding, along with expressions that extract
+/// each of its variables from `encoded`.
+fn encoding_fields(instr: &Instruction) -> syn::Result<(u32, u32, HashMap<char, TokenStream>)> {
+	let mut required_mask = 0u32;
+	let mut required_mask_value = 0u32;
+	let mut var_bits: HashMap<char, (TokenStream, usize)> = HashMap::new();
+
+	for (i, bit) in instr.bits.iter().enumerate() {
+		match bit {
+			InstructionBit::Zero | InstructionBit::One => {
+				let bit_as_val = 1u32 << (i as u32);
+				required_mask |= bit_as_val;
+				if matches!(bit, InstructionBit::One) {
+					required_mask_value |= bit_as_val;
+				}
+			},
+			InstructionBit::Variable(name) => {
+				let (stream, dest_bit) = var_bits.entry(*name).or_insert_with(|| (quote!(0u64), 0));
+				let source_bit = i as u32;
+				let source_mask = 1u32 << source_bit;
+				let dest_bit_u64 = *dest_bit as u64;
+				*stream = quote!((
+					(#stream) |
+					((((encoded & #source_mask) >> #source_bit) as u64) << #dest_bit_u64)
+				));
+				*dest_bit += 1;
+			},
+		}
+	}
+
+	for id in var_bits.keys() {
+		if !instr.parameters.contains_key(id) {
+			return Err(syn::Error::new(
+				instr.encoding_span,
+				format!(
+					"Missing parameter annotation for encoding variable \"{}\"",
+					id
+				),
+			));
+		}
+	}
+
+	for (id, param) in &instr.parameters {
+		if !var_bits.contains_key(id) {
+			return Err(syn::Error::new(
+				param.encoding_name_span,
+				format!(
+					"Superfluous parameter annotation \"{}\" with no corresponding encoding variable",
+					id
+				),
+			));
+		}
+	}
+
+	Ok((
+		required_mask,
+		required_mask_value,
+		var_bits
+			.into_iter()
+			.map(|(id, (stream, _))| (id, stream))
+			.collect(),
+	))
+}
+
+/// Whether `stream` (an instruction body) can change the instruction pointer other than by advancing it.
+fn changes_control_flow(stream: TokenStream) -> bool {
+	let tokens: Vec<_> = stream.into_iter().collect();
+
+	tokens.iter().enumerate().any(|(i, token)| {
+		match (token, tokens.get(i + 1), tokens.get(i + 2)) {
+			(TokenTree::Ident(ident), Some(TokenTree::Punct(punct)), _)
+				if matches!(ident.to_string().as_str(), "jump" | "call" | "do_jump")
+					&& punct.as_char() == '!' =>
+			{
+				true
+			},
+			// `instruction_pointer = ...`, but not `instruction_pointer == ...`
+			(TokenTree::Ident(ident), Some(TokenTree::Punct(punct)), next)
+				if ident == "instruction_pointer"
+					&& punct.as_char() == '='
+					&& punct.spacing() == proc_macro2::Spacing::Alone =>
+			{
+				!matches!(next, Some(TokenTree::Punct(next)) if next.as_char() == '=')
+			},
+			(TokenTree::Group(group), _, _) => changes_control_flow(group.stream()),
+			_ => false,
+		}
+	})
+}
+
+/// Expands to the dispatch over a `DecodedInstruction` named `instruction` (produced by `instruction_predecoder!`),
+/// running the body of the matching instruction with its operands bound to their declared names.
 #[proc_macro]
 pub fn instructions(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
 	let instructions = parse_macro_input!(item as InstructionsWithBodies);
@@ -311,65 +403,26 @@ pub fn instructions(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
 
 	let def = &instructions.default_case;
 
-	for InstructionWithBody {
-		instruction: instr,
-		body,
-	} in instructions.instructions
+	for (
+		opcode,
+		InstructionWithBody {
+			instruction: instr,
+			body,
+		},
+	) in instructions.instructions.into_iter().enumerate()
 	{
-		let mut required_mask = 0u32;
-		let mut required_mask_value = 0u32;
-		let mut var_bits: HashMap<char, (TokenStream, usize)> = HashMap::new();
-
-		for (i, bit) in instr.bits.iter().enumerate() {
-			match bit {
-				InstructionBit::Zero | InstructionBit::One => {
-					let bit_as_val = 1u32 << (i as u32);
-					required_mask |= bit_as_val;
-					if matches!(bit, InstructionBit::One) {
-						required_mask_value |= bit_as_val;
-					}
-				},
-				InstructionBit::Variable(name) => {
-					let (stream, dest_bit) =
-						var_bits.entry(*name).or_insert_with(|| (quote!(0u64), 0));
-					let source_bit = i as u32;
-					let source_mask = 1u32 << source_bit;
-					let dest_bit_u64 = *dest_bit as u64;
-					*stream = quote!((
-						(#stream) |
-						((((encoded & #source_mask) >> #source_bit) as u64) << #dest_bit_u64)
-					));
-					*dest_bit += 1;
-				},
-			}
+		if let Err(e) = encoding_fields(&instr) {
+			return e.to_compile_error().into();
 		}
+		let opcode = opcode as u16;
 
-		for (id, _) in &var_bits {
-			if !instr.parameters.contains_key(id) {
-				return syn::Error::new(
-					instr.encoding_span,
-					format!(
-						"Missing parameter annotation for encoding variable \"{}\"",
-						id
-					),
-				)
-				.to_compile_error()
-				.into();
-			}
-		}
-
-		for (id, param) in &instr.parameters {
-			if !var_bits.contains_key(id) {
-				return syn::Error::new(param.encoding_name_span, format!("Superfluous parameter annotation \"{}\" with no corresponding encoding variable", id)).to_compile_error().into();
-			}
-		}
-
-		let vars = instr.parameters.iter().map(|(id, param)| {
+		let vars = instr.parameter_order.iter().enumerate().map(|(index, id)| {
+			let param = &instr.parameters[id];
 			let param_source_name = &param.source_name;
-			let bits_stream = var_bits.remove(id).unwrap().0;
+			let bits_stream = quote!((instruction.operands[#index] as u64));
 
 			let val = match param.ty {
-				ParameterType::Boolean => quote!((#bits_stream & 1) != 0),
+				ParameterType::Boolean => quote!(#bits_stream != 0),
 				ParameterType::Condition => quote!(Condition::from(#bits_stream)),
 				ParameterType::NullableCondition => quote! {
 					match #bits_stream {
@@ -378,9 +431,10 @@ pub fn instructions(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
 						_ => unreachable!(),
 					}
 				},
-				ParameterType::Immediate(_) => bits_stream,
-				ParameterType::RelativeImmediate(width) => {
-					quote!(sign_extend_immediate(#bits_stream, #width))
+				ParameterType::Immediate => bits_stream,
+				// relative immediates are sign-extended when they're decoded
+				ParameterType::RelativeImmediate(_) => {
+					quote!((instruction.operands[#index] as i32 as u64))
 				},
 				ParameterType::NullableRegister => quote! {
 					match #bits_stream {
@@ -401,24 +455,305 @@ pub fn instructions(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
 
 		let instr_name = instr.name.to_string();
 
+		let operand_names = instr
+			.parameter_order
+			.iter()
+			.map(|id| instr.parameters[id].source_name.to_string());
+		let operand_values = instr.parameter_order.iter().map(|id| {
+			let param = &instr.parameters[id];
+			let name = &param.source_name;
+			match param.ty {
+				ParameterType::RelativeImmediate(_) => quote!(Operand::Relative(#name as i64)),
+				_ => quote!(Operand::from(#name)),
+			}
+		});
+
 		result = quote! {
 			#result
-			_ if (encoded & #required_mask) == #required_mask_value => {
+			#opcode => {
 				if self.print_instructions {
 					println!(concat!("{:#x} @ ", #instr_name), u64::from(self.instruction_pointer));
 				}
 
 				#(#vars)*
+
+				if let Some(record) = self.trace_record.as_mut() {
+					record.instruction = Some(InstructionInfo {
+						encoding: instruction.encoded,
+						mnemonic: #instr_name,
+						operands: vec![#(NamedOperand {
+							name: #operand_names,
+							value: #operand_values,
+						}),*],
+					});
+				}
+
 				#body
 			},
 		};
 	}
 
 	quote! {
-		match encoded {
+		match instruction.opcode {
 			#result
 			_ => #def,
 		}
 	}
 	.into()
 }
+
+/// Finds the body of the (only) `instructions!` invocation in `stream`.
+fn find_instructions_invocation(stream: TokenStream) -> Option<TokenStream> {
+	let tokens: Vec<_> = stream.into_iter().collect();
+
+	for (i, token) in tokens.iter().enumerate() {
+		match (token, tokens.get(i + 1), tokens.get(i + 2)) {
+			(
+				TokenTree::Ident(ident),
+				Some(TokenTree::Punct(punct)),
+				Some(TokenTree::Group(group)),
+			) if ident == "instructions" && punct.as_char() == '!' => {
+				return Some(group.stream());
+			},
+			(TokenTree::Group(group), _, _) => {
+				if let Some(result) = find_instructions_invocation(group.stream()) {
+					return Some(result);
+				}
+			},
+			_ => {},
+		}
+	}
+
+	None
+}
+
+/// Reads and parses the `instructions!` invocation in the given source file (relative to the crate's manifest
+/// directory), returning it along with the file's full path.
+fn read_instructions(path: &syn::LitStr) -> Result<(InstructionsWithBodies, String), TokenStream> {
+	let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+	let full_path = std::path::Path::new(&manifest_dir).join(path.value());
+
+	let source = std::fs::read_to_string(&full_path).map_err(|e| {
+		syn::Error::new(
+			path.span(),
+			format!("Failed to read \"{}\": {}", full_path.display(), e),
+		)
+		.to_compile_error()
+	})?;
+
+	let stream: TokenStream = source.parse().map_err(|e| {
+		syn::Error::new(
+			path.span(),
+			format!("Failed to tokenize \"{}\": {}", full_path.display(), e),
+		)
+		.to_compile_error()
+	})?;
+
+	let instructions =
+		match find_instructions_invocation(stream).map(syn::parse2::<InstructionsWithBodies>) {
+			Some(Ok(x)) => x,
+			Some(Err(e)) => {
+				return Err(syn::Error::new(
+					path.span(),
+					format!(
+						"Failed to parse the instructions in \"{}\": {}",
+						full_path.display(),
+						e
+					),
+				)
+				.to_compile_error())
+			},
+			None => {
+				return Err(syn::Error::new(
+					path.span(),
+					format!(
+						"No `instructions!` invocation in \"{}\"",
+						full_path.display()
+					),
+				)
+				.to_compile_error())
+			},
+		};
+
+	Ok((instructions, full_path.display().to_string()))
+}
+
+/// Generates a decoder for the instructions declared in the `instructions!` invocation in the given source file
+/// (relative to the crate's manifest directory).
+///
+/// Like `instructions!`, this expands to an expression that uses `encoded`. It evaluates to an
+/// `Option<InstructionInfo>` that's `None` if `encoded` isn't a valid instruction.
+#[proc_macro]
+pub fn instruction_decoder(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+	let path = parse_macro_input!(item as syn::LitStr);
+
+	let (instructions, full_path) = match read_instructions(&path) {
+		Ok(x) => x,
+		Err(e) => return e.into(),
+	};
+
+	let mut result = quote!();
+
+	for InstructionWithBody {
+		instruction: instr, ..
+	} in instructions.instructions
+	{
+		let (required_mask, required_mask_value, var_bits) = match encoding_fields(&instr) {
+			Ok(x) => x,
+			Err(e) => return e.to_compile_error().into(),
+		};
+
+		// unlike in the emulator, invalid field values are reported rather than treated as unreachable
+		let operands = instr.parameter_order.iter().map(|id| {
+			let param = &instr.parameters[id];
+			let name = param.source_name.to_string();
+			let bits_stream = &var_bits[id];
+
+			let value = match param.ty {
+				ParameterType::Boolean => quote!(Operand::Boolean((bits & 1) != 0)),
+				ParameterType::Condition => quote! {
+					match bits {
+						0..=9 => Operand::Condition(Some(Condition::from(bits))),
+						_ => return None,
+					}
+				},
+				ParameterType::NullableCondition => quote! {
+					match bits {
+						0..=9 => Operand::Condition(Some(Condition::from(bits))),
+						15 => Operand::Condition(None),
+						_ => return None,
+					}
+				},
+				ParameterType::Immediate => quote!(Operand::Immediate(bits)),
+				ParameterType::RelativeImmediate(width) => {
+					quote!(Operand::Relative(sign_extend_immediate(bits, #width) as i64))
+				},
+				ParameterType::NullableRegister => quote! {
+					match bits {
+						0..=15 => Operand::Register(Some(RegisterID::from(bits))),
+						31 => Operand::Register(None),
+						_ => return None,
+					}
+				},
+				ParameterType::Register => quote!(Operand::Register(Some(RegisterID::from(bits)))),
+				ParameterType::Size => quote!(Operand::Size(Size::from(bits))),
+			};
+
+			quote! {
+				NamedOperand {
+					name: #name,
+					value: {
+						let bits = #bits_stream;
+						#value
+					},
+				}
+			}
+		});
+
+		let instr_name = instr.name.to_string();
+
+		result = quote! {
+			#result
+			_ if (encoded & #required_mask) == #required_mask_value => Some(InstructionInfo {
+				encoding: encoded,
+				mnemonic: #instr_name,
+				operands: vec![#(#operands),*],
+			}),
+		};
+	}
+
+	quote! {
+		{
+			// rebuild the decoder whenever the instructions change
+			const _: &str = include_str!(#full_path);
+
+			match encoded {
+				#result
+				_ => None,
+			}
+		}
+	}
+	.into()
+}
+
+/// Generates the predecoder for the instructions declared in the `instructions!` invocation in the given source file
+/// (relative to the crate's manifest directory).
+///
+/// This expands to an expression that uses `encoded` and evaluates to a `DecodedInstruction` that `instructions!`
+/// can dispatch on. Each instruction's opcode is its index in the invocation; anything else is invalid.
+#[proc_macro]
+pub fn instruction_predecoder(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+	let path = parse_macro_input!(item as syn::LitStr);
+
+	let (instructions, full_path) = match read_instructions(&path) {
+		Ok(x) => x,
+		Err(e) => return e.into(),
+	};
+
+	let max_operands = instructions
+		.instructions
+		.iter()
+		.map(|instr| instr.instruction.parameter_order.len())
+		.max()
+		.unwrap_or(0);
+	let mut result = quote!();
+
+	for (
+		opcode,
+		InstructionWithBody {
+			instruction: instr,
+			body,
+		},
+	) in instructions.instructions.into_iter().enumerate()
+	{
+		let (required_mask, required_mask_value, var_bits) = match encoding_fields(&instr) {
+			Ok(x) => x,
+			Err(e) => return e.to_compile_error().into(),
+		};
+		let opcode = opcode as u16;
+		let ends_block = changes_control_flow(body.into_token_stream());
+
+		let operands = instr.parameter_order.iter().enumerate().map(|(index, id)| {
+			let param = &instr.parameters[id];
+			let bits_stream = &var_bits[id];
+
+			let value = match param.ty {
+				ParameterType::RelativeImmediate(width) => {
+					quote!(sign_extend_immediate(#bits_stream, #width))
+				},
+				_ => bits_stream.clone(),
+			};
+
+			// no field is wider than 32 bits, so this doesn't lose anything
+			quote!(operands[#index] = #value as u32;)
+		});
+
+		result = quote! {
+			#result
+			_ if (encoded & #required_mask) == #required_mask_value => {
+				let mut operands = [0u32; MAX_OPERANDS];
+				#(#operands)*
+				DecodedInstruction {
+					opcode: #opcode,
+					encoded,
+					ends_block: #ends_block,
+					operands,
+				}
+			},
+		};
+	}
+
+	quote! {
+		{
+			// rebuild the predecoder whenever the instructions change
+			const _: &str = include_str!(#full_path);
+			const _: () = assert!(#max_operands <= MAX_OPERANDS, "MAX_OPERANDS is too small");
+
+			match encoded {
+				#result
+				_ => DecodedInstruction::invalid(encoded),
+			}
+		}
+	}
+	.into()
+}
@@ -23,9 +23,9 @@ impl<const LEN: usize, T: Iterator> CollectIntoArray<LEN> for T {
 			match self.next() {
 				Some(item) => arr[i].write(item),
 				None => {
-					for j in 0..i {
+					for item in arr.iter_mut().take(i) {
 						// SAFETY: we already initialized the previous elements, so we can safely drop them
-						unsafe { arr[j].assume_init_drop() }
+						unsafe { item.assume_init_drop() }
 					}
 
 					return None;
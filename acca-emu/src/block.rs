@@ -0,0 +1,484 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	collections::HashMap,
+	fs::{File, OpenOptions},
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::Path,
+};
+
+use bitflags::bitflags;
+use num_enum::TryFromPrimitive;
+use serde::Deserialize;
+
+use crate::{
+	bus::{Device, Dma},
+	util::Size,
+};
+
+/// The size of the block device's register block.
+pub const BLOCK_SIZE: u64 = 0x40;
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Read-write; the first sector to transfer.
+const SECTOR_REGISTER: u64 = 0x00;
+/// Read-write; the physical address of the guest buffer to transfer to or from.
+const BUFFER_REGISTER: u64 = 0x08;
+/// Read-write; the number of sectors to transfer.
+const COUNT_REGISTER: u64 = 0x10;
+/// Write-only; writing a [`Command`] starts it. Reads return 0.
+const COMMAND_REGISTER: u64 = 0x18;
+/// Read-only; see [`BlockStatus`].
+const STATUS_REGISTER: u64 = 0x20;
+/// Read-write; see [`BlockControl`].
+const CONTROL_REGISTER: u64 = 0x28;
+/// Read-only; the [`BlockError`] of the last command.
+const ERROR_REGISTER: u64 = 0x30;
+/// Read-only; the size of the disk in sectors.
+const CAPACITY_REGISTER: u64 = 0x38;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u64)]
+pub enum Command {
+	/// Copies sectors from the disk to the buffer.
+	Read = 1,
+	/// Copies sectors from the buffer to the disk.
+	Write = 2,
+	/// Makes sure everything written so far has reached the host's disk.
+	Flush = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum BlockError {
+	None = 0,
+	/// The command register was written with an unknown command.
+	InvalidCommand = 1,
+	/// Part of the transfer lies beyond the end of the disk.
+	SectorOutOfRange = 2,
+	/// Part of the guest buffer isn't RAM (or, for reads, isn't writable).
+	BufferOutOfRange = 3,
+	/// The disk was opened read-only.
+	ReadOnly = 4,
+	/// The host failed to read or write the disk image.
+	Io = 5,
+}
+
+bitflags! {
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct BlockStatus: u64 {
+		/// A command has been started but hasn't completed yet.
+		const BUSY = 1 << 0;
+		/// The last command failed; the error register says why.
+		const ERROR = 1 << 1;
+		/// The disk can't be written to.
+		const READ_ONLY = 1 << 2;
+	}
+}
+
+bitflags! {
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct BlockControl: u64 {
+		/// Raise the device's interrupt whenever a command completes.
+		const COMPLETION_INTERRUPT = 1 << 0;
+	}
+}
+
+/// How the host disk image is opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskMode {
+	#[default]
+	ReadWrite,
+	/// Guest writes fail.
+	ReadOnly,
+	/// Guest writes succeed, but they're kept in memory and never reach the image.
+	CopyOnWrite,
+}
+
+/// A disk controller that transfers whole sectors between a host disk image and guest RAM.
+///
+/// The guest programs the sector, buffer, and count registers, then writes a command. The command completes before the
+/// next instruction; the guest can either poll the status register or enable the completion interrupt.
+pub struct BlockDevice {
+	file: File,
+	mode: DiskMode,
+	/// The size of the disk in sectors. A partial sector at the end of the image is ignored.
+	capacity: u64,
+	/// Sectors written in copy-on-write mode.
+	overlay: HashMap<u64, Box<[u8; SECTOR_SIZE as usize]>>,
+
+	sector: u64,
+	buffer: u64,
+	count: u64,
+	control: BlockControl,
+	pending: Option<u64>,
+	error: BlockError,
+	interrupt_requested: bool,
+}
+
+impl BlockDevice {
+	/// Opens the disk image at `path`.
+	pub fn open(path: &Path, mode: DiskMode) -> io::Result<Self> {
+		let file = OpenOptions::new()
+			.read(true)
+			.write(mode == DiskMode::ReadWrite)
+			.open(path)?;
+		let capacity = file.metadata()?.len() / SECTOR_SIZE;
+
+		Ok(Self {
+			file,
+			mode,
+			capacity,
+			overlay: HashMap::new(),
+
+			sector: 0,
+			buffer: 0,
+			count: 1,
+			control: BlockControl::empty(),
+			pending: None,
+			error: BlockError::None,
+			interrupt_requested: false,
+		})
+	}
+
+	fn status(&self) -> BlockStatus {
+		let mut status = BlockStatus::empty();
+		status.set(BlockStatus::BUSY, self.pending.is_some());
+		status.set(BlockStatus::ERROR, self.error != BlockError::None);
+		status.set(BlockStatus::READ_ONLY, self.mode == DiskMode::ReadOnly);
+		status
+	}
+
+	/// Checks the current transfer, returning its first sector, buffer address, and length in bytes.
+	fn transfer(&self) -> Result<(u64, u64, usize), BlockError> {
+		let end = self
+			.sector
+			.checked_add(self.count)
+			.filter(|&end| end <= self.capacity)
+			.ok_or(BlockError::SectorOutOfRange)?;
+		let length = (end - self.sector) * SECTOR_SIZE;
+		self.buffer
+			.checked_add(length)
+			.ok_or(BlockError::BufferOutOfRange)?;
+		Ok((self.sector, self.buffer, length as usize))
+	}
+
+	fn read_sectors(&mut self, first: u64, data: &mut [u8]) -> io::Result<()> {
+		self.file.seek(SeekFrom::Start(first * SECTOR_SIZE))?;
+		self.file.read_exact(data)?;
+		for (index, sector) in data.chunks_exact_mut(SECTOR_SIZE as usize).enumerate() {
+			if let Some(written) = self.overlay.get(&(first + index as u64)) {
+				sector.copy_from_slice(&written[..]);
+			}
+		}
+		Ok(())
+	}
+
+	fn write_sectors(&mut self, first: u64, data: &[u8]) -> io::Result<()> {
+		match self.mode {
+			DiskMode::ReadWrite => {
+				self.file.seek(SeekFrom::Start(first * SECTOR_SIZE))?;
+				self.file.write_all(data)
+			},
+			DiskMode::CopyOnWrite => {
+				for (index, sector) in data.chunks_exact(SECTOR_SIZE as usize).enumerate() {
+					let copy = self
+						.overlay
+						.entry(first + index as u64)
+						.or_insert_with(|| Box::new([0; SECTOR_SIZE as usize]));
+					copy.copy_from_slice(sector);
+				}
+				Ok(())
+			},
+			DiskMode::ReadOnly => unreachable!("writes to read-only disks are rejected"),
+		}
+	}
+
+	fn execute(&mut self, command: u64, dma: &mut Dma) -> Result<(), BlockError> {
+		match Command::try_from(command).map_err(|_| BlockError::InvalidCommand)? {
+			Command::Read => {
+				let (sector, buffer, length) = self.transfer()?;
+				let mut data = vec![0u8; length];
+				self.read_sectors(sector, &mut data)
+					.map_err(|_| BlockError::Io)?;
+				dma.write(buffer, &data).ok_or(BlockError::BufferOutOfRange)
+			},
+			Command::Write => {
+				if self.mode == DiskMode::ReadOnly {
+					return Err(BlockError::ReadOnly);
+				}
+				let (sector, buffer, length) = self.transfer()?;
+				let mut data = vec![0u8; length];
+				dma.read(buffer, &mut data)
+					.ok_or(BlockError::BufferOutOfRange)?;
+				self.write_sectors(sector, &data)
+					.map_err(|_| BlockError::Io)
+			},
+			Command::Flush => match self.mode {
+				DiskMode::ReadWrite => self.file.sync_data().map_err(|_| BlockError::Io),
+				DiskMode::ReadOnly | DiskMode::CopyOnWrite => Ok(()),
+			},
+		}
+	}
+}
+
+impl Device for BlockDevice {
+	fn read(&mut self, offset: u64, _size: Size) -> Option<u64> {
+		match offset {
+			SECTOR_REGISTER => Some(self.sector),
+			BUFFER_REGISTER => Some(self.buffer),
+			COUNT_REGISTER => Some(self.count),
+			COMMAND_REGISTER => Some(0),
+			STATUS_REGISTER => Some(self.status().bits()),
+			CONTROL_REGISTER => Some(self.control.bits()),
+			ERROR_REGISTER => Some(self.error as u64),
+			CAPACITY_REGISTER => Some(self.capacity),
+			_ => None,
+		}
+	}
+
+	fn write(&mut self, offset: u64, _size: Size, value: u64) -> Option<()> {
+		// the transfer registers can't be changed while a command is running
+		let busy = self.pending.is_some();
+		match offset {
+			SECTOR_REGISTER if !busy => self.sector = value,
+			BUFFER_REGISTER if !busy => self.buffer = value,
+			COUNT_REGISTER if !busy => self.count = value,
+			COMMAND_REGISTER if !busy => self.pending = Some(value),
+			CONTROL_REGISTER => self.control = BlockControl::from_bits(value)?,
+			_ => return None,
+		}
+		Some(())
+	}
+
+	fn dma(&mut self, dma: &mut Dma) {
+		let Some(command) = self.pending.take() else {
+			return;
+		};
+		self.error = match self.execute(command, dma) {
+			Ok(()) => BlockError::None,
+			Err(error) => error,
+		};
+		self.interrupt_requested = true;
+	}
+
+	fn poll(&mut self) -> bool {
+		std::mem::take(&mut self.interrupt_requested)
+			&& self.control.contains(BlockControl::COMPLETION_INTERRUPT)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use super::*;
+	use crate::{bus::Bus, memory::Memory, util::VMAddress};
+
+	const BASE: u64 = 0x10_0000;
+	const RAM_SIZE: u64 = 0x4000;
+	const SECTORS: u64 = 4;
+
+	/// A bus with a block device backed by a scratch image, whose sector `n` is filled with `n + 1`.
+	struct Disk {
+		bus: Bus,
+		path: PathBuf,
+	}
+
+	impl Disk {
+		fn new(name: &str, mode: DiskMode) -> Self {
+			let path = std::env::temp_dir().join(format!(
+				"acca-emu-block-{}-{}.img",
+				name,
+				std::process::id()
+			));
+			let image = (0..SECTORS)
+				.flat_map(|sector| [sector as u8 + 1; SECTOR_SIZE as usize])
+				.collect::<Vec<_>>();
+			std::fs::write(&path, image).unwrap();
+
+			let mut bus = Bus::new(Memory::contiguous(RAM_SIZE as usize).unwrap());
+			bus.attach(
+				VMAddress::new(BASE),
+				BLOCK_SIZE,
+				Some(7),
+				Box::new(BlockDevice::open(&path, mode).unwrap()),
+			)
+			.unwrap();
+			Self { bus, path }
+		}
+
+		fn register(&mut self, offset: u64) -> u64 {
+			self.bus
+				.read(VMAddress::new(BASE + offset), Size::Word)
+				.unwrap()
+		}
+
+		/// Runs `command` on `count` sectors starting at `sector`, returning the error register and any interrupts.
+		fn run(&mut self, command: u64, sector: u64, buffer: u64, count: u64) -> (u64, Vec<u64>) {
+			for (offset, value) in [
+				(SECTOR_REGISTER, sector),
+				(BUFFER_REGISTER, buffer),
+				(COUNT_REGISTER, count),
+				(COMMAND_REGISTER, command),
+			] {
+				self.bus
+					.write(VMAddress::new(BASE + offset), Size::Word, value)
+					.unwrap();
+			}
+			assert!(
+				BlockStatus::from_bits_truncate(self.register(STATUS_REGISTER))
+					.contains(BlockStatus::BUSY)
+			);
+
+			let mut interrupts = Vec::new();
+			self.bus
+				.poll(|interrupt| interrupts.push(interrupt), |_| {});
+			(self.register(ERROR_REGISTER), interrupts)
+		}
+
+		fn ram(&self, address: u64, length: usize) -> Vec<u8> {
+			let mut buffer = vec![0; length];
+			self.bus.memory().read_bytes(address, &mut buffer).unwrap();
+			buffer
+		}
+	}
+
+	impl Drop for Disk {
+		fn drop(&mut self) {
+			let _ = std::fs::remove_file(&self.path);
+		}
+	}
+
+	#[test]
+	fn reads_sectors_into_ram() {
+		let mut disk = Disk::new("read", DiskMode::ReadWrite);
+		assert_eq!(disk.register(CAPACITY_REGISTER), SECTORS);
+
+		let (error, interrupts) = disk.run(Command::Read as u64, 1, 0x1000, 2);
+		assert_eq!(error, BlockError::None as u64);
+		assert!(interrupts.is_empty());
+		assert_eq!(disk.ram(0x1000, 512), [2; 512]);
+		assert_eq!(disk.ram(0x1200, 512), [3; 512]);
+		assert_eq!(disk.ram(0x1400, 1), [0]);
+	}
+
+	#[test]
+	fn rejects_transfers_out_of_range() {
+		let mut disk = Disk::new("range", DiskMode::ReadWrite);
+		let read = Command::Read as u64;
+
+		assert_eq!(
+			disk.run(read, 3, 0x1000, 2).0,
+			BlockError::SectorOutOfRange as u64
+		);
+		assert_eq!(
+			disk.run(read, u64::MAX, 0x1000, 2).0,
+			BlockError::SectorOutOfRange as u64
+		);
+		// past the end of RAM
+		assert_eq!(
+			disk.run(read, 0, RAM_SIZE - 0x100, 1).0,
+			BlockError::BufferOutOfRange as u64
+		);
+		// wrapping around the address space
+		assert_eq!(
+			disk.run(read, 0, u64::MAX - 0x100, 1).0,
+			BlockError::BufferOutOfRange as u64
+		);
+		// devices can't be the source of a write
+		assert_eq!(
+			disk.run(Command::Write as u64, 0, BASE, 1).0,
+			BlockError::BufferOutOfRange as u64
+		);
+		assert_eq!(
+			disk.run(9, 0, 0x1000, 1).0,
+			BlockError::InvalidCommand as u64
+		);
+		assert!(
+			BlockStatus::from_bits_truncate(disk.register(STATUS_REGISTER))
+				.contains(BlockStatus::ERROR)
+		);
+
+		// a failed read leaves RAM alone
+		assert_eq!(disk.ram(RAM_SIZE - 0x100, 0x100), [0; 0x100]);
+		assert_eq!(disk.run(read, 0, 0x1000, 1).0, BlockError::None as u64);
+		assert_eq!(disk.register(STATUS_REGISTER), 0);
+	}
+
+	#[test]
+	fn writes_reach_the_image() {
+		let mut disk = Disk::new("write", DiskMode::ReadWrite);
+		disk.bus.memory_mut().load(0x1000, &[0xaa; 512]).unwrap();
+
+		assert_eq!(
+			disk.run(Command::Write as u64, 2, 0x1000, 1).0,
+			BlockError::None as u64
+		);
+		assert_eq!(
+			disk.run(Command::Flush as u64, 0, 0, 0).0,
+			BlockError::None as u64
+		);
+		let image = std::fs::read(&disk.path).unwrap();
+		assert_eq!(image[2 * 512..3 * 512], [0xaa; 512]);
+		assert_eq!(image[3 * 512..], [4; 512]);
+	}
+
+	#[test]
+	fn read_only_disks_reject_writes() {
+		let mut disk = Disk::new("read-only", DiskMode::ReadOnly);
+		assert!(
+			BlockStatus::from_bits_truncate(disk.register(STATUS_REGISTER))
+				.contains(BlockStatus::READ_ONLY)
+		);
+		assert_eq!(
+			disk.run(Command::Write as u64, 0, 0x1000, 1).0,
+			BlockError::ReadOnly as u64
+		);
+		assert_eq!(std::fs::read(&disk.path).unwrap()[..512], [1; 512]);
+	}
+
+	#[test]
+	fn copy_on_write_keeps_writes_in_memory() {
+		let mut disk = Disk::new("cow", DiskMode::CopyOnWrite);
+		disk.bus.memory_mut().load(0x1000, &[0xaa; 512]).unwrap();
+
+		assert_eq!(
+			disk.run(Command::Write as u64, 1, 0x1000, 1).0,
+			BlockError::None as u64
+		);
+		// the guest sees its own writes, next to the untouched sectors
+		assert_eq!(
+			disk.run(Command::Read as u64, 0, 0x2000, 3).0,
+			BlockError::None as u64
+		);
+		assert_eq!(disk.ram(0x2000, 512), [1; 512]);
+		assert_eq!(disk.ram(0x2200, 512), [0xaa; 512]);
+		assert_eq!(disk.ram(0x2400, 512), [3; 512]);
+
+		assert_eq!(std::fs::read(&disk.path).unwrap()[512..1024], [2; 512]);
+	}
+
+	#[test]
+	fn completion_raises_an_interrupt_when_enabled() {
+		let mut disk = Disk::new("interrupt", DiskMode::ReadWrite);
+		disk.bus
+			.write(
+				VMAddress::new(BASE + CONTROL_REGISTER),
+				Size::Word,
+				BlockControl::COMPLETION_INTERRUPT.bits(),
+			)
+			.unwrap();
+
+		let (error, interrupts) = disk.run(Command::Read as u64, 4, 0x1000, 1);
+		assert_eq!(error, BlockError::SectorOutOfRange as u64);
+		assert_eq!(interrupts, [7]);
+	}
+}
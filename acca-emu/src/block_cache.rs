@@ -0,0 +1,266 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{collections::HashMap, rc::Rc};
+
+use acca_emu_proc_macro::instruction_predecoder;
+
+use crate::{
+	memory::Memory,
+	mmu::PAGE_SIZE,
+	util::{sign_extend_immediate, Size},
+};
+
+/// The most operands any instruction has.
+pub const MAX_OPERANDS: usize = 8;
+
+/// Blocks are cut off after this many instructions, even if they don't end in a branch.
+const MAX_BLOCK_LENGTH: usize = 64;
+
+/// Code pages below this are also tracked in a bitmap, so stores to them can skip the index lookup.
+const BITMAP_PAGES: u64 = 1 << 20;
+
+/// An instruction whose operands have already been extracted from its encoding.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodedInstruction {
+	/// The index of the instruction in the VM's instruction table, or [`DecodedInstruction::INVALID`].
+	pub opcode: u16,
+	pub encoded: u32,
+	/// Whether the instruction can change the instruction pointer (other than by advancing it).
+	pub ends_block: bool,
+	/// The raw value of each operand, in declaration order. Relative operands are already sign-extended
+	/// (so they need to be sign-extended again from 32 bits when they're used).
+	pub operands: [u32; MAX_OPERANDS],
+}
+
+/// A run of consecutive instructions, ending at the first one that can branch (or at a page boundary).
+#[derive(Debug)]
+struct Block {
+	start: u64,
+	instructions: Vec<DecodedInstruction>,
+}
+
+/// A cache of decoded basic blocks, keyed by their physical start address.
+///
+/// Blocks never cross a page boundary, so stores only need to invalidate the blocks in the page they hit.
+#[derive(Debug, Default)]
+pub struct BlockCache {
+	blocks: HashMap<u64, Rc<Block>>,
+	/// The start addresses of the cached blocks in each page.
+	page_blocks: HashMap<u64, Vec<u64>>,
+	/// One bit per page, set if any cached block lives in that page.
+	code_pages: Vec<u64>,
+	/// The highest page with cached blocks in it.
+	last_code_page: u64,
+	/// The block being executed and the index of the next instruction in it.
+	current: Option<(Rc<Block>, usize)>,
+}
+
+impl DecodedInstruction {
+	pub const INVALID: u16 = u16::MAX;
+
+	pub fn invalid(encoded: u32) -> Self {
+		Self {
+			opcode: Self::INVALID,
+			encoded,
+			ends_block: true,
+			operands: [0; MAX_OPERANDS],
+		}
+	}
+
+	pub fn decode(encoded: u32) -> Self {
+		instruction_predecoder!("src/vm.rs")
+	}
+}
+
+impl BlockCache {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	fn page_bit(page: u64) -> (usize, u64) {
+		((page / 64) as usize, 1 << (page % 64))
+	}
+
+	/// Records that the block starting at `start` is cached.
+	fn add_to_page(&mut self, start: u64) {
+		let page = start / PAGE_SIZE;
+		if page < BITMAP_PAGES {
+			let (word, bit) = Self::page_bit(page);
+			if self.code_pages.len() <= word {
+				self.code_pages.resize(word + 1, 0);
+			}
+			self.code_pages[word] |= bit;
+		}
+		self.page_blocks.entry(page).or_default().push(start);
+		self.last_code_page = self.last_code_page.max(page);
+	}
+
+	/// Forgets the blocks in `page`, returning their start addresses.
+	fn take_page(&mut self, page: u64) -> Option<Vec<u64>> {
+		if page < BITMAP_PAGES {
+			let (word, bit) = Self::page_bit(page);
+			match self.code_pages.get_mut(word) {
+				Some(bits) if *bits & bit != 0 => *bits &= !bit,
+				_ => return None,
+			}
+		}
+		self.page_blocks.remove(&page)
+	}
+
+	fn decode_block(memory: &Memory, start: u64) -> Option<Block> {
+		let page_end = (start & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+		let mut instructions = Vec::new();
+
+		for address in (start..page_end).step_by(4).take(MAX_BLOCK_LENGTH) {
+			let encoded = match memory.read(address, Size::QuadByte) {
+				Some(encoded) => encoded as u32,
+				None => break,
+			};
+			let instruction = DecodedInstruction::decode(encoded);
+			instructions.push(instruction);
+			if instruction.ends_block {
+				break;
+			}
+		}
+
+		(!instructions.is_empty()).then_some(Block {
+			start,
+			instructions,
+		})
+	}
+
+	/// Returns the decoded instruction at the physical address `address` in `memory`, decoding (and caching) the block
+	/// starting there if necessary.
+	///
+	/// Returns `None` if `address` isn't in memory.
+	pub fn fetch(&mut self, memory: &Memory, address: u64) -> Option<DecodedInstruction> {
+		// the common case: continuing through the current block
+		if let Some((block, index)) = &mut self.current {
+			if block.start + (*index as u64) * 4 == address {
+				if let Some(&instruction) = block.instructions.get(*index) {
+					*index += 1;
+					return Some(instruction);
+				}
+			}
+		}
+
+		let block = match self.blocks.get(&address) {
+			Some(block) => block.clone(),
+			None => {
+				let block = Rc::new(Self::decode_block(memory, address)?);
+				self.add_to_page(address);
+				self.blocks.insert(address, block.clone());
+				block
+			},
+		};
+
+		let instruction = block.instructions[0];
+		self.current = Some((block, 1));
+		Some(instruction)
+	}
+
+	/// Drops the cached blocks in the pages overlapping the `size` bytes at the physical address `address`.
+	pub fn invalidate(&mut self, address: u64, size: u64) {
+		let first = address / PAGE_SIZE;
+		// nothing is cached above the highest code page
+		let last = (address.saturating_add(size.max(1) - 1) / PAGE_SIZE).min(self.last_code_page);
+
+		for page in first..=last {
+			let Some(starts) = self.take_page(page) else {
+				continue;
+			};
+
+			for start in starts {
+				self.blocks.remove(&start);
+			}
+			if self
+				.current
+				.as_ref()
+				.is_some_and(|(block, _)| block.start / PAGE_SIZE == page)
+			{
+				self.current = None;
+			}
+		}
+	}
+
+	pub fn clear(&mut self) {
+		self.blocks.clear();
+		self.page_blocks.clear();
+		self.code_pages.clear();
+		self.last_code_page = 0;
+		self.current = None;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory::SparseMemory;
+
+	fn fetch_encoded(cache: &mut BlockCache, memory: &Memory, address: u64) -> u32 {
+		cache.fetch(memory, address).unwrap().encoded
+	}
+
+	#[test]
+	fn stores_to_code_drop_stale_blocks() {
+		let mut memory = Memory::contiguous(4 * PAGE_SIZE as usize).unwrap();
+		let mut cache = BlockCache::new();
+
+		memory.write(0x1000, Size::QuadByte, 0x1111_1111).unwrap();
+		assert_eq!(fetch_encoded(&mut cache, &memory, 0x1000), 0x1111_1111);
+
+		// without invalidation, the cached copy wins
+		memory.write(0x1000, Size::QuadByte, 0x2222_2222).unwrap();
+		assert_eq!(fetch_encoded(&mut cache, &memory, 0x1000), 0x1111_1111);
+
+		cache.invalidate(0x1000, 4);
+		assert_eq!(fetch_encoded(&mut cache, &memory, 0x1000), 0x2222_2222);
+	}
+
+	#[test]
+	fn stores_only_drop_blocks_in_their_page() {
+		let mut memory = Memory::contiguous(4 * PAGE_SIZE as usize).unwrap();
+		let mut cache = BlockCache::new();
+
+		memory.write(0x1000, Size::QuadByte, 0x1111_1111).unwrap();
+		memory.write(0x1100, Size::QuadByte, 0x3333_3333).unwrap();
+		memory.write(0x2000, Size::QuadByte, 0x5555_5555).unwrap();
+		assert_eq!(fetch_encoded(&mut cache, &memory, 0x1000), 0x1111_1111);
+		assert_eq!(fetch_encoded(&mut cache, &memory, 0x1100), 0x3333_3333);
+		assert_eq!(fetch_encoded(&mut cache, &memory, 0x2000), 0x5555_5555);
+
+		memory.write(0x1000, Size::QuadByte, 0x2222_2222).unwrap();
+		memory.write(0x1100, Size::QuadByte, 0x4444_4444).unwrap();
+		memory.write(0x2000, Size::QuadByte, 0x6666_6666).unwrap();
+
+		// a store to a page without code doesn't drop anything
+		cache.invalidate(0x3000, 8);
+		assert_eq!(fetch_encoded(&mut cache, &memory, 0x1000), 0x1111_1111);
+
+		// data sharing a page with code drops every block in that page, but only in that page
+		cache.invalidate(0x1800, 8);
+		assert_eq!(fetch_encoded(&mut cache, &memory, 0x1000), 0x2222_2222);
+		assert_eq!(fetch_encoded(&mut cache, &memory, 0x1100), 0x4444_4444);
+		assert_eq!(fetch_encoded(&mut cache, &memory, 0x2000), 0x5555_5555);
+	}
+
+	#[test]
+	fn stores_above_the_bitmap_drop_stale_blocks() {
+		let address = BITMAP_PAGES * PAGE_SIZE;
+		let mut memory = Memory::Sparse(SparseMemory::new(PAGE_SIZE));
+		let mut cache = BlockCache::new();
+
+		memory.write(address, Size::QuadByte, 0x1111_1111).unwrap();
+		assert_eq!(fetch_encoded(&mut cache, &memory, address), 0x1111_1111);
+
+		memory.write(address, Size::QuadByte, 0x2222_2222).unwrap();
+		cache.invalidate(address + 0x10, 1);
+		assert_eq!(fetch_encoded(&mut cache, &memory, address), 0x2222_2222);
+	}
+}
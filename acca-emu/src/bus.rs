@@ -0,0 +1,252 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{fmt, ops::Range};
+
+use crate::{
+	memory::{Memory, Region, RegionKind},
+	util::{Size, VMAddress},
+};
+
+/// A memory-mapped device.
+///
+/// Devices are attached to a [`Bus`] at a fixed address range and receive all data accesses within that range.
+/// Offsets passed to the callbacks are relative to the start of the device's range, and accesses never cross
+/// the end of it.
+pub trait Device {
+	/// Handles a read of `size` at `offset`, returning the (zero-extended) value read.
+	///
+	/// Returning `None` makes the access fail with a data load error.
+	fn read(&mut self, offset: u64, size: Size) -> Option<u64>;
+
+	/// Handles a write of the lowest `size` bits of `value` at `offset`.
+	///
+	/// Returning `None` makes the access fail with a data load error.
+	fn write(&mut self, offset: u64, size: Size, value: u64) -> Option<()>;
+
+	/// Called before every instruction (before [`Device::poll`]) so that the device can access RAM directly.
+	fn dma(&mut self, _dma: &mut Dma) {}
+
+	/// Called before every instruction; returns `true` if the device wants to raise its interrupt.
+	fn poll(&mut self) -> bool {
+		false
+	}
+}
+
+/// Direct access to RAM for devices, by physical address.
+///
+/// Accesses are checked like guest accesses (e.g. read-only regions can't be written), but they never reach other
+/// devices.
+pub struct Dma<'a> {
+	memory: &'a mut Memory,
+	/// The ranges of RAM that have been written to.
+	written: Vec<Range<u64>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachError {
+	/// The device's range is empty or wraps around the end of the address space.
+	InvalidRange,
+	/// The device's range overlaps RAM or another device.
+	Overlap(Range<u64>),
+}
+
+struct Mapping {
+	range: Range<u64>,
+	interrupt: Option<u64>,
+	device: Box<dyn Device>,
+}
+
+/// The guest's physical address space: RAM, plus any number of devices.
+///
+/// Contiguous RAM starts at address 0 and devices are mapped above it. Sparse memory covers the whole address space,
+/// so devices are carved out of it instead. Addresses that aren't backed by either are holes; accesses to them fail.
+pub struct Bus {
+	memory: Memory,
+	devices: Vec<Mapping>,
+}
+
+impl fmt::Display for AttachError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			AttachError::InvalidRange => write!(f, "invalid device address range"),
+			AttachError::Overlap(range) => write!(
+				f,
+				"device address range overlaps existing mapping at {:#x}-{:#x}",
+				range.start, range.end
+			),
+		}
+	}
+}
+
+impl std::error::Error for AttachError {}
+
+impl fmt::Debug for Bus {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Bus")
+			.field("memory", &self.memory)
+			.field(
+				"devices",
+				&self
+					.devices
+					.iter()
+					.map(|mapping| &mapping.range)
+					.collect::<Vec<_>>(),
+			)
+			.finish()
+	}
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+	a.start < b.end && b.start < a.end
+}
+
+impl Dma<'_> {
+	/// Fills `buffer` with the RAM at `address`. Fails if any of it isn't RAM.
+	pub fn read(&self, address: u64, buffer: &mut [u8]) -> Option<()> {
+		self.memory.read_bytes(address, buffer)
+	}
+
+	/// Copies `data` to the RAM at `address`. Nothing is written if any of it isn't writable RAM.
+	pub fn write(&mut self, address: u64, data: &[u8]) -> Option<()> {
+		self.memory.write_bytes(address, data)?;
+		self.written.push(address..address + data.len() as u64);
+		Some(())
+	}
+}
+
+impl Bus {
+	pub fn new(memory: Memory) -> Self {
+		Self {
+			memory,
+			devices: Vec::new(),
+		}
+	}
+
+	pub fn memory(&self) -> &Memory {
+		&self.memory
+	}
+
+	pub fn memory_mut(&mut self) -> &mut Memory {
+		&mut self.memory
+	}
+
+	/// Maps `device` at `base`. If `interrupt` is given, the device raises that interrupt when it asks to.
+	pub fn attach(
+		&mut self,
+		base: VMAddress,
+		size: u64,
+		interrupt: Option<u64>,
+		device: Box<dyn Device>,
+	) -> Result<(), AttachError> {
+		let start = u64::from(base);
+		let end = match start.checked_add(size) {
+			Some(end) if size > 0 => end,
+			_ => return Err(AttachError::InvalidRange),
+		};
+		let range = start..end;
+
+		let ram_range = 0..self.memory.size();
+		if !self.memory.is_sparse() && overlaps(&range, &ram_range) {
+			return Err(AttachError::Overlap(ram_range));
+		}
+		if let Some(mapping) = self
+			.devices
+			.iter()
+			.find(|mapping| overlaps(&range, &mapping.range))
+		{
+			return Err(AttachError::Overlap(mapping.range.clone()));
+		}
+
+		if let Memory::Sparse(memory) = &mut self.memory {
+			memory.add_region(Region::new(range.clone(), RegionKind::Unmapped));
+		}
+		self.devices.push(Mapping {
+			range,
+			interrupt,
+			device,
+		});
+		Ok(())
+	}
+
+	/// Polls all devices, calling `raise_interrupt` for each interrupt requested and `written` for each range of RAM
+	/// written by a device.
+	pub fn poll(
+		&mut self,
+		mut raise_interrupt: impl FnMut(u64),
+		mut written: impl FnMut(Range<u64>),
+	) {
+		for mapping in &mut self.devices {
+			let mut dma = Dma {
+				memory: &mut self.memory,
+				written: Vec::new(),
+			};
+			mapping.device.dma(&mut dma);
+			dma.written.into_iter().for_each(&mut written);
+
+			if mapping.device.poll() {
+				if let Some(interrupt) = mapping.interrupt {
+					raise_interrupt(interrupt);
+				}
+			}
+		}
+	}
+
+	/// Finds the device mapping containing the entirety of `range`.
+	fn device_index(&self, range: &Range<u64>) -> Option<usize> {
+		self.devices.iter().position(|mapping| {
+			mapping.range.start <= range.start && range.end <= mapping.range.end
+		})
+	}
+
+	fn byte_range(address: VMAddress, byte_size: u64) -> Option<Range<u64>> {
+		let start = u64::from(address);
+		Some(start..start.checked_add(byte_size)?)
+	}
+
+	/// Checks whether all `byte_size` bytes at `address` are backed by a single region (RAM or a device), and whether
+	/// RAM there can be written to if `write` is set.
+	pub fn is_mapped(&self, address: VMAddress, byte_size: u64, write: bool) -> bool {
+		match Self::byte_range(address, byte_size) {
+			Some(range) => {
+				self.memory.is_mapped(range.start, byte_size, write)
+					|| self.device_index(&range).is_some()
+			},
+			None => false,
+		}
+	}
+
+	pub fn read(&mut self, address: VMAddress, size: Size) -> Option<u64> {
+		let range = Self::byte_range(address, size.byte_size() as u64)?;
+
+		if let Some(value) = self.memory.read(range.start, size) {
+			return Some(value);
+		}
+
+		let index = self.device_index(&range)?;
+		let mapping = &mut self.devices[index];
+		mapping
+			.device
+			.read(range.start - mapping.range.start, size)
+			.map(|value| value & size.mask())
+	}
+
+	pub fn write(&mut self, address: VMAddress, size: Size, value: u64) -> Option<()> {
+		let range = Self::byte_range(address, size.byte_size() as u64)?;
+
+		if self.memory.write(range.start, size, value).is_some() {
+			return Some(());
+		}
+
+		let index = self.device_index(&range)?;
+		let mapping = &mut self.devices[index];
+		mapping
+			.device
+			.write(range.start - mapping.range.start, size, value & size.mask())
+	}
+}
@@ -0,0 +1,46 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::io::{self, Write};
+
+use crate::{bus::Device, util::Size};
+
+/// The size of the console's register block.
+pub const CONSOLE_SIZE: u64 = 8;
+
+/// A write-only debug console.
+///
+/// Writing to offset 0 prints the lowest byte of the value written to the host's standard output.
+#[derive(Debug, Default)]
+pub struct Console;
+
+impl Console {
+	pub fn new() -> Self {
+		Self
+	}
+}
+
+impl Device for Console {
+	fn read(&mut self, _offset: u64, _size: Size) -> Option<u64> {
+		None
+	}
+
+	fn write(&mut self, offset: u64, _size: Size, value: u64) -> Option<()> {
+		if offset != 0 {
+			return None;
+		}
+
+		let mut stdout = io::stdout();
+		// there's nothing useful the guest can do about a failed write to the console, so just ignore it
+		let _ = stdout.write_all(&[value as u8]);
+		if value as u8 == b'\n' {
+			let _ = stdout.flush();
+		}
+		Some(())
+	}
+}
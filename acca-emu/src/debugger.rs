@@ -0,0 +1,980 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	collections::BTreeSet,
+	io::{self, BufRead, Write},
+	sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+	disasm::disassemble,
+	mmu::{Access, PAGE_SIZE},
+	symbols::{parse_address, SymbolTable},
+	util::{
+		sign_extend_immediate, CPUFlags, CallKind, MachineRegisterID, PrivilegeLevel, RegisterID,
+		Size, VMAddress,
+	},
+	vm::{Exception, StepResult, VM},
+	watch::{WatchKind, Watchpoint},
+};
+
+/// Set by the Ctrl-C handler to ask a running guest to stop.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+const MACHINE_REGISTERS: [(&str, MachineRegisterID); 11] = [
+	("flags", MachineRegisterID::flags),
+	("elr", MachineRegisterID::elr),
+	("esp", MachineRegisterID::esp),
+	("eflags", MachineRegisterID::eflags),
+	("einfo", MachineRegisterID::einfo),
+	("eaddr", MachineRegisterID::eaddr),
+	("evtable", MachineRegisterID::evtable),
+	("ectable", MachineRegisterID::ectable),
+	("ptbase", MachineRegisterID::ptbase),
+	("vm_timer_control", MachineRegisterID::vm_timer_control),
+	("vm_timer_interval", MachineRegisterID::vm_timer_interval),
+];
+
+const HELP: &str = "\
+Commands:
+  break|b <location>          set a breakpoint
+  delete|d [location]         delete a breakpoint (or all of them)
+  breakpoints|bl              list breakpoints
+  watch|rwatch|awatch <location> [length]
+                              stop after writes, reads, or any access to memory (8 bytes by default)
+  unwatch [location]          delete the watchpoints starting at a location (or all of them)
+  watchpoints|wl              list watchpoints
+  step|s [count]              execute one (or `count`) instructions
+  next|n [count]              like `step`, but step over calls
+  finish|fin                  run until the current frame returns
+  continue|c                  run until a breakpoint or exception (other than an interrupt) is hit
+  backtrace|bt                show the frames entered since the session started
+  registers|regs|r            show all registers
+  print|p <register>          show a register (e.g. `r0`, `r0b`, `rspq`, `ip`, `elr`)
+  x[.b|.d|.q|.w] <location> [count]
+                              examine memory (words by default)
+  flags                       decode `flags` and `eflags`
+  exception|exc               decode the exception being handled, if any
+  help|h                      show this message
+  quit|q                      exit the emulator
+
+Locations are addresses (e.g. `0x400`), symbols (optionally with an offset, e.g. `main+8`), or registers.
+Pressing enter on an empty line repeats the last `step`, `next`, `finish`, or `continue`.
+Press Ctrl-C to interrupt a running guest.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Frame {
+	Call {
+		return_address: VMAddress,
+	},
+	Exception {
+		exception: Exception,
+		return_address: VMAddress,
+	},
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameEvent {
+	None,
+	Entered,
+	/// A frame was exited; this contains the number of known frames there were before exiting it.
+	Exited(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+	Done,
+	Breakpoint,
+	Watchpoint,
+	Exception(Exception),
+	Interrupted,
+	Exited(u64),
+}
+
+/// An interactive, line-based debugger controlling a VM.
+///
+/// Frames are tracked by watching for taken calls (which set `rlr`) and `ret`s, as well as exceptions and `eret`s.
+/// Since the debugger only sees frames entered while it's running, frames entered before the session started are unknown;
+/// `finish` in such a frame simply runs until the next unmatched `ret` or `eret`.
+pub struct Debugger<'a> {
+	vm: &'a mut VM,
+	symbols: &'a SymbolTable,
+	breakpoints: BTreeSet<u64>,
+	frames: Vec<Frame>,
+	last_command: Option<String>,
+	/// Set when the last instruction executed hit a watchpoint.
+	watch_triggered: bool,
+}
+
+fn parse_register(name: &str) -> Option<(RegisterID, Option<Size>)> {
+	let name = name.strip_prefix('r')?;
+	let (id, rest) = if let Some(rest) = name.strip_prefix("sp") {
+		(RegisterID::SP, rest)
+	} else if let Some(rest) = name.strip_prefix("fp") {
+		(RegisterID::FP, rest)
+	} else if let Some(rest) = name.strip_prefix("lr") {
+		(RegisterID::LR, rest)
+	} else {
+		let digits = name
+			.find(|c: char| !c.is_ascii_digit())
+			.unwrap_or(name.len());
+		let number: u64 = name[..digits].parse().ok()?;
+		if number > 15 {
+			return None;
+		}
+		(RegisterID::from(number), &name[digits..])
+	};
+
+	let size = match rest {
+		"" => None,
+		_ => Some(parse_size(rest)?),
+	};
+
+	Some((id, size))
+}
+
+fn parse_size(suffix: &str) -> Option<Size> {
+	match suffix {
+		"b" => Some(Size::Byte),
+		"d" => Some(Size::DoubleByte),
+		"q" => Some(Size::QuadByte),
+		"w" => Some(Size::Word),
+		_ => None,
+	}
+}
+
+fn machine_register_by_name(name: &str) -> Option<MachineRegisterID> {
+	MACHINE_REGISTERS
+		.iter()
+		.find(|(mreg_name, _)| *mreg_name == name)
+		.map(|(_, id)| *id)
+}
+
+fn describe_flags(flags: CPUFlags) -> String {
+	let mut names = Vec::new();
+	for (set, name) in [
+		(flags.carry(), "C"),
+		(flags.zero(), "Z"),
+		(flags.overflow(), "O"),
+		(flags.sign(), "S"),
+		(flags.exceptions_enabled(), "E"),
+	] {
+		if set {
+			names.push(name);
+		}
+	}
+	names.push(match flags.privilege_level() {
+		PrivilegeLevel::PL0 => "PL0",
+		PrivilegeLevel::PL1 => "PL1",
+	});
+	format!("{:#x} [ {} ]", u64::from(flags), names.join(" "))
+}
+
+/// Decodes `einfo` as written by [`VM::take_exception`].
+fn describe_einfo(einfo: u64) -> String {
+	match einfo & 7 {
+		0 => "Unknown".to_string(),
+		1 => "InvalidInstruction".to_string(),
+		2 => "Debug".to_string(),
+		3 => format!("User({:#x})", einfo >> 3),
+		4 => "InvalidOperation".to_string(),
+		5 => "InstructionLoadError".to_string(),
+		6 => format!(
+			"DataLoadError ({}, {} bytes)",
+			if (einfo & (1 << 3)) != 0 {
+				"write"
+			} else {
+				"read"
+			},
+			(einfo >> 4) & 0xffff
+		),
+		_ => format!("Interrupt({})", einfo >> 3),
+	}
+}
+
+fn describe_value(value: u64, size: Size) -> String {
+	let signed = sign_extend_immediate(value, size.bit_size() as u64) as i64;
+	if signed < 0 {
+		format!("{:#x} ({}, {})", value, value, signed)
+	} else {
+		format!("{:#x} ({})", value, value)
+	}
+}
+
+impl<'a> Debugger<'a> {
+	pub fn new(vm: &'a mut VM, symbols: &'a SymbolTable) -> Self {
+		Self {
+			vm,
+			symbols,
+			breakpoints: BTreeSet::new(),
+			frames: Vec::new(),
+			last_command: None,
+			watch_triggered: false,
+		}
+	}
+
+	/// Reads and executes commands from standard input until the user quits or standard input is closed.
+	pub fn run(mut self) -> io::Result<()> {
+		if let Err(e) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst)) {
+			eprintln!("Warning: failed to install Ctrl-C handler: {}", e);
+		}
+
+		self.print_location();
+
+		let stdin = io::stdin();
+		let mut lines = stdin.lock().lines();
+
+		loop {
+			print!("(acca) ");
+			io::stdout().flush()?;
+
+			let line = match lines.next() {
+				Some(line) => line?,
+				None => {
+					println!();
+					return Ok(());
+				},
+			};
+
+			let line = match line.trim() {
+				"" => match &self.last_command {
+					Some(command) => command.clone(),
+					None => continue,
+				},
+				line => line.to_string(),
+			};
+
+			if !self.execute_command(&line) {
+				return Ok(());
+			}
+		}
+	}
+
+	/// Executes a single command, returning `false` if the user asked to quit.
+	fn execute_command(&mut self, line: &str) -> bool {
+		let mut words = line.split_whitespace();
+		let command = words.next().unwrap_or("");
+		let args: Vec<&str> = words.collect();
+
+		let result = match command {
+			"break" | "b" => self.command_break(&args),
+			"delete" | "d" => self.command_delete(&args),
+			"breakpoints" | "bl" => {
+				self.command_breakpoints();
+				Ok(())
+			},
+			"watch" => self.command_watch(&args, WatchKind::Write),
+			"rwatch" => self.command_watch(&args, WatchKind::Read),
+			"awatch" => self.command_watch(&args, WatchKind::Access),
+			"unwatch" => self.command_unwatch(&args),
+			"watchpoints" | "wl" => {
+				self.command_watchpoints();
+				Ok(())
+			},
+			"step" | "s" | "next" | "n" => {
+				self.last_command = Some(line.to_string());
+				self.command_step(&args, command.starts_with('n'))
+			},
+			"finish" | "fin" => {
+				self.last_command = Some(line.to_string());
+				let stop = self.finish();
+				self.report_stop(stop);
+				Ok(())
+			},
+			"continue" | "c" => {
+				self.last_command = Some(line.to_string());
+				let stop = self.resume(|_, _| false);
+				self.report_stop(stop);
+				Ok(())
+			},
+			"backtrace" | "bt" => {
+				self.command_backtrace();
+				Ok(())
+			},
+			"registers" | "regs" | "r" => {
+				self.command_registers();
+				Ok(())
+			},
+			"print" | "p" => self.command_print(&args),
+			"flags" => {
+				println!("flags  = {}", describe_flags(self.vm.flags()));
+				println!(
+					"eflags = {}",
+					describe_flags(
+						self.vm
+							.machine_register(MachineRegisterID::eflags)
+							.try_into()
+							.unwrap_or_default()
+					)
+				);
+				Ok(())
+			},
+			"exception" | "exc" => {
+				self.command_exception();
+				Ok(())
+			},
+			"help" | "h" => {
+				println!("{}", HELP);
+				Ok(())
+			},
+			"quit" | "q" => return false,
+			_ => match command.strip_prefix('x') {
+				Some("") => self.command_examine(Size::Word, &args),
+				Some(suffix) => match suffix.strip_prefix('.').and_then(parse_size) {
+					Some(size) => self.command_examine(size, &args),
+					None => Err(format!("Unknown command \"{}\"; try \"help\"", command)),
+				},
+				None => Err(format!("Unknown command \"{}\"; try \"help\"", command)),
+			},
+		};
+
+		if let Err(message) = result {
+			println!("{}", message);
+		}
+
+		true
+	}
+
+	fn describe_address(&self, address: VMAddress) -> String {
+		match self.symbols.describe(address) {
+			Some(symbol) => format!("{:#x} <{}>", u64::from(address), symbol),
+			None => format!("{:#x}", u64::from(address)),
+		}
+	}
+
+	/// Parses an address, symbol (with an optional offset), or register into an address.
+	fn parse_location(&self, location: &str) -> Result<VMAddress, String> {
+		let (base, offset) = match location.find(['+', '-']) {
+			Some(index) if index > 0 => {
+				let offset = parse_address(&location[index + 1..])
+					.ok_or_else(|| format!("Invalid offset in \"{}\"", location))?;
+				let offset = match &location[index..index + 1] {
+					"-" => offset.wrapping_neg(),
+					_ => offset,
+				};
+				(&location[..index], offset)
+			},
+			_ => (location, 0),
+		};
+
+		let base = if let Some(address) = parse_address(base) {
+			address
+		} else if base == "ip" {
+			self.vm.instruction_pointer().into()
+		} else if let Some((id, size)) = parse_register(base) {
+			self.vm.register_file()[id].get_unsigned(size.unwrap_or(Size::Word))
+		} else if let Some(address) = self.symbols.address_of(base) {
+			address.into()
+		} else {
+			return Err(format!("Unknown location \"{}\"", base));
+		};
+
+		Ok(base.wrapping_add(offset).into())
+	}
+
+	fn single_location(&self, args: &[&str]) -> Result<VMAddress, String> {
+		match args {
+			[location] => self.parse_location(location),
+			_ => Err("Expected a single location".to_string()),
+		}
+	}
+
+	fn parse_count(args: &[&str]) -> Result<u64, String> {
+		match args {
+			[] => Ok(1),
+			[count] => count
+				.parse()
+				.map_err(|_| format!("Invalid count \"{}\"", count)),
+			_ => Err("Too many arguments".to_string()),
+		}
+	}
+
+	//
+	// execution
+	//
+
+	/// Executes a single instruction, keeping track of the frames it enters and exits.
+	fn step_instruction(&mut self) -> (StepResult, FrameEvent) {
+		let ip = self.vm.instruction_pointer();
+		let encoded = self.vm.fetch_instruction(ip);
+
+		let result = self.vm.step();
+
+		// the access has already completed, so the instruction won't be re-executed when resuming
+		for hit in self.vm.take_watch_hits() {
+			println!("Watchpoint hit: {}", hit);
+			self.watch_triggered = true;
+		}
+
+		if let StepResult::Exception(exception) = result {
+			self.frames.push(Frame::Exception {
+				exception,
+				return_address: self.vm.machine_register(MachineRegisterID::elr).into(),
+			});
+			return (result, FrameEvent::Entered);
+		}
+
+		let encoded = match encoded {
+			Some(x) => x,
+			None => return (result, FrameEvent::None),
+		};
+		let frame_count = self.frames.len();
+		let return_address = ip + 4;
+
+		let event = match CallKind::of(encoded) {
+			CallKind::Call => {
+				// a call that was actually taken jumps elsewhere and links back to the next instruction
+				let link = self.vm.register_file()[RegisterID::LR].get_address();
+				if self.vm.instruction_pointer() != return_address && link == return_address {
+					self.frames.push(Frame::Call { return_address });
+					FrameEvent::Entered
+				} else {
+					FrameEvent::None
+				}
+			},
+			CallKind::Return => {
+				if let Some(Frame::Call { .. }) = self.frames.last() {
+					self.frames.pop();
+				}
+				FrameEvent::Exited(frame_count)
+			},
+			CallKind::ExceptionReturn => {
+				// unwind any calls made by the handler along with the handler itself
+				if let Some(index) = self
+					.frames
+					.iter()
+					.rposition(|frame| matches!(frame, Frame::Exception { .. }))
+				{
+					self.frames.truncate(index);
+				}
+				FrameEvent::Exited(frame_count)
+			},
+			CallKind::Other => FrameEvent::None,
+		};
+
+		(result, event)
+	}
+
+	/// Runs the guest until `done` returns `true` for an executed instruction, a breakpoint is hit, an exception other than
+	/// an interrupt is taken, or the user interrupts it.
+	fn resume(&mut self, mut done: impl FnMut(&Self, FrameEvent) -> bool) -> StopReason {
+		INTERRUPTED.store(false, Ordering::SeqCst);
+		self.watch_triggered = false;
+
+		loop {
+			let (result, event) = self.step_instruction();
+
+			match result {
+				// interrupts are asynchronous, so they'd just be noise when running freely
+				StepResult::Exception(Exception::Interrupt(_)) => {},
+				StepResult::Exception(exception) => return StopReason::Exception(exception),
+				StepResult::Exited(code) => return StopReason::Exited(code),
+				_ => {},
+			}
+			if done(self, event) {
+				return StopReason::Done;
+			}
+			if std::mem::take(&mut self.watch_triggered) {
+				return StopReason::Watchpoint;
+			}
+			if self
+				.breakpoints
+				.contains(&self.vm.instruction_pointer().into())
+			{
+				return StopReason::Breakpoint;
+			}
+			if INTERRUPTED.swap(false, Ordering::SeqCst) {
+				return StopReason::Interrupted;
+			}
+		}
+	}
+
+	/// Runs until the current frame is exited.
+	fn finish(&mut self) -> StopReason {
+		let start = self.frames.len();
+		self.resume(|_, event| matches!(event, FrameEvent::Exited(count) if count <= start))
+	}
+
+	fn report_stop(&self, stop: StopReason) {
+		match stop {
+			StopReason::Done => {},
+			StopReason::Breakpoint => println!("Breakpoint hit"),
+			// the hits themselves have already been printed
+			StopReason::Watchpoint => {},
+			StopReason::Exception(exception) => {
+				println!(
+					"Exception {:?} taken at {}",
+					exception,
+					self.describe_address(self.vm.machine_register(MachineRegisterID::elr).into())
+				);
+			},
+			StopReason::Interrupted => println!("Interrupted"),
+			StopReason::Exited(code) => {
+				println!("Guest exited with status {}", code);
+				return;
+			},
+		}
+		self.print_location();
+	}
+
+	fn print_location(&self) {
+		let ip = self.vm.instruction_pointer();
+		match self.vm.fetch_instruction(ip) {
+			Some(encoded) => {
+				println!(
+					"=> {}: {:#010x}  {}",
+					self.describe_address(ip),
+					encoded,
+					disassemble(encoded, ip.into(), self.symbols)
+						.unwrap_or_else(|| "<invalid>".to_string())
+				)
+			},
+			None => println!("=> {}: <inaccessible>", self.describe_address(ip)),
+		}
+	}
+
+	//
+	// commands
+	//
+
+	fn command_break(&mut self, args: &[&str]) -> Result<(), String> {
+		let address = self.single_location(args)?;
+		if !address.is_valid_instruction_pointer() {
+			return Err(format!(
+				"{:#x} is not a valid instruction address",
+				u64::from(address)
+			));
+		}
+
+		self.breakpoints.insert(address.into());
+		println!("Breakpoint set at {}", self.describe_address(address));
+		Ok(())
+	}
+
+	fn command_delete(&mut self, args: &[&str]) -> Result<(), String> {
+		if args.is_empty() {
+			self.breakpoints.clear();
+			println!("Deleted all breakpoints");
+			return Ok(());
+		}
+
+		let address = self.single_location(args)?;
+		if !self.breakpoints.remove(&address.into()) {
+			return Err(format!(
+				"No breakpoint at {}",
+				self.describe_address(address)
+			));
+		}
+		println!("Deleted breakpoint at {}", self.describe_address(address));
+		Ok(())
+	}
+
+	fn command_breakpoints(&self) {
+		if self.breakpoints.is_empty() {
+			println!("No breakpoints");
+		}
+		for &address in &self.breakpoints {
+			println!("  {}", self.describe_address(address.into()));
+		}
+	}
+
+	fn command_watch(&mut self, args: &[&str], kind: WatchKind) -> Result<(), String> {
+		let (location, length) = match args {
+			[location] => (location, 8),
+			[location, length] => (
+				location,
+				parse_address(length)
+					.filter(|&length| length > 0)
+					.ok_or_else(|| format!("Invalid length \"{}\"", length))?,
+			),
+			_ => return Err("Expected a location and an optional length".to_string()),
+		};
+
+		let start = u64::from(self.parse_location(location)?);
+		let end = start
+			.checked_add(length)
+			.ok_or_else(|| "Watched range wraps around the address space".to_string())?;
+
+		let watchpoint = Watchpoint::new(start..end, kind);
+		println!("Watchpoint set: {}", watchpoint);
+		self.vm.add_watchpoint(watchpoint);
+		Ok(())
+	}
+
+	fn command_unwatch(&mut self, args: &[&str]) -> Result<(), String> {
+		if args.is_empty() {
+			self.vm.clear_watchpoints();
+			println!("Deleted all watchpoints");
+			return Ok(());
+		}
+
+		let address = u64::from(self.single_location(args)?);
+		let matching: Vec<Watchpoint> = self
+			.vm
+			.watchpoints()
+			.iter()
+			.filter(|watchpoint| watchpoint.range.start == address)
+			.cloned()
+			.collect();
+		if matching.is_empty() {
+			return Err(format!(
+				"No watchpoint at {}",
+				self.describe_address(address.into())
+			));
+		}
+		for watchpoint in matching {
+			self.vm.remove_watchpoint(&watchpoint);
+			println!("Deleted watchpoint: {}", watchpoint);
+		}
+		Ok(())
+	}
+
+	fn command_watchpoints(&self) {
+		if self.vm.watchpoints().is_empty() {
+			println!("No watchpoints");
+		}
+		for watchpoint in self.vm.watchpoints() {
+			println!("  {}", watchpoint);
+		}
+	}
+
+	fn command_step(&mut self, args: &[&str], step_over_calls: bool) -> Result<(), String> {
+		let count = Self::parse_count(args)?;
+
+		let mut stop = StopReason::Done;
+		for _ in 0..count {
+			let start = self.frames.len();
+			self.watch_triggered = false;
+			let (result, event) = self.step_instruction();
+
+			stop = match result {
+				StepResult::Exception(exception) => StopReason::Exception(exception),
+				StepResult::Exited(code) => StopReason::Exited(code),
+				_ if self.watch_triggered => StopReason::Watchpoint,
+				_ if step_over_calls && event == FrameEvent::Entered => self.resume(
+					|_, event| matches!(event, FrameEvent::Exited(count) if count <= start + 1),
+				),
+				_ => StopReason::Done,
+			};
+
+			if stop != StopReason::Done {
+				break;
+			}
+		}
+
+		self.report_stop(stop);
+		Ok(())
+	}
+
+	fn command_backtrace(&self) {
+		println!(
+			"#0 {}",
+			self.describe_address(self.vm.instruction_pointer())
+		);
+		for (index, frame) in self.frames.iter().rev().enumerate() {
+			match frame {
+				Frame::Call { return_address } => {
+					println!("#{} {}", index + 1, self.describe_address(*return_address))
+				},
+				Frame::Exception {
+					exception,
+					return_address,
+				} => println!(
+					"#{} {} (interrupted by exception {:?})",
+					index + 1,
+					self.describe_address(*return_address),
+					exception
+				),
+			}
+		}
+	}
+
+	fn command_registers(&self) {
+		let register_file = self.vm.register_file();
+		for index in 0..16 {
+			let id = RegisterID::from(index);
+			println!("{:<17} {:#018x}", id.to_string(), register_file[id].get());
+		}
+		println!(
+			"{:<17} {}",
+			"ip",
+			self.describe_address(self.vm.instruction_pointer())
+		);
+		for (name, id) in MACHINE_REGISTERS {
+			if !self.vm.has_machine_register(id) {
+				continue;
+			}
+			match id {
+				MachineRegisterID::flags | MachineRegisterID::eflags => println!(
+					"{:<17} {}",
+					name,
+					describe_flags(self.vm.machine_register(id).try_into().unwrap_or_default())
+				),
+				_ => println!("{:<17} {:#018x}", name, self.vm.machine_register(id)),
+			}
+		}
+	}
+
+	fn command_print(&self, args: &[&str]) -> Result<(), String> {
+		let name = match args {
+			[name] => *name,
+			_ => return Err("Expected a single register name".to_string()),
+		};
+
+		if name == "ip" {
+			println!(
+				"ip = {}",
+				self.describe_address(self.vm.instruction_pointer())
+			);
+		} else if let Some((id, size)) = parse_register(name) {
+			let size = size.unwrap_or(Size::Word);
+			let value = self.vm.register_file()[id].get_unsigned(size);
+			println!("{} = {}", name, describe_value(value, size));
+		} else if let Some(id) = machine_register_by_name(name) {
+			let value = self.vm.machine_register(id);
+			match id {
+				MachineRegisterID::flags | MachineRegisterID::eflags => println!(
+					"{} = {}",
+					name,
+					describe_flags(value.try_into().unwrap_or_default())
+				),
+				MachineRegisterID::einfo => {
+					println!("{} = {:#x} {}", name, value, describe_einfo(value))
+				},
+				_ => println!("{} = {}", name, self.describe_address(value.into())),
+			}
+		} else {
+			return Err(format!("Unknown register \"{}\"", name));
+		}
+
+		Ok(())
+	}
+
+	/// Reads `size` bytes at the virtual `address` the way the guest would see them (including devices).
+	fn read_virtual(&mut self, address: VMAddress, size: Size) -> Result<u64, String> {
+		let byte_size = size.byte_size() as u64;
+		let fault = |address: VMAddress| {
+			format!(
+				"Cannot access memory at {:#x} (translation fault)",
+				u64::from(address)
+			)
+		};
+
+		if u64::from(address) % PAGE_SIZE + byte_size <= PAGE_SIZE {
+			let physical = self
+				.vm
+				.translate_checked(address, Access::Read)
+				.ok_or_else(|| fault(address))?;
+			return self
+				.vm
+				.read_memory(physical, size)
+				.ok_or_else(|| format!("Cannot access memory at {:#x}", u64::from(address)));
+		}
+
+		// the value straddles a page boundary, so each byte may live somewhere else
+		let mut value = 0;
+		for offset in 0..byte_size {
+			let address = address + offset;
+			let physical = self
+				.vm
+				.translate_checked(address, Access::Read)
+				.ok_or_else(|| fault(address))?;
+			let byte = self
+				.vm
+				.read_memory(physical, Size::Byte)
+				.ok_or_else(|| format!("Cannot access memory at {:#x}", u64::from(address)))?;
+			value |= byte << (offset * 8);
+		}
+		Ok(value)
+	}
+
+	fn command_examine(&mut self, size: Size, args: &[&str]) -> Result<(), String> {
+		let (location, count) = match args {
+			[location, rest @ ..] => (self.parse_location(location)?, Self::parse_count(rest)?),
+			[] => return Err("Expected a location".to_string()),
+		};
+
+		let byte_size = size.byte_size() as u64;
+		let per_line = 16 / byte_size;
+
+		for line_start in (0..count).step_by(per_line as usize) {
+			let address = location + line_start * byte_size;
+			let mut line = format!("{}:", self.describe_address(address));
+
+			for index in line_start..count.min(line_start + per_line) {
+				let address = location + index * byte_size;
+				match self.read_virtual(address, size) {
+					Ok(value) => line.push_str(&format!(
+						" {:#0width$x}",
+						value,
+						width = byte_size as usize * 2 + 2
+					)),
+					Err(message) => {
+						println!("{}", line);
+						return Err(message);
+					},
+				}
+			}
+
+			println!("{}", line);
+		}
+
+		Ok(())
+	}
+
+	fn command_exception(&self) {
+		let exception = self.frames.iter().rev().find_map(|frame| match frame {
+			Frame::Exception { exception, .. } => Some(exception),
+			_ => None,
+		});
+
+		match exception {
+			Some(exception) => {
+				let einfo = self.vm.machine_register(MachineRegisterID::einfo);
+				println!("Handling exception {:?}", exception);
+				println!(
+					"  elr   = {}",
+					self.describe_address(self.vm.machine_register(MachineRegisterID::elr).into())
+				);
+				println!("  einfo = {:#x} {}", einfo, describe_einfo(einfo));
+				println!(
+					"  eaddr = {:#x}",
+					self.vm.machine_register(MachineRegisterID::eaddr)
+				);
+			},
+			None => println!("No exception is being handled"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn symbols() -> SymbolTable {
+		let mut symbols = SymbolTable::new();
+		symbols.insert("entry", VMAddress::new(0x400));
+		symbols.insert("data", VMAddress::new(0x1000));
+		symbols
+	}
+
+	#[test]
+	fn parses_register_names() {
+		assert_eq!(parse_register("r3"), Some((RegisterID::from(3), None)));
+		assert_eq!(
+			parse_register("r15q"),
+			Some((RegisterID::from(15), Some(Size::QuadByte)))
+		);
+		assert_eq!(
+			parse_register("rspb"),
+			Some((RegisterID::SP, Some(Size::Byte)))
+		);
+		assert_eq!(parse_register("rlr"), Some((RegisterID::LR, None)));
+		assert_eq!(parse_register("r16"), None);
+		assert_eq!(parse_register("r1x"), None);
+		assert_eq!(parse_register("r"), None);
+		assert_eq!(parse_register("sp"), None);
+	}
+
+	#[test]
+	fn parses_locations() {
+		let mut vm = VM::new(0x4000).unwrap();
+		vm.register_file_mut()[RegisterID::from(2)].set(Size::Word, 0x2000);
+		let symbols = symbols();
+		let debugger = Debugger::new(&mut vm, &symbols);
+
+		let location = |text| debugger.parse_location(text).map(u64::from);
+		assert_eq!(location("0x800"), Ok(0x800));
+		assert_eq!(location("entry"), Ok(0x400));
+		assert_eq!(location("entry+0x10"), Ok(0x410));
+		assert_eq!(location("data-8"), Ok(0xff8));
+		assert_eq!(location("ip"), Ok(0x400));
+		assert_eq!(location("r2+4"), Ok(0x2004));
+		assert_eq!(
+			location("nowhere"),
+			Err("Unknown location \"nowhere\"".to_string())
+		);
+		assert_eq!(
+			location("entry+zz"),
+			Err("Invalid offset in \"entry+zz\"".to_string())
+		);
+
+		assert_eq!(Debugger::parse_count(&[]), Ok(1));
+		assert_eq!(Debugger::parse_count(&["4"]), Ok(4));
+		assert!(Debugger::parse_count(&["four"]).is_err());
+		assert!(Debugger::parse_count(&["4", "5"]).is_err());
+	}
+
+	#[test]
+	fn executes_breakpoint_commands() {
+		let mut vm = VM::new(0x4000).unwrap();
+		let symbols = symbols();
+		let mut debugger = Debugger::new(&mut vm, &symbols);
+
+		assert!(debugger.execute_command("break entry+4"));
+		assert!(debugger.execute_command("b 0x800"));
+		// neither of these is a valid instruction address, so they're rejected
+		assert!(debugger.execute_command("b 0x802"));
+		assert!(debugger.execute_command("b entry extra"));
+		assert_eq!(
+			debugger.breakpoints.iter().copied().collect::<Vec<_>>(),
+			[0x404, 0x800]
+		);
+
+		assert!(debugger.execute_command("d 0x800"));
+		assert_eq!(
+			debugger.breakpoints.iter().copied().collect::<Vec<_>>(),
+			[0x404]
+		);
+		assert!(debugger.execute_command("delete"));
+		assert!(debugger.breakpoints.is_empty());
+
+		assert!(debugger.execute_command("bogus"));
+		assert!(debugger.execute_command("x.z entry"));
+		assert!(debugger.last_command.is_none());
+		assert!(!debugger.execute_command("quit"));
+	}
+
+	#[test]
+	fn examines_memory_through_the_mmu() {
+		let mut vm = VM::new(0x8000).unwrap();
+		vm.set_mmu(true);
+		// map 0x40_0000 to 0x5000 (readable) through tables at 0x1000-0x4000
+		for (address, entry) in [
+			(0x1000, 0x2001),
+			(0x2000, 0x3001),
+			(0x3010, 0x4001),
+			(0x4000, 0x5003),
+		] {
+			vm.load_bytes(&u64::to_le_bytes(entry), VMAddress::new(address))
+				.unwrap();
+		}
+		vm.load_bytes(&0x1234_5678u32.to_le_bytes(), VMAddress::new(0x5ffc))
+			.unwrap();
+		vm.set_machine_register(MachineRegisterID::ptbase, 0x1001)
+			.unwrap();
+
+		let symbols = SymbolTable::new();
+		let mut debugger = Debugger::new(&mut vm, &symbols);
+		assert_eq!(
+			debugger.read_virtual(VMAddress::new(0x40_0ffc), Size::QuadByte),
+			Ok(0x1234_5678)
+		);
+		// the physical address isn't mapped at all
+		assert_eq!(
+			debugger.read_virtual(VMAddress::new(0x5ffc), Size::QuadByte),
+			Err("Cannot access memory at 0x5ffc (translation fault)".to_string())
+		);
+		// the second half of this is on the next (unmapped) page
+		assert_eq!(
+			debugger.read_virtual(VMAddress::new(0x40_0ffc), Size::Word),
+			Err("Cannot access memory at 0x401000 (translation fault)".to_string())
+		);
+	}
+}
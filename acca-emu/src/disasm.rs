@@ -0,0 +1,187 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::fmt::Write;
+
+use acca_emu_proc_macro::instruction_decoder;
+
+use crate::{
+	symbols::SymbolTable,
+	trace::{InstructionInfo, NamedOperand, Operand},
+	util::{sign_extend_immediate, Condition, MachineRegisterID, RegisterID, Size},
+};
+
+/// Decodes a single instruction, or returns `None` if `encoded` isn't a valid instruction.
+///
+/// The decoder is generated from the encodings the VM executes, so the two always agree.
+pub fn decode(encoded: u32) -> Option<InstructionInfo> {
+	instruction_decoder!("src/vm.rs")
+}
+
+/// The absolute address that a relative operand of `instruction` (located at `address`) refers to, if it has one.
+pub fn relative_target(instruction: &InstructionInfo, address: u64) -> Option<u64> {
+	instruction
+		.operands
+		.iter()
+		.find_map(|operand| match operand.value {
+			// `ldr` offsets are in bytes, but branch offsets are in instructions
+			Operand::Relative(offset) if instruction.mnemonic == "ldr" => Some(offset),
+			Operand::Relative(offset) => Some(offset.wrapping_mul(4)),
+			_ => None,
+		})
+		.map(|offset| address.wrapping_add(4).wrapping_add(offset as u64))
+}
+
+fn format_immediate(value: u64) -> String {
+	if value < 10 {
+		value.to_string()
+	} else {
+		format!("{:#x}", value)
+	}
+}
+
+fn format_machine_register(value: u64) -> String {
+	match u32::try_from(value).ok().map(MachineRegisterID::try_from) {
+		// only the architectural machine registers have names in the assembler
+		Some(Ok(id)) if value <= MachineRegisterID::tlbinv as u64 => format!("{:?}", id),
+		_ => format_immediate(value),
+	}
+}
+
+fn size_suffix(size: Size) -> &'static str {
+	match size {
+		// the assembler defaults to word-sized operations
+		Size::Word => "",
+		_ => size.suffix(),
+	}
+}
+
+/// Whether the assembler can fill `operand` in with its default value when it's omitted.
+fn is_default(operand: &NamedOperand, previous: Option<&NamedOperand>) -> bool {
+	match operand.value {
+		Operand::Boolean(value) => !value,
+		// immediates are only optional when they follow another immediate
+		Operand::Immediate(value) => {
+			value == 0
+				&& matches!(
+					previous.map(|operand| operand.value),
+					Some(Operand::Immediate(_) | Operand::Boolean(_))
+				)
+		},
+		_ => false,
+	}
+}
+
+/// Formats `instruction` (located at `address`) in the syntax accepted by the assembler.
+///
+/// `name_target` names the absolute addresses that relative operands refer to.
+pub fn format_instruction(
+	instruction: &InstructionInfo,
+	address: u64,
+	name_target: &dyn Fn(u64) -> String,
+) -> String {
+	let operand = |name: &str| {
+		instruction
+			.operands
+			.iter()
+			.find(|operand| operand.name == name)
+			.map(|operand| operand.value)
+	};
+
+	// `sub` with a null destination that sets the flags is the `cmp` pseudo-instruction
+	let is_compare = instruction.mnemonic == "sub"
+		&& matches!(operand("dst"), Some(Operand::Register(None)))
+		&& matches!(operand("set_flags"), Some(Operand::Boolean(true)));
+
+	let mut result = match is_compare {
+		true => "cmp".to_owned(),
+		false => instruction.mnemonic.to_owned(),
+	};
+
+	if let Some(Operand::Condition(Some(condition))) = instruction
+		.operands
+		.iter()
+		.map(|operand| operand.value)
+		.find(|value| matches!(value, Operand::Condition(_)))
+	{
+		let _ = write!(result, ".{}", condition.suffix());
+	}
+
+	// `mul` takes its sizes from its registers rather than from a suffix
+	let register_size = |name: &str| match (instruction.mnemonic, name) {
+		("mul", "dst") => operand("dst_size"),
+		("mul", "lhs" | "rhs") => operand("src_size"),
+		_ => None,
+	};
+	if instruction.mnemonic != "mul" {
+		if let Some(Operand::Size(size)) = instruction
+			.operands
+			.iter()
+			.map(|operand| operand.value)
+			.find(|value| matches!(value, Operand::Size(_)))
+		{
+			let suffix = size_suffix(size);
+			if !suffix.is_empty() {
+				let _ = write!(result, ".{}", suffix);
+			}
+		}
+	}
+
+	let mut operands: Vec<&NamedOperand> = instruction
+		.operands
+		.iter()
+		.filter(|operand| !matches!(operand.value, Operand::Size(_) | Operand::Condition(_)))
+		.filter(|operand| !is_compare || !matches!(operand.name, "dst" | "set_flags"))
+		.collect();
+	while let Some(&last) = operands.last() {
+		if !is_default(last, operands.iter().rev().nth(1).copied()) {
+			break;
+		}
+		operands.pop();
+	}
+
+	let target = relative_target(instruction, address);
+	let formatted: Vec<String> = operands
+		.iter()
+		.map(|operand| match operand.value {
+			Operand::Register(None) => "null".to_owned(),
+			Operand::Register(Some(id)) => match register_size(operand.name) {
+				Some(Operand::Size(size)) => format!("{}{}", id, size_suffix(size)),
+				_ => id.to_string(),
+			},
+			Operand::Immediate(value) if operand.name.ends_with("mreg") => {
+				format_machine_register(value)
+			},
+			Operand::Immediate(value) => format_immediate(value),
+			Operand::Relative(_) => name_target(target.unwrap()),
+			Operand::Boolean(value) => value.to_string(),
+			Operand::Size(_) | Operand::Condition(_) => unreachable!(),
+		})
+		.collect();
+
+	if !formatted.is_empty() {
+		result.push(' ');
+		result.push_str(&formatted.join(", "));
+	}
+
+	result
+}
+
+/// Disassembles a single instruction, naming relative targets with `symbols` where possible.
+pub fn disassemble(encoded: u32, address: u64, symbols: &SymbolTable) -> Option<String> {
+	decode(encoded).map(|instruction| {
+		format_instruction(
+			&instruction,
+			address,
+			&|target| match symbols.lookup(target.into()) {
+				Some((name, 0)) => name.to_owned(),
+				_ => format!("{:#x}", target),
+			},
+		)
+	})
+}
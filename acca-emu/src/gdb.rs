@@ -0,0 +1,870 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	collections::HashMap,
+	io::{self, ErrorKind, Read, Write},
+	net::{TcpListener, TcpStream},
+	os::unix::net::{UnixListener, UnixStream},
+};
+
+use crate::{
+	mmu::{Access, PAGE_SIZE},
+	util::{MachineRegisterID, RegisterID, VMAddress},
+	vm::{Exception, StepResult, VM},
+	watch::{WatchKind, Watchpoint},
+};
+
+const TARGET_XML: &str = include_str!("gdb_target.xml");
+
+/// The encoding of the `dbg` instruction, which is what we use for software breakpoints.
+const BREAKPOINT_INSTRUCTION: u32 = 0x0800_0000;
+
+/// The packet size we advertise to GDB in `qSupported`.
+const PACKET_SIZE: usize = 0x1000;
+
+/// The most bytes an `m` packet can read, since they're hex-encoded in the reply.
+const MAX_READ_LENGTH: u64 = PACKET_SIZE as u64 / 2;
+
+/// The number of instructions to execute between checks for an interrupt request from GDB.
+const INTERRUPT_POLL_INTERVAL: u64 = 0x1000;
+
+/// The machine registers exposed to GDB, in the order they appear in the target description
+/// (right after `r0`-`r15` and `ip`).
+const MACHINE_REGISTERS: [MachineRegisterID; 8] = [
+	MachineRegisterID::flags,
+	MachineRegisterID::elr,
+	MachineRegisterID::esp,
+	MachineRegisterID::eflags,
+	MachineRegisterID::einfo,
+	MachineRegisterID::eaddr,
+	MachineRegisterID::evtable,
+	MachineRegisterID::ectable,
+];
+
+const IP_REGISTER_NUMBER: usize = 16;
+const REGISTER_COUNT: usize = IP_REGISTER_NUMBER + 1 + MACHINE_REGISTERS.len();
+
+// GDB's own signal numbers (which don't necessarily match the host's)
+const GDB_SIGNAL_INT: u8 = 2;
+const GDB_SIGNAL_ILL: u8 = 4;
+const GDB_SIGNAL_TRAP: u8 = 5;
+const GDB_SIGNAL_ABRT: u8 = 6;
+const GDB_SIGNAL_SEGV: u8 = 11;
+const GDB_SIGNAL_IO: u8 = 23;
+const GDB_SIGNAL_USR1: u8 = 30;
+
+pub trait Connection: Read + Write {
+	fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+	fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+		TcpStream::set_nonblocking(self, nonblocking)
+	}
+}
+
+impl Connection for UnixStream {
+	fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+		UnixStream::set_nonblocking(self, nonblocking)
+	}
+}
+
+/// Waits for a single GDB connection on `address`.
+///
+/// If `address` is a port number, this listens on that TCP port on the loopback interface;
+/// otherwise, it's treated as the path of a Unix socket to create.
+pub fn accept(address: &str) -> io::Result<Box<dyn Connection>> {
+	match address.parse::<u16>() {
+		Ok(port) => {
+			let (stream, _) = TcpListener::bind(("127.0.0.1", port))?.accept()?;
+			stream.set_nodelay(true)?;
+			Ok(Box::new(stream))
+		},
+		Err(_) => {
+			let (stream, _) = UnixListener::bind(address)?.accept()?;
+			Ok(Box::new(stream))
+		},
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+	/// GDB detached (or disconnected); the VM can keep running on its own.
+	Detached,
+	/// GDB asked us to kill the VM.
+	Killed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+	Step,
+	Breakpoint,
+	/// A watchpoint was hit by an access to the given address.
+	Watchpoint(WatchKind, u64),
+	Interrupted,
+	Exception(Exception),
+	Exited(u64),
+}
+
+/// A GDB remote serial protocol stub controlling a VM.
+///
+/// Exceptions raised by the guest are reported to GDB as signals before they're taken;
+/// they're delivered to the guest once GDB resumes execution. Breakpoints inserted by
+/// GDB are the only exception to this: they're consumed by the stub.
+pub struct GdbStub<'a> {
+	vm: &'a mut VM,
+	conn: Box<dyn Connection>,
+	/// Maps (virtual) breakpoint addresses to where they were planted.
+	breakpoints: HashMap<u64, Breakpoint>,
+	pending_exception: Option<Exception>,
+	last_stop: StopReason,
+}
+
+/// A software breakpoint planted in guest memory.
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+	/// The physical address the breakpoint was translated to when it was inserted.
+	physical: u64,
+	/// The instruction bytes that the breakpoint replaced.
+	original: [u8; 4],
+}
+
+fn exception_name(exception: &Exception) -> &'static str {
+	match exception {
+		Exception::Unknown => "unknown",
+		Exception::InvalidInstruction => "invalid_instruction",
+		Exception::Debug => "debug",
+		Exception::User(_) => "user",
+		Exception::InvalidOperation => "invalid_operation",
+		Exception::InstructionLoadError { .. } => "instruction_load_error",
+		Exception::DataLoadError { .. } => "data_load_error",
+		Exception::Interrupt(_) => "interrupt",
+	}
+}
+
+fn exception_signal(exception: &Exception) -> u8 {
+	match exception {
+		Exception::Unknown => GDB_SIGNAL_ABRT,
+		Exception::InvalidInstruction | Exception::InvalidOperation => GDB_SIGNAL_ILL,
+		Exception::Debug => GDB_SIGNAL_TRAP,
+		Exception::User(_) => GDB_SIGNAL_USR1,
+		Exception::InstructionLoadError { .. } | Exception::DataLoadError { .. } => GDB_SIGNAL_SEGV,
+		Exception::Interrupt(_) => GDB_SIGNAL_IO,
+	}
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+	if !hex.len().is_multiple_of(2) {
+		return None;
+	}
+
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+		.collect()
+}
+
+fn decode_register(hex: &str) -> Option<u64> {
+	let bytes: [u8; 8] = decode_hex(hex)?.try_into().ok()?;
+	Some(u64::from_le_bytes(bytes))
+}
+
+fn parse_address_and_length(args: &str) -> Option<(u64, u64)> {
+	let (addr, len) = args.split_once(',')?;
+	Some((
+		u64::from_str_radix(addr, 16).ok()?,
+		u64::from_str_radix(len, 16).ok()?,
+	))
+}
+
+impl<'a> GdbStub<'a> {
+	pub fn new(vm: &'a mut VM, conn: Box<dyn Connection>) -> Self {
+		Self {
+			vm,
+			conn,
+			breakpoints: HashMap::new(),
+			pending_exception: None,
+			last_stop: StopReason::Step,
+		}
+	}
+
+	/// Serves GDB requests until GDB detaches or kills the VM.
+	///
+	/// Upon return, all breakpoints have been removed and exception trapping has been disabled again.
+	pub fn serve(mut self) -> io::Result<SessionEnd> {
+		self.vm.set_trap_exceptions(true);
+		let result = self.serve_packets();
+		self.vm.set_trap_exceptions(false);
+
+		for breakpoint in std::mem::take(&mut self.breakpoints).into_values() {
+			self.write_raw(breakpoint.physical, &breakpoint.original);
+		}
+
+		if let Some(exception) = self.pending_exception.take() {
+			self.vm.take_exception(exception);
+		}
+
+		result
+	}
+
+	fn serve_packets(&mut self) -> io::Result<SessionEnd> {
+		loop {
+			let packet = match self.read_packet() {
+				Ok(packet) => packet,
+				Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(SessionEnd::Detached),
+				Err(e) => return Err(e),
+			};
+
+			// packets are never empty, but the first character isn't necessarily ASCII
+			let first = packet.chars().next().map_or(0, char::len_utf8);
+			let (command, args) = packet.split_at(first);
+
+			let response = match command {
+				"?" => self.stop_reply(),
+				"g" => self.read_registers(),
+				"G" => self.write_registers(args),
+				"p" => self.read_register(args),
+				"P" => self.write_register(args),
+				"m" => self.read_memory(args),
+				"M" => self.write_memory(args),
+				"c" | "C" | "s" | "S" => {
+					// `c`/`s` may carry a resume address; `C`/`S` carry a signal (which we ignore) before it
+					let resume_addr = match command {
+						"c" | "s" => Some(args),
+						_ => args.split_once(';').map(|(_, addr)| addr),
+					};
+					if let Some(addr) =
+						resume_addr.and_then(|addr| u64::from_str_radix(addr, 16).ok())
+					{
+						self.vm.set_instruction_pointer(addr.into());
+					}
+
+					self.last_stop = self.resume(command == "s" || command == "S")?;
+					self.stop_reply()
+				},
+				"Z" => self.insert_breakpoint(args),
+				"z" => self.remove_breakpoint(args),
+				"H" | "T" => "OK".to_string(),
+				"D" => {
+					self.send_packet("OK")?;
+					return Ok(SessionEnd::Detached);
+				},
+				"k" => return Ok(SessionEnd::Killed),
+				"q" => self.query(args),
+				"v" if args == "Kill" || args.starts_with("Kill;") => {
+					self.send_packet("OK")?;
+					return Ok(SessionEnd::Killed);
+				},
+				_ => String::new(),
+			};
+
+			self.send_packet(&response)?;
+		}
+	}
+
+	fn read_byte(&mut self) -> io::Result<u8> {
+		let mut byte = [0u8];
+		self.conn.read_exact(&mut byte)?;
+		Ok(byte[0])
+	}
+
+	fn read_packet(&mut self) -> io::Result<String> {
+		loop {
+			// skip everything up to the start of the packet (e.g. acks and stray interrupt requests)
+			while self.read_byte()? != b'$' {}
+
+			let mut data = Vec::new();
+			let mut checksum = 0u8;
+
+			loop {
+				let byte = self.read_byte()?;
+				if byte == b'#' {
+					break;
+				}
+				checksum = checksum.wrapping_add(byte);
+
+				// `}` escapes the next byte (which the checksum covers as-is)
+				if byte == b'}' {
+					let escaped = self.read_byte()?;
+					checksum = checksum.wrapping_add(escaped);
+					data.push(escaped ^ 0x20);
+				} else {
+					data.push(byte);
+				}
+			}
+
+			let expected = [self.read_byte()?, self.read_byte()?];
+			let expected = std::str::from_utf8(&expected)
+				.ok()
+				.and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+			if expected != Some(checksum) || data.is_empty() {
+				self.conn.write_all(b"-")?;
+				self.conn.flush()?;
+				continue;
+			}
+
+			self.conn.write_all(b"+")?;
+			self.conn.flush()?;
+
+			return String::from_utf8(data).map_err(|e| io::Error::new(ErrorKind::InvalidData, e));
+		}
+	}
+
+	fn send_packet(&mut self, data: &str) -> io::Result<()> {
+		let checksum = data.bytes().fold(0u8, |acc, byte| acc.wrapping_add(byte));
+		let packet = format!("${}#{:02x}", data, checksum);
+
+		loop {
+			self.conn.write_all(packet.as_bytes())?;
+			self.conn.flush()?;
+
+			match self.read_byte()? {
+				b'+' => return Ok(()),
+				b'-' => continue,
+				// GDB sometimes sends other things before acking; just assume it received the packet.
+				_ => return Ok(()),
+			}
+		}
+	}
+
+	/// Checks whether GDB has asked us to interrupt the guest (by sending a `^C`) without blocking.
+	fn poll_interrupt(&mut self) -> io::Result<bool> {
+		let mut byte = [0u8];
+		self.conn.set_nonblocking(true)?;
+		let result = self.conn.read(&mut byte);
+		self.conn.set_nonblocking(false)?;
+
+		match result {
+			Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+			Ok(_) => Ok(byte[0] == 0x03),
+			Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn stop_reply(&self) -> String {
+		match &self.last_stop {
+			StopReason::Step => format!("S{:02x}", GDB_SIGNAL_TRAP),
+			StopReason::Breakpoint => format!("T{:02x}swbreak:;", GDB_SIGNAL_TRAP),
+			StopReason::Watchpoint(kind, address) => format!(
+				"T{:02x}{}:{:x};",
+				GDB_SIGNAL_TRAP,
+				match kind {
+					WatchKind::Write => "watch",
+					WatchKind::Read => "rwatch",
+					WatchKind::Access => "awatch",
+				},
+				address
+			),
+			StopReason::Interrupted => format!("S{:02x}", GDB_SIGNAL_INT),
+			StopReason::Exception(exception) => format!(
+				"T{:02x}exception:{};",
+				exception_signal(exception),
+				exception_name(exception)
+			),
+			// exit statuses are truncated to 8 bits, just like on the host
+			StopReason::Exited(code) => format!("W{:02x}", code & 0xff),
+		}
+	}
+
+	fn register(&self, number: usize) -> Option<u64> {
+		match number {
+			0..=15 => Some(self.vm.register_file()[number].get()),
+			IP_REGISTER_NUMBER => Some(self.vm.instruction_pointer().into()),
+			_ => MACHINE_REGISTERS
+				.get(number - IP_REGISTER_NUMBER - 1)
+				.map(|&id| self.vm.machine_register(id)),
+		}
+	}
+
+	fn set_register(&mut self, number: usize, value: u64) -> bool {
+		// writing back the same value to a machine register can still have side effects
+		// (e.g. `ectable` reloads the table from memory), so don't do that.
+		if self.register(number) == Some(value) {
+			return true;
+		}
+
+		match number {
+			0..=15 => self.vm.register_file_mut()[RegisterID::from(number as u64)] = value.into(),
+			IP_REGISTER_NUMBER => self.vm.set_instruction_pointer(value.into()),
+			_ => {
+				return match MACHINE_REGISTERS.get(number - IP_REGISTER_NUMBER - 1) {
+					Some(id) => self.vm.set_machine_register(*id, value).is_ok(),
+					None => false,
+				}
+			},
+		}
+
+		true
+	}
+
+	fn read_registers(&self) -> String {
+		(0..REGISTER_COUNT)
+			.map(|number| encode_hex(&self.register(number).unwrap_or(0).to_le_bytes()))
+			.collect()
+	}
+
+	fn write_registers(&mut self, args: &str) -> String {
+		if args.len() != REGISTER_COUNT * 16 {
+			return "E01".to_string();
+		}
+
+		let mut ok = true;
+		for number in 0..REGISTER_COUNT {
+			match decode_register(&args[number * 16..(number + 1) * 16]) {
+				Some(value) => ok &= self.set_register(number, value),
+				None => return "E01".to_string(),
+			}
+		}
+
+		if ok {
+			"OK".to_string()
+		} else {
+			"E02".to_string()
+		}
+	}
+
+	fn read_register(&self, args: &str) -> String {
+		usize::from_str_radix(args, 16)
+			.ok()
+			.and_then(|number| self.register(number))
+			.map(|value| encode_hex(&value.to_le_bytes()))
+			.unwrap_or_else(|| "E01".to_string())
+	}
+
+	fn write_register(&mut self, args: &str) -> String {
+		let result = args.split_once('=').and_then(|(number, value)| {
+			Some((
+				usize::from_str_radix(number, 16).ok()?,
+				decode_register(value)?,
+			))
+		});
+
+		match result {
+			Some((number, value)) if self.set_register(number, value) => "OK".to_string(),
+			_ => "E01".to_string(),
+		}
+	}
+
+	/// Writes `bytes` to the physical address `addr`. This also drops any cached code in the pages it touches.
+	fn write_raw(&mut self, addr: u64, bytes: &[u8]) -> bool {
+		self.vm.load_bytes(bytes, addr.into()).is_some()
+	}
+
+	/// Translates the `len` bytes at the virtual address `addr` for the given kind of access, splitting them at page
+	/// boundaries. Returns the physical address and length of each piece, or `None` if any page can't be accessed.
+	fn translate_range(&self, addr: u64, len: u64, access: Access) -> Option<Vec<(u64, usize)>> {
+		let mut pieces = Vec::new();
+		let mut address = addr;
+		let mut remaining = len;
+
+		while remaining > 0 {
+			let length = remaining.min(PAGE_SIZE - (address % PAGE_SIZE));
+			let physical = self.vm.translate_checked(address.into(), access)?;
+			pieces.push((u64::from(physical), length as usize));
+			address = address.checked_add(length)?;
+			remaining -= length;
+		}
+
+		Some(pieces)
+	}
+
+	/// Returns the breakpoints that overlap the given memory range.
+	fn breakpoints_in(&self, addr: u64, len: u64) -> Vec<u64> {
+		self.breakpoints
+			.keys()
+			.copied()
+			.filter(|&bp| bp < addr.wrapping_add(len) && addr < bp + 4)
+			.collect()
+	}
+
+	fn read_memory(&self, args: &str) -> String {
+		let (addr, len) = match parse_address_and_length(args) {
+			Some(x) => x,
+			None => return "E01".to_string(),
+		};
+
+		if len > MAX_READ_LENGTH {
+			return "E01".to_string();
+		}
+
+		let pieces = match self.translate_range(addr, len, Access::Read) {
+			Some(pieces) => pieces,
+			None => return "E14".to_string(),
+		};
+
+		let mut bytes = vec![0u8; len as usize];
+		let mut offset = 0;
+		for (physical, length) in pieces {
+			let chunk = &mut bytes[offset..offset + length];
+			if self.vm.read_ram(physical.into(), chunk).is_none() {
+				return "E01".to_string();
+			}
+			offset += length;
+		}
+
+		// hide our breakpoints from GDB
+		for bp in self.breakpoints_in(addr, len) {
+			for (i, byte) in self.breakpoints[&bp].original.iter().enumerate() {
+				let offset = (bp + i as u64).wrapping_sub(addr);
+				if offset < len {
+					bytes[offset as usize] = *byte;
+				}
+			}
+		}
+
+		encode_hex(&bytes)
+	}
+
+	fn write_memory(&mut self, args: &str) -> String {
+		let (addr, len, bytes) = match args.split_once(':').and_then(|(range, data)| {
+			let (addr, len) = parse_address_and_length(range)?;
+			Some((addr, len, decode_hex(data)?))
+		}) {
+			Some(x) => x,
+			None => return "E01".to_string(),
+		};
+
+		if bytes.len() as u64 != len {
+			return "E01".to_string();
+		}
+
+		let pieces = match self.translate_range(addr, len, Access::Write) {
+			Some(pieces) => pieces,
+			None => return "E14".to_string(),
+		};
+
+		// lift any breakpoints in the way, write the new data, and then put them back
+		// (with the new data as their original instructions)
+		let affected = self.breakpoints_in(addr, len);
+		for bp in &affected {
+			let breakpoint = self.breakpoints[bp];
+			self.write_raw(breakpoint.physical, &breakpoint.original);
+		}
+
+		let mut ok = true;
+		let mut offset = 0;
+		for (physical, length) in pieces {
+			ok &= self.write_raw(physical, &bytes[offset..offset + length]);
+			offset += length;
+		}
+
+		for bp in affected {
+			let breakpoint = self.breakpoints.get_mut(&bp).unwrap();
+			let mut original = [0u8; 4];
+			if self
+				.vm
+				.read_ram(breakpoint.physical.into(), &mut original)
+				.is_some()
+			{
+				breakpoint.original = original;
+			}
+			let physical = breakpoint.physical;
+			self.write_raw(physical, &BREAKPOINT_INSTRUCTION.to_le_bytes());
+		}
+
+		if ok {
+			"OK".to_string()
+		} else {
+			"E01".to_string()
+		}
+	}
+
+	/// Parses the arguments of a `Z`/`z` packet into the breakpoint type, address, and kind (or length, for
+	/// watchpoints).
+	fn parse_breakpoint(args: &str) -> Option<(&str, u64, u64)> {
+		let mut parts = args.split(';').next()?.split(',');
+		let ty = parts.next()?;
+		let addr = u64::from_str_radix(parts.next()?, 16).ok()?;
+		let kind = u64::from_str_radix(parts.next()?, 16).ok()?;
+		Some((ty, addr, kind))
+	}
+
+	/// Converts the arguments of a `Z2`-`Z4`/`z2`-`z4` packet into a watchpoint.
+	fn parse_watchpoint(ty: &str, addr: u64, len: u64) -> Option<Watchpoint> {
+		let kind = match ty {
+			"2" => WatchKind::Write,
+			"3" => WatchKind::Read,
+			"4" => WatchKind::Access,
+			_ => return None,
+		};
+		Some(Watchpoint::new(addr..addr.checked_add(len)?, kind))
+	}
+
+	fn insert_breakpoint(&mut self, args: &str) -> String {
+		let addr = match Self::parse_breakpoint(args) {
+			Some(("0", addr, _)) => addr,
+			Some((ty, addr, len)) => {
+				return match Self::parse_watchpoint(ty, addr, len) {
+					Some(watchpoint) => {
+						self.vm.add_watchpoint(watchpoint);
+						"OK".to_string()
+					},
+					// hardware breakpoints aren't supported
+					None => String::new(),
+				};
+			},
+			None => return "E01".to_string(),
+		};
+
+		if self.breakpoints.contains_key(&addr) {
+			return "OK".to_string();
+		}
+
+		let start = VMAddress::from(addr);
+		if !start.is_valid_instruction_pointer() {
+			return "E01".to_string();
+		}
+
+		let physical = match self.vm.translate_checked(start, Access::Execute) {
+			Some(physical) => physical,
+			None => return "E14".to_string(),
+		};
+
+		let mut original = [0u8; 4];
+		if self.vm.read_ram(physical, &mut original).is_none() {
+			return "E01".to_string();
+		}
+
+		let physical = u64::from(physical);
+		self.write_raw(physical, &BREAKPOINT_INSTRUCTION.to_le_bytes());
+		self.breakpoints
+			.insert(addr, Breakpoint { physical, original });
+
+		"OK".to_string()
+	}
+
+	fn remove_breakpoint(&mut self, args: &str) -> String {
+		let addr = match Self::parse_breakpoint(args) {
+			Some(("0", addr, _)) => addr,
+			Some((ty, addr, len)) => {
+				return match Self::parse_watchpoint(ty, addr, len) {
+					Some(watchpoint) => {
+						self.vm.remove_watchpoint(&watchpoint);
+						"OK".to_string()
+					},
+					None => String::new(),
+				};
+			},
+			None => return "E01".to_string(),
+		};
+
+		if let Some(breakpoint) = self.breakpoints.remove(&addr) {
+			self.write_raw(breakpoint.physical, &breakpoint.original);
+		}
+
+		"OK".to_string()
+	}
+
+	fn query(&mut self, args: &str) -> String {
+		if args.starts_with("Supported") {
+			return format!("PacketSize={PACKET_SIZE:x};qXfer:features:read+;swbreak+");
+		}
+
+		if let Some(rest) = args.strip_prefix("Xfer:features:read:target.xml:") {
+			let (offset, len) = match parse_address_and_length(rest) {
+				Some((offset, len)) => (offset as usize, len as usize),
+				None => return "E01".to_string(),
+			};
+
+			if offset >= TARGET_XML.len() {
+				return "l".to_string();
+			}
+
+			let end = (offset + len).min(TARGET_XML.len());
+			let prefix = if end < TARGET_XML.len() { 'm' } else { 'l' };
+			return format!("{}{}", prefix, &TARGET_XML[offset..end]);
+		}
+
+		match args {
+			"Attached" => "1".to_string(),
+			"C" => "QC1".to_string(),
+			"fThreadInfo" => "m1".to_string(),
+			"sThreadInfo" => "l".to_string(),
+			_ => String::new(),
+		}
+	}
+
+	/// Executes a single instruction, stepping over a breakpoint at the current instruction pointer if there is one.
+	fn step_vm(&mut self) -> StepResult {
+		let ip = u64::from(self.vm.instruction_pointer());
+
+		match self.breakpoints.get(&ip).copied() {
+			Some(breakpoint) => {
+				self.write_raw(breakpoint.physical, &breakpoint.original);
+				let result = self.vm.step();
+				self.write_raw(breakpoint.physical, &BREAKPOINT_INSTRUCTION.to_le_bytes());
+				result
+			},
+			None => self.vm.step(),
+		}
+	}
+
+	fn resume(&mut self, single_step: bool) -> io::Result<StopReason> {
+		if let Some(exception) = self.pending_exception.take() {
+			self.vm.take_exception(exception);
+			if single_step {
+				return Ok(StopReason::Step);
+			}
+		}
+
+		let mut first = true;
+		let mut executed = 0u64;
+
+		loop {
+			let result = if first {
+				first = false;
+				self.step_vm()
+			} else {
+				self.vm.step()
+			};
+
+			match result {
+				StepResult::Executed | StepResult::Exception(_) => {},
+				StepResult::Trapped(Exception::Debug)
+					if self
+						.breakpoints
+						.contains_key(&u64::from(self.vm.instruction_pointer())) =>
+				{
+					return Ok(StopReason::Breakpoint)
+				},
+				StepResult::Trapped(exception) => {
+					self.pending_exception = Some(exception);
+					return Ok(StopReason::Exception(exception));
+				},
+				StepResult::Exited(code) => return Ok(StopReason::Exited(code)),
+			}
+
+			// stores have already completed by the time they're reported, so GDB sees the new value
+			if let Some(hit) = self.vm.take_watch_hits().first() {
+				return Ok(StopReason::Watchpoint(hit.watchpoint.kind, hit.address));
+			}
+
+			if single_step {
+				return Ok(StopReason::Step);
+			}
+
+			executed += 1;
+			if executed.is_multiple_of(INTERRUPT_POLL_INTERVAL) && self.poll_interrupt()? {
+				return Ok(StopReason::Interrupted);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{cell::RefCell, io::Cursor, rc::Rc};
+
+	use super::*;
+
+	/// A connection that replays canned input and records everything written to it.
+	struct FakeConnection {
+		input: Cursor<Vec<u8>>,
+		output: Rc<RefCell<Vec<u8>>>,
+	}
+
+	impl Read for FakeConnection {
+		fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+			self.input.read(buffer)
+		}
+	}
+
+	impl Write for FakeConnection {
+		fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+			self.output.borrow_mut().extend_from_slice(buffer);
+			Ok(buffer.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl Connection for FakeConnection {
+		fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	/// Runs `body` with a stub reading `input`, returning its result and everything the stub sent.
+	fn with_stub<T>(input: &[u8], body: impl FnOnce(&mut GdbStub) -> T) -> (T, String) {
+		let mut vm = VM::new(PAGE_SIZE as usize).unwrap();
+		let output = Rc::new(RefCell::new(Vec::new()));
+		let mut stub = GdbStub::new(
+			&mut vm,
+			Box::new(FakeConnection {
+				input: Cursor::new(input.to_vec()),
+				output: output.clone(),
+			}),
+		);
+		let result = body(&mut stub);
+		let output = String::from_utf8(output.take()).unwrap();
+		(result, output)
+	}
+
+	fn read_packet(input: &[u8]) -> (io::Result<String>, String) {
+		with_stub(input, |stub| stub.read_packet())
+	}
+
+	#[test]
+	fn reads_packets_with_valid_checksums() {
+		let (packet, sent) = read_packet(b"$?#3f");
+		assert_eq!(packet.unwrap(), "?");
+		assert_eq!(sent, "+");
+
+		// acks and interrupt requests before the packet are skipped
+		let (packet, sent) = read_packet(b"+\x03$m400,4#61");
+		assert_eq!(packet.unwrap(), "m400,4");
+		assert_eq!(sent, "+");
+	}
+
+	#[test]
+	fn rejects_corrupted_packets() {
+		// a bad checksum and an empty packet are both retransmitted
+		let (packet, sent) = read_packet(b"$g#00$#00$g#67");
+		assert_eq!(packet.unwrap(), "g");
+		assert_eq!(sent, "--+");
+
+		let (packet, sent) = read_packet(b"$g#zz");
+		assert_eq!(packet.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+		assert_eq!(sent, "-");
+	}
+
+	#[test]
+	fn unescapes_packets() {
+		// `}]` is an escaped `}` and `}\x03` an escaped `#`; the checksum covers the escaped form
+		let (packet, sent) = read_packet(b"$X0,2:}]}\x03#7a");
+		assert_eq!(packet.unwrap(), "X0,2:}#");
+		assert_eq!(sent, "+");
+	}
+
+	#[test]
+	fn resends_packets_until_acknowledged() {
+		let (result, sent) = with_stub(b"-+", |stub| stub.send_packet("OK"));
+		result.unwrap();
+		assert_eq!(sent, "$OK#9a$OK#9a");
+	}
+
+	#[test]
+	fn parses_memory_arguments() {
+		assert_eq!(parse_address_and_length("400,10"), Some((0x400, 0x10)));
+		assert_eq!(parse_address_and_length("400"), None);
+		assert_eq!(parse_address_and_length("40g,10"), None);
+		assert_eq!(decode_hex("0aff"), Some(vec![0x0a, 0xff]));
+		assert_eq!(decode_hex("0af"), None);
+		assert_eq!(decode_register("0004000000000000"), Some(0x400));
+		assert_eq!(decode_register("00040000"), None);
+	}
+}
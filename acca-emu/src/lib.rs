@@ -0,0 +1,29 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+pub mod block;
+pub mod block_cache;
+pub mod bus;
+pub mod console;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod loader;
+pub mod machine;
+pub mod memory;
+pub mod mmu;
+pub mod profile;
+pub mod semihosting;
+pub mod snapshot;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+pub mod uart;
+pub mod util;
+pub mod vm;
+pub mod watch;
@@ -0,0 +1,676 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	fmt,
+	fs::File,
+	io::{self, Read, Seek},
+	ops::Range,
+	path::{Path, PathBuf},
+};
+
+use goblin::elf::{header, program_header::PT_LOAD, Elf};
+
+use crate::{symbols::SymbolTable, vm::VM};
+
+pub const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+#[derive(Debug)]
+pub enum LoadError {
+	Io(io::Error),
+	/// The file isn't a valid ELF image, or it's one that can't be loaded.
+	InvalidElf(String),
+	/// Part of the image lies outside of RAM.
+	OutOfBounds {
+		address: u64,
+		size: u64,
+		memory_size: u64,
+	},
+	/// The entry point isn't a valid instruction address.
+	InvalidEntry(u64),
+	/// A record in an Intel HEX or S-record file is malformed.
+	InvalidRecord {
+		line: usize,
+		reason: String,
+	},
+	/// Part of the image would overwrite a previously loaded image.
+	Overlap {
+		other: PathBuf,
+		start: u64,
+		end: u64,
+	},
+	/// A load address was given for an image that specifies its own.
+	AddressNotAllowed,
+}
+
+impl fmt::Display for LoadError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			LoadError::Io(e) => write!(f, "{}", e),
+			LoadError::InvalidElf(reason) => write!(f, "invalid ELF image: {}", reason),
+			LoadError::OutOfBounds {
+				address,
+				size,
+				memory_size,
+			} => write!(
+				f,
+				"{:#x} bytes at {:#x} don't fit in {:#x} bytes of memory",
+				size, address, memory_size
+			),
+			LoadError::InvalidEntry(address) => {
+				write!(
+					f,
+					"entry point {:#x} is not a valid instruction address",
+					address
+				)
+			},
+			LoadError::InvalidRecord { line, reason } => write!(f, "line {}: {}", line, reason),
+			LoadError::Overlap { other, start, end } => write!(
+				f,
+				"overlaps \"{}\" at {:#x}-{:#x}",
+				other.display(),
+				start,
+				end
+			),
+			LoadError::AddressNotAllowed => {
+				write!(f, "the image specifies its own load addresses")
+			},
+		}
+	}
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+	fn from(value: io::Error) -> Self {
+		Self::Io(value)
+	}
+}
+
+/// A contiguous part of an image and where it's loaded.
+#[derive(Debug, Clone)]
+pub struct Segment {
+	pub address: u64,
+	/// The segment's contents from the file.
+	pub data: Vec<u8>,
+	/// The size of the segment in memory. Anything past the end of `data` (e.g. `.bss`) is zero-filled.
+	pub memory_size: u64,
+}
+
+/// An ELF executable, ready to be loaded into a VM with [`VM::load_elf`](crate::vm::VM::load_elf).
+#[derive(Debug, Clone)]
+pub struct ElfImage {
+	pub entry: u64,
+	pub segments: Vec<Segment>,
+	pub symbols: SymbolTable,
+}
+
+impl ElfImage {
+	pub fn parse(bytes: &[u8]) -> Result<Self, LoadError> {
+		let elf = Elf::parse(bytes).map_err(|e| LoadError::InvalidElf(e.to_string()))?;
+
+		if !elf.is_64 {
+			return Err(LoadError::InvalidElf("not a 64-bit image".to_string()));
+		}
+		if !elf.little_endian {
+			return Err(LoadError::InvalidElf(
+				"not a little-endian image".to_string(),
+			));
+		}
+		if elf.header.e_type != header::ET_EXEC {
+			return Err(LoadError::InvalidElf("not an executable".to_string()));
+		}
+
+		let mut segments = Vec::new();
+		for header in &elf.program_headers {
+			if header.p_type != PT_LOAD || header.p_memsz == 0 {
+				continue;
+			}
+
+			if header.p_filesz > header.p_memsz {
+				return Err(LoadError::InvalidElf(format!(
+					"segment at {:#x} is larger in the file than in memory",
+					header.p_vaddr
+				)));
+			}
+
+			let data = header
+				.p_offset
+				.checked_add(header.p_filesz)
+				.and_then(|end| bytes.get(header.p_offset as usize..end as usize))
+				.ok_or_else(|| {
+					LoadError::InvalidElf(format!(
+						"segment at {:#x} extends past the end of the file",
+						header.p_vaddr
+					))
+				})?;
+
+			segments.push(Segment {
+				address: header.p_vaddr,
+				data: data.to_vec(),
+				memory_size: header.p_memsz,
+			});
+		}
+
+		Ok(Self {
+			entry: elf.entry,
+			segments,
+			symbols: SymbolTable::from_elf(&elf),
+		})
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+	/// A flat binary, loaded as-is.
+	Raw,
+	Elf,
+	IntelHex,
+	SRecord,
+}
+
+impl ImageFormat {
+	/// Determines the format of the image at `path` from its extension, or from its first few bytes for ELF.
+	pub fn detect(path: &Path, magic: &[u8]) -> Self {
+		if magic.starts_with(ELF_MAGIC) {
+			return Self::Elf;
+		}
+
+		let extension = path
+			.extension()
+			.and_then(|extension| extension.to_str())
+			.map(|extension| extension.to_ascii_lowercase());
+		match extension.as_deref() {
+			Some("hex" | "ihex" | "ihx") => Self::IntelHex,
+			Some("srec" | "s19" | "s28" | "s37" | "mot") => Self::SRecord,
+			_ => Self::Raw,
+		}
+	}
+}
+
+/// Collects data records into segments, merging records that directly follow each other.
+#[derive(Debug, Default)]
+struct SegmentBuilder {
+	segments: Vec<Segment>,
+}
+
+impl SegmentBuilder {
+	fn add(&mut self, address: u64, data: &[u8]) {
+		if data.is_empty() {
+			return;
+		}
+
+		match self.segments.last_mut() {
+			Some(last) if last.address + last.memory_size == address => {
+				last.data.extend_from_slice(data);
+				last.memory_size += data.len() as u64;
+			},
+			_ => self.segments.push(Segment {
+				address,
+				data: data.to_vec(),
+				memory_size: data.len() as u64,
+			}),
+		}
+	}
+}
+
+/// Decodes the hexadecimal digits of a record, which are followed by a checksum byte.
+fn record_bytes(digits: &str, line: usize) -> Result<Vec<u8>, LoadError> {
+	let invalid = |reason: &str| LoadError::InvalidRecord {
+		line,
+		reason: reason.to_string(),
+	};
+
+	if !digits.len().is_multiple_of(2) {
+		return Err(invalid("odd number of hexadecimal digits"));
+	}
+
+	(0..digits.len())
+		.step_by(2)
+		.map(|i| {
+			digits
+				.get(i..i + 2)
+				.and_then(|byte| u8::from_str_radix(byte, 16).ok())
+				.ok_or_else(|| invalid("invalid hexadecimal digits"))
+		})
+		.collect()
+}
+
+fn be_value(bytes: &[u8]) -> u64 {
+	bytes
+		.iter()
+		.fold(0, |value, &byte| (value << 8) | u64::from(byte))
+}
+
+/// Parses an Intel HEX file, returning its segments and its start address (if it has one).
+pub fn parse_intel_hex(text: &str) -> Result<(Vec<Segment>, Option<u64>), LoadError> {
+	let mut builder = SegmentBuilder::default();
+	let mut base = 0u64;
+	let mut entry = None;
+
+	for (index, line) in text.lines().enumerate() {
+		let line_number = index + 1;
+		let invalid = |reason: &str| LoadError::InvalidRecord {
+			line: line_number,
+			reason: reason.to_string(),
+		};
+
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+
+		let bytes = match line.strip_prefix(':') {
+			Some(digits) => record_bytes(digits, line_number)?,
+			None => return Err(invalid("record doesn't start with ':'")),
+		};
+		if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+			return Err(invalid("record length doesn't match its byte count"));
+		}
+		if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+			return Err(invalid("checksum mismatch"));
+		}
+
+		let offset = be_value(&bytes[1..3]);
+		let data = &bytes[4..bytes.len() - 1];
+
+		match bytes[3] {
+			0x00 => builder.add(base + offset, data),
+			0x01 => break,
+			0x02 if data.len() == 2 => base = be_value(data) << 4,
+			0x03 if data.len() == 4 => {
+				entry = Some((be_value(&data[0..2]) << 4) + be_value(&data[2..4]))
+			},
+			0x04 if data.len() == 2 => base = be_value(data) << 16,
+			0x05 if data.len() == 4 => entry = Some(be_value(data)),
+			0x02..=0x05 => return Err(invalid("record has the wrong length for its type")),
+			other => return Err(invalid(&format!("unknown record type {:#04x}", other))),
+		}
+	}
+
+	Ok((builder.segments, entry))
+}
+
+/// Parses a Motorola S-record file, returning its segments and its start address (if it has one).
+pub fn parse_srecord(text: &str) -> Result<(Vec<Segment>, Option<u64>), LoadError> {
+	let mut builder = SegmentBuilder::default();
+	let mut entry = None;
+
+	for (index, line) in text.lines().enumerate() {
+		let line_number = index + 1;
+		let invalid = |reason: &str| LoadError::InvalidRecord {
+			line: line_number,
+			reason: reason.to_string(),
+		};
+
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+
+		let (kind, digits) = match line.strip_prefix('S').and_then(|rest| {
+			let kind = rest.chars().next()?.to_digit(10)?;
+			Some((kind, &rest[1..]))
+		}) {
+			Some(x) => x,
+			None => return Err(invalid("record doesn't start with 'S' and a type digit")),
+		};
+
+		let bytes = record_bytes(digits, line_number)?;
+		if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+			return Err(invalid("record length doesn't match its byte count"));
+		}
+		if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0xff {
+			return Err(invalid("checksum mismatch"));
+		}
+
+		let address_size = match kind {
+			0 | 1 | 5 | 9 => 2,
+			2 | 6 | 8 => 3,
+			3 | 7 => 4,
+			_ => return Err(invalid(&format!("unknown record type S{}", kind))),
+		};
+		if bytes.len() < address_size + 2 {
+			return Err(invalid("record is too short for its address"));
+		}
+
+		let address = be_value(&bytes[1..1 + address_size]);
+		let data = &bytes[1 + address_size..bytes.len() - 1];
+
+		match kind {
+			1..=3 => builder.add(address, data),
+			7..=9 => entry = Some(address),
+			// headers and record counts
+			_ => {},
+		}
+	}
+
+	Ok((builder.segments, entry))
+}
+
+/// Loads a set of images into a VM, making sure that they don't overlap each other.
+#[derive(Debug, Default)]
+pub struct ImageLoader {
+	loaded: Vec<(PathBuf, Range<u64>)>,
+	/// The entry point of the first image that specifies one.
+	pub entry: Option<u64>,
+	/// The symbols of all the images that have any.
+	pub symbols: SymbolTable,
+}
+
+impl ImageLoader {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	fn check_overlap(&self, start: u64, size: u64) -> Result<(), LoadError> {
+		let end = start.saturating_add(size);
+		match self
+			.loaded
+			.iter()
+			.find(|(_, range)| start < range.end && range.start < end)
+		{
+			Some((other, range)) => Err(LoadError::Overlap {
+				other: other.clone(),
+				start: start.max(range.start),
+				end: end.min(range.end),
+			}),
+			None => Ok(()),
+		}
+	}
+
+	fn load_segments(
+		&mut self,
+		vm: &mut VM,
+		path: &Path,
+		segments: &[Segment],
+	) -> Result<(), LoadError> {
+		for segment in segments {
+			self.check_overlap(segment.address, segment.memory_size)?;
+		}
+		vm.load_segments(segments)?;
+		for segment in segments {
+			self.loaded.push((
+				path.to_owned(),
+				segment.address..segment.address + segment.memory_size,
+			));
+		}
+		Ok(())
+	}
+
+	/// Loads the image at `path` into `vm`.
+	///
+	/// `address` is where a raw image is loaded (0 by default). Other formats specify their own load addresses.
+	pub fn load(
+		&mut self,
+		vm: &mut VM,
+		path: &Path,
+		address: Option<u64>,
+	) -> Result<(), LoadError> {
+		let mut file = File::open(path)?;
+
+		let mut magic = [0u8; 4];
+		let magic = match file.read_exact(&mut magic) {
+			Ok(_) => &magic[..],
+			Err(_) => &[],
+		};
+		let format = ImageFormat::detect(path, magic);
+		file.rewind()?;
+
+		let (segments, entry) = match format {
+			ImageFormat::Raw => {
+				let address = address.unwrap_or(0);
+				let size = file.metadata()?.len();
+				self.check_overlap(address, size)?;
+				vm.load_file(&mut file, address.into())?;
+				self.loaded.push((path.to_owned(), address..address + size));
+				return Ok(());
+			},
+			_ if address.is_some() => return Err(LoadError::AddressNotAllowed),
+			ImageFormat::Elf => {
+				let mut bytes = Vec::new();
+				file.read_to_end(&mut bytes)?;
+				let image = ElfImage::parse(&bytes)?;
+				self.symbols.extend(&image.symbols);
+				(image.segments, Some(image.entry))
+			},
+			ImageFormat::IntelHex | ImageFormat::SRecord => {
+				let mut text = String::new();
+				file.read_to_string(&mut text)?;
+				match format {
+					ImageFormat::IntelHex => parse_intel_hex(&text)?,
+					_ => parse_srecord(&text)?,
+				}
+			},
+		};
+
+		self.load_segments(vm, path, &segments)?;
+		self.entry = self.entry.or(entry);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const INTEL_HEX: &str = "\
+:020000040001F9
+:04001000DEADBEEFB4
+:02001400CAFE22
+:01010000AA54
+:0400000500010400F2
+:00000001FF
+";
+
+	const SRECORD: &str = "\
+S00600004844521B
+S2060100001122C5
+S20501000233C4
+S10420004497
+S5030002FA
+S804010000FA
+";
+
+	fn addresses_and_data(segments: &[Segment]) -> Vec<(u64, &[u8])> {
+		segments
+			.iter()
+			.map(|segment| (segment.address, &segment.data[..]))
+			.collect()
+	}
+
+	fn record_error(result: Result<(Vec<Segment>, Option<u64>), LoadError>) -> (usize, String) {
+		match result {
+			Err(LoadError::InvalidRecord { line, reason }) => (line, reason),
+			other => panic!("expected an invalid record, got {:?}", other),
+		}
+	}
+
+	fn elf_error(bytes: &[u8]) -> String {
+		match ElfImage::parse(bytes) {
+			Err(LoadError::InvalidElf(reason)) => reason,
+			other => panic!("expected an invalid ELF, got {:?}", other),
+		}
+	}
+
+	/// Builds an ELF header followed by one loadable program header per `(offset, address, file size, memory size)`.
+	fn elf(
+		class: u8,
+		little_endian: bool,
+		kind: u16,
+		segments: &[(u64, u64, u64, u64)],
+	) -> Vec<u8> {
+		let word = if class == 2 { 8 } else { 4 };
+		let (header_size, program_header_size) = if class == 2 { (64, 56) } else { (52, 32) };
+
+		let mut fields = vec![
+			(u64::from(kind), 2),
+			(0, 2),              // machine
+			(1, 4),              // version
+			(0x400, word),       // entry
+			(header_size, word), // program header offset
+			(0, word),           // section header offset
+			(0, 4),              // flags
+			(header_size, 2),
+			(program_header_size, 2),
+			(segments.len() as u64, 2),
+			(0, 2), // section header size
+			(0, 2), // section header count
+			(0, 2), // section name table index
+		];
+		for &(offset, address, file_size, memory_size) in segments {
+			fields.extend([
+				(u64::from(PT_LOAD), 4),
+				(0b101, 4), // flags
+				(offset, 8),
+				(address, 8),
+				(address, 8),
+				(file_size, 8),
+				(memory_size, 8),
+				(0x1000, 8), // alignment
+			]);
+		}
+
+		let mut bytes = vec![
+			0x7f,
+			b'E',
+			b'L',
+			b'F',
+			class,
+			if little_endian { 1 } else { 2 },
+			1,
+		];
+		bytes.resize(16, 0);
+		for (value, size) in fields {
+			let value = &value.to_le_bytes()[..size];
+			if little_endian {
+				bytes.extend(value);
+			} else {
+				bytes.extend(value.iter().rev());
+			}
+		}
+		bytes
+	}
+
+	#[test]
+	fn parses_intel_hex() {
+		let (segments, entry) = parse_intel_hex(INTEL_HEX).unwrap();
+		// the extended linear address applies to every data record, and adjacent records are merged
+		assert_eq!(
+			addresses_and_data(&segments),
+			[
+				(0x1_0010, &[0xde, 0xad, 0xbe, 0xef, 0xca, 0xfe][..]),
+				(0x1_0100, &[0xaa][..]),
+			]
+		);
+		assert_eq!(segments[0].memory_size, 6);
+		assert_eq!(entry, Some(0x1_0400));
+	}
+
+	#[test]
+	fn rejects_malformed_intel_hex() {
+		assert_eq!(
+			record_error(parse_intel_hex(":020000040001F9\n:04001000DEADBEEFB5\n")),
+			(2, "checksum mismatch".to_string())
+		);
+		assert_eq!(
+			record_error(parse_intel_hex(":0100000400FB\n")),
+			(1, "record has the wrong length for its type".to_string())
+		);
+		assert_eq!(
+			record_error(parse_intel_hex(":0400100DEADBEEFB4\n")),
+			(1, "odd number of hexadecimal digits".to_string())
+		);
+		assert_eq!(
+			record_error(parse_intel_hex("04001000DEADBEEFB4\n")),
+			(1, "record doesn't start with ':'".to_string())
+		);
+	}
+
+	#[test]
+	fn parses_srecords() {
+		let (segments, entry) = parse_srecord(SRECORD).unwrap();
+		assert_eq!(
+			addresses_and_data(&segments),
+			[(0x1_0000, &[0x11, 0x22, 0x33][..]), (0x2000, &[0x44][..]),]
+		);
+		assert_eq!(entry, Some(0x1_0000));
+	}
+
+	#[test]
+	fn rejects_malformed_srecords() {
+		assert_eq!(
+			record_error(parse_srecord("S00600004844521B\nS2060100001122C6\n")),
+			(2, "checksum mismatch".to_string())
+		);
+		assert_eq!(
+			record_error(parse_srecord("S4030000FC\n")),
+			(1, "unknown record type S4".to_string())
+		);
+		assert_eq!(
+			record_error(parse_srecord("S20501000233\n")),
+			(1, "record length doesn't match its byte count".to_string())
+		);
+	}
+
+	#[test]
+	fn parses_elf_segments() {
+		let mut bytes = elf(2, true, header::ET_EXEC, &[(120, 0x400, 4, 8)]);
+		bytes.extend([1, 2, 3, 4]);
+
+		let image = ElfImage::parse(&bytes).unwrap();
+		assert_eq!(image.entry, 0x400);
+		assert_eq!(
+			addresses_and_data(&image.segments),
+			[(0x400, &[1, 2, 3, 4][..])]
+		);
+		assert_eq!(image.segments[0].memory_size, 8);
+	}
+
+	#[test]
+	fn rejects_unloadable_elf_images() {
+		assert_eq!(
+			elf_error(&elf(1, true, header::ET_EXEC, &[])),
+			"not a 64-bit image"
+		);
+		assert_eq!(
+			elf_error(&elf(2, false, header::ET_EXEC, &[])),
+			"not a little-endian image"
+		);
+		assert_eq!(
+			elf_error(&elf(2, true, header::ET_REL, &[])),
+			"not an executable"
+		);
+		assert_eq!(
+			elf_error(&elf(2, true, header::ET_EXEC, &[(0, 0x400, 16, 8)])),
+			"segment at 0x400 is larger in the file than in memory"
+		);
+		assert_eq!(
+			elf_error(&elf(2, true, header::ET_EXEC, &[(120, 0x400, 16, 16)])),
+			"segment at 0x400 extends past the end of the file"
+		);
+	}
+
+	#[test]
+	fn detects_overlapping_images() {
+		let mut loader = ImageLoader::new();
+		loader
+			.loaded
+			.push((PathBuf::from("first.bin"), 0x1000..0x2000));
+
+		assert!(loader.check_overlap(0, 0x1000).is_ok());
+		assert!(loader.check_overlap(0x2000, 0x1000).is_ok());
+		match loader.check_overlap(0x1800, 0x1000) {
+			Err(LoadError::Overlap { other, start, end }) => {
+				assert_eq!(other, Path::new("first.bin"));
+				assert_eq!((start, end), (0x1800, 0x2000));
+			},
+			other => panic!("expected an overlap, got {:?}", other),
+		}
+	}
+}
@@ -0,0 +1,304 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{collections::BTreeMap, fmt, fs, io, path::Path, path::PathBuf};
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+	block::DiskMode,
+	memory::{Memory, Region, RegionKind, SparseMemory},
+	symbols::parse_address,
+	util::RegisterID,
+	vm::TIMER_INTERRUPT,
+};
+
+/// The size of contiguous RAM (or the allocation limit of sparse memory) when none is given.
+pub const DEFAULT_MEMORY_SIZE: u64 = /* 32MiB */ 32 * 1024 * 1024;
+pub const DEFAULT_CONSOLE_ADDRESS: u64 = 0x1000_0000;
+
+/// A number that can also be written as a string, like `"0xffff_0000_0000_0000"` or `"32M"`.
+///
+/// TOML integers are signed, so strings are the only way to write large addresses there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Number(pub u64);
+
+/// An address that can also be given as the name of a symbol in one of the loaded images.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+	Address(u64),
+	Symbol(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryKind {
+	#[default]
+	Contiguous,
+	Sparse,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegionConfig {
+	pub start: Number,
+	pub size: Number,
+	pub access: RegionKind,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct MemoryConfig {
+	pub kind: MemoryKind,
+	/// The size of contiguous RAM, or the allocation limit of sparse memory.
+	pub size: Number,
+	/// Read-only and unmapped regions; only sparse memory supports these.
+	pub regions: Vec<RegionConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum DeviceConfig {
+	Console {
+		base: Number,
+	},
+	Uart {
+		base: Number,
+		interrupt: Option<Number>,
+		/// `stdio`, `pty`, or the path of a file or character device.
+		backend: String,
+	},
+	Block {
+		base: Number,
+		interrupt: Option<Number>,
+		/// The disk image. Relative paths are relative to the machine description.
+		path: PathBuf,
+		#[serde(default)]
+		mode: DiskMode,
+	},
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageConfig {
+	/// Relative paths are relative to the machine description.
+	pub path: PathBuf,
+	/// Where to load raw images. Other formats specify their own addresses.
+	pub address: Option<Number>,
+}
+
+/// A description of the machine to emulate: its memory and devices, plus the images and state it starts with.
+///
+/// The default machine is the one `acca-emu` emulates without `--machine`: 32 MiB of contiguous RAM with the console at
+/// `0x1000_0000`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct MachineConfig {
+	pub memory: MemoryConfig,
+	pub mmu: bool,
+	/// The interrupt raised by the built-in timer.
+	pub timer_interrupt: Number,
+	pub devices: Vec<DeviceConfig>,
+	/// Images to load, in order.
+	pub images: Vec<ImageConfig>,
+	/// Initial register values, keyed by register name (e.g. `r0` or `rsp`).
+	pub registers: BTreeMap<String, Number>,
+	pub entry: Option<Location>,
+}
+
+#[derive(Debug)]
+pub enum MachineError {
+	Io(io::Error),
+	/// The file isn't valid TOML or RON, or it doesn't describe a machine.
+	Parse(String),
+	/// The file's extension isn't `.toml` or `.ron`.
+	UnknownFormat,
+	/// The description is well-formed, but it doesn't make sense.
+	Invalid(String),
+}
+
+impl fmt::Display for MachineError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			MachineError::Io(e) => write!(f, "{}", e),
+			MachineError::Parse(reason) => write!(f, "{}", reason.trim_end()),
+			MachineError::UnknownFormat => {
+				write!(f, "machine descriptions must be .toml or .ron files")
+			},
+			MachineError::Invalid(reason) => write!(f, "{}", reason),
+		}
+	}
+}
+
+impl std::error::Error for MachineError {}
+
+impl From<io::Error> for MachineError {
+	fn from(value: io::Error) -> Self {
+		Self::Io(value)
+	}
+}
+
+/// Parses a size: a number of bytes, optionally followed by `K`, `M` or `G`.
+pub fn parse_size(spec: &str) -> Option<u64> {
+	let (number, shift) = match spec.as_bytes().last()? {
+		b'K' | b'k' => (&spec[..spec.len() - 1], 10),
+		b'M' | b'm' => (&spec[..spec.len() - 1], 20),
+		b'G' | b'g' => (&spec[..spec.len() - 1], 30),
+		_ => (spec, 0),
+	};
+	parse_address(number)?.checked_mul(1 << shift)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawNumber {
+	Integer(u64),
+	String(String),
+}
+
+impl<'de> Deserialize<'de> for Number {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		match RawNumber::deserialize(deserializer)? {
+			RawNumber::Integer(value) => Ok(Number(value)),
+			RawNumber::String(string) => parse_size(&string)
+				.map(Number)
+				.ok_or_else(|| de::Error::custom(format!("invalid number \"{}\"", string))),
+		}
+	}
+}
+
+impl<'de> Deserialize<'de> for Location {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		Ok(match RawNumber::deserialize(deserializer)? {
+			RawNumber::Integer(address) => Location::Address(address),
+			RawNumber::String(string) => match parse_address(&string) {
+				Some(address) => Location::Address(address),
+				None => Location::Symbol(string),
+			},
+		})
+	}
+}
+
+impl Default for MemoryConfig {
+	fn default() -> Self {
+		Self {
+			kind: MemoryKind::Contiguous,
+			size: Number(DEFAULT_MEMORY_SIZE),
+			regions: Vec::new(),
+		}
+	}
+}
+
+impl MemoryConfig {
+	/// Creates the described memory. Returns `None` if contiguous RAM can't be allocated.
+	pub fn create(&self) -> Option<Memory> {
+		match self.kind {
+			MemoryKind::Contiguous => Memory::contiguous(self.size.0 as usize),
+			MemoryKind::Sparse => {
+				let mut memory = SparseMemory::new(self.size.0);
+				for region in &self.regions {
+					memory.add_region(Region::new(
+						region.start.0..region.start.0 + region.size.0,
+						region.access,
+					));
+				}
+				Some(Memory::Sparse(memory))
+			},
+		}
+	}
+}
+
+impl Default for MachineConfig {
+	fn default() -> Self {
+		Self {
+			memory: MemoryConfig::default(),
+			mmu: false,
+			timer_interrupt: Number(TIMER_INTERRUPT),
+			devices: vec![DeviceConfig::Console {
+				base: Number(DEFAULT_CONSOLE_ADDRESS),
+			}],
+			images: Vec::new(),
+			registers: BTreeMap::new(),
+			entry: None,
+		}
+	}
+}
+
+impl MachineConfig {
+	/// Loads a machine description from a `.toml` or `.ron` file.
+	pub fn load(path: &Path) -> Result<Self, MachineError> {
+		let extension = path.extension().and_then(|extension| extension.to_str());
+		let text = fs::read_to_string(path)?;
+		let mut machine = match extension {
+			Some("toml") => Self::from_toml(&text)?,
+			Some("ron") => Self::from_ron(&text)?,
+			_ => return Err(MachineError::UnknownFormat),
+		};
+
+		let directory = path.parent().unwrap_or(Path::new(""));
+		for image in &mut machine.images {
+			image.path = directory.join(&image.path);
+		}
+		for device in &mut machine.devices {
+			if let DeviceConfig::Block { path, .. } = device {
+				*path = directory.join(&*path);
+			}
+		}
+		Ok(machine)
+	}
+
+	pub fn from_toml(text: &str) -> Result<Self, MachineError> {
+		let machine: Self = toml::from_str(text).map_err(|e| MachineError::Parse(e.to_string()))?;
+		machine.validate()?;
+		Ok(machine)
+	}
+
+	pub fn from_ron(text: &str) -> Result<Self, MachineError> {
+		// optional fields can be written without `Some(...)`, like in TOML
+		let machine: Self = ron::Options::default()
+			.with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+			.from_str(text)
+			.map_err(|e| MachineError::Parse(e.to_string()))?;
+		machine.validate()?;
+		Ok(machine)
+	}
+
+	/// Returns the initial register values, resolving register names.
+	pub fn initial_registers(&self) -> Vec<(RegisterID, u64)> {
+		self.registers
+			.iter()
+			.filter_map(|(name, value)| Some((RegisterID::from_name(name)?, value.0)))
+			.collect()
+	}
+
+	fn validate(&self) -> Result<(), MachineError> {
+		let invalid = |reason: String| Err(MachineError::Invalid(reason));
+
+		if self.memory.kind == MemoryKind::Contiguous && !self.memory.regions.is_empty() {
+			return invalid("read-only and unmapped regions require sparse memory".to_string());
+		}
+		for region in &self.memory.regions {
+			if region.size.0 == 0 || region.start.0.checked_add(region.size.0).is_none() {
+				return invalid(format!(
+					"invalid memory region at {:#x} with size {:#x}",
+					region.start.0, region.size.0
+				));
+			}
+		}
+
+		if let Some(name) = self
+			.registers
+			.keys()
+			.find(|name| RegisterID::from_name(name).is_none())
+		{
+			return invalid(format!("unknown register \"{}\"", name));
+		}
+
+		Ok(())
+	}
+}
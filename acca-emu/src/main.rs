@@ -6,52 +6,507 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
-mod util;
-mod vm;
-
-use std::{fs, path::PathBuf, process::exit};
+use std::{
+	fs,
+	io::{self, BufWriter, Write},
+	path::{Path, PathBuf},
+	process::exit,
+	sync::atomic::{AtomicBool, Ordering},
+};
 
+use acca_emu::{
+	block::{BlockDevice, DiskMode, BLOCK_SIZE},
+	bus::Device,
+	console::{Console, CONSOLE_SIZE},
+	debugger::Debugger,
+	gdb::{self, GdbStub, SessionEnd},
+	loader::ImageLoader,
+	machine::{
+		parse_size, DeviceConfig, Location, MachineConfig, MemoryKind, Number, RegionConfig,
+	},
+	memory::RegionKind,
+	profile::Profiler,
+	semihosting::Semihosting,
+	snapshot::Snapshot,
+	symbols::{parse_address, SymbolTable},
+	trace::{TraceFormat, Tracer},
+	uart::{Uart, UART_SIZE},
+	util::{MachineRegisterID, VMAddress},
+	vm::{Exception, StepResult, VM},
+	watch::{WatchKind, Watchpoint},
+};
 use clap::Parser as ClapParser;
 
 #[derive(ClapParser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-	image: PathBuf,
+	#[arg(required_unless_present_any = ["restore", "load", "machine"], conflicts_with = "restore")]
+	image: Option<PathBuf>,
+
+	#[arg(long, value_name = "FILE[@ADDRESS]", conflicts_with = "restore")]
+	load: Vec<String>,
+
+	#[arg(long, value_name = "ADDRESS|SYMBOL", conflicts_with = "restore")]
+	entry: Option<String>,
 
 	#[arg(long)]
 	print_instructions: bool,
+
+	#[arg(long, value_name = "FILE")]
+	machine: Option<PathBuf>,
+
+	#[arg(long, conflicts_with = "machine")]
+	mmu: bool,
+
+	#[arg(
+		long,
+		value_name = "contiguous|sparse",
+		default_value = "contiguous",
+		conflicts_with = "machine"
+	)]
+	memory: String,
+
+	#[arg(long, value_name = "SIZE", conflicts_with = "machine")]
+	memory_size: Option<String>,
+
+	#[arg(long, value_name = "ADDRESS,LENGTH", conflicts_with = "machine")]
+	read_only: Vec<String>,
+
+	#[arg(long, value_name = "ADDRESS,LENGTH", conflicts_with = "machine")]
+	unmapped: Vec<String>,
+
+	#[arg(long, value_name = "PORT|SOCKET", conflicts_with = "debug")]
+	gdb: Option<String>,
+
+	#[arg(long)]
+	debug: bool,
+
+	#[arg(long, value_name = "FILE")]
+	symbols: Option<PathBuf>,
+
+	#[arg(long, value_name = "stdio|pty|FILE", conflicts_with = "machine")]
+	uart: Option<String>,
+
+	#[arg(long, value_name = "FILE", conflicts_with = "machine")]
+	disk: Option<PathBuf>,
+
+	#[arg(
+		long,
+		value_name = "read-write|read-only|copy-on-write",
+		default_value = "read-write",
+		requires = "disk"
+	)]
+	disk_mode: String,
+
+	#[arg(long, value_name = "FILE")]
+	trace: Option<PathBuf>,
+
+	#[arg(
+		long,
+		value_name = "text|json",
+		default_value = "text",
+		requires = "trace"
+	)]
+	trace_format: String,
+
+	#[arg(
+		long,
+		value_name = "COUNT|0xADDRESS|SYMBOL",
+		requires = "snapshot",
+		conflicts_with_all = ["debug", "gdb"]
+	)]
+	snapshot_at: Option<String>,
+
+	#[arg(long, value_name = "FILE", requires = "snapshot_at")]
+	snapshot: Option<PathBuf>,
+
+	#[arg(long, value_name = "FILE")]
+	restore: Option<PathBuf>,
+
+	#[arg(long, value_name = "FILE", conflicts_with_all = ["debug", "gdb"])]
+	profile: Option<PathBuf>,
+
+	#[arg(long, value_name = "FILE", conflicts_with_all = ["debug", "gdb"])]
+	profile_folded: Option<PathBuf>,
+
+	#[arg(long, value_name = "COUNT", default_value_t = 20)]
+	profile_top: usize,
+
+	#[arg(long, value_name = "[r:|w:|a:]LOCATION[,LENGTH]")]
+	watch: Vec<String>,
+
+	#[arg(long, value_name = "DIRECTORY")]
+	semihosting: Option<PathBuf>,
+
+	#[arg(last = true, value_name = "GUEST_ARGS", requires = "semihosting")]
+	guest_args: Vec<String>,
+}
+
+/// When to take the snapshot requested with `--snapshot-at`.
+#[derive(Debug, Clone, Copy)]
+enum SnapshotPoint {
+	/// After this many instructions have completed, not counting ones that raised an exception.
+	Count(u64),
+	/// Right before executing the instruction at this address for the first time.
+	Address(u64),
+}
+
+const UART_ADDRESS: u64 = 0x1000_1000;
+const UART_INTERRUPT: u64 = 1;
+const DISK_ADDRESS: u64 = 0x1000_2000;
+const DISK_INTERRUPT: u64 = 2;
+
+/// Parses a `--watch` argument. Watchpoints are for writes by default and cover 8 bytes.
+fn parse_watchpoint(spec: &str, symbols: &SymbolTable) -> Option<Watchpoint> {
+	let (kind, rest) = match spec.split_once(':') {
+		Some(("r", rest)) => (WatchKind::Read, rest),
+		Some(("w", rest)) => (WatchKind::Write, rest),
+		Some(("a", rest)) => (WatchKind::Access, rest),
+		Some(_) => return None,
+		None => (WatchKind::Write, spec),
+	};
+	let (location, length) = match rest.split_once(',') {
+		Some((location, length)) => (location, parse_address(length).filter(|&x| x > 0)?),
+		None => (rest, 8),
+	};
+	let start = parse_address(location).or_else(|| symbols.address_of(location).map(u64::from))?;
+	Some(Watchpoint::new(start..start.checked_add(length)?, kind))
+}
+
+/// Parses a `--read-only` or `--unmapped` argument.
+fn parse_region(spec: &str, access: RegionKind) -> Option<RegionConfig> {
+	let (start, length) = spec.split_once(',')?;
+	let start = parse_address(start)?;
+	let length = parse_address(length).filter(|&x| x > 0)?;
+	start.checked_add(length)?;
+	Some(RegionConfig {
+		start: Number(start),
+		size: Number(length),
+		access,
+	})
 }
 
-const VM_MEMORY_SIZE: usize = /* 32MiB */ 32 * 1024 * 1024;
+/// Describes the machine selected by the command-line options, for when there's no `--machine`.
+fn machine_from_args(cli: &Args) -> MachineConfig {
+	let mut machine = MachineConfig {
+		mmu: cli.mmu,
+		..Default::default()
+	};
+
+	if let Some(spec) = &cli.memory_size {
+		match parse_size(spec) {
+			Some(size) => machine.memory.size = Number(size),
+			None => {
+				eprintln!("Invalid memory size \"{}\"", spec);
+				exit(1);
+			},
+		}
+	}
+	machine.memory.regions = cli
+		.read_only
+		.iter()
+		.map(|spec| (spec, RegionKind::ReadOnly))
+		.chain(cli.unmapped.iter().map(|spec| (spec, RegionKind::Unmapped)))
+		.map(|(spec, kind)| match parse_region(spec, kind) {
+			Some(region) => region,
+			None => {
+				eprintln!("Invalid memory region \"{}\"", spec);
+				exit(1);
+			},
+		})
+		.collect();
+
+	machine.memory.kind = match cli.memory.as_str() {
+		"contiguous" if !machine.memory.regions.is_empty() => {
+			eprintln!("Read-only and unmapped regions require sparse memory");
+			exit(1);
+		},
+		"contiguous" => MemoryKind::Contiguous,
+		"sparse" => MemoryKind::Sparse,
+		other => {
+			eprintln!("Unknown memory backend \"{}\"", other);
+			exit(1);
+		},
+	};
+
+	if let Some(backend) = &cli.uart {
+		machine.devices.push(DeviceConfig::Uart {
+			base: Number(UART_ADDRESS),
+			interrupt: Some(Number(UART_INTERRUPT)),
+			backend: backend.clone(),
+		});
+	}
+
+	if let Some(path) = &cli.disk {
+		let mode = match cli.disk_mode.as_str() {
+			"read-write" => DiskMode::ReadWrite,
+			"read-only" => DiskMode::ReadOnly,
+			"copy-on-write" => DiskMode::CopyOnWrite,
+			other => {
+				eprintln!("Unknown disk mode \"{}\"", other);
+				exit(1);
+			},
+		};
+		machine.devices.push(DeviceConfig::Block {
+			base: Number(DISK_ADDRESS),
+			interrupt: Some(Number(DISK_INTERRUPT)),
+			path: path.clone(),
+			mode,
+		});
+	}
+
+	machine
+}
+
+fn attach_device(vm: &mut VM, config: &DeviceConfig, debug: bool) {
+	let (name, base, size, interrupt, device): (_, _, _, _, Box<dyn Device>) = match config {
+		DeviceConfig::Console { base } => (
+			"console",
+			base,
+			CONSOLE_SIZE,
+			None,
+			Box::new(Console::new()),
+		),
+		DeviceConfig::Uart {
+			base,
+			interrupt,
+			backend,
+		} => {
+			let uart = match backend.as_str() {
+				"stdio" if debug => {
+					eprintln!("The UART can't use stdio while the debugger is active");
+					exit(1);
+				},
+				"stdio" => Ok(Uart::stdio()),
+				"pty" => Uart::pty().map(|(uart, path)| {
+					eprintln!("UART connected to {}", path);
+					uart
+				}),
+				path => Uart::file(path.as_ref()),
+			};
+
+			match uart {
+				Ok(uart) => (
+					"UART",
+					base,
+					UART_SIZE,
+					interrupt.map(|x| x.0),
+					Box::new(uart),
+				),
+				Err(e) => {
+					eprintln!("Failed to set up UART: {}", e);
+					exit(1);
+				},
+			}
+		},
+		DeviceConfig::Block {
+			base,
+			interrupt,
+			path,
+			mode,
+		} => match BlockDevice::open(path, *mode) {
+			Ok(disk) => (
+				"disk",
+				base,
+				BLOCK_SIZE,
+				interrupt.map(|x| x.0),
+				Box::new(disk),
+			),
+			Err(e) => {
+				eprintln!("Failed to open disk image \"{}\": {}", path.display(), e);
+				exit(1);
+			},
+		},
+	};
+
+	if let Err(e) = vm.attach_device(base.0.into(), size, interrupt, device) {
+		eprintln!("Failed to attach {}: {}", name, e);
+		exit(1);
+	}
+}
+
+/// Parses a `--load` argument into a path and an optional load address.
+///
+/// The address is only split off if it parses, so paths containing `@` still work.
+fn parse_load(spec: &str) -> (PathBuf, Option<u64>) {
+	match spec.rsplit_once('@') {
+		Some((path, address)) if !path.is_empty() => match parse_address(address) {
+			Some(address) => (path.into(), Some(address)),
+			None => (spec.into(), None),
+		},
+		_ => (spec.into(), None),
+	}
+}
+
+/// Set by the Ctrl-C handler to stop the guest.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn write_report(path: &Path, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) {
+	let result = fs::File::create(path).and_then(|file| {
+		let mut writer = BufWriter::new(file);
+		write(&mut writer)?;
+		writer.flush()
+	});
+	if let Err(e) = result {
+		eprintln!("Failed to write profile to \"{}\": {}", path.display(), e);
+	}
+}
+
+/// Flushes the trace (if any) and exits with the given status.
+fn finish(vm: &mut VM, code: i32) -> ! {
+	if let Some(tracer) = vm.set_tracer(None) {
+		if let Err(e) = tracer.finish() {
+			eprintln!("Failed to write trace: {}", e);
+			exit(1);
+		}
+	}
+	exit(code);
+}
 
 fn main() {
 	let cli = Args::parse();
-	let mut vm = match vm::VM::new(VM_MEMORY_SIZE) {
-		Some(vm) => vm,
+
+	let machine = match &cli.machine {
+		Some(path) => match MachineConfig::load(path) {
+			Ok(machine) => machine,
+			Err(e) => {
+				eprintln!("Failed to load machine \"{}\": {}", path.display(), e);
+				exit(1);
+			},
+		},
+		None => machine_from_args(&cli),
+	};
+
+	let mut vm = match machine.memory.create() {
+		Some(memory) => VM::with_memory(memory),
 		None => {
 			eprintln!("Failed to create VM");
 			exit(1);
 		},
 	};
+	vm.set_mmu(machine.mmu);
+	vm.set_timer_interrupt(machine.timer_interrupt.0);
 
-	// read the input image into memory
-	{
-		let mut file = match fs::File::open(&cli.image) {
-			Ok(x) => x,
+	if let Some(directory) = &cli.semihosting {
+		// the guest's first argument is its own name, like on the host
+		let program = cli
+			.image
+			.as_ref()
+			.map_or_else(String::new, |path| path.display().to_string());
+		let args = std::iter::once(program)
+			.chain(cli.guest_args.iter().cloned())
+			.collect();
+		match Semihosting::new(directory, args) {
+			Ok(semihosting) => vm.set_semihosting(Some(semihosting)),
 			Err(e) => {
-				eprintln!("Failed to open \"{}\": {}", cli.image.display(), e);
+				eprintln!(
+					"Failed to use \"{}\" for semihosting: {}",
+					directory.display(),
+					e
+				);
 				exit(1);
 			},
-		};
+		}
+	}
+
+	for device in &machine.devices {
+		attach_device(&mut vm, device, cli.debug);
+	}
 
-		match vm.load_file(&mut file, 0.into()) {
-			Ok(_) => {},
+	// read the input images into memory (the machine's first)
+	let mut loader = ImageLoader::new();
+	let images = machine
+		.images
+		.iter()
+		.map(|image| (image.path.clone(), image.address.map(|x| x.0)))
+		.chain(cli.image.iter().map(|path| (path.clone(), None)))
+		.chain(cli.load.iter().map(|spec| parse_load(spec)));
+	for (path, address) in images {
+		if let Err(e) = loader.load(&mut vm, &path, address) {
+			eprintln!(
+				"Failed to load file \"{}\" into VM memory: {}",
+				path.display(),
+				e
+			);
+			exit(1);
+		}
+	}
+
+	for (register, value) in machine.initial_registers() {
+		vm.register_file_mut()[register] = value.into();
+	}
+
+	let mut symbols = loader.symbols;
+	if let Some(path) = &cli.symbols {
+		match SymbolTable::load(path) {
+			Ok(x) => symbols.extend(&x),
 			Err(e) => {
-				eprintln!(
-					"Failed to load file \"{}\" into VM memory: {}",
-					cli.image.display(),
-					e
-				);
+				eprintln!("Failed to load symbols from \"{}\": {}", path.display(), e);
+				exit(1);
+			},
+		}
+	}
+
+	let entry_location = match &cli.entry {
+		Some(location) => Some(match parse_address(location) {
+			Some(address) => Location::Address(address),
+			None => Location::Symbol(location.clone()),
+		}),
+		None => machine.entry.clone(),
+	};
+	let entry = match entry_location {
+		Some(Location::Address(address)) => Some(address),
+		Some(Location::Symbol(name)) => match symbols.address_of(&name) {
+			Some(address) => Some(address.into()),
+			None => {
+				eprintln!("Unknown entry point \"{}\"", name);
+				exit(1);
+			},
+		},
+		None => loader.entry,
+	};
+	if let Some(entry) = entry {
+		let address = VMAddress::from(entry);
+		if !address.is_valid_instruction_pointer() {
+			eprintln!("Invalid entry point {:#x}", entry);
+			exit(1);
+		}
+		vm.set_instruction_pointer(address);
+	}
+
+	// the snapshot goes last so that it overrides the machine's entry point and initial registers
+	if let Some(path) = &cli.restore {
+		if let Err(e) = Snapshot::load(path).and_then(|snapshot| vm.restore(&snapshot)) {
+			eprintln!("Failed to restore snapshot \"{}\": {}", path.display(), e);
+			exit(1);
+		}
+	}
+
+	let snapshot_point = cli.snapshot_at.as_ref().map(|location| {
+		if location.chars().all(|c| c.is_ascii_digit() || c == '_') {
+			match parse_address(location) {
+				Some(count) => SnapshotPoint::Count(count),
+				None => {
+					eprintln!("Invalid instruction count \"{}\"", location);
+					exit(1);
+				},
+			}
+		} else {
+			match parse_address(location).or_else(|| symbols.address_of(location).map(u64::from)) {
+				Some(address) => SnapshotPoint::Address(address),
+				None => {
+					eprintln!("Unknown snapshot location \"{}\"", location);
+					exit(1);
+				},
+			}
+		}
+	});
+
+	for spec in &cli.watch {
+		match parse_watchpoint(spec, &symbols) {
+			Some(watchpoint) => vm.add_watchpoint(watchpoint),
+			None => {
+				eprintln!("Invalid watchpoint \"{}\"", spec);
 				exit(1);
 			},
 		}
@@ -59,5 +514,121 @@ fn main() {
 
 	vm.set_print_instructions(cli.print_instructions);
 
-	vm.run()
+	if let Some(path) = &cli.trace {
+		let format = match cli.trace_format.as_str() {
+			"text" => TraceFormat::Text,
+			"json" => TraceFormat::Json,
+			other => {
+				eprintln!("Unknown trace format \"{}\"", other);
+				exit(1);
+			},
+		};
+
+		match Tracer::create(path, format) {
+			Ok(tracer) => {
+				vm.set_tracer(Some(tracer));
+			},
+			Err(e) => {
+				eprintln!("Failed to create trace file \"{}\": {}", path.display(), e);
+				exit(1);
+			},
+		}
+	}
+
+	if cli.debug {
+		match Debugger::new(&mut vm, &symbols).run() {
+			Ok(_) => {
+				let code = vm.exit_code().unwrap_or(0) as i32;
+				finish(&mut vm, code);
+			},
+			Err(e) => {
+				eprintln!("Debugger failed: {}", e);
+				exit(1);
+			},
+		}
+	}
+
+	if let Some(address) = &cli.gdb {
+		eprintln!("Waiting for GDB to connect on {}...", address);
+
+		let result = gdb::accept(address).and_then(|conn| GdbStub::new(&mut vm, conn).serve());
+
+		match result {
+			Ok(SessionEnd::Detached) => {},
+			Ok(SessionEnd::Killed) => {
+				let code = vm.exit_code().unwrap_or(0) as i32;
+				finish(&mut vm, code);
+			},
+			Err(e) => {
+				eprintln!("GDB session failed: {}", e);
+				exit(1);
+			},
+		}
+	}
+
+	let mut executed = 0u64;
+	let mut snapshot_point = snapshot_point;
+	let mut profiler = (cli.profile.is_some() || cli.profile_folded.is_some()).then(Profiler::new);
+
+	// stop cleanly on Ctrl-C so that the trace and profile are still written
+	if let Err(e) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst)) {
+		eprintln!("Failed to install Ctrl-C handler: {}", e);
+	}
+
+	let code = loop {
+		if INTERRUPTED.load(Ordering::Relaxed) {
+			break 130;
+		}
+
+		let reached = match snapshot_point {
+			Some(SnapshotPoint::Count(count)) => executed == count,
+			Some(SnapshotPoint::Address(address)) => u64::from(vm.instruction_pointer()) == address,
+			None => false,
+		};
+		if reached {
+			snapshot_point = None;
+
+			// `--snapshot` is required by `--snapshot-at`
+			let path = cli.snapshot.as_ref().unwrap();
+			if let Err(e) = vm.snapshot().save(path) {
+				eprintln!("Failed to save snapshot to \"{}\": {}", path.display(), e);
+				finish(&mut vm, 1);
+			}
+			eprintln!("Saved snapshot to \"{}\"", path.display());
+		}
+
+		let result = match profiler.as_mut() {
+			Some(profiler) => profiler.step(&mut vm),
+			None => vm.step(),
+		};
+		match result {
+			StepResult::Executed => executed += 1,
+			// interrupts are part of normal operation (e.g. for the UART), so don't clutter the output with them
+			StepResult::Exception(Exception::Interrupt(_)) => {},
+			StepResult::Exception(exception) => println!(
+				"***Exception ({:?}) at {:#x}***",
+				exception,
+				vm.machine_register(MachineRegisterID::elr)
+			),
+			StepResult::Exited(code) => break code as i32,
+			StepResult::Trapped(_) => {},
+		}
+
+		for hit in vm.take_watch_hits() {
+			eprintln!("Watchpoint hit: {}", hit);
+		}
+	};
+
+	if let Some(profiler) = &profiler {
+		if let Some(path) = &cli.profile {
+			write_report(path, |output| {
+				profiler.write_flat(&symbols, cli.profile_top, output)
+			});
+		}
+		if let Some(path) = &cli.profile_folded {
+			write_report(path, |output| profiler.write_folded(&symbols, output));
+		}
+	}
+
+	finish(&mut vm, code);
 }
@@ -0,0 +1,496 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	collections::{HashMap, HashSet},
+	fmt,
+	ops::Range,
+};
+
+use memmap2::MmapMut;
+use serde::Deserialize;
+
+use crate::{mmu::PAGE_SIZE, util::Size};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegionKind {
+	/// The guest can read the region, but not write to it. Images can still be loaded into it.
+	ReadOnly,
+	/// The region isn't backed by memory at all, so accesses to it go to devices (or fail).
+	Unmapped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+	pub range: Range<u64>,
+	pub kind: RegionKind,
+}
+
+type Page = Box<[u8; PAGE_SIZE as usize]>;
+
+/// Memory covering the whole 64-bit address space, allocated a page at a time the first time each page is written.
+///
+/// Pages that have never been written read as zero.
+pub struct SparseMemory {
+	pages: HashMap<u64, Page>,
+	/// The most memory (in bytes) that may be allocated.
+	limit: u64,
+	regions: Vec<Region>,
+}
+
+/// The RAM backing the guest's physical address space.
+pub enum Memory {
+	/// A single block of RAM starting at address 0.
+	Contiguous(MmapMut),
+	Sparse(SparseMemory),
+}
+
+impl Region {
+	pub fn new(range: Range<u64>, kind: RegionKind) -> Self {
+		Self { range, kind }
+	}
+}
+
+impl fmt::Debug for SparseMemory {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("SparseMemory")
+			.field("allocated_pages", &self.pages.len())
+			.field("limit", &self.limit)
+			.field("regions", &self.regions)
+			.finish()
+	}
+}
+
+impl fmt::Debug for Memory {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Memory::Contiguous(ram) => f.debug_tuple("Contiguous").field(&ram.len()).finish(),
+			Memory::Sparse(memory) => memory.fmt(f),
+		}
+	}
+}
+
+fn byte_range(address: u64, byte_size: u64) -> Option<Range<u64>> {
+	Some(address..address.checked_add(byte_size)?)
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+	a.start < b.end && b.start < a.end
+}
+
+/// The pages overlapping `range`, which must not be empty.
+fn pages_in(range: &Range<u64>) -> Range<u64> {
+	range.start / PAGE_SIZE..(range.end - 1) / PAGE_SIZE + 1
+}
+
+impl SparseMemory {
+	/// Creates an empty address space that can allocate at most `limit` bytes (rounded down to whole pages).
+	pub fn new(limit: u64) -> Self {
+		Self {
+			pages: HashMap::new(),
+			limit,
+			regions: Vec::new(),
+		}
+	}
+
+	/// Marks `range` as read-only or unmapped. Where regions overlap, the most restrictive one applies.
+	pub fn add_region(&mut self, region: Region) {
+		self.regions.push(region);
+	}
+
+	pub fn regions(&self) -> &[Region] {
+		&self.regions
+	}
+
+	pub fn limit(&self) -> u64 {
+		self.limit
+	}
+
+	/// Returns the size of the memory allocated so far.
+	pub fn allocated(&self) -> u64 {
+		self.pages.len() as u64 * PAGE_SIZE
+	}
+
+	/// Checks that no byte in `range` is unmapped, and (if `write` is set) that none of it is read-only.
+	fn is_accessible(&self, range: &Range<u64>, write: bool) -> bool {
+		!self.regions.iter().any(|region| {
+			overlaps(&region.range, range) && (write || region.kind == RegionKind::Unmapped)
+		})
+	}
+
+	/// Counts the pages overlapping `ranges` that haven't been allocated yet.
+	fn new_pages(&self, ranges: &[Range<u64>]) -> u64 {
+		let mut pages = HashSet::new();
+		for range in ranges.iter().filter(|range| !range.is_empty()) {
+			pages.extend(pages_in(range).filter(|page| !self.pages.contains_key(page)));
+		}
+		pages.len() as u64
+	}
+
+	/// Checks whether the pages overlapping `range` can all be allocated.
+	fn has_room_in(&self, range: &Range<u64>) -> bool {
+		let new_pages = match range.is_empty() {
+			true => 0,
+			false => pages_in(range)
+				.filter(|page| !self.pages.contains_key(page))
+				.count() as u64,
+		};
+		self.has_room_for(new_pages)
+	}
+
+	fn has_room_for(&self, new_pages: u64) -> bool {
+		self.allocated() + new_pages * PAGE_SIZE <= self.limit
+	}
+
+	fn page_mut(&mut self, page: u64) -> Option<&mut Page> {
+		if !self.pages.contains_key(&page) && !self.has_room_for(1) {
+			return None;
+		}
+		Some(
+			self.pages
+				.entry(page)
+				.or_insert_with(|| Box::new([0; PAGE_SIZE as usize])),
+		)
+	}
+
+	fn read_bytes(&self, address: u64, buffer: &mut [u8]) {
+		let mut done = 0;
+		while done < buffer.len() {
+			let current = address + done as u64;
+			let offset = (current % PAGE_SIZE) as usize;
+			let length = (buffer.len() - done).min(PAGE_SIZE as usize - offset);
+			let chunk = &mut buffer[done..done + length];
+			match self.pages.get(&(current / PAGE_SIZE)) {
+				Some(page) => chunk.copy_from_slice(&page[offset..offset + length]),
+				None => chunk.fill(0),
+			}
+			done += length;
+		}
+	}
+
+	/// Writes `data` at `address`, allocating pages as needed. This fails partway through if the
+	/// limit is reached, so callers check beforehand.
+	fn write_bytes(&mut self, address: u64, data: &[u8]) -> Option<()> {
+		let mut address = address;
+		let mut data = data;
+		while !data.is_empty() {
+			let offset = (address % PAGE_SIZE) as usize;
+			let length = data.len().min(PAGE_SIZE as usize - offset);
+			self.page_mut(address / PAGE_SIZE)?[offset..offset + length]
+				.copy_from_slice(&data[..length]);
+			address += length as u64;
+			data = &data[length..];
+		}
+		Some(())
+	}
+}
+
+impl Memory {
+	/// Creates `size` bytes of contiguous RAM.
+	pub fn contiguous(size: usize) -> Option<Self> {
+		Some(Memory::Contiguous(MmapMut::map_anon(size).ok()?))
+	}
+
+	pub fn is_sparse(&self) -> bool {
+		matches!(self, Memory::Sparse(_))
+	}
+
+	/// Returns the size of contiguous RAM, or the allocation limit of sparse memory.
+	pub fn size(&self) -> u64 {
+		match self {
+			Memory::Contiguous(ram) => ram.len() as u64,
+			Memory::Sparse(memory) => memory.limit,
+		}
+	}
+
+	/// Checks whether an access of `byte_size` bytes at `address` would succeed.
+	pub fn is_mapped(&self, address: u64, byte_size: u64, write: bool) -> bool {
+		let range = match byte_range(address, byte_size) {
+			Some(range) => range,
+			None => return false,
+		};
+		match self {
+			Memory::Contiguous(ram) => range.end <= ram.len() as u64,
+			Memory::Sparse(memory) => {
+				memory.is_accessible(&range, write) && (!write || memory.has_room_in(&range))
+			},
+		}
+	}
+
+	pub fn read(&self, address: u64, size: Size) -> Option<u64> {
+		match self {
+			Memory::Contiguous(ram) => {
+				let range = byte_range(address, size.byte_size() as u64)?;
+				ram.get(range.start as usize..range.end as usize)
+					.map(|bytes| size.read(bytes, false))
+			},
+			Memory::Sparse(_) => {
+				let mut bytes = [0u8; 8];
+				self.read_bytes(address, &mut bytes[..size.byte_size() as usize])?;
+				Some(size.read(&bytes, false))
+			},
+		}
+	}
+
+	/// Writes the lowest `size` bits of `value` at `address`, like a guest store would.
+	pub fn write(&mut self, address: u64, size: Size, value: u64) -> Option<()> {
+		match self {
+			Memory::Contiguous(ram) => {
+				let range = byte_range(address, size.byte_size() as u64)?;
+				let bytes = ram.get_mut(range.start as usize..range.end as usize)?;
+				size.write(value, bytes);
+				Some(())
+			},
+			Memory::Sparse(memory) => {
+				let byte_size = size.byte_size() as usize;
+				let range = byte_range(address, byte_size as u64)?;
+				if !memory.is_accessible(&range, true) || !memory.has_room_in(&range) {
+					return None;
+				}
+				let mut bytes = [0u8; 8];
+				size.write(value, &mut bytes);
+				memory.write_bytes(address, &bytes[..byte_size])
+			},
+		}
+	}
+
+	/// Fills `buffer` with the memory at `address`. Fails if any of it is unmapped.
+	pub fn read_bytes(&self, address: u64, buffer: &mut [u8]) -> Option<()> {
+		let range = byte_range(address, buffer.len() as u64)?;
+		match self {
+			Memory::Contiguous(ram) => {
+				buffer.copy_from_slice(ram.get(range.start as usize..range.end as usize)?);
+			},
+			Memory::Sparse(memory) => {
+				if !memory.is_accessible(&range, false) {
+					return None;
+				}
+				memory.read_bytes(address, buffer);
+			},
+		}
+		Some(())
+	}
+
+	/// Copies `data` to `address`, like guest stores would. Nothing is written if any of it can't be.
+	pub fn write_bytes(&mut self, address: u64, data: &[u8]) -> Option<()> {
+		if !self.is_mapped(address, data.len() as u64, true) {
+			return None;
+		}
+		self.load(address, data)
+	}
+
+	/// Checks whether all of `ranges` could be loaded at once with [`Memory::load`].
+	pub fn can_load(&self, ranges: &[Range<u64>]) -> bool {
+		match self {
+			Memory::Contiguous(ram) => ranges.iter().all(|range| range.end <= ram.len() as u64),
+			Memory::Sparse(memory) => {
+				ranges
+					.iter()
+					.all(|range| memory.is_accessible(range, false))
+					&& memory.has_room_for(memory.new_pages(ranges))
+			},
+		}
+	}
+
+	/// Checks whether all of `ranges` could be loaded with [`Memory::load`] after clearing memory.
+	pub fn can_restore(&self, ranges: &[Range<u64>]) -> bool {
+		match self {
+			Memory::Contiguous(_) => self.can_load(ranges),
+			Memory::Sparse(memory) => {
+				let pages = ranges
+					.iter()
+					.filter(|range| !range.is_empty())
+					.flat_map(pages_in)
+					.collect::<HashSet<_>>();
+				ranges
+					.iter()
+					.all(|range| memory.is_accessible(range, false))
+					&& pages.len() as u64 * PAGE_SIZE <= memory.limit
+			},
+		}
+	}
+
+	/// Copies `data` to `address`. Unlike guest stores, this can write to read-only regions.
+	pub fn load(&mut self, address: u64, data: &[u8]) -> Option<()> {
+		let range = byte_range(address, data.len() as u64)?;
+		if !self.can_load(std::slice::from_ref(&range)) {
+			return None;
+		}
+		match self {
+			Memory::Contiguous(ram) => {
+				ram[range.start as usize..range.end as usize].copy_from_slice(data);
+				Some(())
+			},
+			Memory::Sparse(memory) => memory.write_bytes(address, data),
+		}
+	}
+
+	/// Zeroes `size` bytes at `address`. Sparse memory doesn't allocate pages for this.
+	pub fn zero(&mut self, address: u64, size: u64) -> Option<()> {
+		let range = byte_range(address, size)?;
+		match self {
+			Memory::Contiguous(ram) => {
+				ram.get_mut(range.start as usize..range.end as usize)?
+					.fill(0);
+			},
+			Memory::Sparse(memory) => {
+				if !memory.is_accessible(&range, false) {
+					return None;
+				}
+				if range.is_empty() {
+					return Some(());
+				}
+				for page in pages_in(&range) {
+					if let Some(bytes) = memory.pages.get_mut(&page) {
+						let page_start = page * PAGE_SIZE;
+						let start = range.start.max(page_start) - page_start;
+						let end = range.end.min(page_start + PAGE_SIZE) - page_start;
+						bytes[start as usize..end as usize].fill(0);
+					}
+				}
+			},
+		}
+		Some(())
+	}
+
+	/// Returns every page that may hold non-zero bytes, in address order.
+	///
+	/// Pages are [`PAGE_SIZE`] bytes long, except possibly the last page of contiguous RAM.
+	pub fn pages(&self) -> Vec<(u64, &[u8])> {
+		match self {
+			Memory::Contiguous(ram) => ram
+				.chunks(PAGE_SIZE as usize)
+				.enumerate()
+				.map(|(index, page)| (index as u64 * PAGE_SIZE, page))
+				.collect(),
+			Memory::Sparse(memory) => {
+				let mut pages = memory
+					.pages
+					.iter()
+					.map(|(&page, bytes)| (page * PAGE_SIZE, &bytes[..]))
+					.collect::<Vec<_>>();
+				pages.sort_unstable_by_key(|&(address, _)| address);
+				pages
+			},
+		}
+	}
+
+	/// Zeroes all of memory (freeing every page of sparse memory).
+	pub fn clear(&mut self) {
+		match self {
+			Memory::Contiguous(ram) => ram.fill(0),
+			Memory::Sparse(memory) => memory.pages.clear(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sparse(limit: u64, regions: &[(Range<u64>, RegionKind)]) -> Memory {
+		let mut memory = SparseMemory::new(limit);
+		for (range, kind) in regions {
+			memory.add_region(Region::new(range.clone(), *kind));
+		}
+		Memory::Sparse(memory)
+	}
+
+	fn allocated(memory: &Memory) -> u64 {
+		match memory {
+			Memory::Sparse(memory) => memory.allocated(),
+			Memory::Contiguous(_) => unreachable!(),
+		}
+	}
+
+	#[test]
+	fn sparse_pages_are_allocated_on_write() {
+		let mut memory = sparse(4 * PAGE_SIZE, &[]);
+		let high = 0xffff_ffff_0000_0000;
+
+		assert_eq!(memory.read(high, Size::Word), Some(0));
+		assert_eq!(allocated(&memory), 0);
+
+		memory.write(high + 4, Size::QuadByte, 0x1234_5678).unwrap();
+		assert_eq!(memory.read(high + 4, Size::QuadByte), Some(0x1234_5678));
+		assert_eq!(allocated(&memory), PAGE_SIZE);
+
+		// zeroing never allocates
+		memory.zero(0, 2 * PAGE_SIZE).unwrap();
+		assert_eq!(allocated(&memory), PAGE_SIZE);
+
+		memory.clear();
+		assert_eq!(allocated(&memory), 0);
+		assert_eq!(memory.read(high + 4, Size::QuadByte), Some(0));
+	}
+
+	#[test]
+	fn read_only_regions_reject_guest_writes() {
+		let mut memory = sparse(4 * PAGE_SIZE, &[(0x1000..0x2000, RegionKind::ReadOnly)]);
+
+		assert!(memory.is_mapped(0x1000, 8, false));
+		assert!(!memory.is_mapped(0x1000, 8, true));
+		assert_eq!(memory.write(0x1000, Size::Word, 1), None);
+		// a store that only partly overlaps the region fails as a whole
+		assert_eq!(memory.write_bytes(0xffc, &[1; 8]), None);
+		assert_eq!(memory.read(0xff8, Size::Word), Some(0));
+
+		// images can still be loaded into it
+		memory.load(0x1000, &[0xaa, 0xbb]).unwrap();
+		assert_eq!(memory.read(0x1000, Size::DoubleByte), Some(0xbbaa));
+	}
+
+	#[test]
+	fn unmapped_regions_reject_every_access() {
+		let mut memory = sparse(
+			4 * PAGE_SIZE,
+			&[(0x1000_0000..0x1000_1000, RegionKind::Unmapped)],
+		);
+
+		assert!(!memory.is_mapped(0x1000_0000, 1, false));
+		assert_eq!(memory.read(0x1000_0000, Size::Byte), None);
+		assert_eq!(memory.read(0x0fff_fffc, Size::Word), None);
+		assert_eq!(memory.write(0x1000_0000, Size::Byte, 1), None);
+		assert_eq!(memory.load(0x1000_0000, &[1]), None);
+		assert_eq!(memory.zero(0x1000_0000, 1), None);
+		assert_eq!(memory.read(0x1000_1000, Size::Byte), Some(0));
+	}
+
+	#[test]
+	fn sparse_memory_respects_its_limit() {
+		let mut memory = sparse(2 * PAGE_SIZE, &[]);
+
+		memory.write(0, Size::Byte, 1).unwrap();
+		memory.write(0x10_0000, Size::Byte, 2).unwrap();
+		assert_eq!(allocated(&memory), 2 * PAGE_SIZE);
+
+		// allocated pages can still be written, but no new ones
+		memory.write(0x10, Size::Byte, 3).unwrap();
+		assert!(!memory.is_mapped(0x20_0000, 1, true));
+		assert_eq!(memory.write(0x20_0000, Size::Byte, 4), None);
+		// nothing is written when only part of an access fits
+		assert_eq!(memory.write(PAGE_SIZE - 4, Size::Word, u64::MAX), None);
+		assert_eq!(memory.read(PAGE_SIZE - 4, Size::QuadByte), Some(0));
+		assert_eq!(memory.load(PAGE_SIZE - 1, &[1, 2]), None);
+		assert_eq!(memory.read(PAGE_SIZE - 1, Size::Byte), Some(0));
+		assert_eq!(allocated(&memory), 2 * PAGE_SIZE);
+
+		assert!(!memory.can_load(std::slice::from_ref(&(0x30_0000..0x30_0001))));
+		assert!(memory.can_restore(&[0x30_0000..0x30_0001, 0x40_0000..0x40_0001]));
+		assert!(!memory.can_restore(&[0..1, 0x30_0000..0x30_0001, 0x40_0000..0x40_0001]));
+	}
+
+	#[test]
+	fn accesses_past_the_end_of_the_address_space_fail() {
+		let mut memory = sparse(4 * PAGE_SIZE, &[]);
+		assert_eq!(memory.read(u64::MAX - 2, Size::QuadByte), None);
+		assert_eq!(memory.write(u64::MAX - 2, Size::QuadByte, 0), None);
+		assert!(!memory.is_mapped(u64::MAX, 2, false));
+	}
+}
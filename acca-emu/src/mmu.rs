@@ -0,0 +1,393 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use bitflags::bitflags;
+
+use crate::{
+	memory::Memory,
+	util::{PrivilegeLevel, Size},
+};
+
+pub const PAGE_SIZE: u64 = 4096;
+const PAGE_SHIFT: u64 = 12;
+
+/// The number of page table levels. Each level translates 9 bits of the virtual address.
+const LEVELS: u64 = 4;
+const LEVEL_BITS: u64 = 9;
+/// Virtual addresses must fit in this many bits; anything above them must be zero.
+pub const VIRTUAL_ADDRESS_BITS: u64 = PAGE_SHIFT + LEVELS * LEVEL_BITS;
+
+const TLB_ENTRIES: usize = 64;
+
+/// Setting this bit in `ptbase` enables paging.
+const PTBASE_ENABLE: u64 = 1;
+const PTBASE_RESERVED: u64 = (PAGE_SIZE - 1) & !PTBASE_ENABLE;
+
+bitflags! {
+	/// The permission bits of a page table entry.
+	///
+	/// Entries in the upper levels only need `VALID`; the permissions of the last level apply.
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct PageFlags: u64 {
+		const VALID = 1 << 0;
+		const READ = 1 << 1;
+		const WRITE = 1 << 2;
+		const EXECUTE = 1 << 3;
+		/// The page can be accessed from PL1. PL0 can access every valid page.
+		const PL1 = 1 << 4;
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+	Read,
+	Write,
+	Execute,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+	virtual_page: u64,
+	physical_page: u64,
+	flags: PageFlags,
+}
+
+/// A paging memory management unit with a small direct-mapped TLB.
+///
+/// Translation is controlled by the `ptbase` machine register, which holds the physical address of the root page
+/// table (which must be page-aligned) plus an enable bit. Page tables always live in memory (not devices).
+#[derive(Debug, Clone)]
+pub struct Mmu {
+	ptbase: u64,
+	tlb: [Option<TlbEntry>; TLB_ENTRIES],
+}
+
+impl Access {
+	fn allowed_by(&self, flags: PageFlags) -> bool {
+		flags.contains(match self {
+			Access::Read => PageFlags::READ,
+			Access::Write => PageFlags::WRITE,
+			Access::Execute => PageFlags::EXECUTE,
+		})
+	}
+
+	fn allowed_from(&self, flags: PageFlags, privilege_level: PrivilegeLevel) -> bool {
+		self.allowed_by(flags)
+			&& (privilege_level != PrivilegeLevel::PL1 || flags.contains(PageFlags::PL1))
+	}
+}
+
+impl Default for Mmu {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Mmu {
+	pub fn new() -> Self {
+		Self {
+			ptbase: 0,
+			tlb: [None; TLB_ENTRIES],
+		}
+	}
+
+	pub fn ptbase(&self) -> u64 {
+		self.ptbase
+	}
+
+	/// Sets `ptbase`, flushing the TLB. Returns `false` (and changes nothing) if reserved bits are set.
+	pub fn set_ptbase(&mut self, value: u64) -> bool {
+		if value & PTBASE_RESERVED != 0 {
+			return false;
+		}
+		self.ptbase = value;
+		self.flush();
+		true
+	}
+
+	pub fn enabled(&self) -> bool {
+		self.ptbase & PTBASE_ENABLE != 0
+	}
+
+	pub fn flush(&mut self) {
+		self.tlb = [None; TLB_ENTRIES];
+	}
+
+	/// Drops the cached translation of the page containing `address`, if there is one.
+	pub fn invalidate(&mut self, address: u64) {
+		let virtual_page = address >> PAGE_SHIFT;
+		let slot = &mut self.tlb[virtual_page as usize % TLB_ENTRIES];
+		if slot.is_some_and(|entry| entry.virtual_page == virtual_page) {
+			*slot = None;
+		}
+	}
+
+	/// Walks the page tables in `memory`, returning the physical page and flags that `virtual_page` maps to.
+	fn walk(&self, memory: &Memory, virtual_page: u64) -> Option<(u64, PageFlags)> {
+		let mut table = self.ptbase & !(PAGE_SIZE - 1);
+
+		for level in (0..LEVELS).rev() {
+			let index = (virtual_page >> (level * LEVEL_BITS)) & ((1 << LEVEL_BITS) - 1);
+			let entry = memory.read(table + index * 8, Size::Word)?;
+
+			let flags = PageFlags::from_bits_truncate(entry);
+			if !flags.contains(PageFlags::VALID) {
+				return None;
+			}
+
+			table = entry & !(PAGE_SIZE - 1);
+			if level == 0 {
+				return Some((table >> PAGE_SHIFT, flags));
+			}
+		}
+
+		unreachable!()
+	}
+
+	/// Translates `address` without checking permissions or touching the TLB.
+	///
+	/// This is meant for tools that need to see memory the way the guest does.
+	pub fn lookup(&self, memory: &Memory, address: u64) -> Option<u64> {
+		if !self.enabled() {
+			return Some(address);
+		}
+		if address >> VIRTUAL_ADDRESS_BITS != 0 {
+			return None;
+		}
+		self.walk(memory, address >> PAGE_SHIFT)
+			.map(|(physical_page, _)| (physical_page << PAGE_SHIFT) | (address & (PAGE_SIZE - 1)))
+	}
+
+	/// Translates `address` for an access from `privilege_level` like [`Mmu::translate`], but without touching the TLB.
+	///
+	/// This is meant for tools that access memory on the guest's behalf (e.g. debuggers).
+	pub fn lookup_access(
+		&self,
+		memory: &Memory,
+		address: u64,
+		access: Access,
+		privilege_level: PrivilegeLevel,
+	) -> Option<u64> {
+		if !self.enabled() {
+			return Some(address);
+		}
+		if address >> VIRTUAL_ADDRESS_BITS != 0 {
+			return None;
+		}
+
+		let (physical_page, flags) = self.walk(memory, address >> PAGE_SHIFT)?;
+		if !access.allowed_from(flags, privilege_level) {
+			return None;
+		}
+		Some((physical_page << PAGE_SHIFT) | (address & (PAGE_SIZE - 1)))
+	}
+
+	/// Translates `address` for an access from `privilege_level`, returning the physical address.
+	///
+	/// Returns `None` if the address isn't mapped or the access isn't allowed. Successful page table walks are
+	/// cached in the TLB, so the guest must invalidate translations after changing its page tables.
+	pub fn translate(
+		&mut self,
+		memory: &Memory,
+		address: u64,
+		access: Access,
+		privilege_level: PrivilegeLevel,
+	) -> Option<u64> {
+		if !self.enabled() {
+			return Some(address);
+		}
+		if address >> VIRTUAL_ADDRESS_BITS != 0 {
+			return None;
+		}
+
+		let virtual_page = address >> PAGE_SHIFT;
+		let slot = virtual_page as usize % TLB_ENTRIES;
+		let entry = match self.tlb[slot] {
+			Some(entry) if entry.virtual_page == virtual_page => entry,
+			_ => {
+				let (physical_page, flags) = self.walk(memory, virtual_page)?;
+				let entry = TlbEntry {
+					virtual_page,
+					physical_page,
+					flags,
+				};
+				self.tlb[slot] = Some(entry);
+				entry
+			},
+		};
+
+		if !access.allowed_from(entry.flags, privilege_level) {
+			return None;
+		}
+
+		Some((entry.physical_page << PAGE_SHIFT) | (address & (PAGE_SIZE - 1)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ROOT_TABLE: u64 = 0x1000;
+	/// Maps through the tables at 0x2000-0x4000 (the first entry of each upper level, and entry 2 of level 1).
+	const VIRTUAL: u64 = 0x40_0000;
+	const LEAF_ENTRY: u64 = 0x4000;
+
+	/// Sets up page tables mapping [`VIRTUAL`] to `physical` with `flags`, and an MMU using them.
+	fn map(physical: u64, flags: PageFlags) -> (Memory, Mmu) {
+		let mut memory = Memory::contiguous(8 * PAGE_SIZE as usize).unwrap();
+		let valid = PageFlags::VALID.bits();
+		memory
+			.write(ROOT_TABLE, Size::Word, 0x2000 | valid)
+			.unwrap();
+		memory.write(0x2000, Size::Word, 0x3000 | valid).unwrap();
+		memory
+			.write(0x3000 + 2 * 8, Size::Word, 0x4000 | valid)
+			.unwrap();
+		memory
+			.write(LEAF_ENTRY, Size::Word, physical | flags.bits())
+			.unwrap();
+
+		let mut mmu = Mmu::new();
+		assert!(mmu.set_ptbase(ROOT_TABLE | PTBASE_ENABLE));
+		(memory, mmu)
+	}
+
+	#[test]
+	fn translation_is_identity_when_disabled() {
+		let memory = Memory::contiguous(PAGE_SIZE as usize).unwrap();
+		let mut mmu = Mmu::new();
+		assert_eq!(
+			mmu.translate(&memory, 0x1234, Access::Write, PrivilegeLevel::PL1),
+			Some(0x1234)
+		);
+		assert!(!mmu.set_ptbase(ROOT_TABLE | 0x10));
+		assert!(!mmu.enabled());
+	}
+
+	#[test]
+	fn walk_checks_permissions() {
+		let flags = PageFlags::VALID | PageFlags::READ | PageFlags::PL1;
+		let (memory, mut mmu) = map(0x5000, flags);
+
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL + 0x24, Access::Read, PrivilegeLevel::PL1),
+			Some(0x5024)
+		);
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL, Access::Write, PrivilegeLevel::PL1),
+			None
+		);
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL, Access::Execute, PrivilegeLevel::PL0),
+			None
+		);
+		assert_eq!(
+			mmu.lookup_access(&memory, VIRTUAL, Access::Write, PrivilegeLevel::PL0),
+			None
+		);
+		// permissions don't matter to a plain lookup
+		assert_eq!(mmu.lookup(&memory, VIRTUAL + 4), Some(0x5004));
+	}
+
+	#[test]
+	fn pl1_needs_pl1_pages() {
+		let (memory, mut mmu) = map(0x5000, PageFlags::VALID | PageFlags::READ);
+
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL, Access::Read, PrivilegeLevel::PL0),
+			Some(0x5000)
+		);
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL, Access::Read, PrivilegeLevel::PL1),
+			None
+		);
+	}
+
+	#[test]
+	fn unmapped_addresses_fault() {
+		let (memory, mut mmu) = map(0x5000, PageFlags::VALID | PageFlags::READ);
+
+		// the next page has no leaf entry, and the upper levels of this one aren't valid
+		assert_eq!(
+			mmu.translate(
+				&memory,
+				VIRTUAL + PAGE_SIZE,
+				Access::Read,
+				PrivilegeLevel::PL0
+			),
+			None
+		);
+		assert_eq!(
+			mmu.translate(&memory, 1 << 30, Access::Read, PrivilegeLevel::PL0),
+			None
+		);
+		// above the virtual address space
+		assert_eq!(
+			mmu.translate(
+				&memory,
+				VIRTUAL | (1 << VIRTUAL_ADDRESS_BITS),
+				Access::Read,
+				PrivilegeLevel::PL0
+			),
+			None
+		);
+	}
+
+	#[test]
+	fn tlb_keeps_translations_until_invalidated() {
+		let (mut memory, mut mmu) = map(0x5000, PageFlags::VALID | PageFlags::READ);
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL, Access::Read, PrivilegeLevel::PL0),
+			Some(0x5000)
+		);
+
+		memory
+			.write(
+				LEAF_ENTRY,
+				Size::Word,
+				0x6000 | (PageFlags::VALID | PageFlags::READ).bits(),
+			)
+			.unwrap();
+		// the stale entry is still used, but lookups always walk the tables
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL, Access::Read, PrivilegeLevel::PL0),
+			Some(0x5000)
+		);
+		assert_eq!(mmu.lookup(&memory, VIRTUAL), Some(0x6000));
+
+		// a page that shares the TLB slot doesn't drop the entry
+		mmu.invalidate(VIRTUAL + TLB_ENTRIES as u64 * PAGE_SIZE);
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL, Access::Read, PrivilegeLevel::PL0),
+			Some(0x5000)
+		);
+
+		mmu.invalidate(VIRTUAL + 0x10);
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL, Access::Read, PrivilegeLevel::PL0),
+			Some(0x6000)
+		);
+	}
+
+	#[test]
+	fn changing_ptbase_flushes_the_tlb() {
+		let (mut memory, mut mmu) = map(0x5000, PageFlags::VALID | PageFlags::READ);
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL, Access::Read, PrivilegeLevel::PL0),
+			Some(0x5000)
+		);
+
+		memory.write(LEAF_ENTRY, Size::Word, 0).unwrap();
+		assert!(mmu.set_ptbase(ROOT_TABLE | PTBASE_ENABLE));
+		assert_eq!(
+			mmu.translate(&memory, VIRTUAL, Access::Read, PrivilegeLevel::PL0),
+			None
+		);
+	}
+}
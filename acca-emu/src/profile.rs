@@ -0,0 +1,232 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	io::{self, Write},
+};
+
+use crate::{
+	symbols::SymbolTable,
+	util::{CallKind, RegisterID},
+	vm::{Exception, StepResult, VM},
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+	/// The address of the call instruction that entered this frame, or of the instruction that was interrupted by
+	/// the exception that entered it.
+	call_site: u64,
+	exception: bool,
+}
+
+/// Counts the instructions executed at each address and in each call stack.
+///
+/// Call stacks are reconstructed by watching for taken calls, `ret`s, exceptions, and `eret`s, so only frames entered
+/// while profiling are known.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+	counts: HashMap<u64, u64>,
+	/// Instruction counts keyed by the call sites of the frames active when they were executed (outermost first),
+	/// followed by the address of the instruction itself.
+	stacks: HashMap<Vec<u64>, u64>,
+	frames: Vec<Frame>,
+	total: u64,
+	/// Reused to build stack keys without allocating for every instruction.
+	key: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct SymbolCounts {
+	/// Instructions executed within the symbol itself.
+	own: u64,
+	/// Instructions executed while the symbol was anywhere on the call stack.
+	total: u64,
+}
+
+/// Names an address by the function containing it, or by the address itself if there isn't one.
+///
+/// Local labels are skipped, so that samples in a loop count towards the function the loop is in.
+fn symbol_name(symbols: &SymbolTable, address: u64) -> String {
+	match symbols.lookup_global(address.into()) {
+		Some((name, _)) => name.to_owned(),
+		None => format!("{:#x}", address),
+	}
+}
+
+fn percentage(count: u64, total: u64) -> f64 {
+	if total == 0 {
+		0.0
+	} else {
+		count as f64 * 100.0 / total as f64
+	}
+}
+
+impl Profiler {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The total number of instructions executed while profiling.
+	pub fn total(&self) -> u64 {
+		self.total
+	}
+
+	/// Executes a single step of `vm` (just like [`VM::step`]), recording the instruction it executes.
+	pub fn step(&mut self, vm: &mut VM) -> StepResult {
+		if vm.exit_code().is_some() {
+			return vm.step();
+		}
+
+		let ip = vm.instruction_pointer();
+		let encoded = vm.fetch_instruction(ip);
+		let ip = u64::from(ip);
+
+		let result = vm.step();
+
+		// interrupts are taken instead of executing an instruction
+		if let StepResult::Exception(Exception::Interrupt(_)) = result {
+			self.frames.push(Frame {
+				call_site: ip,
+				exception: true,
+			});
+			return result;
+		}
+
+		self.record(ip);
+
+		if let StepResult::Exception(_) = result {
+			self.frames.push(Frame {
+				call_site: ip,
+				exception: true,
+			});
+			return result;
+		}
+
+		match encoded.map(CallKind::of) {
+			Some(CallKind::Call) => {
+				// a call that was actually taken jumps elsewhere and links back to the next instruction
+				let return_address = ip + 4;
+				let link = u64::from(vm.register_file()[RegisterID::LR].get_address());
+				if u64::from(vm.instruction_pointer()) != return_address && link == return_address {
+					self.frames.push(Frame {
+						call_site: ip,
+						exception: false,
+					});
+				}
+			},
+			Some(CallKind::Return) => {
+				if matches!(self.frames.last(), Some(frame) if !frame.exception) {
+					self.frames.pop();
+				}
+			},
+			Some(CallKind::ExceptionReturn) => {
+				// unwind any calls made by the handler along with the handler itself
+				if let Some(index) = self.frames.iter().rposition(|frame| frame.exception) {
+					self.frames.truncate(index);
+				}
+			},
+			Some(CallKind::Other) | None => {},
+		}
+
+		result
+	}
+
+	fn record(&mut self, address: u64) {
+		*self.counts.entry(address).or_default() += 1;
+		self.total += 1;
+
+		self.key.clear();
+		self.key
+			.extend(self.frames.iter().map(|frame| frame.call_site));
+		self.key.push(address);
+		match self.stacks.get_mut(self.key.as_slice()) {
+			Some(count) => *count += 1,
+			None => {
+				self.stacks.insert(self.key.clone(), 1);
+			},
+		}
+	}
+
+	/// Writes a report of the `top` symbols that the most instructions were executed in.
+	pub fn write_flat(
+		&self,
+		symbols: &SymbolTable,
+		top: usize,
+		output: &mut dyn Write,
+	) -> io::Result<()> {
+		let mut by_symbol: HashMap<String, SymbolCounts> = HashMap::new();
+
+		for (&address, &count) in &self.counts {
+			by_symbol
+				.entry(symbol_name(symbols, address))
+				.or_default()
+				.own += count;
+		}
+
+		for (stack, &count) in &self.stacks {
+			// recursive calls shouldn't count the same instructions more than once
+			let names: HashSet<String> = stack
+				.iter()
+				.map(|&address| symbol_name(symbols, address))
+				.collect();
+			for name in names {
+				by_symbol.entry(name).or_default().total += count;
+			}
+		}
+
+		let mut entries: Vec<_> = by_symbol.into_iter().collect();
+		entries.sort_by(|(a_name, a), (b_name, b)| {
+			b.own
+				.cmp(&a.own)
+				.then(b.total.cmp(&a.total))
+				.then(a_name.cmp(b_name))
+		});
+
+		writeln!(output, "{} instructions executed", self.total)?;
+		writeln!(output)?;
+		writeln!(
+			output,
+			"{:>12} {:>7} {:>12} {:>7}  symbol",
+			"self", "self%", "total", "total%"
+		)?;
+		for (name, counts) in entries.iter().take(top) {
+			writeln!(
+				output,
+				"{:>12} {:>6.2}% {:>12} {:>6.2}%  {}",
+				counts.own,
+				percentage(counts.own, self.total),
+				counts.total,
+				percentage(counts.total, self.total),
+				name
+			)?;
+		}
+
+		Ok(())
+	}
+
+	/// Writes the call stacks in the "folded" format used by flamegraph tools: one line per unique stack, with the
+	/// frames' symbols separated by semicolons (outermost first), followed by a space and the instruction count.
+	pub fn write_folded(&self, symbols: &SymbolTable, output: &mut dyn Write) -> io::Result<()> {
+		let mut folded: BTreeMap<String, u64> = BTreeMap::new();
+
+		for (stack, &count) in &self.stacks {
+			let names: Vec<String> = stack
+				.iter()
+				.map(|&address| symbol_name(symbols, address))
+				.collect();
+			*folded.entry(names.join(";")).or_default() += count;
+		}
+
+		for (stack, count) in folded {
+			writeln!(output, "{} {}", stack, count)?;
+		}
+
+		Ok(())
+	}
+}
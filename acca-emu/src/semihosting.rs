@@ -0,0 +1,365 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	fs::{File, OpenOptions},
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::{Component, Path, PathBuf},
+	time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bitflags::bitflags;
+use num_enum::TryFromPrimitive;
+
+use crate::{util::RegisterID, vm::VM};
+
+/// The `exc` number that makes a semihosting request (when semihosting is enabled).
+pub const SEMIHOSTING_EXCEPTION: u16 = 0xacca;
+
+/// The most bytes transferred by a single read or write; larger requests are shortened.
+const MAX_TRANSFER: u64 = 1024 * 1024;
+const MAX_PATH_LENGTH: u64 = 4096;
+
+// errors are returned as negated (Linux) errno values
+const EPERM: u64 = 1;
+const EIO: u64 = 5;
+const EBADF: u64 = 9;
+const EFAULT: u64 = 14;
+const EINVAL: u64 = 22;
+const ENAMETOOLONG: u64 = 36;
+const ENOSYS: u64 = 38;
+
+/// The operation requested, which is passed in `r0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u64)]
+pub enum Operation {
+	/// `open(path, path_length, flags) -> fd`
+	Open = 1,
+	/// `close(fd) -> 0`
+	Close = 2,
+	/// `read(fd, buffer, length) -> bytes read`
+	Read = 3,
+	/// `write(fd, buffer, length) -> bytes written`
+	Write = 4,
+	/// `seek(fd, offset, whence) -> new position`
+	Seek = 5,
+	/// `time() -> nanoseconds since the Unix epoch`
+	Time = 6,
+	/// `clock() -> nanoseconds since the VM started`
+	Clock = 7,
+	/// `arg_count() -> number of arguments`
+	ArgCount = 8,
+	/// `arg(index, buffer, length) -> length of the argument`
+	Arg = 9,
+	/// `exit(code)`
+	Exit = 10,
+}
+
+bitflags! {
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct OpenFlags: u64 {
+		const READ = 1 << 0;
+		const WRITE = 1 << 1;
+		const CREATE = 1 << 2;
+		const TRUNCATE = 1 << 3;
+		const APPEND = 1 << 4;
+	}
+}
+
+/// What the VM should do once a request has been handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+	/// Return the value in `r0` and continue with the next instruction.
+	Return(u64),
+	Exit(u64),
+}
+
+#[derive(Debug)]
+enum Handle {
+	Stdin,
+	Stdout,
+	Stderr,
+	File(File),
+}
+
+/// Host services for the guest: file I/O within a sandbox directory, clocks, command-line arguments, and exiting.
+///
+/// The guest makes requests with `exc 0xacca`, passing the operation in `r0` and its arguments in `r1`-`r6`. The result
+/// is returned in `r0`; errors are returned as negated errno values. File descriptors 0, 1, and 2 are the host's
+/// standard input, output, and error.
+#[derive(Debug)]
+pub struct Semihosting {
+	/// The (canonical) directory that guest paths are relative to. Paths can't leave it.
+	root: PathBuf,
+	args: Vec<String>,
+	started: Instant,
+	handles: Vec<Option<Handle>>,
+}
+
+fn error(errno: u64) -> u64 {
+	errno.wrapping_neg()
+}
+
+fn io_error(e: io::Error) -> u64 {
+	error(e.raw_os_error().map_or(EIO, |errno| errno as u64))
+}
+
+impl Semihosting {
+	/// Sets up semihosting with guest files in `root` and the given command-line arguments (including the program name).
+	pub fn new(root: &Path, args: Vec<String>) -> io::Result<Self> {
+		Ok(Self {
+			root: root.canonicalize()?,
+			args,
+			started: Instant::now(),
+			handles: vec![
+				Some(Handle::Stdin),
+				Some(Handle::Stdout),
+				Some(Handle::Stderr),
+			],
+		})
+	}
+
+	/// Handles the request described by the guest's registers.
+	pub fn handle(&mut self, vm: &mut VM) -> Outcome {
+		let registers = vm.register_file();
+		let [op, a, b, c] = [0, 1, 2, 3].map(|index| registers[RegisterID::from(index)].get());
+
+		let result = match Operation::try_from(op) {
+			Ok(Operation::Open) => self.open(vm, a, b, c),
+			Ok(Operation::Close) => self.close(a),
+			Ok(Operation::Read) => self.read(vm, a, b, c),
+			Ok(Operation::Write) => self.write(vm, a, b, c),
+			Ok(Operation::Seek) => self.seek(a, b as i64, c),
+			Ok(Operation::Time) => SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map_or(0, |time| time.as_nanos() as u64),
+			Ok(Operation::Clock) => self.started.elapsed().as_nanos() as u64,
+			Ok(Operation::ArgCount) => self.args.len() as u64,
+			Ok(Operation::Arg) => self.arg(vm, a, b, c),
+			Ok(Operation::Exit) => return Outcome::Exit(a),
+			Err(_) => error(ENOSYS),
+		};
+		Outcome::Return(result)
+	}
+
+	/// Resolves a guest path within the sandbox, rejecting absolute paths, `..`, and symlinks that lead outside of it.
+	fn resolve(&self, path: &str) -> Result<PathBuf, u64> {
+		let relative = Path::new(path);
+		if path.is_empty()
+			|| !relative
+				.components()
+				.all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+		{
+			return Err(EPERM);
+		}
+
+		let full = self.root.join(relative);
+		// the file itself may not exist yet (when creating it), but its directory must
+		let existing = match full.canonicalize() {
+			Ok(path) => path,
+			// a dangling symlink would be followed (out of the sandbox) when the file is created
+			Err(_)
+				if full
+					.symlink_metadata()
+					.is_ok_and(|metadata| metadata.file_type().is_symlink()) =>
+			{
+				return Err(EPERM)
+			},
+			Err(_) => full
+				.parent()
+				.and_then(|parent| parent.canonicalize().ok())
+				.ok_or(EPERM)?,
+		};
+		if !existing.starts_with(&self.root) {
+			return Err(EPERM);
+		}
+		Ok(full)
+	}
+
+	fn handle_mut(&mut self, fd: u64) -> Result<&mut Handle, u64> {
+		self.handles
+			.get_mut(fd as usize)
+			.and_then(Option::as_mut)
+			.ok_or(EBADF)
+	}
+
+	fn open(&mut self, vm: &mut VM, path: u64, length: u64, flags: u64) -> u64 {
+		let flags = match OpenFlags::from_bits(flags) {
+			Some(flags) if flags.intersects(OpenFlags::READ | OpenFlags::WRITE) => flags,
+			_ => return error(EINVAL),
+		};
+		if length > MAX_PATH_LENGTH {
+			return error(ENAMETOOLONG);
+		}
+
+		let mut path_bytes = vec![0u8; length as usize];
+		if vm.read_guest(path, &mut path_bytes).is_none() {
+			return error(EFAULT);
+		}
+		let path = match String::from_utf8(path_bytes) {
+			Ok(path) => path,
+			Err(_) => return error(EINVAL),
+		};
+		let path = match self.resolve(&path) {
+			Ok(path) => path,
+			Err(errno) => return error(errno),
+		};
+
+		let file = OpenOptions::new()
+			.read(flags.contains(OpenFlags::READ))
+			.write(flags.contains(OpenFlags::WRITE))
+			.create(flags.contains(OpenFlags::CREATE))
+			.truncate(flags.contains(OpenFlags::TRUNCATE))
+			.append(flags.contains(OpenFlags::APPEND))
+			.open(path);
+		match file {
+			Ok(file) => {
+				let handle = Some(Handle::File(file));
+				match self.handles.iter().position(Option::is_none) {
+					Some(fd) => {
+						self.handles[fd] = handle;
+						fd as u64
+					},
+					None => {
+						self.handles.push(handle);
+						self.handles.len() as u64 - 1
+					},
+				}
+			},
+			Err(e) => io_error(e),
+		}
+	}
+
+	fn close(&mut self, fd: u64) -> u64 {
+		match self.handles.get_mut(fd as usize) {
+			Some(handle @ Some(_)) => {
+				*handle = None;
+				0
+			},
+			_ => error(EBADF),
+		}
+	}
+
+	fn read(&mut self, vm: &mut VM, fd: u64, buffer: u64, length: u64) -> u64 {
+		let mut data = vec![0u8; length.min(MAX_TRANSFER) as usize];
+		let result = match self.handle_mut(fd) {
+			Ok(Handle::Stdin) => io::stdin().read(&mut data),
+			Ok(Handle::File(file)) => file.read(&mut data),
+			Ok(_) => return error(EBADF),
+			Err(errno) => return error(errno),
+		};
+
+		match result {
+			Ok(count) => match vm.write_guest(buffer, &data[..count]) {
+				Some(()) => count as u64,
+				None => error(EFAULT),
+			},
+			Err(e) => io_error(e),
+		}
+	}
+
+	fn write(&mut self, vm: &mut VM, fd: u64, buffer: u64, length: u64) -> u64 {
+		let mut data = vec![0u8; length.min(MAX_TRANSFER) as usize];
+		if vm.read_guest(buffer, &mut data).is_none() {
+			return error(EFAULT);
+		}
+
+		let result = match self.handle_mut(fd) {
+			Ok(Handle::Stdout) => {
+				let mut stdout = io::stdout();
+				stdout.write_all(&data).and_then(|_| stdout.flush())
+			},
+			Ok(Handle::Stderr) => io::stderr().write_all(&data),
+			Ok(Handle::File(file)) => file.write_all(&data),
+			Ok(Handle::Stdin) => return error(EBADF),
+			Err(errno) => return error(errno),
+		};
+
+		match result {
+			Ok(()) => data.len() as u64,
+			Err(e) => io_error(e),
+		}
+	}
+
+	fn seek(&mut self, fd: u64, offset: i64, whence: u64) -> u64 {
+		let position = match whence {
+			0 if offset >= 0 => SeekFrom::Start(offset as u64),
+			1 => SeekFrom::Current(offset),
+			2 => SeekFrom::End(offset),
+			_ => return error(EINVAL),
+		};
+
+		match self.handle_mut(fd) {
+			Ok(Handle::File(file)) => file.seek(position).unwrap_or_else(io_error),
+			Ok(_) => error(EINVAL),
+			Err(errno) => error(errno),
+		}
+	}
+
+	fn arg(&self, vm: &mut VM, index: u64, buffer: u64, length: u64) -> u64 {
+		let arg = match self.args.get(index as usize) {
+			Some(arg) => arg.as_bytes(),
+			None => return error(EINVAL),
+		};
+
+		let count = arg.len().min(length as usize);
+		match vm.write_guest(buffer, &arg[..count]) {
+			Some(()) => arg.len() as u64,
+			None => error(EFAULT),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sandbox(name: &str) -> PathBuf {
+		let directory = std::env::temp_dir().join(format!(
+			"acca-emu-semihosting-{}-{}",
+			name,
+			std::process::id()
+		));
+		let _ = std::fs::remove_dir_all(&directory);
+		std::fs::create_dir_all(directory.join("root")).unwrap();
+		directory
+	}
+
+	#[test]
+	fn resolve_rejects_escapes() {
+		let directory = sandbox("escape");
+		let semihosting = Semihosting::new(&directory.join("root"), Vec::new()).unwrap();
+
+		assert_eq!(semihosting.resolve(""), Err(EPERM));
+		assert_eq!(semihosting.resolve("/etc/passwd"), Err(EPERM));
+		assert_eq!(semihosting.resolve("../outside"), Err(EPERM));
+		assert_eq!(
+			semihosting.resolve("./new.txt"),
+			Ok(semihosting.root.join("./new.txt"))
+		);
+
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn resolve_rejects_dangling_symlinks() {
+		let directory = sandbox("symlink");
+		let root = directory.join("root");
+		std::os::unix::fs::symlink(directory.join("outside-new"), root.join("out")).unwrap();
+		std::os::unix::fs::symlink(directory.join("root"), root.join("inside")).unwrap();
+		let semihosting = Semihosting::new(&root, Vec::new()).unwrap();
+
+		assert_eq!(semihosting.resolve("out"), Err(EPERM));
+		// symlinks that stay within the sandbox are fine
+		assert!(semihosting.resolve("inside/new.txt").is_ok());
+		assert!(!directory.join("outside-new").exists());
+
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+}
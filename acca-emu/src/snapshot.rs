@@ -0,0 +1,293 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	collections::BTreeMap,
+	fmt,
+	fs::File,
+	io::{self, BufReader, BufWriter, Read, Write},
+	path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+const MAGIC: &[u8; 8] = b"ACCASNAP";
+const VERSION: u32 = 3;
+/// Version 2 snapshots are identical, except that they're always of contiguous RAM.
+const VERSION_WITHOUT_SPARSE_MEMORY: u32 = 2;
+/// Version 1 snapshots don't have `ptbase` either.
+const VERSION_WITHOUT_PTBASE: u32 = 1;
+
+/// RAM is stored in chunks of this size; chunks that are entirely zero are omitted.
+pub const SNAPSHOT_PAGE_SIZE: u64 = 4096;
+
+/// The number of words in the cached exception configuration table (2 privilege levels * 8 exceptions * 3 words).
+pub const ECTABLE_WORDS: usize = 48;
+
+/// The complete architectural state of a VM (plus the state of its built-in timer).
+///
+/// The state of attached devices isn't included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+	/// Whether the VM used sparse memory rather than contiguous RAM.
+	pub sparse_memory: bool,
+	/// The size of contiguous RAM, or the allocation limit of sparse memory.
+	pub ram_size: u64,
+	/// The non-zero pages of RAM, keyed by their address. Each is [`SNAPSHOT_PAGE_SIZE`] bytes long,
+	/// except possibly the last page of contiguous RAM.
+	pub ram_pages: BTreeMap<u64, Vec<u8>>,
+
+	pub registers: [u64; 16],
+	pub flags: u64,
+	pub instruction_pointer: u64,
+
+	pub elr: u64,
+	pub esp: u64,
+	pub eflags: u64,
+	pub einfo: u64,
+	pub eaddr: u64,
+	pub evtable_addr: u64,
+	pub ectable_addr: u64,
+	pub ectable: [u64; ECTABLE_WORDS],
+	/// Always 0 for VMs without an MMU.
+	pub ptbase: u64,
+
+	pub timer_control: u64,
+	pub timer_interval: u64,
+	pub timer_remaining: u64,
+	pub pending_interrupts: Vec<u64>,
+	pub exit_code: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+	Io(io::Error),
+	/// The file isn't a snapshot.
+	BadMagic,
+	UnsupportedVersion(u32),
+	/// The file is a snapshot, but its contents are inconsistent.
+	Corrupt(&'static str),
+	/// The snapshot was taken from a VM with a different kind of memory (sparse if `sparse` is set).
+	MemoryKindMismatch {
+		sparse: bool,
+	},
+	/// The snapshot was taken from a VM with a different amount of RAM.
+	RamSizeMismatch {
+		expected: u64,
+		found: u64,
+	},
+	/// A register in the snapshot holds a value the VM doesn't accept.
+	InvalidState(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			SnapshotError::Io(e) => write!(f, "{}", e),
+			SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+			SnapshotError::UnsupportedVersion(version) => {
+				write!(f, "unsupported snapshot version {}", version)
+			},
+			SnapshotError::Corrupt(what) => write!(f, "corrupt snapshot: {}", what),
+			SnapshotError::MemoryKindMismatch { sparse } => {
+				let kinds = ["contiguous", "sparse"];
+				write!(
+					f,
+					"snapshot has {} memory, but the VM has {} memory",
+					kinds[*sparse as usize], kinds[!*sparse as usize]
+				)
+			},
+			SnapshotError::RamSizeMismatch { expected, found } => write!(
+				f,
+				"snapshot has {:#x} bytes of RAM, but the VM has {:#x}",
+				found, expected
+			),
+			SnapshotError::InvalidState(what) => write!(f, "invalid {} in snapshot", what),
+		}
+	}
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+	fn from(value: io::Error) -> Self {
+		Self::Io(value)
+	}
+}
+
+fn write_u64s(writer: &mut impl Write, values: &[u64]) -> io::Result<()> {
+	for &value in values {
+		writer.write_u64::<LittleEndian>(value)?;
+	}
+	Ok(())
+}
+
+fn read_u64s<const N: usize>(reader: &mut impl Read) -> io::Result<[u64; N]> {
+	let mut values = [0; N];
+	reader.read_u64_into::<LittleEndian>(&mut values)?;
+	Ok(values)
+}
+
+impl Snapshot {
+	/// Records the non-zero ones among `pages`, given as `(address, contents)` pairs.
+	pub fn sparse_pages<'a>(
+		pages: impl IntoIterator<Item = (u64, &'a [u8])>,
+	) -> BTreeMap<u64, Vec<u8>> {
+		pages
+			.into_iter()
+			.filter(|(_, page)| page.iter().any(|&byte| byte != 0))
+			.map(|(address, page)| (address, page.to_vec()))
+			.collect()
+	}
+
+	pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+		writer.write_all(MAGIC)?;
+		writer.write_u32::<LittleEndian>(VERSION)?;
+
+		write_u64s(writer, &self.registers)?;
+		write_u64s(
+			writer,
+			&[
+				self.flags,
+				self.instruction_pointer,
+				self.elr,
+				self.esp,
+				self.eflags,
+				self.einfo,
+				self.eaddr,
+				self.evtable_addr,
+				self.ectable_addr,
+			],
+		)?;
+		write_u64s(writer, &self.ectable)?;
+		writer.write_u64::<LittleEndian>(self.ptbase)?;
+
+		write_u64s(
+			writer,
+			&[
+				self.timer_control,
+				self.timer_interval,
+				self.timer_remaining,
+			],
+		)?;
+		writer.write_u64::<LittleEndian>(self.pending_interrupts.len() as u64)?;
+		write_u64s(writer, &self.pending_interrupts)?;
+		match self.exit_code {
+			Some(code) => write_u64s(writer, &[1, code])?,
+			None => write_u64s(writer, &[0, 0])?,
+		}
+
+		write_u64s(
+			writer,
+			&[
+				self.sparse_memory as u64,
+				self.ram_size,
+				self.ram_pages.len() as u64,
+			],
+		)?;
+		for (&address, page) in &self.ram_pages {
+			writer.write_u64::<LittleEndian>(address)?;
+			writer.write_all(page)?;
+		}
+
+		Ok(())
+	}
+
+	pub fn read_from(reader: &mut impl Read) -> Result<Self, SnapshotError> {
+		let mut magic = [0u8; 8];
+		reader.read_exact(&mut magic)?;
+		if &magic != MAGIC {
+			return Err(SnapshotError::BadMagic);
+		}
+
+		let version = reader.read_u32::<LittleEndian>()?;
+		if !(VERSION_WITHOUT_PTBASE..=VERSION).contains(&version) {
+			return Err(SnapshotError::UnsupportedVersion(version));
+		}
+
+		let registers = read_u64s::<16>(reader)?;
+		let [flags, instruction_pointer, elr, esp, eflags, einfo, eaddr, evtable_addr, ectable_addr] =
+			read_u64s::<9>(reader)?;
+		let ectable = read_u64s::<ECTABLE_WORDS>(reader)?;
+		let ptbase = match version {
+			VERSION_WITHOUT_PTBASE => 0,
+			_ => reader.read_u64::<LittleEndian>()?,
+		};
+
+		let [timer_control, timer_interval, timer_remaining] = read_u64s::<3>(reader)?;
+		let pending_count = reader.read_u64::<LittleEndian>()?;
+		let pending_interrupts = (0..pending_count)
+			.map(|_| reader.read_u64::<LittleEndian>())
+			.collect::<io::Result<Vec<_>>>()?;
+		let exit_code = match read_u64s::<2>(reader)? {
+			[0, _] => None,
+			[1, code] => Some(code),
+			_ => return Err(SnapshotError::Corrupt("invalid exit status")),
+		};
+
+		let sparse_memory = match version {
+			VERSION_WITHOUT_PTBASE | VERSION_WITHOUT_SPARSE_MEMORY => false,
+			_ => match reader.read_u64::<LittleEndian>()? {
+				0 => false,
+				1 => true,
+				_ => return Err(SnapshotError::Corrupt("invalid memory kind")),
+			},
+		};
+		let [ram_size, page_count] = read_u64s::<2>(reader)?;
+		let mut ram_pages = BTreeMap::new();
+		for _ in 0..page_count {
+			let address = reader.read_u64::<LittleEndian>()?;
+			if address % SNAPSHOT_PAGE_SIZE != 0 || (!sparse_memory && address >= ram_size) {
+				return Err(SnapshotError::Corrupt("invalid page address"));
+			}
+
+			let page_size = match sparse_memory {
+				true => SNAPSHOT_PAGE_SIZE,
+				false => SNAPSHOT_PAGE_SIZE.min(ram_size - address),
+			};
+			let mut page = vec![0u8; page_size as usize];
+			reader.read_exact(&mut page)?;
+			if ram_pages.insert(address, page).is_some() {
+				return Err(SnapshotError::Corrupt("duplicate page"));
+			}
+		}
+
+		Ok(Self {
+			sparse_memory,
+			ram_size,
+			ram_pages,
+			registers,
+			flags,
+			instruction_pointer,
+			elr,
+			esp,
+			eflags,
+			einfo,
+			eaddr,
+			evtable_addr,
+			ectable_addr,
+			ectable,
+			ptbase,
+			timer_control,
+			timer_interval,
+			timer_remaining,
+			pending_interrupts,
+			exit_code,
+		})
+	}
+
+	pub fn save(&self, path: &Path) -> io::Result<()> {
+		let mut writer = BufWriter::new(File::create(path)?);
+		self.write_to(&mut writer)?;
+		writer.flush()
+	}
+
+	pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+		Self::read_from(&mut BufReader::new(File::open(path)?))
+	}
+}
@@ -0,0 +1,252 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	fs,
+	io::{self, ErrorKind},
+	path::Path,
+};
+
+use goblin::elf::{sym, Elf};
+
+use crate::util::VMAddress;
+
+/// Symbol maps don't record symbol sizes, so addresses further than this from the preceding symbol are considered
+/// to be outside of it (e.g. stack or heap addresses past the end of the image).
+const MAX_SYMBOL_OFFSET: u64 = 0x10000;
+
+/// A mapping between symbol names and guest addresses.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+	by_name: HashMap<String, u64>,
+	by_address: BTreeMap<u64, String>,
+	/// Like `by_address`, but without local labels.
+	globals_by_address: BTreeMap<u64, String>,
+	local_labels: HashSet<String>,
+}
+
+/// Checks whether `name` follows one of the usual conventions for local labels (e.g. `.loop` or `1`).
+fn is_local_label_name(name: &str) -> bool {
+	name.starts_with('.') || name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+impl SymbolTable {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Loads a symbol map as written by `acca-as --symbol-map`.
+	///
+	/// Each non-empty line contains an address followed by a symbol name, separated by whitespace.
+	/// Lines starting with `#` are ignored.
+	pub fn load_map(path: &Path) -> io::Result<Self> {
+		Self::parse_map(&fs::read_to_string(path)?)
+	}
+
+	fn parse_map(map: &str) -> io::Result<Self> {
+		let mut table = Self::new();
+
+		for (index, line) in map.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			let invalid_line = || {
+				io::Error::new(
+					ErrorKind::InvalidData,
+					format!("invalid symbol map entry on line {}", index + 1),
+				)
+			};
+
+			let mut fields = line.split_whitespace();
+			let address = fields
+				.next()
+				.and_then(parse_address)
+				.ok_or_else(invalid_line)?;
+			let name = fields.next().ok_or_else(invalid_line)?;
+
+			table.insert(name, address.into());
+		}
+
+		Ok(table)
+	}
+
+	/// Loads the symbols defined in an ELF file's symbol table.
+	///
+	/// Section and file symbols are ignored, as are undefined symbols. Local symbols without a type are treated as
+	/// local labels.
+	pub fn load_elf(path: &Path) -> io::Result<Self> {
+		Self::parse_elf(&fs::read(path)?)
+	}
+
+	fn parse_elf(bytes: &[u8]) -> io::Result<Self> {
+		let elf = Elf::parse(bytes).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+		Ok(Self::from_elf(&elf))
+	}
+
+	pub fn from_elf(elf: &Elf) -> Self {
+		let mut table = Self::new();
+
+		for symbol in elf.syms.iter() {
+			if symbol.st_shndx == 0
+				|| !matches!(
+					symbol.st_type(),
+					sym::STT_NOTYPE | sym::STT_OBJECT | sym::STT_FUNC
+				) {
+				continue;
+			}
+			let local_label =
+				symbol.st_bind() == sym::STB_LOCAL && symbol.st_type() == sym::STT_NOTYPE;
+			match elf.strtab.get_at(symbol.st_name) {
+				Some(name) if !name.is_empty() && local_label => {
+					table.insert_local_label(name, symbol.st_value.into())
+				},
+				Some(name) if !name.is_empty() => table.insert(name, symbol.st_value.into()),
+				_ => {},
+			}
+		}
+
+		table
+	}
+
+	/// Loads either an ELF file's symbols or a symbol map, depending on the contents of the file.
+	pub fn load(path: &Path) -> io::Result<Self> {
+		let bytes = fs::read(path)?;
+		if bytes.starts_with(b"\x7fELF") {
+			Self::parse_elf(&bytes)
+		} else {
+			let map =
+				String::from_utf8(bytes).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+			Self::parse_map(&map)
+		}
+	}
+
+	/// Adds a symbol. Names like `.loop` or `1` are assumed to be local labels.
+	pub fn insert(&mut self, name: &str, address: VMAddress) {
+		self.insert_symbol(name, address, is_local_label_name(name));
+	}
+
+	/// Adds a local label, i.e. a symbol that marks a place inside a function rather than the start of one.
+	pub fn insert_local_label(&mut self, name: &str, address: VMAddress) {
+		self.insert_symbol(name, address, true);
+	}
+
+	fn insert_symbol(&mut self, name: &str, address: VMAddress, local_label: bool) {
+		let address = u64::from(address);
+		self.by_name.insert(name.to_owned(), address);
+		// if multiple symbols share an address, the first one wins for reverse lookups
+		self.by_address
+			.entry(address)
+			.or_insert_with(|| name.to_owned());
+		if local_label {
+			self.local_labels.insert(name.to_owned());
+		} else {
+			self.globals_by_address
+				.entry(address)
+				.or_insert_with(|| name.to_owned());
+		}
+	}
+
+	/// Adds all of the symbols in `other` to this table.
+	pub fn extend(&mut self, other: &SymbolTable) {
+		// primary names first, so that they keep winning reverse lookups
+		for (&address, name) in other.globals_by_address.iter().chain(&other.by_address) {
+			self.insert_symbol(name, address.into(), other.local_labels.contains(name));
+		}
+		for (name, &address) in &other.by_name {
+			self.insert_symbol(name, address.into(), other.local_labels.contains(name));
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.by_name.is_empty()
+	}
+
+	pub fn address_of(&self, name: &str) -> Option<VMAddress> {
+		self.by_name.get(name).map(|&address| address.into())
+	}
+
+	/// Finds the closest symbol at or before `address`, returning its name and the offset of `address` from it.
+	pub fn lookup(&self, address: VMAddress) -> Option<(&str, u64)> {
+		let address = u64::from(address);
+		self.by_address
+			.range(..=address)
+			.next_back()
+			.map(|(&symbol_address, name)| (name.as_str(), address - symbol_address))
+			.filter(|&(_, offset)| offset < MAX_SYMBOL_OFFSET)
+	}
+
+	/// Like [`SymbolTable::lookup`], but skips local labels, so addresses inside a loop are attributed to the
+	/// function containing it.
+	pub fn lookup_global(&self, address: VMAddress) -> Option<(&str, u64)> {
+		let address = u64::from(address);
+		self.globals_by_address
+			.range(..=address)
+			.next_back()
+			.map(|(&symbol_address, name)| (name.as_str(), address - symbol_address))
+			.filter(|&(_, offset)| offset < MAX_SYMBOL_OFFSET)
+	}
+
+	/// Formats `address` as `name` or `name+offset` if there's a symbol at or before it.
+	pub fn describe(&self, address: VMAddress) -> Option<String> {
+		self.lookup(address).map(|(name, offset)| match offset {
+			0 => name.to_owned(),
+			_ => format!("{}+{:#x}", name, offset),
+		})
+	}
+}
+
+/// Parses an address written in hexadecimal (with a `0x` prefix) or decimal.
+pub fn parse_address(string: &str) -> Option<u64> {
+	let string = string.replace('_', "");
+	match string
+		.strip_prefix("0x")
+		.or_else(|| string.strip_prefix("0X"))
+	{
+		Some(hex) => u64::from_str_radix(hex, 16).ok(),
+		None => string.parse().ok(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn global_lookups_skip_local_labels() {
+		let mut table =
+			SymbolTable::parse_map("0x400 print_string\n0x410 .loop\n0x420 1\n0x440 main\n")
+				.unwrap();
+		table.insert_local_label("print_string_done", 0x430.into());
+
+		assert_eq!(table.lookup(0x414.into()), Some((".loop", 4)));
+		assert_eq!(
+			table.lookup_global(0x414.into()),
+			Some(("print_string", 0x14))
+		);
+		assert_eq!(
+			table.lookup_global(0x424.into()),
+			Some(("print_string", 0x24))
+		);
+		assert_eq!(
+			table.lookup_global(0x434.into()),
+			Some(("print_string", 0x34))
+		);
+		assert_eq!(table.lookup_global(0x444.into()), Some(("main", 4)));
+
+		// local labels stay local when tables are merged
+		let mut merged = SymbolTable::new();
+		merged.extend(&table);
+		assert_eq!(
+			merged.lookup_global(0x434.into()),
+			Some(("print_string", 0x34))
+		);
+	}
+}
@@ -0,0 +1,188 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::time::{Duration, Instant};
+
+use bitflags::bitflags;
+
+bitflags! {
+	/// The format of the `vm_timer_control` machine register.
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct TimerControl: u64 {
+		/// The timer is counting down and will fire once the interval elapses.
+		const ENABLE = 1 << 0;
+		/// Restart the countdown after firing; otherwise, the timer disables itself after firing once.
+		const PERIODIC = 1 << 1;
+		/// Measure the interval in microseconds of host time rather than in executed instructions.
+		const HOST_TIME = 1 << 2;
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Countdown {
+	Instructions(u64),
+	Deadline(Instant),
+}
+
+/// A programmable interval timer that raises an interrupt every `interval` instructions or microseconds.
+#[derive(Debug, Clone)]
+pub struct Timer {
+	interrupt: u64,
+	control: TimerControl,
+	interval: u64,
+	countdown: Countdown,
+}
+
+impl Timer {
+	pub fn new(interrupt: u64) -> Self {
+		Self {
+			interrupt,
+			control: TimerControl::empty(),
+			interval: 0,
+			countdown: Countdown::Instructions(0),
+		}
+	}
+
+	/// The interrupt number raised when the timer fires.
+	pub fn interrupt(&self) -> u64 {
+		self.interrupt
+	}
+
+	pub fn control(&self) -> u64 {
+		self.control.bits()
+	}
+
+	/// Updates the timer's configuration, restarting the countdown.
+	///
+	/// Returns `false` if `value` contains reserved bits.
+	pub fn set_control(&mut self, value: u64) -> bool {
+		match TimerControl::from_bits(value) {
+			Some(control) => {
+				self.control = control;
+				self.restart();
+				true
+			},
+			None => false,
+		}
+	}
+
+	pub fn interval(&self) -> u64 {
+		self.interval
+	}
+
+	/// Updates the timer's interval, restarting the countdown. An interval of 0 keeps the timer from firing.
+	pub fn set_interval(&mut self, interval: u64) {
+		self.interval = interval;
+		self.restart();
+	}
+
+	/// How long until the timer fires, in instructions or (with [`TimerControl::HOST_TIME`]) microseconds.
+	pub fn remaining(&self) -> u64 {
+		match self.countdown {
+			Countdown::Instructions(remaining) => remaining,
+			Countdown::Deadline(deadline) => deadline
+				.saturating_duration_since(Instant::now())
+				.as_micros()
+				.try_into()
+				.unwrap_or(u64::MAX),
+		}
+	}
+
+	/// Restores a state previously saved with [`Timer::control`], [`Timer::interval`], and [`Timer::remaining`].
+	///
+	/// Returns `false` if `control` contains reserved bits.
+	pub fn restore(&mut self, control: u64, interval: u64, remaining: u64) -> bool {
+		let control = match TimerControl::from_bits(control) {
+			Some(x) => x,
+			None => return false,
+		};
+
+		self.control = control;
+		self.interval = interval;
+		self.countdown = if control.contains(TimerControl::HOST_TIME) {
+			Countdown::Deadline(Instant::now() + Duration::from_micros(remaining))
+		} else {
+			Countdown::Instructions(remaining)
+		};
+		true
+	}
+
+	fn restart(&mut self) {
+		self.countdown = if self.control.contains(TimerControl::HOST_TIME) {
+			Countdown::Deadline(Instant::now() + Duration::from_micros(self.interval))
+		} else {
+			Countdown::Instructions(self.interval)
+		};
+	}
+
+	/// Advances the timer by one instruction, returning `true` if it fired.
+	pub fn tick(&mut self) -> bool {
+		// an empty interval would fire on every instruction, leaving the guest no time to do anything else
+		if !self.control.contains(TimerControl::ENABLE) || self.interval == 0 {
+			return false;
+		}
+
+		let fired = match &mut self.countdown {
+			Countdown::Instructions(remaining) => {
+				*remaining = remaining.saturating_sub(1);
+				*remaining == 0
+			},
+			Countdown::Deadline(deadline) => Instant::now() >= *deadline,
+		};
+
+		if fired {
+			if self.control.contains(TimerControl::PERIODIC) {
+				self.restart();
+			} else {
+				self.control.remove(TimerControl::ENABLE);
+			}
+		}
+
+		fired
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ticks_until_fired(timer: &mut Timer, limit: u64) -> Option<u64> {
+		(1..=limit).find(|_| timer.tick())
+	}
+
+	#[test]
+	fn fires_after_the_interval() {
+		let mut timer = Timer::new(1);
+		timer.set_interval(3);
+		assert!(!timer.tick());
+
+		assert!(timer.set_control(TimerControl::ENABLE.bits()));
+		assert_eq!(ticks_until_fired(&mut timer, 10), Some(3));
+		// one-shot timers disable themselves
+		assert_eq!(timer.control(), 0);
+		assert_eq!(ticks_until_fired(&mut timer, 10), None);
+
+		assert!(timer.set_control((TimerControl::ENABLE | TimerControl::PERIODIC).bits()));
+		assert_eq!(ticks_until_fired(&mut timer, 10), Some(3));
+		assert_eq!(ticks_until_fired(&mut timer, 10), Some(3));
+	}
+
+	#[test]
+	fn empty_intervals_never_fire() {
+		let mut timer = Timer::new(1);
+		assert!(timer.set_control((TimerControl::ENABLE | TimerControl::PERIODIC).bits()));
+		assert_eq!(ticks_until_fired(&mut timer, 10), None);
+
+		assert!(timer.set_control((TimerControl::ENABLE | TimerControl::HOST_TIME).bits()));
+		assert_eq!(ticks_until_fired(&mut timer, 10), None);
+
+		timer.set_interval(2);
+		assert!(timer.set_control(TimerControl::ENABLE.bits()));
+		assert_eq!(ticks_until_fired(&mut timer, 10), Some(2));
+	}
+}
@@ -0,0 +1,326 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	fmt,
+	fs::File,
+	io::{self, BufWriter, Write},
+	path::Path,
+};
+
+use serde::{Serialize, Serializer};
+
+use crate::{
+	util::{Condition, RegisterID, Size},
+	vm::Exception,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+	/// One human-readable block per instruction.
+	Text,
+	/// One JSON object per line.
+	Json,
+}
+
+/// A decoded instruction operand.
+#[derive(Debug, Clone, Copy)]
+pub enum Operand {
+	/// A register, or `None` for the null register.
+	Register(Option<RegisterID>),
+	Immediate(u64),
+	/// A sign-extended immediate that's relative to the next instruction.
+	Relative(i64),
+	Boolean(bool),
+	Size(Size),
+	/// A condition, or `None` for an unconditional instruction.
+	Condition(Option<Condition>),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NamedOperand {
+	pub name: &'static str,
+	pub value: Operand,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InstructionInfo {
+	pub encoding: u32,
+	pub mnemonic: &'static str,
+	pub operands: Vec<NamedOperand>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RegisterWrite {
+	#[serde(serialize_with = "serialize_display")]
+	pub register: RegisterID,
+	pub old: u64,
+	pub new: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FlagsChange {
+	pub old: u64,
+	pub new: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryAccess {
+	pub address: u64,
+	pub size: u8,
+	pub write: bool,
+	/// The value read or written.
+	pub value: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExceptionInfo {
+	#[serde(serialize_with = "serialize_debug")]
+	pub exception: Exception,
+	/// The value `einfo` is (or would be) set to when the exception is taken.
+	pub einfo: u64,
+	/// The value `eaddr` is (or would be) set to when the exception is taken.
+	pub eaddr: u64,
+	/// Whether the exception was delivered to the guest (as opposed to being trapped by the host).
+	pub taken: bool,
+}
+
+/// Everything that happened during a single [`VM::step`](crate::vm::VM::step).
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceRecord {
+	/// The number of steps traced before this one.
+	pub index: u64,
+	/// The instruction pointer at the start of the step.
+	pub address: u64,
+	/// The instruction executed; `None` if no instruction was decoded (e.g. when an interrupt was taken instead
+	/// or the instruction couldn't be fetched).
+	pub instruction: Option<InstructionInfo>,
+	pub register_writes: Vec<RegisterWrite>,
+	pub flags: Option<FlagsChange>,
+	pub memory_accesses: Vec<MemoryAccess>,
+	pub exception: Option<ExceptionInfo>,
+}
+
+/// Writes [`TraceRecord`]s to a file in one of the [`TraceFormat`]s.
+///
+/// Write errors don't interrupt the guest; the first one is kept and reported by [`Tracer::finish`].
+pub struct Tracer {
+	output: Box<dyn Write>,
+	format: TraceFormat,
+	next_index: u64,
+	error: Option<io::Error>,
+}
+
+impl fmt::Debug for Tracer {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Tracer")
+			.field("format", &self.format)
+			.field("next_index", &self.next_index)
+			.finish()
+	}
+}
+
+fn serialize_display<T: fmt::Display, S: Serializer>(
+	value: &T,
+	serializer: S,
+) -> Result<S::Ok, S::Error> {
+	serializer.collect_str(value)
+}
+
+fn serialize_debug<T: fmt::Debug, S: Serializer>(
+	value: &T,
+	serializer: S,
+) -> Result<S::Ok, S::Error> {
+	serializer.collect_str(&format_args!("{:?}", value))
+}
+
+impl From<RegisterID> for Operand {
+	fn from(value: RegisterID) -> Self {
+		Self::Register(Some(value))
+	}
+}
+
+impl From<Option<RegisterID>> for Operand {
+	fn from(value: Option<RegisterID>) -> Self {
+		Self::Register(value)
+	}
+}
+
+impl From<u64> for Operand {
+	fn from(value: u64) -> Self {
+		Self::Immediate(value)
+	}
+}
+
+impl From<bool> for Operand {
+	fn from(value: bool) -> Self {
+		Self::Boolean(value)
+	}
+}
+
+impl From<Size> for Operand {
+	fn from(value: Size) -> Self {
+		Self::Size(value)
+	}
+}
+
+impl From<Condition> for Operand {
+	fn from(value: Condition) -> Self {
+		Self::Condition(Some(value))
+	}
+}
+
+impl From<Option<Condition>> for Operand {
+	fn from(value: Option<Condition>) -> Self {
+		Self::Condition(value)
+	}
+}
+
+impl fmt::Display for Operand {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Operand::Register(Some(id)) => write!(f, "{}", id),
+			Operand::Register(None) | Operand::Condition(None) => write!(f, "null"),
+			Operand::Immediate(value) => write!(f, "{:#x}", value),
+			Operand::Relative(value) if *value < 0 => write!(f, "-{:#x}", value.unsigned_abs()),
+			Operand::Relative(value) => write!(f, "+{:#x}", value),
+			Operand::Boolean(value) => write!(f, "{}", value),
+			Operand::Size(size) => write!(f, "{}", size.suffix()),
+			Operand::Condition(Some(cond)) => write!(f, "{}", cond.suffix()),
+		}
+	}
+}
+
+impl Serialize for Operand {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		match self {
+			Operand::Register(None) | Operand::Condition(None) => serializer.serialize_none(),
+			Operand::Immediate(value) => serializer.serialize_u64(*value),
+			Operand::Relative(value) => serializer.serialize_i64(*value),
+			Operand::Boolean(value) => serializer.serialize_bool(*value),
+			_ => serializer.collect_str(self),
+		}
+	}
+}
+
+impl TraceRecord {
+	pub fn new(index: u64, address: u64) -> Self {
+		Self {
+			index,
+			address,
+			instruction: None,
+			register_writes: Vec::new(),
+			flags: None,
+			memory_accesses: Vec::new(),
+			exception: None,
+		}
+	}
+
+	fn write_text(&self, output: &mut dyn Write) -> io::Result<()> {
+		write!(output, "[{}] {:#x}:", self.index, self.address)?;
+		match &self.instruction {
+			Some(instruction) => {
+				write!(
+					output,
+					" {:08x} {}",
+					instruction.encoding, instruction.mnemonic
+				)?;
+				for (i, operand) in instruction.operands.iter().enumerate() {
+					let separator = if i == 0 { " " } else { ", " };
+					write!(output, "{}{}={}", separator, operand.name, operand.value)?;
+				}
+				writeln!(output)?;
+			},
+			None => writeln!(output, " <no instruction>")?,
+		}
+
+		for write in &self.register_writes {
+			writeln!(
+				output,
+				"\t{}: {:#x} -> {:#x}",
+				write.register, write.old, write.new
+			)?;
+		}
+		if let Some(flags) = &self.flags {
+			writeln!(output, "\tflags: {:#x} -> {:#x}", flags.old, flags.new)?;
+		}
+		for access in &self.memory_accesses {
+			writeln!(
+				output,
+				"\t{} {:#x} ({} bytes): {:#x}",
+				if access.write { "write" } else { "read" },
+				access.address,
+				access.size,
+				access.value
+			)?;
+		}
+		if let Some(exception) = &self.exception {
+			writeln!(
+				output,
+				"\texception{}: {:?} (einfo {:#x}, eaddr {:#x})",
+				if exception.taken { "" } else { " (trapped)" },
+				exception.exception,
+				exception.einfo,
+				exception.eaddr
+			)?;
+		}
+
+		Ok(())
+	}
+}
+
+impl Tracer {
+	pub fn new(output: Box<dyn Write>, format: TraceFormat) -> Self {
+		Self {
+			output,
+			format,
+			next_index: 0,
+			error: None,
+		}
+	}
+
+	/// Creates a tracer writing to a new file at `path`.
+	pub fn create(path: &Path, format: TraceFormat) -> io::Result<Self> {
+		Ok(Self::new(
+			Box::new(BufWriter::new(File::create(path)?)),
+			format,
+		))
+	}
+
+	/// Starts a new record for a step beginning at `address`.
+	pub fn begin(&mut self, address: u64) -> TraceRecord {
+		let record = TraceRecord::new(self.next_index, address);
+		self.next_index += 1;
+		record
+	}
+
+	pub fn write(&mut self, record: &TraceRecord) {
+		if self.error.is_some() {
+			return;
+		}
+
+		let result = match self.format {
+			TraceFormat::Text => record.write_text(&mut self.output),
+			TraceFormat::Json => serde_json::to_writer(&mut self.output, record)
+				.map_err(io::Error::from)
+				.and_then(|_| writeln!(self.output)),
+		};
+
+		if let Err(e) = result {
+			self.error = Some(e);
+		}
+	}
+
+	/// Flushes the trace, returning the first error encountered while writing it (if any).
+	pub fn finish(mut self) -> io::Result<()> {
+		match self.error.take() {
+			Some(e) => Err(e),
+			None => self.output.flush(),
+		}
+	}
+}
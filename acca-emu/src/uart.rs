@@ -0,0 +1,235 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	collections::VecDeque,
+	ffi::CStr,
+	fs::{File, OpenOptions},
+	io::{self, Read, Write},
+	os::{
+		fd::{AsRawFd, FromRawFd},
+		unix::fs::OpenOptionsExt,
+	},
+	path::Path,
+	sync::mpsc::{self, Receiver, TryRecvError},
+	thread,
+};
+
+use bitflags::bitflags;
+
+use crate::{bus::Device, util::Size};
+
+/// The size of the UART's register block.
+pub const UART_SIZE: u64 = 0x20;
+
+/// Reading pops a byte from the receive buffer (or returns 0 if it's empty); writing transmits a byte.
+const DATA_REGISTER: u64 = 0x00;
+/// Read-only; see [`UartStatus`].
+const STATUS_REGISTER: u64 = 0x08;
+/// Read-write; see [`UartControl`].
+const CONTROL_REGISTER: u64 = 0x10;
+
+bitflags! {
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct UartStatus: u64 {
+		/// There's at least one byte waiting in the data register.
+		const RX_READY = 1 << 0;
+		/// The data register is ready to accept another byte to transmit.
+		const TX_EMPTY = 1 << 1;
+	}
+}
+
+bitflags! {
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct UartControl: u64 {
+		/// Raise the UART's interrupt whenever new data is received.
+		const RX_INTERRUPT = 1 << 0;
+	}
+}
+
+/// A simple UART with a single-byte transmit register and an unbounded receive buffer.
+///
+/// Received data is read from the host on a background thread, so the guest never blocks waiting for input.
+pub struct Uart {
+	output: Box<dyn Write>,
+	input: Option<Receiver<u8>>,
+	rx_buffer: VecDeque<u8>,
+	control: UartControl,
+	interrupt_requested: bool,
+	/// Keeps the slave side of a pty open so that reads from the master don't fail while nobody's connected.
+	_pty_slave: Option<File>,
+}
+
+fn spawn_reader(mut input: impl Read + Send + 'static) -> Receiver<u8> {
+	let (sender, receiver) = mpsc::channel();
+
+	thread::spawn(move || {
+		let mut buffer = [0u8; 256];
+		loop {
+			let count = match input.read(&mut buffer) {
+				Ok(0) | Err(_) => return,
+				Ok(count) => count,
+			};
+			for &byte in &buffer[..count] {
+				if sender.send(byte).is_err() {
+					return;
+				}
+			}
+		}
+	});
+
+	receiver
+}
+
+impl Uart {
+	fn new(input: Option<Receiver<u8>>, output: Box<dyn Write>) -> Self {
+		Self {
+			output,
+			input,
+			rx_buffer: VecDeque::new(),
+			control: UartControl::empty(),
+			interrupt_requested: false,
+			_pty_slave: None,
+		}
+	}
+
+	/// Creates a UART connected to the host's standard input and output.
+	pub fn stdio() -> Self {
+		Self::new(Some(spawn_reader(io::stdin())), Box::new(io::stdout()))
+	}
+
+	/// Creates a UART whose output is written to the file at `path`. It never receives any input.
+	pub fn file(path: &Path) -> io::Result<Self> {
+		Ok(Self::new(None, Box::new(File::create(path)?)))
+	}
+
+	/// Creates a UART connected to a new pseudo-terminal, returning it along with the path of the terminal device
+	/// (which can be opened with e.g. `screen` or `minicom`).
+	pub fn pty() -> io::Result<(Self, String)> {
+		// SAFETY: these are plain libc calls; the master fd is checked before use and ownership of it is
+		//         transferred to a `File` right away, so it's closed even if a later step fails.
+		let (master, slave_path) = unsafe {
+			let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+			if fd < 0 {
+				return Err(io::Error::last_os_error());
+			}
+			let master = File::from_raw_fd(fd);
+
+			if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+				return Err(io::Error::last_os_error());
+			}
+
+			let mut name = [0 as libc::c_char; 128];
+			if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+				return Err(io::Error::last_os_error());
+			}
+
+			(
+				master,
+				CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned(),
+			)
+		};
+
+		let slave = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.custom_flags(libc::O_NOCTTY)
+			.open(&slave_path)?;
+
+		// put the terminal into raw mode so that the line discipline doesn't echo our own output back to us
+		// SAFETY: `termios` is a plain C struct that `tcgetattr` fully initializes.
+		unsafe {
+			let mut termios = std::mem::zeroed::<libc::termios>();
+			if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+				return Err(io::Error::last_os_error());
+			}
+			libc::cfmakeraw(&mut termios);
+			if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+
+		let mut uart = Self::new(Some(spawn_reader(master.try_clone()?)), Box::new(master));
+		uart._pty_slave = Some(slave);
+
+		Ok((uart, slave_path))
+	}
+
+	/// Moves any data received from the host into the receive buffer.
+	fn receive(&mut self) {
+		let input = match &self.input {
+			Some(x) => x,
+			None => return,
+		};
+
+		loop {
+			match input.try_recv() {
+				Ok(byte) => {
+					self.rx_buffer.push_back(byte);
+					self.interrupt_requested = true;
+				},
+				Err(TryRecvError::Empty) => break,
+				Err(TryRecvError::Disconnected) => {
+					self.input = None;
+					break;
+				},
+			}
+		}
+	}
+
+	fn status(&self) -> UartStatus {
+		let mut status = UartStatus::TX_EMPTY;
+		status.set(UartStatus::RX_READY, !self.rx_buffer.is_empty());
+		status
+	}
+}
+
+impl Device for Uart {
+	fn read(&mut self, offset: u64, _size: Size) -> Option<u64> {
+		match offset {
+			DATA_REGISTER => {
+				self.receive();
+				Some(self.rx_buffer.pop_front().unwrap_or(0) as u64)
+			},
+			STATUS_REGISTER => {
+				self.receive();
+				Some(self.status().bits())
+			},
+			CONTROL_REGISTER => Some(self.control.bits()),
+			_ => None,
+		}
+	}
+
+	fn write(&mut self, offset: u64, _size: Size, value: u64) -> Option<()> {
+		match offset {
+			DATA_REGISTER => {
+				// the guest can't do anything about host output errors, so they're dropped like on a real
+				// UART with nothing connected
+				let _ = self
+					.output
+					.write_all(&[value as u8])
+					.and_then(|_| self.output.flush());
+				Some(())
+			},
+			CONTROL_REGISTER => {
+				self.control = UartControl::from_bits(value)?;
+				// data that arrived while the interrupt was disabled should still be signaled
+				self.interrupt_requested =
+					self.control.contains(UartControl::RX_INTERRUPT) && !self.rx_buffer.is_empty();
+				Some(())
+			},
+			_ => None,
+		}
+	}
+
+	fn poll(&mut self) -> bool {
+		self.receive();
+		std::mem::take(&mut self.interrupt_requested)
+			&& self.control.contains(UartControl::RX_INTERRUPT)
+	}
+}
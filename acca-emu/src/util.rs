@@ -7,6 +7,7 @@
 //
 
 use std::{
+	fmt,
 	ops::{Index, IndexMut},
 	slice::SliceIndex,
 };
@@ -16,8 +17,10 @@ use byteorder::{ByteOrder, LittleEndian};
 use auto_ops::*;
 use num_enum::TryFromPrimitive;
 
+use crate::disasm::decode;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum Condition {
+pub enum Condition {
 	C = 0,
 	NC = 1,
 	Z = 2,
@@ -31,13 +34,13 @@ pub(crate) enum Condition {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) struct RegisterID(u8);
+pub struct RegisterID(u8);
 
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct Register(u64);
+pub struct Register(u64);
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) enum Size {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
 	Byte = 0,
 	DoubleByte = 1,
 	QuadByte = 2,
@@ -45,16 +48,16 @@ pub(crate) enum Size {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) struct VMAddress(u64);
+pub struct VMAddress(u64);
 
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct RegisterFile([Register; 16]);
+pub struct RegisterFile([Register; 16]);
 
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct CPUFlags(u64);
+pub struct CPUFlags(u64);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum PrivilegeLevel {
+pub enum PrivilegeLevel {
 	PL0 = 0,
 	PL1 = 1,
 }
@@ -62,7 +65,7 @@ pub(crate) enum PrivilegeLevel {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u32)]
 #[allow(non_camel_case_types)]
-pub(crate) enum MachineRegisterID {
+pub enum MachineRegisterID {
 	flags = 0,
 	elr = 1,
 	esp = 2,
@@ -71,17 +74,33 @@ pub(crate) enum MachineRegisterID {
 	eaddr = 5,
 	evtable = 6,
 	ectable = 7,
+	ptbase = 8,
+	tlbinv = 9,
+
+	vm_timer_control = 0xdead2,
+	vm_timer_interval = 0xdead3,
+	vm_exit = 0xdead4,
+}
 
-	vm_console = 0xdead_1,
+/// How an instruction affects the call stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallKind {
+	/// `calla` or `callr` (which may or may not be taken, depending on its condition).
+	Call,
+	/// `ret`
+	Return,
+	/// `eret`
+	ExceptionReturn,
+	Other,
 }
 
-pub(crate) fn zero_extend_immediate(immediate: u64, width: u64) -> u64 {
+pub fn zero_extend_immediate(immediate: u64, width: u64) -> u64 {
 	const ALL_BITS: u64 = !0u64;
 	let mask = ALL_BITS.checked_shr(64 - width as u32).unwrap_or(0);
 	immediate & mask
 }
 
-pub(crate) fn sign_extend_immediate(immediate: u64, width: u64) -> u64 {
+pub fn sign_extend_immediate(immediate: u64, width: u64) -> u64 {
 	const ALL_BITS: u64 = !0u64;
 	let masked = zero_extend_immediate(immediate, width);
 	let msb = 1u64 << (width - 1);
@@ -92,8 +111,20 @@ pub(crate) fn sign_extend_immediate(immediate: u64, width: u64) -> u64 {
 	}
 }
 
+impl CallKind {
+	/// Classifies an encoded instruction.
+	pub fn of(encoded: u32) -> Self {
+		match decode(encoded).map(|instruction| instruction.mnemonic) {
+			Some("calla" | "callr") => Self::Call,
+			Some("ret") => Self::Return,
+			Some("eret") => Self::ExceptionReturn,
+			_ => Self::Other,
+		}
+	}
+}
+
 impl Condition {
-	pub(crate) fn test(&self, carry: bool, zero: bool, overflow: bool, sign: bool) -> bool {
+	pub fn test(&self, carry: bool, zero: bool, overflow: bool, sign: bool) -> bool {
 		match self {
 			Condition::C => carry,
 			Condition::NC => !carry,
@@ -107,6 +138,22 @@ impl Condition {
 			Condition::NL => !(sign ^ overflow),
 		}
 	}
+
+	/// The suffix used for this condition in assembly (e.g. `nz` in `jmpr.nz`).
+	pub const fn suffix(&self) -> &'static str {
+		match self {
+			Condition::C => "c",
+			Condition::NC => "nc",
+			Condition::Z => "z",
+			Condition::NZ => "nz",
+			Condition::O => "o",
+			Condition::NO => "no",
+			Condition::S => "s",
+			Condition::NS => "ns",
+			Condition::L => "l",
+			Condition::NL => "nl",
+		}
+	}
 }
 
 impl From<u64> for Condition {
@@ -162,6 +209,16 @@ impl Size {
 		self.byte_size() * 8
 	}
 
+	/// The suffix used for this size in assembly (e.g. `b` in `r0b`).
+	pub const fn suffix(&self) -> &'static str {
+		match self {
+			Size::Byte => "b",
+			Size::DoubleByte => "d",
+			Size::QuadByte => "q",
+			Size::Word => "w",
+		}
+	}
+
 	pub fn read(&self, src: &[u8], sign_extend: bool) -> u64 {
 		let val = match self {
 			Size::Byte => src[0] as u64,
@@ -265,6 +322,42 @@ impl RegisterID {
 	pub const SP: Self = Self(13);
 	pub const FP: Self = Self(14);
 	pub const LR: Self = Self(15);
+
+	pub const fn index(&self) -> usize {
+		self.0 as usize
+	}
+
+	/// Parses a register name, like `r3` or `rsp`.
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"rsp" => Some(Self::SP),
+			"rfp" => Some(Self::FP),
+			"rlr" => Some(Self::LR),
+			_ => {
+				let digits = name.strip_prefix('r')?;
+				if !digits.bytes().all(|c| c.is_ascii_digit())
+					|| digits.starts_with("0") && digits != "0"
+				{
+					return None;
+				}
+				match digits.parse() {
+					Ok(index @ 0..=15) => Some(Self(index)),
+					_ => None,
+				}
+			},
+		}
+	}
+}
+
+impl fmt::Display for RegisterID {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match *self {
+			Self::SP => write!(f, "rsp"),
+			Self::FP => write!(f, "rfp"),
+			Self::LR => write!(f, "rlr"),
+			Self(index) => write!(f, "r{}", index),
+		}
+	}
 }
 
 impl RegisterFile {
@@ -335,7 +428,7 @@ impl AsMut<u64> for Register {
 	}
 }
 
-pub(crate) trait BitBool {
+pub trait BitBool {
 	fn bit_as_bool(&self, index: Self) -> bool;
 	fn set_bit_with_bool(&mut self, index: Self, value: bool);
 }
@@ -462,11 +555,34 @@ impl MachineRegisterID {
 			| MachineRegisterID::esp
 			| MachineRegisterID::eflags
 			| MachineRegisterID::evtable
-			| MachineRegisterID::ectable => priv_level == PrivilegeLevel::PL0,
+			| MachineRegisterID::ectable
+			| MachineRegisterID::ptbase => priv_level == PrivilegeLevel::PL0,
 			MachineRegisterID::einfo | MachineRegisterID::eaddr => {
 				!write && priv_level == PrivilegeLevel::PL0
 			},
-			MachineRegisterID::vm_console => write,
+			MachineRegisterID::vm_timer_control | MachineRegisterID::vm_timer_interval => {
+				priv_level == PrivilegeLevel::PL0
+			},
+			MachineRegisterID::tlbinv | MachineRegisterID::vm_exit => {
+				write && priv_level == PrivilegeLevel::PL0
+			},
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn classifies_calls_and_returns() {
+		// `calla r3`, `callr.z r3`, and `callr` with a rel22 offset
+		assert_eq!(CallKind::of(0x2800_0003), CallKind::Call);
+		assert_eq!(CallKind::of(0x2400_0023), CallKind::Call);
+		assert_eq!(CallKind::of(0x2000_0010), CallKind::Call);
+		assert_eq!(CallKind::of(0x1c00_0000), CallKind::Return);
+		assert_eq!(CallKind::of(0x1800_0000), CallKind::ExceptionReturn);
+		// `jmpa r3`
+		assert_eq!(CallKind::of(0x4c00_0003), CallKind::Other);
+	}
+}
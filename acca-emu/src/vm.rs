@@ -6,12 +6,26 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 //
 
-use std::{fs::File, io::Read, ops::Range};
+use std::{collections::BTreeSet, fs::File, io::Read};
 
 use acca_emu_proc_macro::instructions;
-use memmap2::MmapMut;
 
-use super::util::*;
+use super::{
+	block_cache::BlockCache,
+	bus::{AttachError, Bus, Device},
+	loader::{ElfImage, LoadError, Segment},
+	memory::Memory,
+	mmu::{Access, Mmu, PAGE_SIZE},
+	semihosting::{Outcome, Semihosting, SEMIHOSTING_EXCEPTION},
+	snapshot::{Snapshot, SnapshotError, ECTABLE_WORDS},
+	timer::Timer,
+	trace::{
+		ExceptionInfo, FlagsChange, InstructionInfo, MemoryAccess, NamedOperand, Operand,
+		RegisterWrite, TraceRecord, Tracer,
+	},
+	util::*,
+	watch::{WatchHit, Watchpoint},
+};
 
 use bitflags::bitflags;
 
@@ -23,23 +37,27 @@ bitflags! {
 }
 
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct ExceptionConfigurationEntry {
+pub struct ExceptionConfigurationEntry {
 	flags: ExceptionConfigurationFlags,
 	stack_pointer: VMAddress,
 	stack_size: u64,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
-pub(crate) struct ExceptionConfigurationTable {
+pub struct ExceptionConfigurationTable {
 	pl0: [ExceptionConfigurationEntry; 8],
 	pl1: [ExceptionConfigurationEntry; 8],
 }
 
+/// The interrupt number raised by the built-in interval timer.
+pub const TIMER_INTERRUPT: u64 = 0;
+
 #[derive(Debug)]
-pub(crate) struct VM {
+pub struct VM {
 	print_instructions: bool,
+	trap_exceptions: bool,
 
-	memory: MmapMut,
+	bus: Bus,
 	register_file: RegisterFile,
 	flags: CPUFlags,
 	instruction_pointer: VMAddress,
@@ -53,17 +71,39 @@ pub(crate) struct VM {
 	ectable_addr: VMAddress,
 
 	ectable: ExceptionConfigurationTable,
+
+	/// The paging MMU, if the VM has one.
+	mmu: Option<Mmu>,
+	block_cache: BlockCache,
+
+	timer: Timer,
+	/// Set once the guest asks to exit (by writing to `vm_exit`).
+	exit_code: Option<u64>,
+	/// Host services requested with `exc`, if enabled.
+	semihosting: Option<Semihosting>,
+	/// Interrupts that have been raised but not yet taken (e.g. because they're masked).
+	pending_interrupts: BTreeSet<u64>,
+
+	tracer: Option<Tracer>,
+	/// The record for the step currently being executed, if tracing is enabled.
+	trace_record: Option<TraceRecord>,
+
+	watchpoints: Vec<Watchpoint>,
+	/// The watchpoints hit during the last step.
+	watch_hits: Vec<WatchHit>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-enum Exception {
+pub enum Exception {
 	Unknown = 0,
 	InvalidInstruction = 1,
 	Debug = 2,
 	User(u16) = 3,
 	InvalidOperation = 4,
-	InstructionLoadError = 5,
+	InstructionLoadError {
+		address: VMAddress,
+	} = 5,
 	DataLoadError {
 		address: VMAddress,
 		write: bool,
@@ -72,6 +112,35 @@ enum Exception {
 	Interrupt(u64) = 7,
 }
 
+/// Where the bytes of a data access end up once they've been translated.
+#[derive(Debug, Clone, Copy)]
+enum Translation {
+	Contiguous(VMAddress),
+	/// The access crosses into a page that isn't physically adjacent to the first one.
+	/// The first `first_size` bytes go to `first` and the rest go to `second`.
+	Split {
+		first: VMAddress,
+		first_size: u64,
+		second: VMAddress,
+	},
+}
+
+/// The outcome of executing a single instruction with [`VM::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+	/// The instruction completed normally.
+	Executed,
+	/// The instruction raised an exception, which has already been taken
+	/// (i.e. the instruction pointer now points into the exception vector table).
+	Exception(Exception),
+	/// The instruction raised an exception, but it was not taken because exception trapping is enabled.
+	///
+	/// The VM state is left as it was when the exception was raised. Use [`VM::take_exception`] to deliver it to the guest.
+	Trapped(Exception),
+	/// The guest has exited with the given status. No further instructions will be executed.
+	Exited(u64),
+}
+
 impl Exception {
 	pub fn id(&self) -> u8 {
 		// SAFETY: this enum is a `repr(u8)` enum, meaning the first member
@@ -79,6 +148,52 @@ impl Exception {
 		//         cast the reference to a `u8` pointer and read from it.
 		unsafe { *((self as *const _) as *const u8) }
 	}
+
+	/// The value `einfo` is set to when this exception is taken.
+	pub fn info(&self) -> u64 {
+		match *self {
+			Exception::Unknown => 0,
+			Exception::InvalidInstruction => 1,
+			Exception::Debug => 2,
+			Exception::User(val) => 3 | ((val as u64) << 3),
+			Exception::InvalidOperation => 4,
+			Exception::InstructionLoadError { .. } => 5,
+			Exception::DataLoadError {
+				address: _,
+				write,
+				byte_size,
+			} => 6 | (if write { 1 << 3 } else { 0 }) | ((byte_size as u64) << 4),
+			Exception::Interrupt(val) => 7 | (val << 3),
+		}
+	}
+
+	/// The value `eaddr` is set to when this exception is taken.
+	pub fn address(&self) -> VMAddress {
+		match *self {
+			Exception::InstructionLoadError { address }
+			| Exception::DataLoadError { address, .. } => address,
+			_ => 0.into(),
+		}
+	}
+}
+
+impl Translation {
+	fn byte_address(&self, index: u64) -> VMAddress {
+		match *self {
+			Translation::Contiguous(address) => address + index,
+			Translation::Split {
+				first,
+				first_size,
+				second,
+			} => {
+				if index < first_size {
+					first + index
+				} else {
+					second + (index - first_size)
+				}
+			},
+		}
+	}
 }
 
 impl ExceptionConfigurationEntry {
@@ -98,11 +213,17 @@ impl Default for ExceptionConfigurationEntry {
 }
 
 impl VM {
+	/// Creates a VM with `memory_size` bytes of contiguous RAM.
 	pub fn new(memory_size: usize) -> Option<Self> {
-		Some(Self {
+		Some(Self::with_memory(Memory::contiguous(memory_size)?))
+	}
+
+	pub fn with_memory(memory: Memory) -> Self {
+		Self {
 			print_instructions: false,
+			trap_exceptions: false,
 
-			memory: MmapMut::map_anon(memory_size).ok()?,
+			bus: Bus::new(memory),
 			register_file: RegisterFile::new(),
 			flags: CPUFlags::new(),
 			instruction_pointer: 0x0400.into(),
@@ -116,76 +237,596 @@ impl VM {
 			ectable_addr: VMAddress::new(0),
 
 			ectable: Default::default(),
-		})
+
+			mmu: None,
+			block_cache: BlockCache::new(),
+
+			timer: Timer::new(TIMER_INTERRUPT),
+			exit_code: None,
+			semihosting: None,
+			pending_interrupts: BTreeSet::new(),
+
+			tracer: None,
+			trace_record: None,
+
+			watchpoints: Vec::new(),
+			watch_hits: Vec::new(),
+		}
 	}
 
 	pub fn set_print_instructions(&mut self, print_instructions: bool) {
 		self.print_instructions = print_instructions;
 	}
 
-	pub fn load_file(
+	/// Sets the tracer that every subsequent step is recorded to, returning the previous one (if any).
+	pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+		std::mem::replace(&mut self.tracer, tracer)
+	}
+
+	pub fn watchpoints(&self) -> &[Watchpoint] {
+		&self.watchpoints
+	}
+
+	/// Adds a watchpoint, unless an identical one already exists.
+	pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+		if !self.watchpoints.contains(&watchpoint) {
+			self.watchpoints.push(watchpoint);
+		}
+	}
+
+	/// Removes the given watchpoint, returning `false` if it didn't exist.
+	pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+		let count = self.watchpoints.len();
+		self.watchpoints.retain(|existing| existing != watchpoint);
+		self.watchpoints.len() != count
+	}
+
+	pub fn clear_watchpoints(&mut self) {
+		self.watchpoints.clear();
+	}
+
+	/// Returns (and forgets) the watchpoints hit during the last step.
+	pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+		std::mem::take(&mut self.watch_hits)
+	}
+
+	/// Adds or removes the paging MMU. Without one, `ptbase` and `tlbinv` can't be accessed.
+	pub fn set_mmu(&mut self, present: bool) {
+		self.mmu = present.then(Mmu::new);
+	}
+
+	/// Changes the interrupt raised by the built-in timer, resetting the timer.
+	pub fn set_timer_interrupt(&mut self, interrupt: u64) {
+		self.timer = Timer::new(interrupt);
+	}
+
+	/// Enables (or disables) semihosting. While enabled, `exc 0xacca` is a request to the host rather than an exception.
+	pub fn set_semihosting(&mut self, semihosting: Option<Semihosting>) {
+		self.semihosting = semihosting;
+	}
+
+	pub fn mmu(&self) -> Option<&Mmu> {
+		self.mmu.as_ref()
+	}
+
+	/// If enabled, exceptions raised by instructions are returned as [`StepResult::Trapped`] instead of being taken.
+	pub fn set_trap_exceptions(&mut self, trap_exceptions: bool) {
+		self.trap_exceptions = trap_exceptions;
+	}
+
+	/// Checks that each of the given `(address, size)` blocks can be loaded into memory along with the ones before it.
+	fn check_load(&self, blocks: impl IntoIterator<Item = (u64, u64)>) -> Result<(), LoadError> {
+		let mut ranges = Vec::new();
+		for (address, size) in blocks {
+			let error = || LoadError::OutOfBounds {
+				address,
+				size,
+				memory_size: self.memory_size(),
+			};
+			ranges.push(address..address.checked_add(size).ok_or_else(error)?);
+			if !self.bus.memory().can_load(&ranges) {
+				return Err(error());
+			}
+		}
+		Ok(())
+	}
+
+	pub fn load_file(&mut self, file: &mut File, dest_addr: VMAddress) -> Result<(), LoadError> {
+		let file_len = file.metadata()?.len();
+		self.check_load([(dest_addr.into(), file_len)])?;
+		let mut data = Vec::new();
+		file.read_to_end(&mut data)?;
+		self.load_bytes(&data, dest_addr)
+			.ok_or(LoadError::OutOfBounds {
+				address: dest_addr.into(),
+				size: data.len() as u64,
+				memory_size: self.memory_size(),
+			})
+	}
+
+	/// Loads `segments` into memory, zero-filling anything not present in their data.
+	///
+	/// Nothing is loaded unless every segment fits in memory.
+	pub fn load_segments(&mut self, segments: &[Segment]) -> Result<(), LoadError> {
+		self.check_load(
+			segments
+				.iter()
+				.map(|segment| (segment.address, segment.memory_size)),
+		)?;
+
+		for segment in segments {
+			self.block_cache
+				.invalidate(segment.address, segment.memory_size);
+			let data_size = segment.data.len() as u64;
+			let memory = self.bus.memory_mut();
+			memory
+				.load(segment.address, &segment.data)
+				.and_then(|_| {
+					memory.zero(segment.address + data_size, segment.memory_size - data_size)
+				})
+				.expect("segment should have been checked");
+		}
+
+		Ok(())
+	}
+
+	/// Loads the segments of `image` into RAM and starts execution at its entry point.
+	pub fn load_elf(&mut self, image: &ElfImage) -> Result<(), LoadError> {
+		let entry = VMAddress::from(image.entry);
+		if !entry.is_valid_instruction_pointer() {
+			return Err(LoadError::InvalidEntry(image.entry));
+		}
+
+		self.load_segments(&image.segments)?;
+		self.instruction_pointer = entry;
+		Ok(())
+	}
+
+	/// Copies `bytes` into memory at `dest_addr`, even if it's read-only.
+	///
+	/// This doesn't access devices; use [`VM::write_memory`] for that.
+	pub fn load_bytes(&mut self, bytes: &[u8], dest_addr: VMAddress) -> Option<()> {
+		self.bus.memory_mut().load(dest_addr.into(), bytes)?;
+		// the bytes may be code, so any decoded copies of what was there are stale
+		self.block_cache
+			.invalidate(dest_addr.into(), bytes.len() as u64);
+		Some(())
+	}
+
+	/// Returns the size of contiguous RAM, or the allocation limit of sparse memory.
+	pub fn memory_size(&self) -> u64 {
+		self.bus.memory().size()
+	}
+
+	pub fn memory(&self) -> &Memory {
+		self.bus.memory()
+	}
+
+	/// Maps `device` into the guest's address space at `base`, optionally connecting it to an interrupt.
+	///
+	/// The range may not overlap contiguous RAM or any other device. With sparse memory, it's carved out of memory.
+	pub fn attach_device(
 		&mut self,
-		file: &mut File,
-		dest_addr: VMAddress,
-	) -> Result<(), std::io::Error> {
-		let metadata = file.metadata()?;
-		let file_len = metadata.len();
-		let dest = self
-			.memory
-			.get_mut(u64::from(dest_addr) as usize..u64::from(dest_addr + file_len) as usize)
-			.unwrap();
-		file.read_exact(dest)
+		base: VMAddress,
+		size: u64,
+		interrupt: Option<u64>,
+		device: Box<dyn Device>,
+	) -> Result<(), AttachError> {
+		self.bus.attach(base, size, interrupt, device)
 	}
 
-	fn get_memory(&self, range: Range<VMAddress>) -> Option<&[u8]> {
-		let usize_range = u64::from(range.start) as usize..u64::from(range.end) as usize;
-		self.memory.get(usize_range)
+	pub fn register_file(&self) -> &RegisterFile {
+		&self.register_file
 	}
 
-	fn get_memory_mut(&mut self, range: Range<VMAddress>) -> Option<&mut [u8]> {
-		let usize_range = u64::from(range.start) as usize..u64::from(range.end) as usize;
-		self.memory.get_mut(usize_range)
+	pub fn register_file_mut(&mut self) -> &mut RegisterFile {
+		&mut self.register_file
 	}
 
-	fn execute_one(&mut self) {
-		const ALL_BITS: u64 = !0u64;
+	pub fn flags(&self) -> CPUFlags {
+		self.flags
+	}
+
+	pub fn set_flags(&mut self, flags: CPUFlags) {
+		self.flags = flags;
+	}
+
+	pub fn instruction_pointer(&self) -> VMAddress {
+		self.instruction_pointer
+	}
+
+	pub fn set_instruction_pointer(&mut self, address: VMAddress) {
+		self.instruction_pointer = address;
+	}
+
+	/// Reads the given machine register, ignoring privilege checks.
+	pub fn machine_register(&self, id: MachineRegisterID) -> u64 {
+		match id {
+			MachineRegisterID::flags => self.flags.into(),
+			MachineRegisterID::elr => self.elr.into(),
+			MachineRegisterID::esp => self.esp.into(),
+			MachineRegisterID::eflags => self.eflags.into(),
+			MachineRegisterID::einfo => self.einfo,
+			MachineRegisterID::eaddr => self.eaddr.into(),
+			MachineRegisterID::evtable => self.evtable_addr.into(),
+			MachineRegisterID::ectable => self.ectable_addr.into(),
+			MachineRegisterID::ptbase => self.mmu.as_ref().map_or(0, Mmu::ptbase),
+			MachineRegisterID::tlbinv => 0,
+			MachineRegisterID::vm_timer_control => self.timer.control(),
+			MachineRegisterID::vm_timer_interval => self.timer.interval(),
+			MachineRegisterID::vm_exit => self.exit_code.unwrap_or(0),
+		}
+	}
+
+	/// Whether this VM implements the given machine register (the MMU registers are optional).
+	pub fn has_machine_register(&self, id: MachineRegisterID) -> bool {
+		match id {
+			MachineRegisterID::ptbase | MachineRegisterID::tlbinv => self.mmu.is_some(),
+			_ => true,
+		}
+	}
+
+	/// Writes the given machine register, ignoring privilege checks.
+	///
+	/// On failure, this returns the exception that an `stm` performing the same write would raise.
+	/// Unlike `stm`, this allows writing to read-only registers (`einfo` and `eaddr`).
+	pub fn set_machine_register(
+		&mut self,
+		id: MachineRegisterID,
+		value: u64,
+	) -> Result<(), Exception> {
+		match id {
+			MachineRegisterID::flags => {
+				self.flags = value.try_into().map_err(|_| Exception::InvalidOperation)?;
+			},
+			MachineRegisterID::elr => {
+				let addr = VMAddress::from(value);
+				if !addr.is_valid_instruction_pointer() {
+					return Err(Exception::InvalidOperation);
+				}
+				self.elr = addr;
+			},
+			MachineRegisterID::esp => {
+				self.esp = value.into();
+			},
+			MachineRegisterID::eflags => {
+				self.eflags = value.try_into().map_err(|_| Exception::InvalidOperation)?;
+			},
+			MachineRegisterID::einfo => {
+				self.einfo = value;
+			},
+			MachineRegisterID::eaddr => {
+				self.eaddr = value.into();
+			},
+			MachineRegisterID::evtable => {
+				let addr = VMAddress::from(value);
+				if !addr.is_valid_instruction_pointer() {
+					return Err(Exception::InvalidOperation);
+				}
+				self.evtable_addr = addr;
+			},
+			MachineRegisterID::ectable => {
+				let addr = VMAddress::from(value);
+				let table_size = std::mem::size_of::<ExceptionConfigurationTable>() as u64;
+				let mut mem = [0u8; std::mem::size_of::<ExceptionConfigurationTable>()];
+				self.read_ram(addr, &mut mem)
+					.ok_or(Exception::DataLoadError {
+						address: addr,
+						write: false,
+						byte_size: table_size as u16,
+					})?;
+
+				// SAFETY: it's safe to read the table from the pointer since the type (ExceptionConfigurationTable) is Copy.
+				//         additionally, the buffer above is exactly the size of the table.
+				let tmp = unsafe {
+					std::ptr::read_unaligned(mem.as_ptr() as *const ExceptionConfigurationTable)
+				};
+				self.check_watchpoints(addr, table_size, false, None, None);
+
+				// now let's check the table entries
+				if !tmp.pl0.iter().all(ExceptionConfigurationEntry::validate)
+					|| !tmp.pl1.iter().all(ExceptionConfigurationEntry::validate)
+				{
+					return Err(Exception::InvalidOperation);
+				}
 
-		#[rustfmt::skip]
-		let encoded = match self.get_memory(self.instruction_pointer..self.instruction_pointer + 4) {
-			Some(bytes) => u32::from_le_bytes(bytes.try_into().unwrap()),
-			None => {
-				return self
-					.take_exception(Exception::InstructionLoadError)
+				self.ectable_addr = addr;
+				self.ectable = tmp;
+			},
+			MachineRegisterID::ptbase => {
+				if !self.mmu.as_mut().is_some_and(|mmu| mmu.set_ptbase(value)) {
+					return Err(Exception::InvalidOperation);
+				}
+			},
+			MachineRegisterID::tlbinv => match self.mmu.as_mut() {
+				Some(mmu) => mmu.invalidate(value),
+				None => return Err(Exception::InvalidOperation),
+			},
+			MachineRegisterID::vm_timer_control => {
+				if !self.timer.set_control(value) {
+					return Err(Exception::InvalidOperation);
+				}
+			},
+			MachineRegisterID::vm_timer_interval => {
+				self.timer.set_interval(value);
+			},
+			MachineRegisterID::vm_exit => {
+				self.exit_code = Some(value);
+			},
+		}
+
+		Ok(())
+	}
+
+	/// Fills `buffer` with the memory at `address`.
+	///
+	/// This doesn't access devices; use [`VM::read_memory`] for that.
+	pub fn read_ram(&self, address: VMAddress, buffer: &mut [u8]) -> Option<()> {
+		self.bus.memory().read_bytes(address.into(), buffer)
+	}
+
+	/// Performs a data read through the bus, exactly like a load instruction would (including any device side effects).
+	pub fn read_memory(&mut self, address: VMAddress, size: Size) -> Option<u64> {
+		self.bus.read(address, size)
+	}
+
+	/// Performs a data write through the bus, exactly like a store instruction would.
+	pub fn write_memory(&mut self, address: VMAddress, size: Size, value: u64) -> Option<()> {
+		self.bus.write(address, size, value)?;
+		self.block_cache
+			.invalidate(address.into(), size.byte_size() as u64);
+		Some(())
+	}
+
+	/// Translates a virtual address the way the guest would see it, but without permission checks or side effects.
+	///
+	/// Addresses are returned as-is when paging is disabled.
+	pub fn translate(&self, address: VMAddress) -> Option<VMAddress> {
+		match &self.mmu {
+			Some(mmu) => mmu
+				.lookup(self.bus.memory(), address.into())
+				.map(VMAddress::from),
+			None => Some(address),
+		}
+	}
+
+	/// Translates `address` for an access of the given kind from the current privilege level, like the guest's own
+	/// accesses would be, but without touching the TLB.
+	pub fn translate_checked(&self, address: VMAddress, access: Access) -> Option<VMAddress> {
+		match &self.mmu {
+			Some(mmu) => mmu
+				.lookup_access(
+					self.bus.memory(),
+					address.into(),
+					access,
+					self.flags.privilege_level(),
+				)
+				.map(VMAddress::from),
+			None => Some(address),
+		}
+	}
+
+	/// Reads the instruction at the (virtual) `address`, without permission checks or side effects.
+	pub fn fetch_instruction(&self, address: VMAddress) -> Option<u32> {
+		let physical = self.translate(address)?;
+		self.bus
+			.memory()
+			.read(physical.into(), Size::QuadByte)
+			.map(|value| value as u32)
+	}
+
+	/// Translates `address` for an access of the given kind from the current privilege level.
+	fn translate_access(&mut self, address: VMAddress, access: Access) -> Option<VMAddress> {
+		match &mut self.mmu {
+			Some(mmu) => mmu
+				.translate(
+					self.bus.memory(),
+					address.into(),
+					access,
+					self.flags.privilege_level(),
+				)
+				.map(VMAddress::from),
+			None => Some(address),
+		}
+	}
+
+	/// Translates a data access of `byte_size` bytes at `address`, which may cross into the next page.
+	fn translate_data(
+		&mut self,
+		address: VMAddress,
+		byte_size: u64,
+		write: bool,
+	) -> Option<Translation> {
+		let access = if write { Access::Write } else { Access::Read };
+		let first = self.translate_access(address, access)?;
+
+		let start = u64::from(address);
+		let last = start.checked_add(byte_size - 1)?;
+		if !self.mmu.as_ref().is_some_and(Mmu::enabled) || last / PAGE_SIZE == start / PAGE_SIZE {
+			return Some(Translation::Contiguous(first));
+		}
+
+		let second_start = last & !(PAGE_SIZE - 1);
+		let second = self.translate_access(second_start.into(), access)?;
+		let first_size = second_start - start;
+		if first + first_size == second {
+			Some(Translation::Contiguous(first))
+		} else {
+			Some(Translation::Split {
+				first,
+				first_size,
+				second,
+			})
+		}
+	}
+
+	/// Checks whether a data access of `byte_size` bytes at `address` would succeed.
+	fn is_data_mapped(&mut self, address: VMAddress, byte_size: u64, write: bool) -> bool {
+		match self.translate_data(address, byte_size, write) {
+			Some(Translation::Contiguous(physical)) => {
+				self.bus.is_mapped(physical, byte_size, write)
+			},
+			Some(Translation::Split {
+				first,
+				first_size,
+				second,
+			}) => {
+				self.bus.is_mapped(first, first_size, write)
+					&& self.bus.is_mapped(second, byte_size - first_size, write)
 			},
+			None => false,
+		}
+	}
+
+	/// Performs a data read at the (virtual) `address` for the guest.
+	fn read_data(&mut self, address: VMAddress, size: Size) -> Option<u64> {
+		let byte_size = size.byte_size() as u64;
+		match self.translate_data(address, byte_size, false)? {
+			Translation::Contiguous(physical) => self.bus.read(physical, size),
+			split => {
+				// pages that aren't physically adjacent are read a byte at a time
+				if !self.is_data_mapped(address, byte_size, false) {
+					return None;
+				}
+				let mut bytes = [0u8; 8];
+				for (index, byte) in bytes[..byte_size as usize].iter_mut().enumerate() {
+					*byte = self
+						.bus
+						.read(split.byte_address(index as u64), Size::Byte)? as u8;
+				}
+				Some(size.read(&bytes, false))
+			},
+		}
+	}
+
+	/// Performs a data write at the (virtual) `address` for the guest.
+	fn write_data(&mut self, address: VMAddress, size: Size, value: u64) -> Option<()> {
+		let byte_size = size.byte_size() as u64;
+		match self.translate_data(address, byte_size, true)? {
+			Translation::Contiguous(physical) => {
+				self.bus.write(physical, size, value)?;
+				self.block_cache.invalidate(physical.into(), byte_size);
+				Some(())
+			},
+			split => {
+				if !self.is_data_mapped(address, byte_size, true) {
+					return None;
+				}
+				let mut bytes = [0u8; 8];
+				size.write(value, &mut bytes);
+				for (index, &byte) in bytes[..byte_size as usize].iter().enumerate() {
+					let physical = split.byte_address(index as u64);
+					self.bus.write(physical, Size::Byte, byte as u64)?;
+					self.block_cache.invalidate(physical.into(), 1);
+				}
+				Some(())
+			},
+		}
+	}
+
+	/// Reads guest memory at the (virtual) `address` on behalf of the host, as if the guest had read it.
+	pub(crate) fn read_guest(&mut self, address: u64, buffer: &mut [u8]) -> Option<()> {
+		for (offset, byte) in buffer.iter_mut().enumerate() {
+			*byte = self.read_data(
+				VMAddress::new(address.wrapping_add(offset as u64)),
+				Size::Byte,
+			)? as u8;
+		}
+		Some(())
+	}
+
+	/// Writes guest memory at the (virtual) `address` on behalf of the host, as if the guest had written it.
+	pub(crate) fn write_guest(&mut self, address: u64, data: &[u8]) -> Option<()> {
+		for (offset, &byte) in data.iter().enumerate() {
+			self.write_data(
+				VMAddress::new(address.wrapping_add(offset as u64)),
+				Size::Byte,
+				byte as u64,
+			)?;
+		}
+		Some(())
+	}
+
+	/// Handles a semihosting request from the guest.
+	fn semihost(&mut self) {
+		let Some(mut semihosting) = self.semihosting.take() else {
+			return;
 		};
+		match semihosting.handle(self) {
+			Outcome::Return(value) => self.register_file[RegisterID::from(0)] = value.into(),
+			Outcome::Exit(code) => self.exit_code = Some(code),
+		}
+		self.semihosting = Some(semihosting);
+	}
 
-		macro_rules! get_memory {
-			($addr:expr, $size:expr) => {
-				match self.get_memory($addr..$addr + $size) {
-					Some(bytes) => bytes,
-					None => {
-						return self.take_exception(Exception::DataLoadError {
-							address: $addr.into(),
-							write: false,
-							byte_size: $size as u16,
-						})
-					},
+	#[allow(clippy::nonminimal_bool)]
+	fn execute_one(&mut self) -> StepResult {
+		const ALL_BITS: u64 = !0u64;
+
+		let ip = self.instruction_pointer;
+		let instruction = match self
+			.translate_access(ip, Access::Execute)
+			.and_then(|physical| self.block_cache.fetch(self.bus.memory(), physical.into()))
+		{
+			Some(x) => x,
+			None => return self.raise_exception(Exception::InstructionLoadError { address: ip }),
+		};
+
+		macro_rules! data_load_error {
+			($addr:expr, $byte_size:expr, $write:expr) => {
+				return self.raise_exception(Exception::DataLoadError {
+					address: $addr,
+					write: $write,
+					byte_size: $byte_size as u16,
+				})
+			};
+		}
+
+		// accesses that are split into multiple bus accesses (e.g. `ldp`/`stp`) must check that the whole range
+		// is accessible beforehand so that a failure doesn't leave the access half-done
+		macro_rules! check_mapped {
+			($addr:expr, $byte_size:expr, $write:expr) => {
+				if !self.is_data_mapped($addr, $byte_size, $write) {
+					data_load_error!($addr, $byte_size, $write);
 				}
 			};
 		}
 
-		macro_rules! get_memory_mut {
-			($addr:expr, $size:expr) => {
-				match self.get_memory_mut($addr..$addr + $size) {
-					Some(bytes) => bytes,
-					None => {
-						return self.take_exception(Exception::DataLoadError {
-							address: $addr.into(),
-							write: true,
-							byte_size: $size as u16,
-						})
+		macro_rules! load {
+			($addr:expr, $size:expr) => {{
+				let (addr, size) = ($addr, $size);
+				match self.read_data(addr, size) {
+					Some(value) => {
+						self.trace_memory_access(addr, size, false, value);
+						self.check_watchpoints(
+							addr,
+							size.byte_size() as u64,
+							false,
+							None,
+							Some(value),
+						);
+						value
 					},
+					None => data_load_error!(addr, size.byte_size(), false),
 				}
+			}};
+		}
+
+		macro_rules! store {
+			($addr:expr, $size:expr, $value:expr) => {
+				let (addr, size, value) = ($addr, $size, $value);
+				let old_value = self.watched_old_value(addr, size);
+				if self.write_data(addr, size, value).is_none() {
+					data_load_error!(addr, size.byte_size(), true);
+				}
+				self.trace_memory_access(addr, size, true, value & size.mask());
+				self.check_watchpoints(
+					addr,
+					size.byte_size() as u64,
+					true,
+					old_value,
+					Some(value & size.mask()),
+				);
 			};
 		}
 
@@ -202,7 +843,7 @@ impl VM {
 			($addr:expr) => {
 				let dest = $addr;
 				if (u64::from(dest) & 3) != 0 {
-					return self.take_exception(Exception::InvalidOperation);
+					return self.raise_exception(Exception::InvalidOperation);
 				}
 				self.instruction_pointer = dest - 4;
 			};
@@ -227,7 +868,7 @@ impl VM {
 				let rhs = self.register_file[$rhs].get_signed(size) as u64;
 				let borrow: u64 = if $borrow && self.flags.carry() { 1 } else { 0 };
 
-				let result = lhs.wrapping_sub(rhs).wrapping_sub(borrow) as u64;
+				let result = lhs.wrapping_sub(rhs).wrapping_sub(borrow);
 				let msb = size.msb_index() as u64;
 
 				let lhs_msb = lhs.bit_as_bool(msb);
@@ -276,9 +917,7 @@ impl VM {
 				let val = src.map(|id| self.register_file[id].get_unsigned(size)).unwrap_or(0);
 
 				let new_rsp_val = self.register_file[RegisterID::SP].get_address() - byte_size;
-				let mem = get_memory_mut!(new_rsp_val, byte_size);
-
-				size.write(val, mem);
+				store!(new_rsp_val, size, val);
 
 				self.register_file[RegisterID::SP] = new_rsp_val.into();
 			},
@@ -288,11 +927,9 @@ impl VM {
 				let val2 = src2.map(|id| self.register_file[id].get_unsigned(size)).unwrap_or(0);
 
 				let new_rsp_val = self.register_file[RegisterID::SP].get_address() - 2 * byte_size;
-				let mem = get_memory_mut!(new_rsp_val, 2 * byte_size);
-				let (mem1, mem2) = mem.split_at_mut(byte_size as usize);
-
-				size.write(val1, mem1);
-				size.write(val2, mem2);
+				check_mapped!(new_rsp_val, 2 * byte_size, true);
+				store!(new_rsp_val, size, val1);
+				store!(new_rsp_val + byte_size, size, val2);
 
 				self.register_file[RegisterID::SP] = new_rsp_val.into();
 			},
@@ -300,10 +937,11 @@ impl VM {
 				let byte_size = size.byte_size() as u64;
 
 				let old_rsp_val = self.register_file[RegisterID::SP].get_address();
-				let mem = get_memory!(old_rsp_val, byte_size);
+				let val = load!(old_rsp_val, size);
 
-				let val = size.read(mem, false);
-				dst.map(|id| self.register_file[id].set(size, val));
+				if let Some(id) = dst {
+					self.register_file[id].set(size, val);
+				}
 
 				self.register_file[RegisterID::SP] = (old_rsp_val + byte_size).into();
 			},
@@ -311,27 +949,29 @@ impl VM {
 				let byte_size = size.byte_size() as u64;
 
 				let old_rsp_val = self.register_file[RegisterID::SP].get_address();
-				let mem = get_memory!(old_rsp_val, 2 * byte_size);
-				let (mem1, mem2) = mem.split_at(byte_size as usize);
+				check_mapped!(old_rsp_val, 2 * byte_size, false);
+				let (val1, val2) = (load!(old_rsp_val, size), load!(old_rsp_val + byte_size, size));
 
-				let (val1, val2) = (size.read(mem1, false), size.read(mem2, false));
-				dst1.map(|id| self.register_file[id].set(size, val1));
-				dst2.map(|id| self.register_file[id].set(size, val2));
+				if let Some(id) = dst1 {
+					self.register_file[id].set(size, val1);
+				}
+				if let Some(id) = dst2 {
+					self.register_file[id].set(size, val2);
+				}
 
 				self.register_file[RegisterID::SP] = (old_rsp_val + 2 * byte_size).into();
 			},
 			[1100110000000000000000ssddddaaaa] => lds size = s: size, dst = d: reg, src_addr = a: reg {
 				let addr = self.register_file[src_addr].get_address();
-				let mem = get_memory!(addr, size.byte_size() as u64);
-				let val = size.read(mem, false);
+				let val = load!(addr, size);
 
 				self.register_file[dst].set(size, val);
 			},
 			[110010000000000000ssddddeeeeaaaa] => ldp size = s: size, dst1 = d: reg, dst2 = e: reg, src_addr = a: reg {
+				let byte_size = size.byte_size() as u64;
 				let addr = self.register_file[src_addr].get_address();
-				let mem = get_memory!(addr, 2 * size.byte_size() as u64);
-				let (mem1, mem2) = mem.split_at(size.byte_size() as usize);
-				let (val1, val2) = (size.read(mem1, false), size.read(mem2, false));
+				check_mapped!(addr, 2 * byte_size, false);
+				let (val1, val2) = (load!(addr, size), load!(addr + byte_size, size));
 
 				self.register_file[dst1].set(size, val1);
 				self.register_file[dst2].set(size, val2);
@@ -339,18 +979,17 @@ impl VM {
 			[1100010000000000000000ssaaaabbbb] => sts size = s: size, dst_addr = a: reg, src = b: reg {
 				let addr = self.register_file[dst_addr].get_address();
 				let val = self.register_file[src].get();
-				let mem = get_memory_mut!(addr, size.byte_size() as u64);
 
-				size.write(val, mem);
+				store!(addr, size, val);
 			},
 			[110000000000000000ssaaaabbbbcccc] => stp size = s: size, dst_addr = a: reg, src1 = b: reg, src2 = c: reg {
+				let byte_size = size.byte_size() as u64;
 				let addr = self.register_file[dst_addr].get_address();
 				let (val1, val2) = (self.register_file[src1].get(), self.register_file[src2].get());
-				let mem = get_memory_mut!(addr, size.byte_size() as u64);
-				let (mem1, mem2) = mem.split_at_mut(size.byte_size() as usize);
+				check_mapped!(addr, 2 * byte_size, true);
 
-				size.write(val1, mem1);
-				size.write(val2, mem2);
+				store!(addr, size, val1);
+				store!(addr + byte_size, size, val2);
 			},
 			[1110ccaaaaaaaaaaaaaaaabbbbbbdddd] => ldi dst = d: reg, src = a: imm16, shift = b: imm6, clear = c: imm2 {
 				let old = self.register_file[dst].get();
@@ -390,7 +1029,7 @@ impl VM {
 				let rhs = self.register_file[rhs].get_signed(size) as u64;
 				let carry: u64 = if carry && self.flags.carry() { 1 } else { 0 };
 
-				let result = lhs.wrapping_add(rhs).wrapping_add(carry) as u64;
+				let result = lhs.wrapping_add(rhs).wrapping_add(carry);
 				let msb = size.msb_index() as u64;
 
 				if let Some(dst) = dst {
@@ -412,7 +1051,7 @@ impl VM {
 				let rhs = imm11_with_shift_factor(rhs, shift_factor, sign_extend);
 				let carry: u64 = if carry && self.flags.carry() { 1 } else { 0 };
 
-				let result = lhs.wrapping_add(rhs).wrapping_add(carry) as u64;
+				let result = lhs.wrapping_add(rhs).wrapping_add(carry);
 				let msb = size.msb_index() as u64;
 
 				if let Some(dst) = dst {
@@ -434,7 +1073,7 @@ impl VM {
 				let rhs = self.register_file[rhs].get_signed(size) as u64;
 				let borrow: u64 = if borrow && self.flags.carry() { 1 } else { 0 };
 
-				let result = lhs.wrapping_sub(rhs).wrapping_sub(borrow) as u64;
+				let result = lhs.wrapping_sub(rhs).wrapping_sub(borrow);
 				let msb = size.msb_index() as u64;
 
 				if let Some(dst) = dst {
@@ -456,7 +1095,7 @@ impl VM {
 				let rhs = imm11_with_shift_factor(rhs, shift_factor, sign_extend);
 				let borrow: u64 = if borrow && self.flags.carry() { 1 } else { 0 };
 
-				let result = lhs.wrapping_sub(rhs).wrapping_sub(borrow) as u64;
+				let result = lhs.wrapping_sub(rhs).wrapping_sub(borrow);
 				let msb = size.msb_index() as u64;
 
 				if let Some(dst) = dst {
@@ -727,7 +1366,7 @@ impl VM {
 					Size::Byte => (lhs as u8).rotate_right(rhs) as u64,
 					Size::DoubleByte => (lhs as u16).rotate_right(rhs) as u64,
 					Size::QuadByte => (lhs as u32).rotate_right(rhs) as u64,
-					Size::Word => lhs.rotate_right(rhs) as u64,
+					Size::Word => lhs.rotate_right(rhs),
 				};
 
 				if let Some(dst) = dst {
@@ -746,7 +1385,7 @@ impl VM {
 					Size::Byte => (lhs as u8).rotate_right(rhs) as u64,
 					Size::DoubleByte => (lhs as u16).rotate_right(rhs) as u64,
 					Size::QuadByte => (lhs as u32).rotate_right(rhs) as u64,
-					Size::Word => lhs.rotate_right(rhs) as u64,
+					Size::Word => lhs.rotate_right(rhs),
 				};
 
 				if let Some(dst) = dst {
@@ -855,7 +1494,7 @@ impl VM {
 			},
 			[00011000000000000000000000000000] => eret {
 				if self.flags.privilege_level() != PrivilegeLevel::PL0 {
-					return self.take_exception(Exception::InvalidOperation);
+					return self.raise_exception(Exception::InvalidOperation);
 				}
 
 				self.instruction_pointer = self.elr - 4;
@@ -863,14 +1502,19 @@ impl VM {
 				self.register_file[RegisterID::SP] = self.esp.into();
 			},
 			[00000000000000000000000000000000] => udf {
-				return self.take_exception(Exception::InvalidInstruction);
+				return self.raise_exception(Exception::InvalidInstruction);
 			},
 			[00001000000000000000000000000000] => dbg {
-				return self.take_exception(Exception::Debug);
+				return self.raise_exception(Exception::Debug);
 			},
 			[0000110000000000aaaaaaaaaaaaaaaa] => exc val = a: imm16 {
-				self.instruction_pointer += 4;
-				return self.take_exception(Exception::User(val as u16));
+				if val as u16 == SEMIHOSTING_EXCEPTION && self.semihosting.is_some() {
+					// handled by the host instead of the guest, like any other instruction
+					self.semihost();
+				} else {
+					self.instruction_pointer += 4;
+					return self.raise_exception(Exception::User(val as u16));
+				}
 			},
 
 			//
@@ -881,137 +1525,62 @@ impl VM {
 			[000100ddddaaaaaaaaaaaaaaaaaaaaaa] => ldm dst = d: reg, src_mreg = a: imm22 {
 				let src_mreg = match MachineRegisterID::try_from(src_mreg as u32) {
 					Ok(x) => x,
-					Err(_) => return self.take_exception(Exception::InvalidOperation),
+					Err(_) => return self.raise_exception(Exception::InvalidOperation),
 				};
 
-				if !src_mreg.check_access(self.flags.privilege_level(), false) {
-					return self.take_exception(Exception::InvalidOperation);
+				if !self.has_machine_register(src_mreg)
+					|| !src_mreg.check_access(self.flags.privilege_level(), false)
+				{
+					return self.raise_exception(Exception::InvalidOperation);
 				}
 
-				let val: u64 = match src_mreg {
-					MachineRegisterID::flags => self.flags.into(),
-					MachineRegisterID::elr => self.elr.into(),
-					MachineRegisterID::esp => self.esp.into(),
-					MachineRegisterID::eflags => self.eflags.into(),
-					MachineRegisterID::einfo => self.einfo,
-					MachineRegisterID::eaddr => self.eaddr.into(),
-					MachineRegisterID::evtable => self.evtable_addr.into(),
-					MachineRegisterID::ectable => self.ectable_addr.into(),
-					_ => unreachable!(),
-				};
-
-				self.register_file[dst] = val.into();
+				self.register_file[dst] = self.machine_register(src_mreg).into();
 			},
 			[000101aaaadddddddddddddddddddddd] => stm dst_mreg = d: imm22, src = a: reg {
 				let dst_mreg = match MachineRegisterID::try_from(dst_mreg as u32) {
 					Ok(x) => x,
-					Err(_) => return self.take_exception(Exception::InvalidOperation),
+					Err(_) => return self.raise_exception(Exception::InvalidOperation),
 				};
 				let src = self.register_file[src].get();
 
-				if !dst_mreg.check_access(self.flags.privilege_level(), true) {
-					return self.take_exception(Exception::InvalidOperation);
+				if !self.has_machine_register(dst_mreg)
+					|| !dst_mreg.check_access(self.flags.privilege_level(), true)
+				{
+					return self.raise_exception(Exception::InvalidOperation);
 				}
 
-				match dst_mreg {
-					MachineRegisterID::flags => {
-						if let Ok(flags) = src.try_into() {
-							self.flags = flags;
-						} else {
-							return self.take_exception(Exception::InvalidOperation);
-						}
-					},
-					MachineRegisterID::elr => {
-						let src_addr = VMAddress::from(src);
-						if src_addr.is_valid_instruction_pointer() {
-							self.elr = src_addr;
-						} else {
-							return self.take_exception(Exception::InvalidOperation);
-						}
-					},
-					MachineRegisterID::esp => {
-						self.esp = src.into();
-					},
-					MachineRegisterID::eflags => {
-						if let Ok(flags) = src.try_into() {
-							self.eflags = flags;
-						} else {
-							return self.take_exception(Exception::InvalidOperation);
-						}
-					},
-					MachineRegisterID::evtable => {
-						let src_addr = VMAddress::from(src);
-						if src_addr.is_valid_instruction_pointer() {
-							self.evtable_addr = src_addr;
-						} else {
-							return self.take_exception(Exception::InvalidOperation);
-						}
-					},
-					MachineRegisterID::ectable => {
-						let src_addr = VMAddress::from(src);
-						let mem = get_memory!(src_addr, std::mem::size_of::<ExceptionConfigurationTable>() as u64);
-
-						// SAFETY: it's safe to read the table from the pointer since the type (ExceptionConfigurationTable) is Copy.
-						//         additionally, we've already verified that we have the necessary space because the slice above is
-						//         of the required length.
-						let tmp = unsafe { std::ptr::read_unaligned(mem.as_ptr() as *const ExceptionConfigurationTable) };
-
-						// now let's check the table entries
-						if !tmp.pl0.iter().all(ExceptionConfigurationEntry::validate) || !tmp.pl1.iter().all(ExceptionConfigurationEntry::validate) {
-							return self.take_exception(Exception::InvalidOperation);
-						}
-
-						self.ectable_addr = src.into();
-						self.ectable = tmp;
-					},
-					MachineRegisterID::vm_console => {
-						let character: char = (src as u8).into();
-						print!("{}", character);
-					},
-					_ => unreachable!(),
+				if let Err(exception) = self.set_machine_register(dst_mreg, src) {
+					return self.raise_exception(exception);
 				}
 			},
 			_ => {
 				// invalid instruction
-				self.take_exception(Exception::InvalidInstruction);
+				return self.raise_exception(Exception::InvalidInstruction);
 			},
 		}
 
 		self.instruction_pointer += 4;
+
+		StepResult::Executed
 	}
 
-	fn take_exception(&mut self, exception: Exception) {
-		println!(
-			"***Exception ({:?}) at {:#x}***",
-			exception,
-			u64::from(self.instruction_pointer)
-		);
+	fn raise_exception(&mut self, exception: Exception) -> StepResult {
+		if self.trap_exceptions {
+			StepResult::Trapped(exception)
+		} else {
+			self.take_exception(exception)
+		}
+	}
 
+	pub fn take_exception(&mut self, exception: Exception) -> StepResult {
 		self.eflags = self.flags;
 		self.elr = self.instruction_pointer;
 		self.flags.set_exceptions_enabled(false);
 		self.flags.set_privilege_level(PrivilegeLevel::PL0);
 		self.esp = self.register_file[RegisterID::SP].get_address();
 
-		self.einfo = match exception {
-			Exception::Unknown => 0,
-			Exception::InvalidInstruction => 1,
-			Exception::Debug => 2,
-			Exception::User(val) => 3 | ((val as u64) << 3),
-			Exception::InvalidOperation => 4,
-			Exception::InstructionLoadError => 5,
-			Exception::DataLoadError {
-				address: _,
-				write,
-				byte_size,
-			} => 6 | (if write { 1 << 3 } else { 0 }) | ((byte_size as u64) << 4),
-			Exception::Interrupt(val) => 7 | (val << 3),
-		};
-
-		self.eaddr = match exception {
-			Exception::DataLoadError { address, .. } => address,
-			_ => 0.into(),
-		};
+		self.einfo = exception.info();
+		self.eaddr = exception.address();
 
 		let ectable_pl = match self.eflags.privilege_level() {
 			PrivilegeLevel::PL0 => &self.ectable.pl0,
@@ -1035,11 +1604,308 @@ impl VM {
 		} * 256;
 		let exc_offset = (exception.id() as u64) * 32;
 		self.instruction_pointer = self.evtable_addr + pl_offset + exc_offset;
+
+		StepResult::Exception(exception)
+	}
+
+	/// Reads the RAM that a write is about to overwrite, but only if the write will hit a watchpoint.
+	fn watched_old_value(&self, address: VMAddress, size: Size) -> Option<u64> {
+		let byte_size = size.byte_size() as u64;
+		if !self
+			.watchpoints
+			.iter()
+			.any(|watchpoint| watchpoint.triggered_by(address.into(), byte_size, true))
+		{
+			return None;
+		}
+		let physical = self.translate(address)?;
+		self.bus.memory().read(physical.into(), size)
+	}
+
+	fn check_watchpoints(
+		&mut self,
+		address: VMAddress,
+		byte_size: u64,
+		write: bool,
+		old_value: Option<u64>,
+		value: Option<u64>,
+	) {
+		for watchpoint in &self.watchpoints {
+			if watchpoint.triggered_by(address.into(), byte_size, write) {
+				self.watch_hits.push(WatchHit {
+					watchpoint: watchpoint.clone(),
+					instruction_pointer: self.instruction_pointer.into(),
+					address: address.into(),
+					byte_size,
+					write,
+					old_value,
+					value,
+				});
+			}
+		}
+	}
+
+	fn trace_memory_access(&mut self, address: VMAddress, size: Size, write: bool, value: u64) {
+		if let Some(record) = self.trace_record.as_mut() {
+			record.memory_accesses.push(MemoryAccess {
+				address: address.into(),
+				size: size.byte_size(),
+				write,
+				value,
+			});
+		}
+	}
+
+	/// Captures the complete state of the guest (except for attached devices).
+	pub fn snapshot(&self) -> Snapshot {
+		let mut registers = [0; 16];
+		for (index, value) in registers.iter_mut().enumerate() {
+			*value = self.register_file[index].get();
+		}
+
+		let mut ectable = [0; ECTABLE_WORDS];
+		for (words, entry) in ectable
+			.chunks_mut(3)
+			.zip(self.ectable.pl0.iter().chain(&self.ectable.pl1))
+		{
+			words.copy_from_slice(&[
+				entry.flags.bits(),
+				entry.stack_pointer.into(),
+				entry.stack_size,
+			]);
+		}
+
+		Snapshot {
+			sparse_memory: self.bus.memory().is_sparse(),
+			ram_size: self.memory_size(),
+			ram_pages: Snapshot::sparse_pages(self.bus.memory().pages()),
+
+			registers,
+			flags: self.flags.into(),
+			instruction_pointer: self.instruction_pointer.into(),
+
+			elr: self.elr.into(),
+			esp: self.esp.into(),
+			eflags: self.eflags.into(),
+			einfo: self.einfo,
+			eaddr: self.eaddr.into(),
+			evtable_addr: self.evtable_addr.into(),
+			ectable_addr: self.ectable_addr.into(),
+			ectable,
+			ptbase: self.mmu.as_ref().map_or(0, Mmu::ptbase),
+
+			timer_control: self.timer.control(),
+			timer_interval: self.timer.interval(),
+			timer_remaining: self.timer.remaining(),
+			pending_interrupts: self.pending_interrupts.iter().copied().collect(),
+			exit_code: self.exit_code,
+		}
 	}
 
-	pub fn run(mut self) -> ! {
+	/// Replaces the state of the guest with the given snapshot.
+	///
+	/// The snapshot must have been taken from a VM with the same kind and amount of memory. Nothing is modified if it's
+	/// rejected.
+	pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+		if snapshot.sparse_memory != self.bus.memory().is_sparse() {
+			return Err(SnapshotError::MemoryKindMismatch {
+				sparse: snapshot.sparse_memory,
+			});
+		}
+		if snapshot.ram_size != self.memory_size() {
+			return Err(SnapshotError::RamSizeMismatch {
+				expected: self.memory_size(),
+				found: snapshot.ram_size,
+			});
+		}
+
+		let flags =
+			CPUFlags::try_from(snapshot.flags).map_err(|_| SnapshotError::InvalidState("flags"))?;
+		let eflags = CPUFlags::try_from(snapshot.eflags)
+			.map_err(|_| SnapshotError::InvalidState("eflags"))?;
+
+		let mut ectable = ExceptionConfigurationTable::default();
+		for (words, entry) in snapshot
+			.ectable
+			.chunks(3)
+			.zip(ectable.pl0.iter_mut().chain(ectable.pl1.iter_mut()))
+		{
+			*entry = ExceptionConfigurationEntry {
+				flags: ExceptionConfigurationFlags::from_bits(words[0])
+					.ok_or(SnapshotError::InvalidState("exception configuration table"))?,
+				stack_pointer: words[1].into(),
+				stack_size: words[2],
+			};
+		}
+
+		let mut mmu = self.mmu.clone();
+		let ptbase_ok = match &mut mmu {
+			Some(mmu) => mmu.set_ptbase(snapshot.ptbase),
+			None => snapshot.ptbase == 0,
+		};
+		if !ptbase_ok {
+			return Err(SnapshotError::InvalidState("ptbase"));
+		}
+
+		let mut timer = self.timer.clone();
+		if !timer.restore(
+			snapshot.timer_control,
+			snapshot.timer_interval,
+			snapshot.timer_remaining,
+		) {
+			return Err(SnapshotError::InvalidState("timer control"));
+		}
+
+		let page_ranges = snapshot
+			.ram_pages
+			.iter()
+			.map(|(&address, page)| Some(address..address.checked_add(page.len() as u64)?))
+			.collect::<Option<Vec<_>>>();
+		if !page_ranges.is_some_and(|ranges| self.bus.memory().can_restore(&ranges)) {
+			return Err(SnapshotError::Corrupt("page out of range"));
+		}
+
+		self.block_cache.clear();
+		let memory = self.bus.memory_mut();
+		memory.clear();
+		for (&address, page) in &snapshot.ram_pages {
+			memory
+				.load(address, page)
+				.expect("page should have been checked");
+		}
+
+		for (index, &value) in snapshot.registers.iter().enumerate() {
+			self.register_file[index] = value.into();
+		}
+		self.flags = flags;
+		self.instruction_pointer = snapshot.instruction_pointer.into();
+
+		self.elr = snapshot.elr.into();
+		self.esp = snapshot.esp.into();
+		self.eflags = eflags;
+		self.einfo = snapshot.einfo;
+		self.eaddr = snapshot.eaddr.into();
+		self.evtable_addr = snapshot.evtable_addr.into();
+		self.ectable_addr = snapshot.ectable_addr.into();
+		self.ectable = ectable;
+		self.mmu = mmu;
+
+		self.timer = timer;
+		self.pending_interrupts = snapshot.pending_interrupts.iter().copied().collect();
+		self.exit_code = snapshot.exit_code;
+
+		Ok(())
+	}
+
+	/// The status the guest exited with, if it has exited.
+	pub fn exit_code(&self) -> Option<u64> {
+		self.exit_code
+	}
+
+	/// Marks the given interrupt as pending.
+	///
+	/// It will be taken before the next instruction is executed once maskable exceptions are enabled.
+	/// Raising an interrupt that's already pending has no effect.
+	pub fn raise_interrupt(&mut self, number: u64) {
+		self.pending_interrupts.insert(number);
+	}
+
+	/// Executes a single instruction, taking any exception it raises.
+	///
+	/// If there's a pending interrupt and maskable exceptions are enabled, the interrupt is taken instead
+	/// (without executing an instruction). Lower interrupt numbers are taken first.
+	pub fn step(&mut self) -> StepResult {
+		self.watch_hits.clear();
+
+		if let Some(code) = self.exit_code {
+			return StepResult::Exited(code);
+		}
+
+		let tracer = match self.tracer.as_mut() {
+			Some(x) => x,
+			None => return self.step_untraced(),
+		};
+
+		self.trace_record = Some(tracer.begin(self.instruction_pointer.into()));
+		let old_registers = self.register_file;
+		let old_flags = self.flags;
+
+		let result = self.step_untraced();
+
+		let mut record = self.trace_record.take().unwrap();
+		for index in 0..16 {
+			let id = RegisterID::from(index);
+			let (old, new) = (old_registers[id].get(), self.register_file[id].get());
+			if old != new {
+				record.register_writes.push(RegisterWrite {
+					register: id,
+					old,
+					new,
+				});
+			}
+		}
+		let (old_flags, new_flags) = (u64::from(old_flags), u64::from(self.flags));
+		if old_flags != new_flags {
+			record.flags = Some(FlagsChange {
+				old: old_flags,
+				new: new_flags,
+			});
+		}
+		record.exception = match result {
+			StepResult::Exception(exception) | StepResult::Trapped(exception) => {
+				Some(ExceptionInfo {
+					exception,
+					einfo: exception.info(),
+					eaddr: exception.address().into(),
+					taken: matches!(result, StepResult::Exception(_)),
+				})
+			},
+			_ => None,
+		};
+
+		// the tracer can't have been removed while stepping, since that requires a mutable borrow of the VM
+		self.tracer.as_mut().unwrap().write(&record);
+
+		result
+	}
+
+	fn step_untraced(&mut self) -> StepResult {
+		let pending_interrupts = &mut self.pending_interrupts;
+		let block_cache = &mut self.block_cache;
+		self.bus.poll(
+			|number| {
+				pending_interrupts.insert(number);
+			},
+			|range| block_cache.invalidate(range.start, range.end - range.start),
+		);
+
+		if self.flags.exceptions_enabled() {
+			if let Some(number) = self.pending_interrupts.pop_first() {
+				return self.raise_exception(Exception::Interrupt(number));
+			}
+		}
+
+		match self.execute_one() {
+			StepResult::Executed => {
+				// the timer counts instructions, so steps that only take an exception don't count
+				if self.timer.tick() {
+					self.raise_interrupt(self.timer.interrupt());
+				}
+				match self.exit_code {
+					Some(code) => StepResult::Exited(code),
+					None => StepResult::Executed,
+				}
+			},
+			result => result,
+		}
+	}
+
+	/// Runs the guest until it exits, returning its exit status.
+	pub fn run(&mut self) -> u64 {
 		loop {
-			self.execute_one()
+			if let StepResult::Exited(code) = self.step() {
+				return code;
+			}
 		}
 	}
 }
@@ -0,0 +1,104 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{fmt, ops::Range};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+	Read,
+	Write,
+	/// Both reads and writes.
+	Access,
+}
+
+/// A range of guest memory whose accesses are reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+	pub range: Range<u64>,
+	pub kind: WatchKind,
+}
+
+/// A data access that touched a watched range.
+///
+/// Hits are reported once the instruction that caused them has completed, so the instruction is never re-executed
+/// to report them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+	pub watchpoint: Watchpoint,
+	/// The address of the instruction that performed the access.
+	pub instruction_pointer: u64,
+	pub address: u64,
+	pub byte_size: u64,
+	pub write: bool,
+	/// For writes to RAM, the value that was overwritten.
+	pub old_value: Option<u64>,
+	/// The value read or written, if the access was no larger than a word.
+	pub value: Option<u64>,
+}
+
+impl WatchKind {
+	pub fn matches(&self, write: bool) -> bool {
+		match self {
+			WatchKind::Read => !write,
+			WatchKind::Write => write,
+			WatchKind::Access => true,
+		}
+	}
+}
+
+impl fmt::Display for WatchKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			WatchKind::Read => write!(f, "read"),
+			WatchKind::Write => write!(f, "write"),
+			WatchKind::Access => write!(f, "access"),
+		}
+	}
+}
+
+impl Watchpoint {
+	pub fn new(range: Range<u64>, kind: WatchKind) -> Self {
+		Self { range, kind }
+	}
+
+	/// Checks whether an access of `byte_size` bytes at `address` triggers this watchpoint.
+	pub fn triggered_by(&self, address: u64, byte_size: u64, write: bool) -> bool {
+		self.kind.matches(write)
+			&& address < self.range.end
+			&& self.range.start < address.saturating_add(byte_size)
+	}
+}
+
+impl fmt::Display for Watchpoint {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{} {:#x}-{:#x}",
+			self.kind, self.range.start, self.range.end
+		)
+	}
+}
+
+impl fmt::Display for WatchHit {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{} of {} bytes at {:#x} by instruction at {:#x} ({})",
+			if self.write { "write" } else { "read" },
+			self.byte_size,
+			self.address,
+			self.instruction_pointer,
+			self.watchpoint
+		)?;
+		match (self.old_value, self.value) {
+			(Some(old), Some(new)) => write!(f, ": {:#x} -> {:#x}", old, new),
+			(None, Some(value)) => write!(f, ": {:#x}", value),
+			_ => Ok(()),
+		}
+	}
+}
@@ -0,0 +1,91 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{fs, path::PathBuf, process::Command};
+
+/// Prints `A`, then patches the `ldi` that loaded it (which is already cached) and runs it again.
+const PATCH_CODE: &str = "
+.def CONSOLE 0x1000_0000
+.def mreg_vm_exit 0xdead_4
+
+.addr 0x400
+entry:
+	ldi r10, CONSOLE >> 16, 16, 3
+	ldi r8, 0, 0, 3
+print:
+	ldi r9, 'A', 0, 3
+	sts r10, r9b
+	cmp r8, 0
+	jmpr.nz done
+	ldi r8, 1, 0, 3
+	ldr r11, print
+	ldr r12, replacement
+	lds r13q, r12
+	sts r11, r13q
+	jmpr print
+done:
+	ldi r0, 0, 0, 3
+	stm mreg_vm_exit, r0
+replacement:
+	ldi r9, 'B', 0, 3
+";
+
+/// Counts up in a variable that lives in the same page as the loop that updates it.
+const SHARED_PAGE: &str = "
+.def CONSOLE 0x1000_0000
+.def mreg_vm_exit 0xdead_4
+
+.addr 0x400
+entry:
+	ldi r10, CONSOLE >> 16, 16, 3
+	ldr r11, counter
+loop:
+	lds r9b, r11
+	sts r10, r9b
+	add r9b, r9b, 1
+	sts r11, r9b
+	cmp r9b, '5'
+	jmpr.nz loop
+	ldi r0, 0, 0, 3
+	stm mreg_vm_exit, r0
+counter:
+	.write.b '0'
+";
+
+fn run_program(name: &str, source: &str) -> String {
+	let directory = std::env::temp_dir().join(format!("acca-emu-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&directory);
+	fs::create_dir_all(&directory).unwrap();
+
+	let image = acca_as::assemble(source, &Default::default()).unwrap();
+	let image_path: PathBuf = directory.join("program.bin");
+	fs::write(&image_path, &image.bytes).unwrap();
+
+	let output = Command::new(env!("CARGO_BIN_EXE_acca-emu"))
+		.arg(&image_path)
+		.output()
+		.unwrap();
+	assert!(
+		output.status.success(),
+		"acca-emu failed: {}",
+		String::from_utf8_lossy(&output.stderr)
+	);
+
+	let _ = fs::remove_dir_all(&directory);
+	String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn patched_instructions_are_executed() {
+	assert_eq!(run_program("patch-code", PATCH_CODE), "AB");
+}
+
+#[test]
+fn data_in_a_code_page_can_change() {
+	assert_eq!(run_program("shared-page", SHARED_PAGE), "01234");
+}
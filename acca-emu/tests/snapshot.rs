@@ -0,0 +1,133 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{fs, path::PathBuf, process::Command};
+
+const PROGRAM: &str = "
+.def CONSOLE 0x1000_0000
+.def mreg_vm_exit 0xdead_4
+
+.addr 0x400
+entry:
+	ldi r10, CONSOLE >> 16, 16, 3
+	ldi r9, 'H', 0, 3
+	sts r10, r9b
+resume:
+	ldi r9, 'i', 0, 3
+	sts r10, r9b
+	ldi r0, 0, 0, 3
+	stm mreg_vm_exit, r0
+";
+
+/// Raises (and returns from) an exception before printing, to check that `--snapshot-at COUNT` only counts
+/// instructions that completed.
+const EXCEPTION_PROGRAM: &str = "
+.def CONSOLE 0x1000_0000
+.def mreg_vm_exit 0xdead_4
+
+.addr 0x0260
+evtable_pl0_user:
+	eret
+
+.addr 0x400
+entry:
+	ldi r10, CONSOLE >> 16, 16, 3
+	ldi r9, 0, 0, 3
+	stm ectable, r9
+	ldi r9, 0x0200, 0, 3
+	stm evtable, r9
+	exc 1
+	ldi r9, 'H', 0, 3
+	sts r10, r9b
+	ldi r9, 'i', 0, 3
+	sts r10, r9b
+	ldi r0, 0, 0, 3
+	stm mreg_vm_exit, r0
+";
+
+fn scratch_directory(name: &str) -> PathBuf {
+	let directory = std::env::temp_dir().join(format!("acca-emu-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&directory);
+	fs::create_dir_all(&directory).unwrap();
+	directory
+}
+
+fn run(args: &[&str]) -> String {
+	let output = Command::new(env!("CARGO_BIN_EXE_acca-emu"))
+		.args(args)
+		.output()
+		.unwrap();
+	assert!(
+		output.status.success(),
+		"acca-emu failed: {}",
+		String::from_utf8_lossy(&output.stderr)
+	);
+	String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn restore_resumes_under_machine_entry_point() {
+	let directory = scratch_directory("snapshot");
+	let image = acca_as::assemble(PROGRAM, &Default::default()).unwrap();
+	let image_path = directory.join("program.bin");
+	fs::write(&image_path, &image.bytes).unwrap();
+
+	// the machine's entry point is where the program starts, not where a restored snapshot resumes
+	let machine_path = directory.join("machine.toml");
+	fs::write(
+		&machine_path,
+		format!(
+			"entry = 0x400\n\n[[devices]]\ntype = \"console\"\nbase = 0x1000_0000\n\n[[images]]\npath = {:?}\n",
+			image_path
+		),
+	)
+	.unwrap();
+	let machine = machine_path.to_str().unwrap();
+	let snapshot = directory.join("snapshot.bin");
+	let snapshot = snapshot.to_str().unwrap();
+	let resume = format!("{:#x}", image.symbol("resume").unwrap());
+
+	let output = run(&[
+		"--machine",
+		machine,
+		"--snapshot-at",
+		&resume,
+		"--snapshot",
+		snapshot,
+	]);
+	assert_eq!(output, "Hi");
+
+	let output = run(&["--machine", machine, "--restore", snapshot]);
+	assert_eq!(output, "i");
+
+	fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn snapshot_count_skips_exceptions() {
+	let directory = scratch_directory("snapshot-count");
+	let image = acca_as::assemble(EXCEPTION_PROGRAM, &Default::default()).unwrap();
+	let image_path = directory.join("program.bin");
+	fs::write(&image_path, &image.bytes).unwrap();
+	let image_path = image_path.to_str().unwrap();
+	let snapshot = directory.join("snapshot.bin");
+	let snapshot = snapshot.to_str().unwrap();
+
+	// five setup instructions, the `eret`, and the first `ldi` and `sts`; the `exc` doesn't count
+	let output = run(&[image_path, "--snapshot-at", "8", "--snapshot", snapshot]);
+	assert!(
+		output.ends_with("***\nHi"),
+		"unexpected output: {:?}",
+		output
+	);
+
+	let output = run(&["--restore", snapshot]);
+	assert_eq!(output, "i");
+
+	fs::remove_dir_all(&directory).unwrap();
+}
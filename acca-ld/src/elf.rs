@@ -0,0 +1,266 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Writes ELF executables: one loadable segment per section, plus a symbol table.
+
+/// The `e_machine` of Acca executables.
+pub const EM_ACCA: u16 = 0xacca;
+
+const HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const SECTION_HEADER_SIZE: u64 = 64;
+const SYMBOL_SIZE: u64 = 24;
+
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHN_ABS: u16 = 0xfff1;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+
+pub struct ElfSection<'a> {
+	pub name: &'a str,
+	pub address: u64,
+	pub data: &'a [u8],
+	/// Whether the section contains instructions (rather than data).
+	pub code: bool,
+}
+
+pub struct ElfSymbol<'a> {
+	pub name: &'a str,
+	pub address: u64,
+	pub global: bool,
+}
+
+/// A string table, starting with the empty string.
+struct StringTable(Vec<u8>);
+
+impl StringTable {
+	fn new() -> Self {
+		Self(vec![0])
+	}
+
+	fn add(&mut self, string: &str) -> u32 {
+		let offset = self.0.len() as u32;
+		self.0.extend_from_slice(string.as_bytes());
+		self.0.push(0);
+		offset
+	}
+}
+
+#[derive(Default)]
+struct SectionHeader {
+	name: u32,
+	kind: u32,
+	flags: u64,
+	address: u64,
+	offset: u64,
+	size: u64,
+	link: u32,
+	info: u32,
+	alignment: u64,
+	entry_size: u64,
+}
+
+impl SectionHeader {
+	fn write(&self, writer: &mut Writer) {
+		writer.u32(self.name);
+		writer.u32(self.kind);
+		writer.u64(self.flags);
+		writer.u64(self.address);
+		writer.u64(self.offset);
+		writer.u64(self.size);
+		writer.u32(self.link);
+		writer.u32(self.info);
+		writer.u64(self.alignment);
+		writer.u64(self.entry_size);
+	}
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+	fn u8(&mut self, value: u8) {
+		self.0.push(value);
+	}
+
+	fn u16(&mut self, value: u16) {
+		self.0.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn u32(&mut self, value: u32) {
+		self.0.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn u64(&mut self, value: u64) {
+		self.0.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn align(&mut self, alignment: usize) {
+		while !self.0.len().is_multiple_of(alignment) {
+			self.0.push(0);
+		}
+	}
+
+	fn position(&self) -> u64 {
+		self.0.len() as u64
+	}
+}
+
+/// Builds a 64-bit little-endian executable.
+pub fn write_elf(entry: u64, sections: &[ElfSection], symbols: &[ElfSymbol]) -> Vec<u8> {
+	let mut section_names = StringTable::new();
+	let mut symbol_names = StringTable::new();
+
+	// section indices: null, the sections, .symtab, .strtab, .shstrtab
+	let symtab_index = sections.len() as u32 + 1;
+	let section_count = sections.len() as u64 + 4;
+	let section_index = |address: u64| {
+		sections
+			.iter()
+			.position(|section| {
+				(section.address..section.address + section.data.len() as u64).contains(&address)
+			})
+			.map_or(SHN_ABS, |index| index as u16 + 1)
+	};
+
+	let mut writer = Writer(Vec::new());
+	writer.0.resize(
+		(HEADER_SIZE + PROGRAM_HEADER_SIZE * sections.len() as u64) as usize,
+		0,
+	);
+
+	let mut data_offsets = Vec::new();
+	for section in sections {
+		writer.align(4);
+		data_offsets.push(writer.position());
+		writer.0.extend_from_slice(section.data);
+	}
+
+	// local symbols must come first
+	let mut sorted: Vec<&ElfSymbol> = symbols.iter().collect();
+	sorted.sort_by_key(|symbol| symbol.global);
+	let first_global = 1 + sorted.iter().take_while(|symbol| !symbol.global).count() as u32;
+
+	writer.align(8);
+	let symtab_offset = writer.position();
+	writer.0.resize(writer.0.len() + SYMBOL_SIZE as usize, 0);
+	for symbol in &sorted {
+		writer.u32(symbol_names.add(symbol.name));
+		writer.u8(if symbol.global { STB_GLOBAL } else { STB_LOCAL } << 4);
+		writer.u8(0);
+		writer.u16(section_index(symbol.address));
+		writer.u64(symbol.address);
+		writer.u64(0);
+	}
+	let symtab_size = writer.position() - symtab_offset;
+
+	let strtab_offset = writer.position();
+	writer.0.extend_from_slice(&symbol_names.0);
+
+	let name_offsets: Vec<u32> = sections
+		.iter()
+		.map(|section| section_names.add(section.name))
+		.collect();
+	let symtab_name = section_names.add(".symtab");
+	let strtab_name = section_names.add(".strtab");
+	let shstrtab_name = section_names.add(".shstrtab");
+	let shstrtab_offset = writer.position();
+	writer.0.extend_from_slice(&section_names.0);
+
+	writer.align(8);
+	let section_headers_offset = writer.position();
+	SectionHeader::default().write(&mut writer);
+	for ((section, &name), &offset) in sections.iter().zip(&name_offsets).zip(&data_offsets) {
+		SectionHeader {
+			name,
+			kind: SHT_PROGBITS,
+			flags: SHF_ALLOC
+				| if section.code {
+					SHF_EXECINSTR
+				} else {
+					SHF_WRITE
+				},
+			address: section.address,
+			offset,
+			size: section.data.len() as u64,
+			alignment: 4,
+			..Default::default()
+		}
+		.write(&mut writer);
+	}
+	SectionHeader {
+		name: symtab_name,
+		kind: SHT_SYMTAB,
+		offset: symtab_offset,
+		size: symtab_size,
+		// the string table follows the symbol table
+		link: symtab_index + 1,
+		info: first_global,
+		alignment: 8,
+		entry_size: SYMBOL_SIZE,
+		..Default::default()
+	}
+	.write(&mut writer);
+	for (name, offset, table) in [
+		(strtab_name, strtab_offset, &symbol_names),
+		(shstrtab_name, shstrtab_offset, &section_names),
+	] {
+		SectionHeader {
+			name,
+			kind: SHT_STRTAB,
+			offset,
+			size: table.0.len() as u64,
+			alignment: 1,
+			..Default::default()
+		}
+		.write(&mut writer);
+	}
+
+	// now that everything's been laid out, go back and fill in the headers
+	let mut headers = Writer(Vec::new());
+	headers.0.extend_from_slice(b"\x7fELF");
+	// 64-bit, little-endian, version 1, System V ABI
+	headers.0.extend_from_slice(&[2, 1, 1, 0]);
+	headers.0.resize(16, 0);
+	headers.u16(ET_EXEC);
+	headers.u16(EM_ACCA);
+	headers.u32(1);
+	headers.u64(entry);
+	headers.u64(HEADER_SIZE);
+	headers.u64(section_headers_offset);
+	headers.u32(0);
+	headers.u16(HEADER_SIZE as u16);
+	headers.u16(PROGRAM_HEADER_SIZE as u16);
+	headers.u16(sections.len() as u16);
+	headers.u16(SECTION_HEADER_SIZE as u16);
+	headers.u16(section_count as u16);
+	headers.u16(symtab_index as u16 + 2);
+
+	for (section, &offset) in sections.iter().zip(&data_offsets) {
+		headers.u32(PT_LOAD);
+		headers.u32(PF_R | if section.code { PF_X } else { PF_W });
+		headers.u64(offset);
+		headers.u64(section.address);
+		headers.u64(section.address);
+		headers.u64(section.data.len() as u64);
+		headers.u64(section.data.len() as u64);
+		headers.u64(4);
+	}
+
+	writer.0[..headers.0.len()].copy_from_slice(&headers.0);
+	writer.0
+}
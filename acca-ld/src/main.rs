@@ -0,0 +1,457 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+mod elf;
+mod script;
+
+use std::{collections::HashMap, fs, path::PathBuf, process::exit};
+
+use acca_as::object::{Binding, Object, RelocationError, RelocationTarget, SymbolValue};
+use clap::{Parser as ClapParser, ValueEnum};
+use elf::{write_elf, ElfSection, ElfSymbol};
+use script::{matches, Command, EntryPoint, Script};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+	Flat,
+	Elf,
+}
+
+#[derive(ClapParser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+	#[arg(required = true)]
+	objects: Vec<PathBuf>,
+
+	#[arg(short, long)]
+	output: PathBuf,
+
+	#[arg(short = 'T', long, value_name = "FILE")]
+	script: Option<PathBuf>,
+
+	#[arg(short, long, value_enum, default_value = "flat")]
+	format: OutputFormat,
+
+	#[arg(long, value_name = "FILE")]
+	symbol_map: Option<PathBuf>,
+}
+
+/// An object being linked.
+struct Input {
+	name: String,
+	object: Object,
+	/// The address of each section, once placed.
+	addresses: Vec<Option<u64>>,
+}
+
+/// Where a global symbol is defined.
+#[derive(Debug, Clone, Copy)]
+enum Definition {
+	Object {
+		input: usize,
+		symbol: usize,
+	},
+	/// Defined by the linker script.
+	Script(u64),
+}
+
+/// A section of the output: the contents of one input section, at its final address.
+struct Placed {
+	name: String,
+	address: u64,
+	data: Vec<u8>,
+}
+
+struct Linker {
+	inputs: Vec<Input>,
+	globals: HashMap<String, Definition>,
+	errors: Vec<String>,
+}
+
+impl Linker {
+	fn error(&mut self, message: String) {
+		self.errors.push(message);
+	}
+
+	/// Fails if any errors were reported.
+	fn check(&self) {
+		if self.errors.is_empty() {
+			return;
+		}
+		for error in &self.errors {
+			eprintln!("error: {}", error);
+		}
+		eprintln!(
+			"error: linking failed due to {} error(s)",
+			self.errors.len()
+		);
+		exit(1);
+	}
+
+	fn define(&mut self, name: &str, definition: Definition, defined_by: &str) {
+		let previous = match self.globals.get(name) {
+			Some(Definition::Object { input, .. }) => self.inputs[*input].name.clone(),
+			Some(Definition::Script(_)) => "the linker script".to_string(),
+			None => {
+				self.globals.insert(name.to_string(), definition);
+				return;
+			},
+		};
+		self.error(format!(
+			"duplicate symbol \"{}\" (defined by {} and {})",
+			name, previous, defined_by
+		));
+	}
+
+	fn collect_globals(&mut self) {
+		for input in 0..self.inputs.len() {
+			for symbol in 0..self.inputs[input].object.symbols.len() {
+				let object = &self.inputs[input].object;
+				if object.global(&object.symbols[symbol].name) != Some(symbol as u32) {
+					continue;
+				}
+				let name = object.symbols[symbol].name.clone();
+				let defined_by = self.inputs[input].name.clone();
+				self.define(&name, Definition::Object { input, symbol }, &defined_by);
+			}
+		}
+	}
+
+	/// Places the sections matching `patterns` at `address`, returning the address after them.
+	fn place(&mut self, patterns: &[&str], mut address: u64) -> u64 {
+		for input in &mut self.inputs {
+			for (section, placed) in input.object.sections.iter().zip(&mut input.addresses) {
+				if placed.is_some()
+					|| !patterns
+						.iter()
+						.any(|pattern| matches(pattern, &section.name))
+				{
+					continue;
+				}
+				address = address.next_multiple_of(section.alignment);
+				*placed = Some(address);
+				address += section.data.len() as u64;
+			}
+		}
+		address
+	}
+
+	/// Runs the linker script, returning the entry point it chose (if any).
+	fn run_script(&mut self, script: &Script) -> Option<EntryPoint> {
+		let mut address = 0u64;
+		let mut entry = None;
+
+		for command in &script.commands {
+			match command {
+				Command::Entry(point) => entry = Some(point.clone()),
+				Command::At(new_address) => address = *new_address,
+				Command::Align(alignment) => address = address.next_multiple_of(*alignment),
+				Command::Place(patterns) => {
+					let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+					address = self.place(&patterns, address);
+				},
+				Command::Symbol(name) => {
+					self.define(name, Definition::Script(address), "the linker script")
+				},
+			}
+		}
+
+		// whatever the script didn't mention goes at the end
+		self.place(&["*"], address);
+		entry
+	}
+
+	fn check_overlaps(&mut self) {
+		let mut ranges = Vec::new();
+		for input in &self.inputs {
+			for (section, address) in input.object.sections.iter().zip(&input.addresses) {
+				let start = address.unwrap();
+				if !section.data.is_empty() {
+					ranges.push((
+						start,
+						start + section.data.len() as u64,
+						&input.name,
+						&section.name,
+					));
+				}
+			}
+		}
+		ranges.sort();
+
+		let mut errors = Vec::new();
+		for pair in ranges.windows(2) {
+			let ((_, end, first_input, first), (start, _, second_input, second)) =
+				(pair[0], pair[1]);
+			if start < end {
+				errors.push(format!(
+					"section {} of {} overlaps section {} of {} at {:#x}",
+					second, second_input, first, first_input, start
+				));
+			}
+		}
+		self.errors.extend(errors);
+	}
+
+	fn definition_address(&self, definition: Definition) -> Option<u64> {
+		match definition {
+			Definition::Object { input, symbol } => {
+				self.symbol_address(input, &self.inputs[input].object.symbols[symbol].value)
+			},
+			Definition::Script(address) => Some(address),
+		}
+	}
+
+	/// Returns the final value of a symbol defined by `input`, or `None` if it's undefined.
+	fn symbol_address(&self, input: usize, value: &SymbolValue) -> Option<u64> {
+		match *value {
+			SymbolValue::Undefined => None,
+			SymbolValue::Absolute(value) => Some(value),
+			SymbolValue::Section { section, offset } => {
+				Some(self.inputs[input].addresses[section as usize].unwrap() + offset)
+			},
+		}
+	}
+
+	/// Resolves a symbol referenced by `input`, looking undefined ones up among the global symbols.
+	fn resolve(&self, input: usize, symbol: usize) -> Option<u64> {
+		let symbol = &self.inputs[input].object.symbols[symbol];
+		match symbol.value {
+			SymbolValue::Undefined => self
+				.globals
+				.get(&symbol.name)
+				.and_then(|definition| self.definition_address(*definition)),
+			value => self.symbol_address(input, &value),
+		}
+	}
+
+	/// Copies every section to its final address and applies its relocations.
+	fn relocate(&mut self) -> Vec<Placed> {
+		let mut placed = Vec::new();
+		let mut errors = Vec::new();
+
+		for (index, input) in self.inputs.iter().enumerate() {
+			let mut sections: Vec<Placed> = input
+				.object
+				.sections
+				.iter()
+				.zip(&input.addresses)
+				.map(|(section, address)| Placed {
+					name: section.name.clone(),
+					address: address.unwrap(),
+					data: section.data.clone(),
+				})
+				.collect();
+
+			for relocation in &input.object.relocations {
+				let target = match relocation.target {
+					RelocationTarget::Absolute => Some(0),
+					RelocationTarget::Section(section) => input.addresses[section as usize],
+					RelocationTarget::Symbol(symbol) => self.resolve(index, symbol as usize),
+				};
+				let section = &mut sections[relocation.section as usize];
+				let Some(target) = target else {
+					let RelocationTarget::Symbol(symbol) = relocation.target else {
+						unreachable!()
+					};
+					errors.push(format!(
+						"undefined symbol \"{}\" (referenced by {} at {}+{:#x})",
+						input.object.symbols[symbol as usize].name,
+						input.name,
+						section.name,
+						relocation.offset
+					));
+					continue;
+				};
+
+				let result = section
+					.data
+					.get_mut(relocation.offset as usize..)
+					.ok_or(RelocationError::OutOfBounds)
+					.and_then(|data| {
+						relocation.kind.apply(
+							data,
+							section.address + relocation.offset,
+							target.wrapping_add(relocation.addend),
+							relocation.shift,
+						)
+					});
+				if let Err(error) = result {
+					errors.push(format!(
+						"{} at {}+{:#x}: {}",
+						input.name, section.name, relocation.offset, error
+					));
+				}
+			}
+
+			placed.extend(sections);
+		}
+
+		self.errors.extend(errors);
+		placed.sort_by_key(|section| section.address);
+		placed
+	}
+
+	/// Returns every symbol that has an address, sorted by address.
+	fn symbols(&self) -> Vec<(String, u64, bool)> {
+		let mut symbols = Vec::new();
+		for (index, input) in self.inputs.iter().enumerate() {
+			for symbol in &input.object.symbols {
+				if let SymbolValue::Section { .. } = symbol.value {
+					let address = self.symbol_address(index, &symbol.value).unwrap();
+					symbols.push((
+						symbol.name.clone(),
+						address,
+						symbol.binding == Binding::Global,
+					));
+				}
+			}
+		}
+		for (name, definition) in &self.globals {
+			if let Definition::Script(address) = definition {
+				symbols.push((name.clone(), *address, true));
+			}
+		}
+		symbols.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+		symbols
+	}
+}
+
+/// Merges adjacent sections with the same name (e.g. the `.text`s of several objects), filling the gaps between them.
+fn merge_sections(placed: Vec<Placed>) -> Vec<Placed> {
+	let mut merged: Vec<Placed> = Vec::new();
+	for section in placed {
+		if section.data.is_empty() {
+			continue;
+		}
+		match merged.last_mut() {
+			Some(last) if last.name == section.name => {
+				last.data
+					.resize((section.address - last.address) as usize, 0);
+				last.data.extend_from_slice(&section.data);
+			},
+			_ => merged.push(section),
+		}
+	}
+	merged
+}
+
+fn main() {
+	let cli = Args::parse();
+
+	let script = match &cli.script {
+		Some(path) => {
+			let text = fs::read_to_string(path).unwrap_or_else(|e| {
+				eprintln!("Failed to read \"{}\": {}", path.display(), e);
+				exit(1);
+			});
+			Script::parse(&text).unwrap_or_else(|e| {
+				eprintln!("Invalid linker script \"{}\": {}", path.display(), e);
+				exit(1);
+			})
+		},
+		None => Script::default(),
+	};
+
+	let mut linker = Linker {
+		inputs: Vec::new(),
+		globals: HashMap::new(),
+		errors: Vec::new(),
+	};
+	for path in &cli.objects {
+		let bytes = fs::read(path).unwrap_or_else(|e| {
+			eprintln!("Failed to read \"{}\": {}", path.display(), e);
+			exit(1);
+		});
+		let object = Object::parse(&bytes).unwrap_or_else(|e| {
+			eprintln!("Failed to load \"{}\": {}", path.display(), e);
+			exit(1);
+		});
+		linker.inputs.push(Input {
+			name: path.display().to_string(),
+			addresses: vec![None; object.sections.len()],
+			object,
+		});
+	}
+
+	linker.collect_globals();
+	let entry = linker.run_script(&script);
+	linker.check_overlaps();
+	let placed = linker.relocate();
+
+	// the emulator starts at 0x400 unless told otherwise
+	let entry = match entry {
+		Some(EntryPoint::Address(address)) => address,
+		Some(EntryPoint::Symbol(name)) => match linker.globals.get(&name) {
+			Some(definition) => linker.definition_address(*definition).unwrap(),
+			None => {
+				linker.error(format!("undefined entry point \"{}\"", name));
+				0
+			},
+		},
+		None => linker
+			.globals
+			.get("entry")
+			.and_then(|definition| linker.definition_address(*definition))
+			.unwrap_or(0x400),
+	};
+	linker.check();
+
+	let sections = merge_sections(placed);
+	let symbols = linker.symbols();
+
+	let output = match cli.format {
+		OutputFormat::Flat => {
+			let mut image = Vec::new();
+			for section in &sections {
+				let start = section.address as usize;
+				if image.len() < start + section.data.len() {
+					image.resize(start + section.data.len(), 0);
+				}
+				image[start..start + section.data.len()].copy_from_slice(&section.data);
+			}
+			image
+		},
+		OutputFormat::Elf => {
+			let elf_sections: Vec<_> = sections
+				.iter()
+				.map(|section| ElfSection {
+					name: &section.name,
+					address: section.address,
+					data: &section.data,
+					code: section.name.starts_with(".text"),
+				})
+				.collect();
+			let elf_symbols: Vec<_> = symbols
+				.iter()
+				.map(|(name, address, global)| ElfSymbol {
+					name,
+					address: *address,
+					global: *global,
+				})
+				.collect();
+			write_elf(entry, &elf_sections, &elf_symbols)
+		},
+	};
+
+	if let Some(symbol_map_path) = &cli.symbol_map {
+		let contents: String = symbols
+			.iter()
+			.map(|(name, address, _)| format!("{:#018x} {}\n", address, name))
+			.collect();
+
+		if let Err(err) = fs::write(symbol_map_path, contents) {
+			eprintln!("Failed to write symbol map: {}", err);
+			exit(1);
+		}
+	}
+
+	if let Err(err) = fs::write(&cli.output, output) {
+		eprintln!("Failed to write output file: {}", err);
+		exit(1);
+	}
+}
@@ -0,0 +1,148 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+//! Linker scripts, which say where sections go.
+//!
+//! A script is a list of commands, one per line, executed in order while keeping track of the current address (which
+//! starts at 0). `#` starts a comment.
+//!
+//! ```text
+//! entry SYMBOL|ADDRESS   # the entry point of ELF executables
+//! at ADDRESS             # moves the current address
+//! align ALIGNMENT        # aligns the current address to a power of two
+//! place PATTERN...       # places every section not placed yet whose name matches a pattern, in input order
+//! symbol NAME            # defines a global symbol at the current address
+//! ```
+//!
+//! Patterns may contain `*`, which matches any number of characters. Sections left over at the end of the script are
+//! placed as if by `place *`.
+
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryPoint {
+	Symbol(String),
+	Address(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+	Entry(EntryPoint),
+	At(u64),
+	Align(u64),
+	Place(Vec<String>),
+	Symbol(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+	pub commands: Vec<Command>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+	/// 1-based.
+	pub line: usize,
+	pub message: String,
+}
+
+impl Display for ScriptError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "line {}: {}", self.line, self.message)
+	}
+}
+
+/// Parses a number written like an integer literal in assembly (e.g. `0x400` or `1_000`).
+pub fn parse_number(string: &str) -> Option<u64> {
+	let string = string.replace('_', "");
+	match string.get(..2) {
+		Some("0x" | "0X") => u64::from_str_radix(&string[2..], 16).ok(),
+		Some("0o" | "0O") => u64::from_str_radix(&string[2..], 8).ok(),
+		Some("0b" | "0B") => u64::from_str_radix(&string[2..], 2).ok(),
+		Some("0d" | "0D") => string[2..].parse().ok(),
+		_ => string.parse().ok(),
+	}
+}
+
+/// Checks whether `name` matches `pattern`, where `*` matches any number of characters.
+pub fn matches(pattern: &str, name: &str) -> bool {
+	match pattern.split_once('*') {
+		None => pattern == name,
+		Some((prefix, rest)) => {
+			let Some(name) = name.strip_prefix(prefix) else {
+				return false;
+			};
+			(0..=name.len())
+				.filter(|&index| name.is_char_boundary(index))
+				.any(|index| matches(rest, &name[index..]))
+		},
+	}
+}
+
+impl Script {
+	pub fn parse(text: &str) -> Result<Self, ScriptError> {
+		let mut script = Script::default();
+
+		for (index, line) in text.lines().enumerate() {
+			let error = |message: String| ScriptError {
+				line: index + 1,
+				message,
+			};
+			let line = line.split('#').next().unwrap();
+			let mut words = line.split_whitespace();
+			let Some(command) = words.next() else {
+				continue;
+			};
+			let arguments: Vec<&str> = words.collect();
+
+			let single = || match arguments[..] {
+				[argument] => Ok(argument),
+				_ => Err(error(format!("\"{}\" takes exactly one argument", command))),
+			};
+			let number = || {
+				let argument = single()?;
+				parse_number(argument)
+					.ok_or_else(|| error(format!("invalid number \"{}\"", argument)))
+			};
+
+			script.commands.push(match command {
+				"entry" => {
+					let argument = single()?;
+					Command::Entry(match parse_number(argument) {
+						Some(address) => EntryPoint::Address(address),
+						None => EntryPoint::Symbol(argument.to_string()),
+					})
+				},
+				"at" => Command::At(number()?),
+				"align" => {
+					let alignment = number()?;
+					if !alignment.is_power_of_two() {
+						return Err(error(format!(
+							"alignment {} isn't a power of two",
+							alignment
+						)));
+					}
+					Command::Align(alignment)
+				},
+				"place" if arguments.is_empty() => {
+					return Err(error("\"place\" needs at least one pattern".to_string()));
+				},
+				"place" => Command::Place(
+					arguments
+						.iter()
+						.map(|pattern| pattern.to_string())
+						.collect(),
+				),
+				"symbol" => Command::Symbol(single()?.to_string()),
+				_ => return Err(error(format!("unknown command \"{}\"", command))),
+			});
+		}
+
+		Ok(script)
+	}
+}
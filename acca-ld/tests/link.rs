@@ -0,0 +1,200 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	collections::HashMap,
+	fs,
+	path::{Path, PathBuf},
+	process::{Command, Output},
+};
+
+const SCRIPT: &str = "
+at 0x400
+place .text
+
+at 0x2000
+place .data
+";
+
+/// Calls into (and points at data in) the other object.
+const MAIN: &str = "
+.global entry
+
+.section .text
+entry:
+	callr helper
+	ldi r0, value & 0xffff, 0, 3
+	cjmpr.z helper, r0, r1
+
+.section .data
+pointer:
+	.write.w value
+";
+
+/// Points back at the first object.
+const HELPER: &str = "
+.global helper, value
+
+.section .text
+helper:
+	ret
+
+.section .data
+value:
+	.write.w entry
+";
+
+/// Conditionally jumps to a function placed further away than a `cjmpr` can reach.
+const FAR_MAIN: &str = "
+.global entry
+
+.section .text
+entry:
+	callr far
+	cjmpr.z far, r0, r1
+";
+
+const FAR_HELPER: &str = "
+.global far
+
+.section .far
+far:
+	ret
+";
+
+const FAR_SCRIPT: &str = "
+at 0x400
+place .text
+
+at 0x100000
+place .far
+";
+
+fn scratch_directory(name: &str) -> PathBuf {
+	let directory = std::env::temp_dir().join(format!("acca-ld-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&directory);
+	fs::create_dir_all(&directory).unwrap();
+	directory
+}
+
+/// Assembles each source into an object in `directory`, then links them with `script`.
+fn link(directory: &Path, sources: &[(&str, &str)], script: &str) -> Output {
+	let script_path = directory.join("link.ld");
+	fs::write(&script_path, script).unwrap();
+
+	let mut command = Command::new(env!("CARGO_BIN_EXE_acca-ld"));
+	for (name, source) in sources {
+		let object = acca_as::assemble_object(source, &Default::default()).unwrap();
+		let path = directory.join(name);
+		fs::write(&path, object.object.to_bytes()).unwrap();
+		command.arg(path);
+	}
+	command
+		.arg("-T")
+		.arg(script_path)
+		.arg("-o")
+		.arg(directory.join("linked.bin"))
+		.arg("--symbol-map")
+		.arg(directory.join("linked.map"))
+		.output()
+		.unwrap()
+}
+
+fn read_symbol_map(path: &Path) -> HashMap<String, u64> {
+	fs::read_to_string(path)
+		.unwrap()
+		.lines()
+		.map(|line| {
+			let (address, name) = line.split_once(' ').unwrap();
+			let address = u64::from_str_radix(address.trim_start_matches("0x"), 16).unwrap();
+			(name.to_string(), address)
+		})
+		.collect()
+}
+
+fn word(image: &[u8], address: u64) -> u32 {
+	let address = address as usize;
+	u32::from_le_bytes(image[address..address + 4].try_into().unwrap())
+}
+
+fn double_word(image: &[u8], address: u64) -> u64 {
+	let address = address as usize;
+	u64::from_le_bytes(image[address..address + 8].try_into().unwrap())
+}
+
+/// The instruction offset from the instruction at `from` to `to`, truncated to `bits` bits.
+fn relative(from: u64, to: u64, bits: u32) -> u32 {
+	((to.wrapping_sub(from + 4) as i64 / 4) as u32) & ((1 << bits) - 1)
+}
+
+#[test]
+fn links_references_between_objects() {
+	let directory = scratch_directory("cross");
+	let output = link(
+		&directory,
+		&[("main.o", MAIN), ("helper.o", HELPER)],
+		SCRIPT,
+	);
+	assert!(
+		output.status.success(),
+		"acca-ld failed: {}",
+		String::from_utf8_lossy(&output.stderr)
+	);
+
+	let image = fs::read(directory.join("linked.bin")).unwrap();
+	let symbols = read_symbol_map(&directory.join("linked.map"));
+	let entry = symbols["entry"];
+	let helper = symbols["helper"];
+	let value = symbols["value"];
+	assert_eq!(entry, 0x400);
+	assert!(helper > entry && helper < 0x2000);
+	assert!(value >= 0x2000);
+
+	// Rel22
+	assert_eq!(word(&image, entry) & 0x3f_ffff, relative(entry, helper, 22));
+	// Ldi16
+	assert_eq!(
+		(word(&image, entry + 4) >> 10) & 0xffff,
+		(value & 0xffff) as u32
+	);
+	// Rel13
+	assert_eq!(
+		word(&image, entry + 8) & 0x1fff,
+		relative(entry + 8, helper, 13)
+	);
+	// Data64, in both directions
+	assert_eq!(double_word(&image, symbols["pointer"]), value);
+	assert_eq!(double_word(&image, value), entry);
+
+	fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn rejects_out_of_range_relative_jumps() {
+	let directory = scratch_directory("far");
+	let output = link(
+		&directory,
+		&[("main.o", FAR_MAIN), ("far.o", FAR_HELPER)],
+		FAR_SCRIPT,
+	);
+	assert!(!output.status.success());
+
+	// `callr` can reach it, but `cjmpr` can't
+	let stderr = String::from_utf8(output.stderr).unwrap();
+	let errors: Vec<_> = stderr.lines().collect();
+	assert_eq!(errors.len(), 2, "unexpected errors: {}", stderr);
+	assert!(
+		errors[0].contains("main.o at .text+0x4: value")
+			&& errors[0].ends_with("doesn't fit in the relocated field"),
+		"unexpected error: {}",
+		errors[0]
+	);
+	assert_eq!(errors[1], "error: linking failed due to 1 error(s)");
+
+	fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,616 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fmt::Write as _,
+	fs,
+	io::{self, BufWriter, Write},
+	path::PathBuf,
+	process::exit,
+};
+
+use acca_as::object::{Binding, Object, Relocation, RelocationTarget, SymbolValue};
+use acca_emu::{
+	disasm::{decode, format_instruction, relative_target},
+	symbols::{parse_address, SymbolTable},
+};
+use clap::Parser as ClapParser;
+use goblin::elf::{program_header::PT_LOAD, section_header, Elf};
+
+#[derive(ClapParser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+	input: PathBuf,
+
+	#[arg(short, long)]
+	output: Option<PathBuf>,
+
+	#[arg(long, value_name = "FILE")]
+	symbols: Option<PathBuf>,
+
+	#[arg(long, value_name = "ADDRESS", default_value = "0")]
+	base: String,
+
+	#[arg(long, value_name = "ADDRESS")]
+	start: Option<String>,
+
+	#[arg(long, value_name = "ADDRESS")]
+	end: Option<String>,
+}
+
+/// A contiguous range of the input's memory image.
+struct Section {
+	name: Option<String>,
+	address: u64,
+	bytes: Vec<u8>,
+	/// Whether the section contains instructions (rather than data).
+	code: bool,
+	/// Whether the section is from a relocatable object, where addresses are offsets within the section.
+	relocatable: bool,
+}
+
+/// Sections that share an address space, along with their symbols and relocations.
+struct Unit {
+	sections: Vec<Section>,
+	symbols: SymbolTable,
+	relocations: BTreeMap<u64, String>,
+}
+
+/// Runs of at least this many zero words are skipped over with `.addr` rather than listed.
+const MIN_ZERO_RUN: usize = 4;
+
+/// The column at which the address and encoding comments start.
+const COMMENT_COLUMN: usize = 40;
+
+/// The number of bytes written by each line of a data section.
+const DATA_LINE_BYTES: usize = 16;
+
+impl Section {
+	fn end(&self) -> u64 {
+		self.address + self.bytes.len() as u64
+	}
+
+	fn contains(&self, address: u64) -> bool {
+		(self.address..self.end()).contains(&address)
+	}
+
+	fn word_at(&self, address: u64) -> Option<u32> {
+		let offset = address.checked_sub(self.address)? as usize;
+		self.bytes
+			.get(offset..offset + 4)
+			.map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+	}
+
+	/// Keeps only the part of the section that's within `start..end`.
+	fn clamp(mut self, start: u64, end: u64) -> Option<Self> {
+		let new_start = self.address.max(start);
+		let new_end = self.end().min(end);
+		if new_start >= new_end {
+			return None;
+		}
+		self.bytes = self.bytes
+			[(new_start - self.address) as usize..(new_end - self.address) as usize]
+			.to_vec();
+		self.address = new_start;
+		Some(self)
+	}
+}
+
+/// Splits an object into one unit per section, since each section's offsets start at 0.
+fn object_units(object: &Object) -> Vec<Unit> {
+	object
+		.sections
+		.iter()
+		.enumerate()
+		.map(|(index, section)| {
+			let mut symbols = SymbolTable::new();
+			for symbol in &object.symbols {
+				if let SymbolValue::Section {
+					section: symbol_section,
+					offset,
+				} = symbol.value
+				{
+					if symbol_section as usize == index {
+						symbols.insert(&symbol.name, offset.into());
+					}
+				}
+			}
+
+			let relocations = object
+				.relocations
+				.iter()
+				.filter(|relocation| relocation.section as usize == index)
+				.map(|relocation| (relocation.offset, describe_relocation(object, relocation)))
+				.collect();
+
+			Unit {
+				sections: vec![Section {
+					name: Some(section.name.clone()),
+					address: 0,
+					bytes: section.data.clone(),
+					code: section.name.starts_with(".text"),
+					relocatable: true,
+				}],
+				symbols,
+				relocations,
+			}
+		})
+		.collect()
+}
+
+fn elf_sections(elf: &Elf, bytes: &[u8]) -> Result<Vec<Section>, String> {
+	let file_range = |offset: u64, size: u64| {
+		bytes
+			.get(offset as usize..(offset + size) as usize)
+			.map(|bytes| bytes.to_vec())
+			.ok_or_else(|| "section extends past the end of the file".to_owned())
+	};
+
+	let mut sections = Vec::new();
+
+	for header in &elf.section_headers {
+		if header.sh_flags & u64::from(section_header::SHF_ALLOC) == 0
+			|| header.sh_type == section_header::SHT_NOBITS
+			|| header.sh_size == 0
+		{
+			continue;
+		}
+
+		sections.push(Section {
+			name: elf
+				.shdr_strtab
+				.get_at(header.sh_name)
+				.map(|name| name.to_owned()),
+			address: header.sh_addr,
+			bytes: file_range(header.sh_offset, header.sh_size)?,
+			code: header.sh_flags & u64::from(section_header::SHF_EXECINSTR) != 0,
+			relocatable: false,
+		});
+	}
+
+	// without section headers, all we have to go on are the segments
+	if sections.is_empty() {
+		for header in &elf.program_headers {
+			if header.p_type != PT_LOAD || header.p_filesz == 0 {
+				continue;
+			}
+
+			sections.push(Section {
+				name: None,
+				address: header.p_vaddr,
+				bytes: file_range(header.p_offset, header.p_filesz)?,
+				code: true,
+				relocatable: false,
+			});
+		}
+	}
+
+	Ok(sections)
+}
+
+/// Checks whether the assembler would accept `name` as a label.
+fn is_label_name(name: &str) -> bool {
+	let mut chars = name.chars();
+	let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+	// names that would be parsed as something else
+	let is_register = name
+		.strip_prefix('r')
+		.map(|rest| {
+			let rest = rest.trim_end_matches(['b', 'd', 'q', 'w']);
+			matches!(rest, "sp" | "fp" | "lr") || rest.parse::<u8>().is_ok_and(|id| id <= 15)
+		})
+		.unwrap_or(false);
+
+	valid && !is_register && !matches!(name, "true" | "false" | "null" | "mreg")
+}
+
+/// Describes what the linker fills in for `relocation`, e.g. `Rel22 print_string` or `Ldi16 .data+0x10 >> 16`.
+fn describe_relocation(object: &Object, relocation: &Relocation) -> String {
+	let mut description = format!("{:?} ", relocation.kind);
+	match relocation.target {
+		RelocationTarget::Absolute => {
+			let _ = write!(description, "{:#x}", relocation.addend);
+		},
+		RelocationTarget::Section(index) | RelocationTarget::Symbol(index) => {
+			let name = match relocation.target {
+				RelocationTarget::Section(_) => &object.sections[index as usize].name,
+				_ => &object.symbols[index as usize].name,
+			};
+			description.push_str(name);
+			// addends are two's complement
+			match relocation.addend as i64 {
+				0 => {},
+				addend if addend < 0 => {
+					let _ = write!(description, "-{:#x}", addend.unsigned_abs());
+				},
+				addend => {
+					let _ = write!(description, "+{:#x}", addend);
+				},
+			}
+		},
+	}
+	if relocation.shift != 0 {
+		let _ = write!(description, " >> {}", relocation.shift);
+	}
+	description
+}
+
+/// Writes the symbol table of `object` as comments.
+fn write_object_symbols(output: &mut dyn Write, object: &Object) -> io::Result<()> {
+	writeln!(
+		output,
+		"
+# symbols"
+	)?;
+	for symbol in &object.symbols {
+		let value = match symbol.value {
+			SymbolValue::Undefined => "undefined".to_owned(),
+			SymbolValue::Absolute(value) => format!("{:#x}", value),
+			SymbolValue::Section { section, offset } => {
+				format!("{}+{:#x}", object.sections[section as usize].name, offset)
+			},
+		};
+		let binding = match symbol.binding {
+			Binding::Local => "local",
+			Binding::Global => "global",
+		};
+		writeln!(output, "#	{:<6} {:<24} {}", binding, value, symbol.name)?;
+	}
+	Ok(())
+}
+
+struct Disassembler<'a> {
+	sections: &'a [Section],
+	symbols: &'a SymbolTable,
+	/// Descriptions of the relocations of each address.
+	relocations: &'a BTreeMap<u64, String>,
+	/// Addresses that are referred to by instructions but don't have a usable symbol.
+	local_labels: BTreeSet<u64>,
+}
+
+impl<'a> Disassembler<'a> {
+	fn new(
+		sections: &'a [Section],
+		symbols: &'a SymbolTable,
+		relocations: &'a BTreeMap<u64, String>,
+	) -> Self {
+		let mut result = Self {
+			sections,
+			symbols,
+			relocations,
+			local_labels: BTreeSet::new(),
+		};
+
+		for section in sections.iter().filter(|section| section.code) {
+			for address in (section.address..section.end()).step_by(4) {
+				// relocated operands are filled in by the linker, so they don't point anywhere yet
+				if result.relocations.contains_key(&address) {
+					continue;
+				}
+
+				let target = match section
+					.word_at(address)
+					.and_then(decode)
+					.and_then(|instruction| relative_target(&instruction, address))
+				{
+					Some(x) => x,
+					None => continue,
+				};
+
+				let listed = sections
+					.iter()
+					.any(|section| section.code && section.contains(target));
+				if listed && target.is_multiple_of(4) && result.symbol_at(target).is_none() {
+					result.local_labels.insert(target);
+				}
+			}
+		}
+
+		result
+	}
+
+	fn symbol_at(&self, address: u64) -> Option<&'a str> {
+		match self.symbols.lookup(address.into()) {
+			Some((name, 0)) if is_label_name(name) => Some(name),
+			_ => None,
+		}
+	}
+
+	fn label_at(&self, address: u64) -> Option<String> {
+		self.symbol_at(address)
+			.map(|name| name.to_owned())
+			.or_else(|| {
+				self.local_labels
+					.contains(&address)
+					.then(|| format!("loc_{:x}", address))
+			})
+	}
+
+	fn has_label_in(&self, start: u64, end: u64) -> bool {
+		(start..end).any(|address| self.label_at(address).is_some())
+	}
+
+	/// Appends the relocations of the bytes in `start..end` to `comment`.
+	fn with_relocations(&self, mut comment: String, start: u64, end: u64) -> String {
+		for description in self
+			.relocations
+			.range(start..end)
+			.map(|(_, description)| description)
+		{
+			let _ = write!(comment, " [{}]", description);
+		}
+		comment
+	}
+
+	fn write_line(output: &mut dyn Write, text: &str, comment: &str) -> io::Result<()> {
+		writeln!(
+			output,
+			"\t{:<width$} # {}",
+			text,
+			comment,
+			width = COMMENT_COLUMN
+		)
+	}
+
+	fn write_label(&self, output: &mut dyn Write, address: u64) -> io::Result<()> {
+		match self.label_at(address) {
+			Some(label) => writeln!(output, "{}:", label),
+			None => Ok(()),
+		}
+	}
+
+	fn write_code(&self, output: &mut dyn Write, section: &Section) -> io::Result<()> {
+		let mut address = section.address;
+
+		while address < section.end() {
+			self.write_label(output, address)?;
+
+			let word = match section.word_at(address) {
+				Some(word)
+					if address.is_multiple_of(4)
+						&& !self.has_label_in(address + 1, address + 4) =>
+				{
+					word
+				},
+				// a partial or misaligned word (or one with a label in the middle of it) can only be written as data
+				_ => {
+					let byte = section.bytes[(address - section.address) as usize];
+					Self::write_line(
+						output,
+						&format!(".write.b {:#04x}", byte),
+						&format!("{:#x}", address),
+					)?;
+					address += 1;
+					continue;
+				},
+			};
+
+			if word == 0 {
+				let run_end = (address..section.end())
+					.step_by(4)
+					.find(|&next| section.word_at(next) != Some(0))
+					.unwrap_or(section.end());
+				// keep the last zero word of a run at the end of the section so that the image keeps its size
+				let mut skip_to = if run_end == section.end() {
+					run_end - 4
+				} else {
+					run_end
+				};
+				// labels within the run still need to be written
+				if let Some(label) =
+					(address + 1..skip_to).find(|&next| self.label_at(next).is_some())
+				{
+					skip_to = label;
+				}
+
+				if (skip_to - address) as usize >= MIN_ZERO_RUN * 4 {
+					writeln!(output, "\t.addr {:#x}", skip_to)?;
+					address = skip_to;
+					continue;
+				}
+			}
+
+			let text = match decode(word) {
+				Some(instruction) => format_instruction(&instruction, address, &|target| {
+					self.label_at(target)
+						.unwrap_or_else(|| format!("{:#x}", target))
+				}),
+				None => format!(".write.q {:#010x}", word),
+			};
+			let comment = self.with_relocations(
+				format!("{:#x}: {:08x}", address, word),
+				address,
+				address + 4,
+			);
+			Self::write_line(output, &text, &comment)?;
+
+			address += 4;
+		}
+
+		Ok(())
+	}
+
+	fn write_data(&self, output: &mut dyn Write, section: &Section) -> io::Result<()> {
+		let mut address = section.address;
+
+		while address < section.end() {
+			self.write_label(output, address)?;
+
+			// break lines at labels so that they end up at the right address, and at relocations so that each one is
+			// listed next to the bytes it fills in
+			let mut line_end = (address + DATA_LINE_BYTES as u64).min(section.end());
+			if let Some(label) = (address + 1..line_end)
+				.find(|&next| self.label_at(next).is_some() || self.relocations.contains_key(&next))
+			{
+				line_end = label;
+			}
+
+			let bytes = &section.bytes
+				[(address - section.address) as usize..(line_end - section.address) as usize];
+			let values: Vec<String> = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+			Self::write_line(
+				output,
+				&format!(".write.b {}", values.join(", ")),
+				&self.with_relocations(format!("{:#x}", address), address, line_end),
+			)?;
+
+			address = line_end;
+		}
+
+		Ok(())
+	}
+
+	fn write(&self, output: &mut dyn Write) -> io::Result<()> {
+		for section in self.sections {
+			writeln!(output)?;
+			match &section.name {
+				Some(name) if section.relocatable => writeln!(output, "\t.section {}", name)?,
+				Some(name) => writeln!(output, "# section {}", name)?,
+				None => {},
+			}
+			writeln!(output, "\t.addr {:#x}", section.address)?;
+
+			if section.code {
+				self.write_code(output, section)?;
+			} else {
+				self.write_data(output, section)?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+fn parse_address_arg(name: &str, value: &str) -> u64 {
+	match parse_address(value) {
+		Some(x) => x,
+		None => {
+			eprintln!("Invalid {} address \"{}\"", name, value);
+			exit(1);
+		},
+	}
+}
+
+fn main() {
+	let cli = Args::parse();
+
+	let bytes = match fs::read(&cli.input) {
+		Ok(x) => x,
+		Err(e) => {
+			eprintln!("Failed to read \"{}\": {}", cli.input.display(), e);
+			exit(1);
+		},
+	};
+
+	let mut object = None;
+	let mut units = if bytes.starts_with(b"\x7fELF") {
+		let elf = match Elf::parse(&bytes) {
+			Ok(x) => x,
+			Err(e) => {
+				eprintln!("Failed to parse \"{}\": {}", cli.input.display(), e);
+				exit(1);
+			},
+		};
+
+		match elf_sections(&elf, &bytes) {
+			Ok(sections) => vec![Unit {
+				sections,
+				symbols: SymbolTable::from_elf(&elf),
+				relocations: BTreeMap::new(),
+			}],
+			Err(e) => {
+				eprintln!("Failed to read \"{}\": {}", cli.input.display(), e);
+				exit(1);
+			},
+		}
+	} else if Object::is_object(&bytes) {
+		match Object::parse(&bytes) {
+			Ok(parsed) => object_units(object.insert(parsed)),
+			Err(e) => {
+				eprintln!("Failed to parse \"{}\": {}", cli.input.display(), e);
+				exit(1);
+			},
+		}
+	} else {
+		let section = Section {
+			name: None,
+			address: parse_address_arg("base", &cli.base),
+			bytes,
+			code: true,
+			relocatable: false,
+		};
+		vec![Unit {
+			sections: vec![section],
+			symbols: SymbolTable::new(),
+			relocations: BTreeMap::new(),
+		}]
+	};
+
+	if let Some(path) = &cli.symbols {
+		let symbols = match SymbolTable::load(path) {
+			Ok(x) => x,
+			Err(e) => {
+				eprintln!("Failed to load symbols from \"{}\": {}", path.display(), e);
+				exit(1);
+			},
+		};
+		for unit in &mut units {
+			unit.symbols = symbols.clone();
+		}
+	}
+
+	let start = cli
+		.start
+		.as_ref()
+		.map(|value| parse_address_arg("start", value))
+		.unwrap_or(0);
+	let end = cli
+		.end
+		.as_ref()
+		.map(|value| parse_address_arg("end", value))
+		.unwrap_or(u64::MAX);
+
+	for unit in &mut units {
+		unit.sections = std::mem::take(&mut unit.sections)
+			.into_iter()
+			.filter_map(|section| section.clamp(start, end))
+			.collect();
+		unit.sections.sort_by_key(|section| section.address);
+	}
+
+	let mut output: Box<dyn Write> = match &cli.output {
+		Some(path) => match fs::File::create(path) {
+			Ok(file) => Box::new(BufWriter::new(file)),
+			Err(e) => {
+				eprintln!("Failed to create \"{}\": {}", path.display(), e);
+				exit(1);
+			},
+		},
+		None => Box::new(BufWriter::new(io::stdout())),
+	};
+
+	let result = writeln!(output, "# disassembly of {}", cli.input.display())
+		.and_then(|_| match &object {
+			Some(object) => write_object_symbols(&mut output, object),
+			None => Ok(()),
+		})
+		.and_then(|_| {
+			units.iter().try_for_each(|unit| {
+				Disassembler::new(&unit.sections, &unit.symbols, &unit.relocations)
+					.write(&mut output)
+			})
+		})
+		.and_then(|_| output.flush());
+	if let Err(e) = result {
+		eprintln!("Failed to write disassembly: {}", e);
+		exit(1);
+	}
+}
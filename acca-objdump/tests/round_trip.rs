@@ -0,0 +1,79 @@
+//
+// Copyright (C) 2023 Ariel Abreu
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//
+
+use std::{fs, path::Path, process::Command};
+
+use acca_as::AssembleOptions;
+
+/// Disassembles the image assembled from `example` and checks that the disassembly assembles to the same image.
+fn round_trip(example: &Path) {
+	let source = fs::read_to_string(example).unwrap();
+	let options = AssembleOptions {
+		source_path: Some(example.to_owned()),
+		..Default::default()
+	};
+	let image = acca_as::assemble(&source, &options)
+		.unwrap_or_else(|e| panic!("failed to assemble {}: {:?}", example.display(), e));
+
+	let name = example.file_stem().unwrap().to_str().unwrap();
+	let directory =
+		std::env::temp_dir().join(format!("acca-objdump-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&directory);
+	fs::create_dir_all(&directory).unwrap();
+	let image_path = directory.join("image.bin");
+	let listing_path = directory.join("image.acca");
+	fs::write(&image_path, &image.bytes).unwrap();
+
+	let output = Command::new(env!("CARGO_BIN_EXE_acca-objdump"))
+		.arg(&image_path)
+		.arg("-o")
+		.arg(&listing_path)
+		.output()
+		.unwrap();
+	assert!(
+		output.status.success(),
+		"acca-objdump failed on {}: {}",
+		example.display(),
+		String::from_utf8_lossy(&output.stderr)
+	);
+
+	let listing = fs::read_to_string(&listing_path).unwrap();
+	let reassembled = acca_as::assemble(&listing, &Default::default()).unwrap_or_else(|e| {
+		panic!(
+			"failed to reassemble the disassembly of {}: {:?}",
+			example.display(),
+			e
+		)
+	});
+	assert!(
+		reassembled.bytes == image.bytes,
+		"the disassembly of {} doesn't reassemble to the same image",
+		example.display()
+	);
+
+	fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn examples_round_trip() {
+	let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("../acca-as/examples");
+	let mut paths = fs::read_dir(examples)
+		.unwrap()
+		.map(|entry| entry.unwrap().path())
+		.filter(|path| {
+			path.extension()
+				.is_some_and(|extension| extension == "acca")
+		})
+		.collect::<Vec<_>>();
+	paths.sort();
+	assert!(!paths.is_empty());
+
+	for path in paths {
+		round_trip(&path);
+	}
+}
@@ -10,7 +10,7 @@
 #include <stdint.h>
 #include <acca/acca.h>
 
-#define MREG_VM_CONSOLE 0xdead1
+#define CONSOLE_ADDRESS 0x10000000
 
 __attribute__((section(".data.econfig")))
 acca_evt_t global_evt;
@@ -19,7 +19,7 @@ __attribute__((section(".data.econfig")))
 acca_ect_t global_ect;
 
 void print_char(char character) {
-	__asm__ __volatile__("stm %0, %1" :: "i" (MREG_VM_CONSOLE), "r" ((uint64_t)character));
+	*(volatile uint8_t*)CONSOLE_ADDRESS = (uint8_t)character;
 };
 
 void print_string(const char* string) {
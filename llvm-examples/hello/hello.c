@@ -9,10 +9,10 @@
 #include <stdint.h>
 #include <acca/acca.h>
 
-#define MREG_VM_CONSOLE 0xdead1
+#define CONSOLE_ADDRESS 0x10000000
 
 void print_char(char character) {
-	__asm__ __volatile__("stm %0, %1" :: "i" (MREG_VM_CONSOLE), "r" ((uint64_t)character));
+	*(volatile uint8_t*)CONSOLE_ADDRESS = (uint8_t)character;
 };
 
 void print_string(const char* string) {